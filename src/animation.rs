@@ -0,0 +1,186 @@
+use std::time::Duration;
+
+use crate::model::{Material, MaterialUniform};
+
+/// A single scalar field of [`MaterialUniform`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaterialParam {
+    /// One of the four base color channels.
+    BaseColor(usize),
+    /// One of the three emissive color channels.
+    Emissive(usize),
+    EmissiveIntensity,
+}
+
+impl MaterialParam {
+    fn field<'a>(&self, uniform: &'a mut MaterialUniform) -> &'a mut f32 {
+        match *self {
+            Self::BaseColor(channel) => &mut uniform.base_color[channel],
+            Self::Emissive(channel) => &mut uniform.emissive[channel],
+            Self::EmissiveIntensity => &mut uniform.emissive_intensity,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keyframe {
+    /// Seconds since the start of the animation.
+    pub time: f32,
+    pub value: f32,
+}
+
+/// Writes the animated parameters for a time into the uniform.
+type MaterialCurve = Box<dyn Fn(f32, &mut MaterialUniform) + Send + Sync>;
+
+enum Driver {
+    Keyframes {
+        param: MaterialParam,
+        keyframes: Vec<Keyframe>,
+    },
+    Closure(MaterialCurve),
+}
+
+impl Driver {
+    fn apply(&self, time: f32, uniform: &mut MaterialUniform) {
+        match self {
+            Self::Keyframes { param, keyframes } => {
+                if let Some(value) = sample_keyframes(keyframes, time) {
+                    *param.field(uniform) = value;
+                }
+            }
+            Self::Closure(f) => f(time, uniform),
+        }
+    }
+}
+
+/// Linearly interpolates between the keyframes around `time`, holding the
+/// first and last values outside of them.
+fn sample_keyframes(keyframes: &[Keyframe], time: f32) -> Option<f32> {
+    let first = keyframes.first()?;
+    let last = keyframes.last()?;
+    if time <= first.time {
+        return Some(first.value);
+    }
+    if time >= last.time {
+        return Some(last.value);
+    }
+
+    let next = keyframes.partition_point(|k| k.time <= time);
+    let (a, b) = (keyframes[next - 1], keyframes[next]);
+    let t = (time - a.time) / (b.time - a.time);
+    Some(a.value + (b.value - a.value) * t)
+}
+
+/// Drives the [`MaterialUniform`] of one material of a model over time.
+///
+/// The material's own uniform is left alone, every frame starts from it and
+/// the drivers are applied on top in the order they were added.
+pub struct MaterialAnimator {
+    material: usize,
+    drivers: Vec<Driver>,
+    time: f32,
+    looping: bool,
+}
+
+impl MaterialAnimator {
+    /// Animates the material at index `material` of the model.
+    pub fn new(material: usize) -> Self {
+        Self {
+            material,
+            drivers: Vec::new(),
+            time: 0.0,
+            looping: false,
+        }
+    }
+
+    /// Interpolates `param` between `keyframes`, which are sorted by time.
+    pub fn with_keyframes(mut self, param: MaterialParam, mut keyframes: Vec<Keyframe>) -> Self {
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        self.drivers.push(Driver::Keyframes { param, keyframes });
+        self
+    }
+
+    /// Calls `f` with the time in seconds to change any field of the uniform.
+    pub fn with_closure(
+        mut self,
+        f: impl Fn(f32, &mut MaterialUniform) + Send + Sync + 'static,
+    ) -> Self {
+        self.drivers.push(Driver::Closure(Box::new(f)));
+        self
+    }
+
+    /// Restarts from the first keyframe once the last one is reached.
+    pub fn looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    pub fn material(&self) -> usize {
+        self.material
+    }
+
+    /// Length of the animation, the time of the latest keyframe.
+    pub fn duration(&self) -> f32 {
+        self.drivers
+            .iter()
+            .filter_map(|driver| match driver {
+                Driver::Keyframes { keyframes, .. } => keyframes.last().map(|k| k.time),
+                Driver::Closure(_) => None,
+            })
+            .fold(0.0, f32::max)
+    }
+
+    /// Evaluates the animation at `time` seconds on top of `base`.
+    pub fn sample(&self, base: &MaterialUniform, time: f32) -> MaterialUniform {
+        let mut uniform = *base;
+        for driver in &self.drivers {
+            driver.apply(time, &mut uniform);
+        }
+        uniform
+    }
+
+    /// Advances the animation by `dt` and writes the result to the material's
+    /// uniform buffer.
+    pub fn update(&mut self, queue: &wgpu::Queue, materials: &[Material], dt: Duration) {
+        self.time += dt.as_secs_f32();
+        let duration = self.duration();
+        if self.looping && duration > 0.0 {
+            self.time %= duration;
+        }
+
+        let Some(material) = materials.get(self.material) else {
+            return;
+        };
+        let uniform = self.sample(&material.uniform, self.time);
+        material.write_uniform(queue, &uniform);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emissive_intensity_midpoint() {
+        let animator = MaterialAnimator::new(0).with_keyframes(
+            MaterialParam::EmissiveIntensity,
+            vec![
+                Keyframe {
+                    time: 0.0,
+                    value: 0.0,
+                },
+                Keyframe {
+                    time: 1.0,
+                    value: 1.0,
+                },
+            ],
+        );
+        let base = MaterialUniform::default();
+
+        let midpoint = animator.sample(&base, 0.5);
+        assert!((midpoint.emissive_intensity - 0.5).abs() < 1e-6);
+        assert_eq!(midpoint.base_color, base.base_color);
+
+        assert_eq!(animator.sample(&base, 2.0).emissive_intensity, 1.0);
+    }
+}
@@ -1,18 +1,21 @@
 use std::{
     path::PathBuf,
     sync::{Arc, RwLock},
+    time::Instant,
     vec,
 };
 
 use crate::{
     camera::{CameraController, StaticCamera},
     gpu::Gpu,
-    io::{GuiRenderer, IoEngine, Ui},
-    model, resource, texture, ModelEntry, Renderer, Resources,
+    io::{
+        event::{EventBus, EventReceiver, NativeEvent},
+        GuiRenderer, IoEngine, Ui,
+    },
+    resource, texture, ModelEntry, ModelId, Renderer, Resources,
 };
 use egui::{Align2, Context};
 use transform_gizmo_egui::*;
-use wgpu::util::DeviceExt;
 use winit::{
     event::*,
     event_loop::EventLoop,
@@ -25,6 +28,7 @@ pub struct App {
     gpu: Arc<Gpu>,
     renderer: Renderer,
     io_engine: IoEngine<Arc<RwLock<CameraController>>>,
+    events: EventBus<NativeEvent>,
 }
 
 #[derive(Default)]
@@ -54,6 +58,10 @@ impl Ui for Gui {
 }
 
 impl App {
+    /// Window events a subscriber can fall behind by before it lags, see
+    /// [`App::subscribe_events`].
+    const EVENT_CAPACITY: usize = 64;
+
     pub async fn new(window: Window) -> Self {
         let window = Arc::new(window);
 
@@ -86,32 +94,67 @@ impl App {
             resources,
             gpu,
             io_engine,
+            events: EventBus::new(Self::EVENT_CAPACITY),
         }
     }
 
-    pub async fn handle_file_drop(&mut self, path: &PathBuf) -> anyhow::Result<()> {
+    pub fn renderer(&self) -> &Renderer {
+        &self.renderer
+    }
+
+    /// E.g. to change the light or toggle passes before the next frame.
+    pub fn renderer_mut(&mut self) -> &mut Renderer {
+        &mut self.renderer
+    }
+
+    /// E.g. to add UIs or detach egui's viewports into their own windows.
+    pub fn gui_mut(&mut self) -> &mut GuiRenderer {
+        self.io_engine.gui_mut()
+    }
+
+    /// Loads the model at `path` into the scene, returning the id it's
+    /// stored under.
+    pub async fn handle_file_drop(&mut self, path: &PathBuf) -> anyhow::Result<ModelId> {
         let model = resource::load_model(path.to_path_buf(), &self.gpu).await?;
         let mut model_db = self.resources.model_db.write().unwrap();
-        let device = &self.gpu.device;
-        let instances = vec![model::Instance::default()];
-
-        let instance_data = instances
-            .iter()
-            .map(model::Instance::to_raw)
-            .collect::<Vec<_>>();
-        let model_entry = ModelEntry {
-            instances,
-            instance_buffer: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Model instance"),
-                contents: bytemuck::cast_slice(&instance_data),
-                usage: wgpu::BufferUsages::VERTEX,
-            }),
-            model,
-        };
-        model_db.insert(model_entry);
+        Ok(model_db.insert(ModelEntry::new(&self.gpu, model).with_source(path.to_path_buf())))
+    }
+
+    /// Replaces the lost device with a new one and rebuilds what was created
+    /// from it: the renderers and the models, which are loaded again from
+    /// their files.
+    async fn recover_lost_device(&mut self) -> anyhow::Result<()> {
+        let gpu = Arc::new(self.gpu.recreate().await?);
+        self.renderer = self.renderer.recreate(Arc::clone(&gpu)).await;
+        self.io_engine.on_device_recreated(Arc::clone(&gpu));
+        self.resources.reload_models(&gpu).await;
+        self.gpu = gpu;
         Ok(())
     }
 
+    /// The model under `position` in the main window, e.g. the cursor's,
+    /// `None` over the background. See [`Renderer::pick`].
+    pub fn pick(
+        &mut self,
+        position: impl Into<winit::dpi::Position>,
+    ) -> anyhow::Result<Option<ModelId>> {
+        let model_db = self.resources.model_db.read().unwrap();
+        self.renderer.pick(model_db.iter(), position)
+    }
+
+    /// Runs `f` on the model stored under `id`, `None` if there is none.
+    /// Changes show from the next frame on.
+    pub fn with_model<R>(&self, id: ModelId, f: impl FnOnce(&mut ModelEntry) -> R) -> Option<R> {
+        let mut model_db = self.resources.model_db.write().unwrap();
+        model_db.get_mut(id).map(f)
+    }
+
+    /// Every event of the main window from now on, in the order
+    /// [`App::run`] handles them.
+    pub fn subscribe_events(&self) -> EventReceiver<NativeEvent> {
+        self.events.subscribe()
+    }
+
     #[cfg_attr(target_arch = "wasm32", wasm_bindgen(start))]
     pub async fn run(&mut self, event_loop: EventLoop<()>) {
         let _ = event_loop.run(move |event, ewlt| match event {
@@ -119,6 +162,10 @@ impl App {
                 ref event,
                 window_id,
             } if window_id == self.renderer.window().id() => {
+                // Not worth the clone without subscribers.
+                if self.events.subscriber_count() > 0 {
+                    self.events.send(event.clone().into());
+                }
                 if !self.renderer.input(event) {
                     match event {
                         WindowEvent::CloseRequested
@@ -134,27 +181,52 @@ impl App {
                             log::info!("Resized");
                             self.renderer.resize(*physical_size);
                         }
+                        WindowEvent::RedrawRequested if self.gpu.is_lost() => {
+                            let recovered = futures::executor::block_on(self.recover_lost_device());
+                            match recovered {
+                                Ok(()) => self.renderer.window().request_redraw(),
+                                Err(err) => {
+                                    log::error!("Failed to recover the lost device: {err:#}");
+                                    ewlt.exit();
+                                }
+                            }
+                        }
                         WindowEvent::RedrawRequested => {
                             log::info!("Redraw");
+                            let frame_start = Instant::now();
+
+                            let dt = self.renderer.update();
+                            self.io_engine.collect_loaded_models();
 
-                            self.renderer.update();
+                            let mut model_write = self.resources.model_db.write().unwrap();
+                            for model in model_write.get_all_mut() {
+                                model.animate_materials(&self.gpu, dt);
+                                model.animate_skin(&self.gpu, dt);
+                            }
+                            drop(model_write);
 
                             let model_read = self.resources.model_db.read().unwrap();
                             let models = model_read.get_all();
 
-                            match self.renderer.render_models(models) {
-                                Ok(_) => {}
-                                // Reconfigure the surface if it's lost or outdated
-                                Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
-                                    self.renderer.resize(self.renderer.size)
+                            if let Err(err) = self.renderer.render_models(models) {
+                                match err.downcast_ref::<wgpu::SurfaceError>() {
+                                    // Reconfigure the surface if it's lost or outdated
+                                    Some(
+                                        wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated,
+                                    ) => self.renderer.resize(self.renderer.size),
+                                    // The system is out of memory, we should probably quit
+                                    Some(wgpu::SurfaceError::OutOfMemory) => ewlt.exit(),
+                                    // We're ignoring timeouts
+                                    Some(wgpu::SurfaceError::Timeout) => {
+                                        log::warn!("Surface timeout")
+                                    }
+                                    None => log::error!("Failed to render the frame: {err:#}"),
                                 }
-                                // The system is out of memory, we should probably quit
-                                Err(wgpu::SurfaceError::OutOfMemory) => ewlt.exit(),
-                                // We're ignoring timeouts
-                                Err(wgpu::SurfaceError::Timeout) => log::warn!("Surface timeout"),
-                            };
+                            }
                             self.io_engine.render();
+                            self.io_engine.gui_mut().update_viewport_windows(ewlt);
                             self.gpu.finish();
+                            self.renderer.update_render_scale(frame_start.elapsed());
                         }
                         _ => {
                             log::info!("Other");
@@ -163,6 +235,14 @@ impl App {
                     self.io_engine.handle_event(event);
                 }
             }
+            Event::WindowEvent {
+                ref event,
+                window_id,
+            } => {
+                self.io_engine
+                    .gui_mut()
+                    .handle_viewport_event(window_id, event);
+            }
             _ => {}
         });
     }
@@ -0,0 +1,223 @@
+//! CPU block compression for uploading textures in BCn formats.
+//!
+//! This is a plain range-fit encoder: the endpoints are the per-channel
+//! bounding box of each 4x4 block. Quality is below a proper cluster fit
+//! encoder but it is fast and good enough for memory savings at load time.
+
+use anyhow::*;
+
+const BLOCK_WIDTH: u32 = 4;
+const BLOCK_HEIGHT: u32 = 4;
+
+/// Size in bytes of a single 4x4 block of `format`, `None` if the format is
+/// not supported by the encoder.
+pub fn block_size(format: wgpu::TextureFormat) -> Option<u32> {
+    use wgpu::TextureFormat::*;
+    match format {
+        Bc1RgbaUnorm | Bc1RgbaUnormSrgb => Some(8),
+        Bc3RgbaUnorm | Bc3RgbaUnormSrgb => Some(16),
+        _ => None,
+    }
+}
+
+/// Compresses tightly packed RGBA8 `pixels` into `format`.
+///
+/// `width` and `height` must be multiples of 4, which is what wgpu requires
+/// of block compressed textures anyway.
+pub fn compress(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    format: wgpu::TextureFormat,
+) -> Result<Vec<u8>> {
+    let Some(block_size) = block_size(format) else {
+        bail!("Unsupported compressed format {format:?}");
+    };
+    if !width.is_multiple_of(BLOCK_WIDTH) || !height.is_multiple_of(BLOCK_HEIGHT) {
+        bail!("Texture size {width}x{height} is not a multiple of the 4x4 block size");
+    }
+    if pixels.len() != (width * height * 4) as usize {
+        bail!("Expected {} bytes of RGBA8 data", width * height * 4);
+    }
+
+    let blocks_wide = width / BLOCK_WIDTH;
+    let blocks_high = height / BLOCK_HEIGHT;
+    let mut out = Vec::with_capacity((blocks_wide * blocks_high * block_size) as usize);
+
+    for by in 0..blocks_high {
+        for bx in 0..blocks_wide {
+            let block = read_block(pixels, width, bx, by);
+            if block_size == 16 {
+                out.extend_from_slice(&encode_alpha_block(&block));
+            }
+            out.extend_from_slice(&encode_color_block(&block));
+        }
+    }
+
+    Ok(out)
+}
+
+fn read_block(pixels: &[u8], width: u32, bx: u32, by: u32) -> [[u8; 4]; 16] {
+    let mut block = [[0; 4]; 16];
+    for y in 0..BLOCK_HEIGHT {
+        for x in 0..BLOCK_WIDTH {
+            let px = bx * BLOCK_WIDTH + x;
+            let py = by * BLOCK_HEIGHT + y;
+            let offset = ((py * width + px) * 4) as usize;
+            block[(y * BLOCK_WIDTH + x) as usize].copy_from_slice(&pixels[offset..offset + 4]);
+        }
+    }
+    block
+}
+
+fn to_565(color: [u8; 3]) -> u16 {
+    let r = (color[0] as u16 * 31 + 127) / 255;
+    let g = (color[1] as u16 * 63 + 127) / 255;
+    let b = (color[2] as u16 * 31 + 127) / 255;
+    (r << 11) | (g << 5) | b
+}
+
+fn from_565(color: u16) -> [u8; 3] {
+    let r = ((color >> 11) & 0x1f) as u32;
+    let g = ((color >> 5) & 0x3f) as u32;
+    let b = (color & 0x1f) as u32;
+    [
+        (r * 255 / 31) as u8,
+        (g * 255 / 63) as u8,
+        (b * 255 / 31) as u8,
+    ]
+}
+
+/// Four color BC1 block, shared by BC1 and the color half of BC3.
+fn encode_color_block(block: &[[u8; 4]; 16]) -> [u8; 8] {
+    let mut min = [u8::MAX; 3];
+    let mut max = [u8::MIN; 3];
+    for pixel in block {
+        for c in 0..3 {
+            min[c] = min[c].min(pixel[c]);
+            max[c] = max[c].max(pixel[c]);
+        }
+    }
+
+    let mut color0 = to_565(max);
+    let mut color1 = to_565(min);
+    // color0 > color1 selects the four color mode, equal endpoints mean a
+    // flat block where every index can stay 0.
+    if color0 < color1 {
+        std::mem::swap(&mut color0, &mut color1);
+    }
+
+    let mut indices = 0u32;
+    if color0 != color1 {
+        let c0 = from_565(color0);
+        let c1 = from_565(color1);
+        let mut palette = [[0u8; 3]; 4];
+        palette[0] = c0;
+        palette[1] = c1;
+        for c in 0..3 {
+            palette[2][c] = ((2 * c0[c] as u32 + c1[c] as u32) / 3) as u8;
+            palette[3][c] = ((c0[c] as u32 + 2 * c1[c] as u32) / 3) as u8;
+        }
+
+        for (i, pixel) in block.iter().enumerate() {
+            let nearest = (0..4)
+                .min_by_key(|&p| {
+                    (0..3)
+                        .map(|c| (pixel[c] as i32 - palette[p][c] as i32).pow(2))
+                        .sum::<i32>()
+                })
+                .unwrap();
+            indices |= (nearest as u32) << (i * 2);
+        }
+    }
+
+    let mut out = [0; 8];
+    out[0..2].copy_from_slice(&color0.to_le_bytes());
+    out[2..4].copy_from_slice(&color1.to_le_bytes());
+    out[4..8].copy_from_slice(&indices.to_le_bytes());
+    out
+}
+
+/// Eight alpha BC3 (BC4 style) block.
+fn encode_alpha_block(block: &[[u8; 4]; 16]) -> [u8; 8] {
+    let alpha0 = block.iter().map(|p| p[3]).max().unwrap();
+    let alpha1 = block.iter().map(|p| p[3]).min().unwrap();
+
+    let mut indices = 0u64;
+    if alpha0 != alpha1 {
+        let mut palette = [0u8; 8];
+        palette[0] = alpha0;
+        palette[1] = alpha1;
+        for i in 1..7u32 {
+            palette[i as usize + 1] = (((7 - i) * alpha0 as u32 + i * alpha1 as u32) / 7) as u8;
+        }
+
+        for (i, pixel) in block.iter().enumerate() {
+            let nearest = (0..8)
+                .min_by_key(|&p| (pixel[3] as i32 - palette[p] as i32).abs())
+                .unwrap();
+            indices |= (nearest as u64) << (i * 3);
+        }
+    }
+
+    let mut out = [0; 8];
+    out[0] = alpha0;
+    out[1] = alpha1;
+    out[2..8].copy_from_slice(&indices.to_le_bytes()[0..6]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_bc1(block: &[u8]) -> [[u8; 3]; 16] {
+        let color0 = u16::from_le_bytes([block[0], block[1]]);
+        let color1 = u16::from_le_bytes([block[2], block[3]]);
+        let indices = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+        let c0 = from_565(color0);
+        let c1 = from_565(color1);
+        let mut palette = [c0, c1, [0; 3], [0; 3]];
+        for c in 0..3 {
+            palette[2][c] = ((2 * c0[c] as u32 + c1[c] as u32) / 3) as u8;
+            palette[3][c] = ((c0[c] as u32 + 2 * c1[c] as u32) / 3) as u8;
+        }
+        let mut out = [[0; 3]; 16];
+        for (i, pixel) in out.iter_mut().enumerate() {
+            *pixel = palette[((indices >> (i * 2)) & 0b11) as usize];
+        }
+        out
+    }
+
+    #[test]
+    fn test_bc1_gradient() -> Result<()> {
+        let (width, height) = (16, 16);
+        let mut pixels = Vec::new();
+        for _ in 0..height {
+            for x in 0..width {
+                let v = (x * 255 / (width - 1)) as u8;
+                pixels.extend_from_slice(&[v, v, v, 255]);
+            }
+        }
+
+        let compressed = compress(&pixels, width, height, wgpu::TextureFormat::Bc1RgbaUnorm)?;
+        assert_eq!(compressed.len(), 4 * 4 * 8);
+
+        for (b, block) in compressed.chunks(8).enumerate() {
+            let decoded = decode_bc1(block);
+            let (bx, by) = (b as u32 % 4, b as u32 / 4);
+            for (i, texel) in decoded.iter().enumerate() {
+                let (x, y) = (bx * 4 + i as u32 % 4, by * 4 + i as u32 / 4);
+                let expected = pixels[((y * width + x) * 4) as usize];
+                assert!((texel[0] as i32 - expected as i32).abs() <= 8);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_unaligned_size() {
+        let pixels = vec![0; 6 * 6 * 4];
+        assert!(compress(&pixels, 6, 6, wgpu::TextureFormat::Bc1RgbaUnorm).is_err());
+    }
+}
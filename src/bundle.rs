@@ -0,0 +1,310 @@
+/// Draws recorded once into a [`wgpu::RenderBundle`] and replayed into every
+/// frame's pass, for parts of the scene that don't change between frames.
+///
+/// The bundle is recorded again when the `key` it was recorded with changes,
+/// e.g. the pipeline it draws with, or after [`BundleCache::invalidate`].
+pub struct BundleCache<K> {
+    bundle: Option<(K, wgpu::RenderBundle)>,
+    recordings: usize,
+}
+
+impl<K> Default for BundleCache<K> {
+    fn default() -> Self {
+        Self {
+            bundle: None,
+            recordings: 0,
+        }
+    }
+}
+
+impl<K: PartialEq> BundleCache<K> {
+    /// The cached bundle, recorded with `record` first when there's none for
+    /// `key`. `desc` has to match the passes the bundle is executed in.
+    pub fn get_or_record<'a>(
+        &mut self,
+        key: K,
+        device: &'a wgpu::Device,
+        desc: &wgpu::RenderBundleEncoderDescriptor,
+        record: impl FnOnce(&mut wgpu::RenderBundleEncoder<'a>),
+    ) -> &wgpu::RenderBundle {
+        if !matches!(&self.bundle, Some((cached, _)) if *cached == key) {
+            let mut encoder = device.create_render_bundle_encoder(desc);
+            record(&mut encoder);
+            let bundle = encoder.finish(&wgpu::RenderBundleDescriptor { label: desc.label });
+            self.bundle = Some((key, bundle));
+            self.recordings += 1;
+        }
+        &self.bundle.as_ref().unwrap().1
+    }
+
+    /// Drops the bundle, the next [`BundleCache::get_or_record`] records it
+    /// again. Needed whenever what it draws changes.
+    pub fn invalidate(&mut self) {
+        self.bundle = None;
+    }
+
+    /// How many times the bundle was recorded.
+    pub fn recordings(&self) -> usize {
+        self.recordings
+    }
+}
+
+/// A camera uniform the bundles of every view read from, so geometry can be
+/// recorded into one bundle and drawn from several cameras, e.g. for
+/// reflections, shadows or split screen.
+///
+/// Executing a bundle resets the pass's bind groups, so the camera can't be
+/// set on the pass around it. Instead the bundle binds
+/// [`CameraSlot::bind_group`] and [`CameraSlot::select`] copies a camera
+/// into the slot before each view's pass.
+pub struct CameraSlot {
+    buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+impl CameraSlot {
+    /// A slot of `size` bytes at binding 0 of `layout`, which has to take a
+    /// uniform buffer there like the camera bind group does. `entries` are
+    /// the layout's other bindings, e.g. the frame uniform.
+    pub fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        size: u64,
+        entries: &[wgpu::BindGroupEntry],
+    ) -> Self {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("CameraSlot::buffer"),
+            size,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let slot = wgpu::BindGroupEntry {
+            binding: 0,
+            resource: buffer.as_entire_binding(),
+        };
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("CameraSlot::bind_group"),
+            layout,
+            entries: &[&[slot], entries].concat(),
+        });
+        Self { buffer, bind_group }
+    }
+
+    /// Bound in place of the camera bind group when recording bundles.
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    /// Copies `camera` into the slot, to be encoded before the pass executing
+    /// the bundles. `camera` needs `COPY_SRC` usage.
+    pub fn select(&self, encoder: &mut wgpu::CommandEncoder, camera: &wgpu::Buffer) {
+        encoder.copy_buffer_to_buffer(camera, 0, &self.buffer, 0, self.buffer.size());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::Gpu;
+
+    #[test]
+    fn test_static_scene_is_recorded_once() {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping bundle test");
+            return;
+        };
+        let format = wgpu::TextureFormat::Rgba8Unorm;
+        let target = gpu.device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: 4,
+                height: 4,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+        let view = target.create_view(&Default::default());
+        let desc = wgpu::RenderBundleEncoderDescriptor {
+            label: Some("static scene"),
+            color_formats: &[Some(format)],
+            depth_stencil: None,
+            sample_count: 1,
+            multiview: None,
+        };
+
+        let mut cache = BundleCache::default();
+        let mut recorded = 0;
+        let mut frame = |cache: &mut BundleCache<bool>, wireframe: bool| {
+            // A fresh encoder and pass every frame, only the bundle is reused.
+            let mut encoder = gpu.device.create_command_encoder(&Default::default());
+            {
+                let bundle = cache.get_or_record(wireframe, &gpu.device, &desc, |_| {
+                    recorded += 1;
+                });
+                let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: None,
+                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                        view: &view,
+                        resolve_target: None,
+                        ops: wgpu::Operations::default(),
+                    })],
+                    depth_stencil_attachment: None,
+                    occlusion_query_set: None,
+                    timestamp_writes: None,
+                });
+                pass.execute_bundles(std::iter::once(bundle));
+            }
+            gpu.queue.submit([encoder.finish()]);
+        };
+
+        for _ in 0..3 {
+            frame(&mut cache, false);
+        }
+        assert_eq!(cache.recordings(), 1);
+
+        frame(&mut cache, true);
+        assert_eq!(cache.recordings(), 2);
+
+        cache.invalidate();
+        frame(&mut cache, true);
+        assert_eq!(cache.recordings(), 3);
+        assert_eq!(recorded, 3);
+    }
+
+    #[test]
+    fn test_geometry_bundle_is_drawn_from_two_cameras() {
+        use wgpu::util::DeviceExt;
+
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping camera slot test");
+            return;
+        };
+        let device = &gpu.device;
+        let format = wgpu::TextureFormat::Rgba8Unorm;
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        // The "camera" is just the color the fullscreen triangle is drawn in.
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(
+                "
+                @group(0) @binding(0) var<uniform> camera: vec4<f32>;
+
+                @vertex
+                fn vs_main(@builtin(vertex_index) i: u32) -> @builtin(position) vec4<f32> {
+                    let uv = vec2<f32>(f32((i << 1u) & 2u), f32(i & 2u));
+                    return vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+                }
+
+                @fragment
+                fn fs_main() -> @location(0) vec4<f32> {
+                    return camera;
+                }
+                "
+                .into(),
+            ),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(format.into())],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        let slot = CameraSlot::new(device, &layout, 16, &[]);
+        let cameras = [[1.0f32, 0.0, 0.0, 1.0], [0.0, 0.0, 1.0, 1.0]].map(|color| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(&color),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_SRC,
+            })
+        });
+        let targets = cameras.each_ref().map(|_| {
+            device.create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size: wgpu::Extent3d {
+                    width: 4,
+                    height: 4,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+                view_formats: &[],
+            })
+        });
+
+        let mut cache = BundleCache::default();
+        let desc = wgpu::RenderBundleEncoderDescriptor {
+            label: Some("geometry"),
+            color_formats: &[Some(format)],
+            depth_stencil: None,
+            sample_count: 1,
+            multiview: None,
+        };
+        let mut encoder = device.create_command_encoder(&Default::default());
+        for (camera, target) in cameras.iter().zip(&targets) {
+            let bundle = cache.get_or_record((), device, &desc, |bundle| {
+                bundle.set_pipeline(&pipeline);
+                bundle.set_bind_group(0, slot.bind_group(), &[]);
+                bundle.draw(0..3, 0..1);
+            });
+            slot.select(&mut encoder, camera);
+            let view = target.create_view(&Default::default());
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations::default(),
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+            pass.execute_bundles(std::iter::once(bundle));
+        }
+        gpu.queue.submit([encoder.finish()]);
+
+        assert_eq!(cache.recordings(), 1);
+        let red = gpu.read_texture(&targets[0]).unwrap();
+        let blue = gpu.read_texture(&targets[1]).unwrap();
+        assert_eq!(red.pixels[..4], [255, 0, 0, 255]);
+        assert_eq!(blue.pixels[..4], [0, 0, 255, 255]);
+    }
+}
@@ -1,16 +1,21 @@
 use nalgebra as na;
+use std::{f32::consts::FRAC_PI_2, time::Duration};
 
 use winit::{
-    event::KeyEvent,
-    keyboard::{KeyCode, PhysicalKey},
+    dpi::PhysicalPosition,
+    event::{KeyEvent, MouseScrollDelta, WindowEvent},
+    keyboard::KeyCode,
 };
 
-use super::ICamera;
+use crate::io::input::{Action, Input, InputMap};
+
+use super::{ICamera, ProjectionKind};
 
 pub struct StaticCamera {
     pub position: na::Point3<f32>,
     pub target: na::Point3<f32>,
     pub up: na::Vector3<f32>,
+    pub projection: ProjectionKind,
 }
 
 impl Default for StaticCamera {
@@ -29,6 +34,7 @@ impl StaticCamera {
             target: na::Point3::origin(),
             // which way is "up"
             up: *na::Vector3::y_axis(),
+            projection: ProjectionKind::default(),
         }
     }
 }
@@ -42,91 +48,214 @@ impl ICamera for StaticCamera {
     }
 }
 
+/// Keeps the camera from flipping over the poles.
+const SAFE_FRAC_PI_2: f32 = FRAC_PI_2 - 0.001;
+/// Closest the orbit camera gets to its target.
+const MIN_DISTANCE: f32 = 0.1;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CameraMode {
+    /// Drag rotates around the target, scroll and W/S zoom, A/D orbit.
+    #[default]
+    Orbit,
+    /// Drag looks around, WASD and scroll move the camera and its target.
+    Fly,
+}
+
 pub struct CameraController {
+    mode: CameraMode,
     speed: f32,
-    is_forward_pressed: bool,
-    is_backward_pressed: bool,
-    is_left_pressed: bool,
-    is_right_pressed: bool,
+    sensitivity: f32,
+    smoothing: f32,
+    input_map: InputMap,
+    cursor: Option<PhysicalPosition<f64>>,
+    rotate: na::Vector2<f32>,
+    scroll: f32,
+    velocity: na::Vector2<f32>,
 }
 
 impl Default for CameraController {
     fn default() -> Self {
-        Self::new(0.2)
+        Self::new(4.0)
     }
 }
 
 impl CameraController {
+    /// `speed` is in units per second.
     pub fn new(speed: f32) -> Self {
         Self {
+            mode: CameraMode::default(),
             speed,
-            is_forward_pressed: false,
-            is_backward_pressed: false,
-            is_left_pressed: false,
-            is_right_pressed: false,
+            sensitivity: 0.005,
+            smoothing: 12.0,
+            input_map: InputMap::default(),
+            cursor: None,
+            rotate: na::Vector2::zeros(),
+            scroll: 0.0,
+            velocity: na::Vector2::zeros(),
         }
     }
 
+    pub fn mode(&self) -> CameraMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: CameraMode) {
+        self.mode = mode;
+        self.velocity = na::Vector2::zeros();
+    }
+
+    /// Bindings the controller reads its actions from, for rebinding.
+    pub fn input_map_mut(&mut self) -> &mut InputMap {
+        &mut self.input_map
+    }
+
     pub fn process_key(&mut self, key_event: &KeyEvent) {
-        if let KeyEvent {
-            state,
-            physical_key: PhysicalKey::Code(key_code),
-            ..
-        } = key_event
-        {
-            self.process_events(&key_code, state.is_pressed());
-        }
+        self.input_map.process_key(key_event);
     }
 
     pub fn process_events(&mut self, key: &KeyCode, pressed: bool) {
-        use KeyCode::*;
-        match key {
-            KeyW => {
-                self.is_forward_pressed = pressed;
+        self.input_map.set_pressed(Input::Key(*key), pressed);
+    }
+
+    /// Mouse drag, scroll and keyboard input. The input is accumulated
+    /// until the next [`CameraController::update_camera`].
+    pub fn handle_window_event(&mut self, event: &WindowEvent) {
+        use WindowEvent::*;
+        match event {
+            KeyboardInput { event, .. } => self.process_key(event),
+            MouseInput { button, state, .. } => {
+                self.input_map.process_mouse_button(*button, *state);
             }
-            KeyA => {
-                self.is_left_pressed = pressed;
+            CursorMoved { position, .. } => {
+                let is_dragging = self.input_map.is_active(Action::Rotate);
+                if let (true, Some(last)) = (is_dragging, self.cursor) {
+                    self.rotate.x += (position.x - last.x) as f32;
+                    self.rotate.y += (position.y - last.y) as f32;
+                }
+                self.cursor = Some(*position);
             }
-            KeyS => {
-                self.is_backward_pressed = pressed;
+            CursorLeft { .. } => {
+                self.cursor = None;
             }
-            KeyD => {
-                self.is_right_pressed = pressed;
+            MouseWheel { delta, .. } => {
+                self.scroll += match delta {
+                    MouseScrollDelta::LineDelta(_, lines) => *lines,
+                    MouseScrollDelta::PixelDelta(PhysicalPosition { y, .. }) => *y as f32 / 100.0,
+                };
             }
             _ => {}
         }
     }
 
-    pub fn update_camera(&self, camera: &mut StaticCamera) {
-        let forward = camera.target - camera.position;
-        let forward_norm = forward.normalize();
-        let forward_mag = forward.magnitude();
+    pub fn update_camera(&mut self, camera: &mut StaticCamera, dt: Duration) {
+        // Frames are only drawn on input, a long idle gap must not turn into
+        // a jump on the next key press.
+        let dt = dt.as_secs_f32().min(0.1);
 
-        // Prevents glitching when the camera gets too close to the
-        // center of the scene.
-        if self.is_forward_pressed && forward_mag > self.speed {
-            camera.position += forward_norm * self.speed;
-        }
-        if self.is_backward_pressed {
-            camera.position -= forward_norm * self.speed;
+        let axis = |positive, negative| match (
+            self.input_map.is_active(positive),
+            self.input_map.is_active(negative),
+        ) {
+            (true, false) => 1.0,
+            (false, true) => -1.0,
+            _ => 0.0,
+        };
+        let wanted = na::Vector2::new(
+            axis(Action::MoveRight, Action::MoveLeft),
+            axis(Action::MoveForward, Action::MoveBackward),
+        ) * self.speed;
+        // Frame rate independent exponential ease towards the wanted velocity.
+        let blend = 1.0 - (-self.smoothing * dt).exp();
+        self.velocity += (wanted - self.velocity) * blend;
+
+        let offset = camera.position - camera.target;
+        let distance = offset.magnitude().max(MIN_DISTANCE);
+        let mut yaw = offset.x.atan2(offset.z);
+        let mut pitch = (offset.y / distance).clamp(-1.0, 1.0).asin();
+
+        match self.mode {
+            CameraMode::Orbit => {
+                yaw -= self.rotate.x * self.sensitivity + self.velocity.x * dt / distance;
+                pitch += self.rotate.y * self.sensitivity;
+                pitch = pitch.clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2);
+
+                let zoom = self.velocity.y * dt + self.scroll * distance * 0.1;
+                let distance = (distance - zoom).max(MIN_DISTANCE);
+                camera.position = camera.target + spherical(yaw, pitch) * distance;
+            }
+            CameraMode::Fly => {
+                // The offset points from the target to the eye, looking
+                // the other way is the same as turning it around.
+                yaw -= self.rotate.x * self.sensitivity;
+                pitch += self.rotate.y * self.sensitivity;
+                pitch = pitch.clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2);
+
+                let forward = -spherical(yaw, pitch);
+                let right = forward.cross(&camera.up).normalize();
+                let movement =
+                    forward * (self.velocity.y * dt + self.scroll) + right * self.velocity.x * dt;
+                camera.position += movement;
+                camera.target = camera.position + forward * distance;
+            }
         }
 
-        let right = forward_norm.cross(&camera.up);
+        self.rotate = na::Vector2::zeros();
+        self.scroll = 0.0;
+    }
+}
+
+/// Unit vector for `yaw` around +y, measured from +z, and `pitch` above the
+/// xz plane.
+fn spherical(yaw: f32, pitch: f32) -> na::Vector3<f32> {
+    let (yaw_sin, yaw_cos) = yaw.sin_cos();
+    let (pitch_sin, pitch_cos) = pitch.sin_cos();
+    na::Vector3::new(pitch_cos * yaw_sin, pitch_sin, pitch_cos * yaw_cos)
+}
 
-        // Redo radius calc in case the forward/backward is pressed.
-        let forward = camera.target - camera.position;
-        let forward_mag = forward.magnitude();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if self.is_right_pressed {
-            // Rescale the distance between the target and the eye so
-            // that it doesn't change. The eye, therefore, still
-            // lies on the circle made by the target and eye.
-            camera.position =
-                camera.target - (forward + right * self.speed).normalize() * forward_mag;
+    #[test]
+    fn test_orbit_zoom_stays_positive() {
+        let mut camera = StaticCamera::new();
+        let mut controller = CameraController::default();
+        controller.scroll = 1000.0;
+        controller.update_camera(&mut camera, Duration::from_millis(16));
+
+        let distance = (camera.position - camera.target).magnitude();
+        assert!(distance >= MIN_DISTANCE - f32::EPSILON);
+    }
+
+    #[test]
+    fn test_pitch_is_clamped() {
+        for mode in [CameraMode::Orbit, CameraMode::Fly] {
+            let mut camera = StaticCamera::new();
+            let mut controller = CameraController::default();
+            controller.set_mode(mode);
+            controller.rotate.y = 1.0e6;
+            controller.update_camera(&mut camera, Duration::from_millis(16));
+
+            let direction = (camera.position - camera.target).normalize();
+            assert!(direction.dot(&camera.up).abs() < 1.0);
+            assert!(direction.x.is_finite() && direction.z.is_finite());
         }
-        if self.is_left_pressed {
-            camera.position =
-                camera.target - (forward - right * self.speed).normalize() * forward_mag;
+    }
+
+    #[test]
+    fn test_fly_moves_target() {
+        let mut camera = StaticCamera::new();
+        let mut controller = CameraController::default();
+        controller.set_mode(CameraMode::Fly);
+        controller.process_events(&KeyCode::KeyW, true);
+        let before = camera.target;
+        for _ in 0..10 {
+            controller.update_camera(&mut camera, Duration::from_millis(16));
         }
+
+        let distance = (camera.position - camera.target).magnitude();
+        assert!((camera.target - before).magnitude() > 0.0);
+        assert!((distance - 5.0_f32.sqrt()).abs() < 1.0e-4);
     }
 }
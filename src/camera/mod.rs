@@ -5,30 +5,104 @@ mod camera;
 mod fps;
 pub use camera::*;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProjectionKind {
+    Perspective {
+        fovy: f32,
+        znear: f32,
+        zfar: f32,
+    },
+    /// View volume bounds in view space, the aspect ratio is not applied.
+    Orthographic {
+        left: f32,
+        right: f32,
+        bottom: f32,
+        top: f32,
+        near: f32,
+        far: f32,
+    },
+}
+
+impl Default for ProjectionKind {
+    fn default() -> Self {
+        Self::Perspective {
+            fovy: 45.0,
+            znear: 0.1,
+            zfar: 100.0,
+        }
+    }
+}
+
 pub struct Projection {
     aspect: f32,
-    fovy: f32,
-    znear: f32,
-    zfar: f32,
+    kind: ProjectionKind,
 }
 
 impl Projection {
     pub fn with_aspect(width: f32, height: f32) -> Self {
-        Self::new(width, height, 45.0, 0.1, 100.0)
+        Self::with_kind(width, height, ProjectionKind::default())
     }
     pub fn new(width: f32, height: f32, fovy: f32, znear: f32, zfar: f32) -> Self {
+        Self::with_kind(
+            width,
+            height,
+            ProjectionKind::Perspective { fovy, znear, zfar },
+        )
+    }
+    pub fn with_kind(width: f32, height: f32, kind: ProjectionKind) -> Self {
         Self {
             aspect: width / height,
-            fovy,
-            znear,
-            zfar,
+            kind,
         }
     }
     pub fn build_matrix(&self) -> na::Matrix4<f32> {
-        *na::Perspective3::new(self.aspect, self.fovy, self.znear, self.zfar).as_matrix()
+        match self.kind {
+            ProjectionKind::Perspective { fovy, znear, zfar } => {
+                *na::Perspective3::new(self.aspect, fovy, znear, zfar).as_matrix()
+            }
+            ProjectionKind::Orthographic {
+                left,
+                right,
+                bottom,
+                top,
+                near,
+                far,
+            } => orthographic(left, right, bottom, top, near, far),
+        }
     }
 }
 
+/// Right handed orthographic projection mapping `-near..-far` to wgpu's `0..1`
+/// depth range. nalgebra's `Orthographic3` targets OpenGL's `-1..1`, which
+/// would put half of the volume behind the near plane.
+pub fn orthographic(
+    left: f32,
+    right: f32,
+    bottom: f32,
+    top: f32,
+    near: f32,
+    far: f32,
+) -> Matrix4<f32> {
+    Matrix4::new(
+        2.0 / (right - left),
+        0.0,
+        0.0,
+        -(right + left) / (right - left),
+        0.0,
+        2.0 / (top - bottom),
+        0.0,
+        -(top + bottom) / (top - bottom),
+        0.0,
+        0.0,
+        -1.0 / (far - near),
+        -near / (far - near),
+        0.0,
+        0.0,
+        0.0,
+        1.0,
+    )
+}
+
 pub trait ICamera {
     fn build_view_matrix(&self) -> na::Matrix4<f32>;
     fn position(&self) -> na::Point3<f32>;
@@ -68,4 +142,31 @@ impl CameraUniform {
         self.inv_proj = proj.try_inverse().unwrap().into();
         self.inv_view = view.transpose().into();
     }
+
+    pub fn view_proj(&self) -> Matrix4<f32> {
+        self.view_proj.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_orthographic_depth_range() {
+        let kind = ProjectionKind::Orthographic {
+            left: -2.0,
+            right: 2.0,
+            bottom: -1.0,
+            top: 1.0,
+            near: 0.5,
+            far: 10.0,
+        };
+        let proj = Projection::with_kind(4.0, 2.0, kind).build_matrix();
+
+        let near = proj.transform_point(&na::Point3::new(-2.0, -1.0, -0.5));
+        let far = proj.transform_point(&na::Point3::new(2.0, 1.0, -10.0));
+        assert!((near - na::Point3::new(-1.0, -1.0, 0.0)).norm() < 1.0e-6);
+        assert!((far - na::Point3::new(1.0, 1.0, 1.0)).norm() < 1.0e-6);
+    }
 }
@@ -28,12 +28,24 @@ impl<T> DB<T> {
         item.unwrap()
     }
 
+    pub fn get_mut(&mut self, id: Id) -> Option<&mut T> {
+        self.data.get_mut(&id)
+    }
+
     pub fn get_all<'a>(&'a self) -> impl Iterator<Item = &'a T> {
         self.data.values()
     }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (Id, &'a T)> {
+        self.data.iter().map(|(id, val)| (*id, val))
+    }
+
+    pub fn get_all_mut<'a>(&'a mut self) -> impl Iterator<Item = &'a mut T> {
+        self.data.values_mut()
+    }
 }
 
-#[derive(Hash, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Hash, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Id(pub usize);
 
 impl Display for Id {
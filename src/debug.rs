@@ -0,0 +1,263 @@
+use nalgebra as na;
+use std::mem;
+
+use crate::{
+    gpu::Gpu,
+    model::{ColorVertex, Vertex},
+    pipeline::PipelineBuilder,
+};
+
+/// Lines drawn on top of the scene to make invisible things visible, such
+/// as bounding boxes while debugging culling. Lines are collected over a
+/// frame with [`DebugRenderer::draw_line`] and [`DebugRenderer::draw_aabb`],
+/// drawn with [`DebugRenderer::draw`] and cleared after the frame.
+pub struct DebugRenderer {
+    pipeline: wgpu::RenderPipeline,
+    vertices: Vec<ColorVertex>,
+    buffer: wgpu::Buffer,
+    /// Vertices uploaded to `buffer` by [`DebugRenderer::upload`].
+    uploaded: u32,
+}
+
+impl DebugRenderer {
+    /// Vertices the buffer has room for before it first grows.
+    const INITIAL_CAPACITY: usize = 1024;
+
+    /// `camera_layout` is the layout of the camera bind group, bound to
+    /// group 0 when drawing.
+    pub fn new(
+        gpu: &Gpu,
+        camera_layout: &wgpu::BindGroupLayout,
+        color_format: wgpu::TextureFormat,
+        depth_format: Option<wgpu::TextureFormat>,
+        sample_count: u32,
+    ) -> Self {
+        let layout = gpu
+            .device
+            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("DebugRenderer::layout"),
+                bind_group_layouts: &[camera_layout],
+                push_constant_ranges: &[],
+            });
+        // Depth tested so lines are hidden behind geometry, without hiding
+        // each other.
+        let pipeline =
+            PipelineBuilder::new(&layout, color_format, wgpu::include_wgsl!("debug.wgsl"))
+                .depth_format(depth_format)
+                .depth_write(false)
+                .vertex_layouts(&[ColorVertex::desc()])
+                .topology(wgpu::PrimitiveTopology::LineList)
+                .sample_count(sample_count)
+                .build(gpu);
+
+        Self {
+            pipeline,
+            vertices: Vec::new(),
+            buffer: Self::create_buffer(gpu, Self::INITIAL_CAPACITY),
+            uploaded: 0,
+        }
+    }
+
+    fn create_buffer(gpu: &Gpu, capacity: usize) -> wgpu::Buffer {
+        gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("DebugRenderer::buffer"),
+            size: (capacity * mem::size_of::<ColorVertex>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub fn draw_line(&mut self, a: na::Point3<f32>, b: na::Point3<f32>, color: [f32; 4]) {
+        self.vertices.extend([a, b].map(|point| ColorVertex {
+            position: point.into(),
+            color,
+        }));
+    }
+
+    /// The twelve edges of the box from `min` to `max`.
+    pub fn draw_aabb(&mut self, min: na::Point3<f32>, max: na::Point3<f32>, color: [f32; 4]) {
+        let corner = |i: usize| {
+            na::Point3::new(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            )
+        };
+        // Corners whose index differs in one bit share an edge.
+        for i in 0..8 {
+            for bit in [1, 2, 4] {
+                if i & bit == 0 {
+                    self.draw_line(corner(i), corner(i | bit), color);
+                }
+            }
+        }
+    }
+
+    /// Number of line vertices collected this frame.
+    pub fn len(&self) -> usize {
+        self.vertices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vertices.is_empty()
+    }
+
+    /// Drops the collected lines, done by the renderer once a frame was
+    /// submitted.
+    pub fn clear(&mut self) {
+        self.vertices.clear();
+    }
+
+    /// Writes the collected lines to the vertex buffer, growing it when
+    /// they don't fit. Has to happen before the pass they're drawn in.
+    pub fn upload(&mut self, gpu: &Gpu) {
+        let size = (self.vertices.len() * mem::size_of::<ColorVertex>()) as wgpu::BufferAddress;
+        if size > self.buffer.size() {
+            self.buffer = Self::create_buffer(gpu, self.vertices.len().next_power_of_two());
+        }
+        gpu.queue
+            .write_buffer(&self.buffer, 0, bytemuck::cast_slice(&self.vertices));
+        self.uploaded = self.vertices.len() as u32;
+    }
+
+    /// Draws the lines of the last [`DebugRenderer::upload`].
+    pub fn draw<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        camera_bind_group: &'a wgpu::BindGroup,
+    ) {
+        if self.uploaded == 0 {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.buffer.slice(..));
+        render_pass.draw(0..self.uploaded, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture;
+    use wgpu::util::DeviceExt;
+
+    fn camera_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        })
+    }
+
+    #[test]
+    fn test_lines_are_drawn_and_cleared() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping debug line test");
+            return Ok(());
+        };
+        let device = &gpu.device;
+        let camera_layout = camera_layout(device);
+        // Identity matrices, the positions are in clip space.
+        let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&[crate::camera::CameraUniform::new()]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &camera_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: camera_buffer.as_entire_binding(),
+            }],
+        });
+
+        let format = wgpu::TextureFormat::Rgba8Unorm;
+        let target = texture::Texture::create_render_target(
+            device,
+            4,
+            4,
+            format,
+            wgpu::TextureUsages::COPY_SRC,
+        );
+        let mut debug = DebugRenderer::new(&gpu, &camera_layout, format, None, 1);
+
+        debug.draw_aabb(
+            na::Point3::new(-2.0, -2.0, 0.1),
+            na::Point3::new(2.0, 2.0, 0.9),
+            [0.0, 0.0, 1.0, 1.0],
+        );
+        assert_eq!(debug.len(), 24);
+        debug.clear();
+        // Through the centers of the second row of pixels.
+        debug.draw_line(
+            na::Point3::new(-1.0, 0.25, 0.5),
+            na::Point3::new(1.0, 0.25, 0.5),
+            [1.0, 0.0, 0.0, 1.0],
+        );
+
+        let render = |debug: &mut DebugRenderer| {
+            debug.upload(&gpu);
+            let mut encoder = device.create_command_encoder(&Default::default());
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &target.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            debug.draw(&mut render_pass, &camera_bind_group);
+            drop(render_pass);
+            gpu.queue.submit([encoder.finish()]);
+            debug.clear();
+            gpu.read_texture(&target.texture)
+        };
+
+        let frame = render(&mut debug)?;
+        let pixel = |x: usize, y: usize| &frame.pixels[(y * 4 + x) * 4..][..4];
+        assert_eq!(pixel(1, 1), [255, 0, 0, 255]);
+        assert_eq!(pixel(1, 3), [0, 0, 0, 255]);
+        assert!(debug.is_empty());
+
+        // Nothing is left over for the next frame.
+        let frame = render(&mut debug)?;
+        assert!(frame.pixels.chunks(4).all(|pixel| pixel == [0, 0, 0, 255]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffer_grows() {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping debug buffer test");
+            return;
+        };
+        let layout = camera_layout(&gpu.device);
+        let mut debug = DebugRenderer::new(&gpu, &layout, wgpu::TextureFormat::Rgba8Unorm, None, 1);
+        let origin = na::Point3::origin();
+        for _ in 0..DebugRenderer::INITIAL_CAPACITY {
+            debug.draw_line(origin, origin, [1.0; 4]);
+        }
+        debug.upload(&gpu);
+        assert_eq!(
+            debug.buffer.size(),
+            (2 * DebugRenderer::INITIAL_CAPACITY * mem::size_of::<ColorVertex>()) as u64
+        );
+        assert_eq!(debug.uploaded as usize, 2 * DebugRenderer::INITIAL_CAPACITY);
+    }
+}
@@ -0,0 +1,368 @@
+use nalgebra as na;
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+use crate::model::ModelVertex;
+
+/// How much more than the faces' planes the planes through open borders
+/// weigh, so the outline of a mesh survives simplification.
+const BORDER_WEIGHT: f64 = 1000.0;
+
+/// Removes vertices by collapsing edges into one of their ends, cheapest
+/// first by the quadric error metric, until at most `target_ratio` of the
+/// triangles are left. The result is compacted and indexes only the
+/// vertices still in use.
+///
+/// Collapsing into an existing vertex keeps its attributes as they are.
+/// Vertices sharing their position with another, the two sides of a UV or
+/// normal seam, are never moved so the seam can't tear open. Collapses
+/// that would flip a triangle or fold the surface onto itself are skipped,
+/// so meshes can stop short of the target.
+pub fn decimate(
+    vertices: &[ModelVertex],
+    indices: &[u32],
+    target_ratio: f32,
+) -> (Vec<ModelVertex>, Vec<u32>) {
+    let mut triangles = indices
+        .chunks_exact(3)
+        .map(|triangle| [0, 1, 2].map(|i| triangle[i] as usize))
+        .filter(|&[a, b, c]| a != b && b != c && c != a)
+        .collect::<Vec<_>>();
+    let target = (triangles.len() as f32 * target_ratio.clamp(0.0, 1.0)).ceil() as usize;
+    let position = |vertex: usize| {
+        let [x, y, z] = vertices[vertex].position;
+        na::Point3::new(x as f64, y as f64, z as f64)
+    };
+
+    let mut vertex_triangles = vec![Vec::new(); vertices.len()];
+    for (t, triangle) in triangles.iter().enumerate() {
+        for &vertex in triangle {
+            vertex_triangles[vertex].push(t);
+        }
+    }
+
+    let mut quadrics = vec![na::Matrix4::<f64>::zeros(); vertices.len()];
+    let mut edge_triangles = HashMap::<(usize, usize), Vec<usize>>::new();
+    for (t, &[a, b, c]) in triangles.iter().enumerate() {
+        let cross = (position(b) - position(a)).cross(&(position(c) - position(a)));
+        let area = cross.norm() / 2.0;
+        if area > 0.0 {
+            let quadric = plane_quadric(&position(a), &cross.normalize()) * area;
+            for vertex in [a, b, c] {
+                quadrics[vertex] += quadric;
+            }
+        }
+        for edge in [(a, b), (b, c), (c, a)] {
+            edge_triangles.entry(sorted(edge)).or_default().push(t);
+        }
+    }
+    for (&(a, b), adjacent) in &edge_triangles {
+        let &[t] = adjacent.as_slice() else {
+            continue;
+        };
+        let [x, y, z] = triangles[t].map(position);
+        let face_normal = (y - x).cross(&(z - x));
+        let edge = position(b) - position(a);
+        let normal = edge.cross(&face_normal);
+        if normal.norm() > 0.0 {
+            let quadric = plane_quadric(&position(a), &normal.normalize())
+                * edge.norm_squared()
+                * BORDER_WEIGHT;
+            quadrics[a] += quadric;
+            quadrics[b] += quadric;
+        }
+    }
+
+    let mut same_position = HashMap::<[u32; 3], usize>::new();
+    for vertex in vertices {
+        *same_position
+            .entry(vertex.position.map(f32::to_bits))
+            .or_default() += 1;
+    }
+    let locked = vertices
+        .iter()
+        .map(|vertex| same_position[&vertex.position.map(f32::to_bits)] > 1)
+        .collect::<Vec<_>>();
+
+    let mut heap = BinaryHeap::new();
+    let mut versions = vec![0u32; vertices.len()];
+    let push_collapses = |heap: &mut BinaryHeap<Collapse>,
+                          quadrics: &[na::Matrix4<f64>],
+                          versions: &[u32],
+                          a: usize,
+                          b: usize| {
+        let quadric = quadrics[a] + quadrics[b];
+        for (from, to) in [(a, b), (b, a)] {
+            if !locked[from] {
+                heap.push(Collapse {
+                    cost: error(&quadric, &position(to)),
+                    from,
+                    to,
+                    versions: (versions[from], versions[to]),
+                });
+            }
+        }
+    };
+    for &(a, b) in edge_triangles.keys() {
+        push_collapses(&mut heap, &quadrics, &versions, a, b);
+    }
+
+    let mut alive = vec![true; triangles.len()];
+    let mut remaining = triangles.len();
+    while remaining > target {
+        let Some(collapse) = heap.pop() else {
+            break;
+        };
+        let Collapse { from, to, .. } = collapse;
+        if collapse.versions != (versions[from], versions[to]) {
+            continue;
+        }
+        let around = |vertex_triangles: &[Vec<usize>], alive: &[bool], vertex: usize| {
+            vertex_triangles[vertex]
+                .iter()
+                .copied()
+                .filter(|&t| alive[t])
+                .collect::<Vec<_>>()
+        };
+        let from_triangles = around(&vertex_triangles, &alive, from);
+        let to_triangles = around(&vertex_triangles, &alive, to);
+        let shared = from_triangles
+            .iter()
+            .filter(|&&t| triangles[t].contains(&to))
+            .count();
+        if shared == 0 {
+            // The edge went away with an earlier collapse.
+            continue;
+        }
+
+        // Only the vertices opposite the collapsed edge may neighbour both
+        // ends, any other would end up with two edges to `to`.
+        let neighbours = |triangles_around: &[usize]| {
+            triangles_around
+                .iter()
+                .flat_map(|&t| triangles[t])
+                .collect::<HashSet<_>>()
+        };
+        let common = neighbours(&from_triangles)
+            .intersection(&neighbours(&to_triangles))
+            .filter(|&&vertex| vertex != from && vertex != to)
+            .count();
+        if common > shared {
+            continue;
+        }
+
+        let flips = from_triangles
+            .iter()
+            .filter(|&&t| !triangles[t].contains(&to))
+            .any(|&t| {
+                let before = triangles[t].map(position);
+                let after =
+                    triangles[t].map(|vertex| position(if vertex == from { to } else { vertex }));
+                let normal_before = (before[1] - before[0]).cross(&(before[2] - before[0]));
+                let normal_after = (after[1] - after[0]).cross(&(after[2] - after[0]));
+                normal_after.norm_squared() == 0.0 || normal_before.dot(&normal_after) <= 0.0
+            });
+        if flips {
+            continue;
+        }
+
+        for t in from_triangles {
+            if triangles[t].contains(&to) {
+                alive[t] = false;
+                remaining -= 1;
+            } else {
+                for vertex in &mut triangles[t] {
+                    if *vertex == from {
+                        *vertex = to;
+                    }
+                }
+                vertex_triangles[to].push(t);
+            }
+        }
+        vertex_triangles[from].clear();
+        let from_quadric = quadrics[from];
+        quadrics[to] += from_quadric;
+        versions[from] += 1;
+        versions[to] += 1;
+
+        let neighbours = around(&vertex_triangles, &alive, to)
+            .into_iter()
+            .flat_map(|t| triangles[t])
+            .filter(|&vertex| vertex != to)
+            .collect::<HashSet<_>>();
+        for neighbour in neighbours {
+            push_collapses(&mut heap, &quadrics, &versions, to, neighbour);
+        }
+    }
+
+    let mut remap = HashMap::new();
+    let mut kept_vertices = Vec::new();
+    let kept_indices = triangles
+        .iter()
+        .zip(&alive)
+        .filter(|(_, &alive)| alive)
+        .flat_map(|(triangle, _)| *triangle)
+        .map(|vertex| {
+            *remap.entry(vertex).or_insert_with(|| {
+                kept_vertices.push(vertices[vertex]);
+                kept_vertices.len() as u32 - 1
+            })
+        })
+        .collect();
+    (kept_vertices, kept_indices)
+}
+
+/// A candidate edge collapse moving `from` onto `to`, stale once either
+/// vertex changed since it was queued.
+struct Collapse {
+    cost: f64,
+    from: usize,
+    to: usize,
+    versions: (u32, u32),
+}
+
+impl PartialEq for Collapse {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Collapse {}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Collapse {
+    /// Reversed, so the max heap pops the cheapest collapse first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+fn sorted((a, b): (usize, usize)) -> (usize, usize) {
+    (a.min(b), a.max(b))
+}
+
+/// The quadric measuring the squared distance to the plane through `point`
+/// with the unit `normal`.
+fn plane_quadric(point: &na::Point3<f64>, normal: &na::Vector3<f64>) -> na::Matrix4<f64> {
+    let plane = normal.push(-normal.dot(&point.coords));
+    plane * plane.transpose()
+}
+
+fn error(quadric: &na::Matrix4<f64>, point: &na::Point3<f64>) -> f64 {
+    let point = point.to_homogeneous();
+    (point.transpose() * quadric * point)[0].max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{frustum::Aabb, gpu::Gpu, model::Mesh};
+
+    /// A unit square in the xy plane split into `n` by `n` quads.
+    fn grid(n: u32) -> (Vec<ModelVertex>, Vec<u32>) {
+        let vertices = (0..=n)
+            .flat_map(|y| (0..=n).map(move |x| [x as f32 / n as f32, y as f32 / n as f32]))
+            .map(|[x, y]| ModelVertex {
+                position: [x, y, 0.0],
+                tex_coord: [x, y],
+                normal: [0.0, 0.0, 1.0],
+                tangent: [1.0, 0.0, 0.0, 1.0],
+            })
+            .collect();
+        let indices = (0..n)
+            .flat_map(|y| (0..n).map(move |x| y * (n + 1) + x))
+            .flat_map(|corner| {
+                let above = corner + n + 1;
+                [corner, corner + 1, above + 1, corner, above + 1, above]
+            })
+            .collect();
+        (vertices, indices)
+    }
+
+    fn bounds(vertices: &[ModelVertex]) -> Aabb {
+        Aabb::from_points(vertices.iter().map(|vertex| vertex.position.into()))
+    }
+
+    #[test]
+    fn test_decimate_keeps_the_outline() {
+        let (vertices, indices) = grid(8);
+        let (decimated, decimated_indices) = decimate(&vertices, &indices, 0.25);
+
+        assert!(decimated_indices.len() / 3 <= indices.len() / 3 / 4);
+        assert!(decimated.len() < vertices.len());
+        assert!(decimated_indices
+            .iter()
+            .all(|&index| (index as usize) < decimated.len()));
+        assert_eq!(bounds(&decimated), bounds(&vertices));
+
+        // The plane stays covered: no triangle flipped or went missing.
+        let area = |vertices: &[ModelVertex], indices: &[u32]| {
+            indices
+                .chunks_exact(3)
+                .map(|triangle| {
+                    let [a, b, c] = [0, 1, 2].map(|i| {
+                        let [x, y, _] = vertices[triangle[i] as usize].position;
+                        na::Vector2::new(x, y)
+                    });
+                    (b - a).perp(&(c - a)) / 2.0
+                })
+                .sum::<f32>()
+        };
+        assert!((area(&decimated, &decimated_indices) - 1.0).abs() < 1e-4);
+        assert!((area(&vertices, &indices) - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_mesh_decimate() {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping mesh decimation test");
+            return;
+        };
+        // Packed behind a triangle, so its indices start at a base vertex.
+        let (vertices, indices) = grid(16);
+        let triangle = &vertices[..3];
+        let meshes = Mesh::pack(
+            &gpu.device,
+            "plane",
+            &[(triangle, &[0, 1, 2], 0), (&vertices, &indices, 0)],
+        );
+        let plane = &meshes[1];
+
+        let half = plane.decimate(&gpu, 0.5).unwrap();
+        let triangles = half.num_elements / 3;
+        assert!(triangles <= plane.num_elements / 3 / 2);
+        assert!(triangles >= plane.num_elements / 3 * 2 / 5);
+        assert_eq!(half.aabb, plane.aabb);
+        assert_eq!(half.base_vertex, 0);
+    }
+
+    #[test]
+    fn test_decimate_leaves_seams_alone() {
+        // Two grids side by side, the shared column duplicated like a UV
+        // seam.
+        let (mut vertices, mut indices) = grid(4);
+        let (right, right_indices) = grid(4);
+        let offset = vertices.len() as u32;
+        vertices.extend(right.into_iter().map(|vertex| ModelVertex {
+            position: [vertex.position[0] + 1.0, vertex.position[1], 0.0],
+            ..vertex
+        }));
+        indices.extend(right_indices.into_iter().map(|index| index + offset));
+
+        let (decimated, _) = decimate(&vertices, &indices, 0.5);
+        let seam = |vertices: &[ModelVertex]| {
+            vertices
+                .iter()
+                .filter(|vertex| vertex.position[0] == 1.0)
+                .count()
+        };
+        assert_eq!(seam(&decimated), seam(&vertices));
+    }
+}
@@ -0,0 +1,279 @@
+use std::{mem, time::Duration};
+
+use crate::gpu::Gpu;
+
+const BIN_COUNT: u64 = 256;
+const WORKGROUP_SIZE: u32 = 16;
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ExposureParams {
+    min_log_luminance: f32,
+    inv_log_luminance_range: f32,
+    log_luminance_range: f32,
+    adapt: f32,
+    key: f32,
+    pixel_count: u32,
+    // Uniform structs are padded to 16 bytes.
+    _padding: [u32; 2],
+}
+
+/// Average luminance the exposure adapted to and the exposure derived from it.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ExposureState {
+    pub luminance: f32,
+    pub exposure: f32,
+}
+
+/// Computes an exposure for the HDR target every frame from a histogram of
+/// its log luminance, easing towards the scene's average luminance instead
+/// of jumping to it.
+pub struct AutoExposure {
+    layout: wgpu::BindGroupLayout,
+    histogram_pipeline: wgpu::ComputePipeline,
+    average_pipeline: wgpu::ComputePipeline,
+    params_buffer: wgpu::Buffer,
+    histogram_buffer: wgpu::Buffer,
+    state_buffer: wgpu::Buffer,
+    /// Luminances outside `2^min_log_luminance..2^(min_log_luminance +
+    /// log_luminance_range)` are clamped into the first or last bin.
+    pub min_log_luminance: f32,
+    pub log_luminance_range: f32,
+    /// How quickly the exposure follows the scene, per second. Higher is faster.
+    pub adaptation_speed: f32,
+    /// Middle grey, the average luminance ends up at this value.
+    pub key: f32,
+}
+
+fn buffer_entry(binding: u32, ty: wgpu::BufferBindingType) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+impl AutoExposure {
+    pub fn new(gpu: &Gpu) -> Self {
+        let device = &gpu.device;
+        let shader = device.create_shader_module(wgpu::include_wgsl!("exposure.wgsl"));
+
+        // Only read with textureLoad, so 32 bit float targets work as well.
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("AutoExposure::layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                buffer_entry(1, wgpu::BufferBindingType::Uniform),
+                buffer_entry(2, wgpu::BufferBindingType::Storage { read_only: false }),
+                buffer_entry(3, wgpu::BufferBindingType::Storage { read_only: false }),
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("AutoExposure::pipeline_layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let create_pipeline = |entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point,
+            })
+        };
+        let histogram_pipeline = create_pipeline("build_histogram");
+        let average_pipeline = create_pipeline("average");
+
+        let params_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("AutoExposure::params"),
+            size: mem::size_of::<ExposureParams>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        // Zeroed, the shader relies on it to clear the bins after each frame.
+        let histogram_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("AutoExposure::histogram"),
+            size: BIN_COUNT * mem::size_of::<u32>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        // A zero luminance makes the first frame skip adaptation.
+        let state_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("AutoExposure::state"),
+            size: mem::size_of::<ExposureState>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            layout,
+            histogram_pipeline,
+            average_pipeline,
+            params_buffer,
+            histogram_buffer,
+            state_buffer,
+            min_log_luminance: -8.0,
+            log_luminance_range: 12.0,
+            adaptation_speed: 1.5,
+            key: 0.18,
+        }
+    }
+
+    /// Fraction of the way towards the new luminance covered after `dt`,
+    /// independent of the frame rate.
+    pub fn adapt_factor(speed: f32, dt: Duration) -> f32 {
+        1.0 - (-dt.as_secs_f32() * speed.max(0.0)).exp()
+    }
+
+    /// Buffer holding the [`ExposureState`], `exposure` is at offset 4.
+    pub fn state_buffer(&self) -> &wgpu::Buffer {
+        &self.state_buffer
+    }
+
+    /// Builds the histogram of `hdr` and moves the exposure towards its
+    /// average luminance, `dt` being the time since the last call.
+    pub fn process(
+        &self,
+        gpu: &Gpu,
+        encoder: &mut wgpu::CommandEncoder,
+        hdr: &wgpu::Texture,
+        dt: Duration,
+    ) {
+        let view = hdr.create_view(&wgpu::TextureViewDescriptor::default());
+        let params = ExposureParams {
+            min_log_luminance: self.min_log_luminance,
+            inv_log_luminance_range: 1.0 / self.log_luminance_range,
+            log_luminance_range: self.log_luminance_range,
+            adapt: Self::adapt_factor(self.adaptation_speed, dt),
+            key: self.key,
+            pixel_count: hdr.width() * hdr.height(),
+            _padding: [0; 2],
+        };
+        gpu.queue
+            .write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[params]));
+
+        let bind_group = gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("AutoExposure::bind_group"),
+            layout: &self.layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: self.params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: self.histogram_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: self.state_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("AutoExposure::process"),
+            timestamp_writes: None,
+        });
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.set_pipeline(&self.histogram_pipeline);
+        pass.dispatch_workgroups(
+            hdr.width().div_ceil(WORKGROUP_SIZE),
+            hdr.height().div_ceil(WORKGROUP_SIZE),
+            1,
+        );
+        pass.set_pipeline(&self.average_pipeline);
+        pass.dispatch_workgroups(1, 1, 1);
+    }
+
+    /// Reads back the current state, blocking until the GPU is done.
+    pub fn read_state(&self, gpu: &Gpu) -> anyhow::Result<ExposureState> {
+        let bytes = gpu.read_buffer(&self.state_buffer)?;
+        Ok(*bytemuck::from_bytes(&bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adapt_factor() {
+        assert_eq!(AutoExposure::adapt_factor(2.0, Duration::ZERO), 0.0);
+        let half = AutoExposure::adapt_factor(std::f32::consts::LN_2, Duration::from_secs(1));
+        assert!((half - 0.5).abs() < 1e-6);
+        assert!(AutoExposure::adapt_factor(100.0, Duration::from_secs(1)) > 0.999);
+    }
+
+    #[test]
+    fn test_uniform_image_average_luminance() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping auto exposure test");
+            return Ok(());
+        };
+        let (width, height) = (50, 30);
+        let texture = gpu.device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        // Luminance weights sum to one, so a grey pixel's luminance is its value.
+        let pixels = [[2.0f32, 2.0, 2.0, 1.0]].repeat((width * height) as usize);
+        gpu.queue.write_texture(
+            texture.as_image_copy(),
+            bytemuck::cast_slice(&pixels),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(width * 16),
+                rows_per_image: None,
+            },
+            texture.size(),
+        );
+
+        let exposure = AutoExposure::new(&gpu);
+        // The first frame snaps, the second runs through the adaptation.
+        for _ in 0..2 {
+            let mut encoder = gpu.device.create_command_encoder(&Default::default());
+            exposure.process(&gpu, &mut encoder, &texture, Duration::from_millis(16));
+            gpu.queue.submit([encoder.finish()]);
+        }
+
+        let state = exposure.read_state(&gpu)?;
+        assert!(
+            (state.luminance - 2.0).abs() < 0.02,
+            "average luminance {}",
+            state.luminance
+        );
+        assert!((state.exposure - exposure.key / state.luminance).abs() < 1e-4);
+        Ok(())
+    }
+}
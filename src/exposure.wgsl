@@ -0,0 +1,109 @@
+// Auto exposure: a histogram of the HDR target's log luminance, reduced to
+// an average the exposure adapts towards.
+
+const BIN_COUNT: u32 = 256u;
+const EPSILON: f32 = 0.0001;
+
+struct Params {
+    min_log_luminance: f32,
+    inv_log_luminance_range: f32,
+    log_luminance_range: f32,
+    // How far to move towards this frame's luminance, 0..1.
+    adapt: f32,
+    // Middle grey the average luminance is mapped to.
+    key: f32,
+    pixel_count: u32,
+}
+
+struct State {
+    luminance: f32,
+    exposure: f32,
+}
+
+@group(0)
+@binding(0)
+var hdr: texture_2d<f32>;
+
+@group(0)
+@binding(1)
+var<uniform> params: Params;
+
+@group(0)
+@binding(2)
+var<storage, read_write> histogram: array<atomic<u32>, BIN_COUNT>;
+
+@group(0)
+@binding(3)
+var<storage, read_write> state: State;
+
+var<workgroup> local_bins: array<atomic<u32>, BIN_COUNT>;
+
+// Bin 0 is for black pixels, the rest covers the log luminance range.
+fn bin_index(color: vec3<f32>) -> u32 {
+    let luminance = dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+    if luminance < EPSILON {
+        return 0u;
+    }
+    let t = clamp((log2(luminance) - params.min_log_luminance) * params.inv_log_luminance_range, 0.0, 1.0);
+    return u32(t * f32(BIN_COUNT - 2u) + 1.0);
+}
+
+@compute
+@workgroup_size(16, 16, 1)
+fn build_histogram(
+    @builtin(global_invocation_id)
+    gid: vec3<u32>,
+    @builtin(local_invocation_index)
+    local_index: u32,
+) {
+    atomicStore(&local_bins[local_index], 0u);
+    workgroupBarrier();
+
+    let size = textureDimensions(hdr);
+    if gid.x < size.x && gid.y < size.y {
+        let color = textureLoad(hdr, gid.xy, 0).rgb;
+        atomicAdd(&local_bins[bin_index(color)], 1u);
+    }
+    workgroupBarrier();
+
+    atomicAdd(&histogram[local_index], atomicLoad(&local_bins[local_index]));
+}
+
+var<workgroup> weighted: array<f32, BIN_COUNT>;
+
+@compute
+@workgroup_size(256, 1, 1)
+fn average(
+    @builtin(local_invocation_index)
+    local_index: u32,
+) {
+    let count = atomicLoad(&histogram[local_index]);
+    weighted[local_index] = f32(count) * f32(local_index);
+    // Ready for the next frame.
+    atomicStore(&histogram[local_index], 0u);
+    workgroupBarrier();
+
+    for (var stride = BIN_COUNT / 2u; stride > 0u; stride >>= 1u) {
+        if local_index < stride {
+            weighted[local_index] += weighted[local_index + stride];
+        }
+        workgroupBarrier();
+    }
+
+    if local_index == 0u {
+        // Black pixels don't pull the average down, `count` is bin 0 here.
+        let lit = max(f32(params.pixel_count) - f32(count), 1.0);
+        // Bins are floored, their centers are half a bin further.
+        let bin = weighted[0] / lit - 0.5;
+        let log_luminance = bin / f32(BIN_COUNT - 2u) * params.log_luminance_range + params.min_log_luminance;
+        let luminance = exp2(log_luminance);
+
+        var adapted = state.luminance + (luminance - state.luminance) * params.adapt;
+        // Nothing to adapt from on the first frame.
+        if state.luminance <= 0.0 {
+            adapted = luminance;
+        }
+        state.luminance = adapted;
+        state.exposure = params.key / max(adapted, EPSILON);
+    }
+}
@@ -0,0 +1,152 @@
+use nalgebra as na;
+
+/// Axis aligned bounding box.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: na::Point3<f32>,
+    pub max: na::Point3<f32>,
+}
+
+impl Aabb {
+    /// Smallest box holding every point, a degenerate box at the origin when
+    /// there are none.
+    pub fn from_points(points: impl IntoIterator<Item = na::Point3<f32>>) -> Self {
+        let mut points = points.into_iter();
+        let Some(first) = points.next() else {
+            return Self {
+                min: na::Point3::origin(),
+                max: na::Point3::origin(),
+            };
+        };
+        points.fold(
+            Self {
+                min: first,
+                max: first,
+            },
+            |aabb, point| Self {
+                min: aabb.min.inf(&point),
+                max: aabb.max.sup(&point),
+            },
+        )
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+
+    pub fn center(&self) -> na::Point3<f32> {
+        na::center(&self.min, &self.max)
+    }
+
+    /// Box around this one once moved by `isometry`.
+    pub fn transform(&self, isometry: &na::Isometry3<f32>) -> Self {
+        let center = isometry * self.center();
+        let half_extents = (self.max - self.min) / 2.0;
+        let rotation = isometry.rotation.to_rotation_matrix().into_inner().abs();
+        let half_extents = rotation * half_extents;
+        Self {
+            min: center - half_extents,
+            max: center + half_extents,
+        }
+    }
+}
+
+/// The six planes bounding what a camera sees, each `[a, b, c, d]` with the
+/// inside where `ax + by + cz + d >= 0`.
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+    planes: [na::Vector4<f32>; 6],
+}
+
+impl Frustum {
+    /// Extracts the planes from a view projection matrix. The near plane is
+    /// taken at the `-1` clip depth, which also covers projections mapping
+    /// depth to `0..1` at the cost of keeping a little behind their near
+    /// plane.
+    pub fn from_view_proj(matrix: &na::Matrix4<f32>) -> Self {
+        let row = |i| matrix.row(i).transpose();
+        let w = row(3);
+        let planes = [
+            w + row(0),
+            w - row(0),
+            w + row(1),
+            w - row(1),
+            w + row(2),
+            w - row(2),
+        ]
+        .map(|plane| plane / plane.xyz().norm());
+        Self { planes }
+    }
+
+    /// Whether any of `aabb` may be visible. Boxes near the frustum's corners
+    /// can pass while being outside, never the other way around.
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // The corner furthest along the plane's normal.
+            let corner = na::Vector3::from_fn(|i, _| {
+                if plane[i] >= 0.0 {
+                    aabb.max[i]
+                } else {
+                    aabb.min[i]
+                }
+            });
+            plane.xyz().dot(&corner) + plane.w >= 0.0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{ICamera, Projection, StaticCamera};
+
+    fn unit_box(center: na::Point3<f32>) -> Aabb {
+        Aabb {
+            min: center - na::Vector3::repeat(0.5),
+            max: center + na::Vector3::repeat(0.5),
+        }
+    }
+
+    fn visible(camera: &StaticCamera, boxes: &[Aabb]) -> usize {
+        let projection = Projection::with_kind(800.0, 600.0, camera.projection);
+        let view_proj = projection.build_matrix() * camera.build_view_matrix();
+        let frustum = Frustum::from_view_proj(&view_proj);
+        boxes.iter().filter(|aabb| frustum.intersects(aabb)).count()
+    }
+
+    #[test]
+    fn test_camera_looking_away_sees_nothing() {
+        let boxes = [
+            unit_box(na::Point3::new(0.0, 0.0, -5.0)),
+            unit_box(na::Point3::new(2.0, 1.0, -10.0)),
+            unit_box(na::Point3::new(-1.0, 0.0, -3.0)),
+        ];
+        let mut camera = StaticCamera {
+            position: na::Point3::origin(),
+            target: na::Point3::new(0.0, 0.0, -1.0),
+            ..StaticCamera::new()
+        };
+        assert_eq!(visible(&camera, &boxes), boxes.len());
+
+        camera.target = na::Point3::new(0.0, 0.0, 1.0);
+        assert_eq!(visible(&camera, &boxes), 0);
+    }
+
+    #[test]
+    fn test_aabb_transform() {
+        let aabb = Aabb::from_points([
+            na::Point3::new(-1.0, -2.0, 0.0),
+            na::Point3::new(1.0, 2.0, 0.0),
+        ]);
+        let isometry = na::Isometry3::new(
+            na::Vector3::new(10.0, 0.0, 0.0),
+            na::Vector3::z() * std::f32::consts::FRAC_PI_2,
+        );
+        let moved = aabb.transform(&isometry);
+        assert!((moved.min - na::Point3::new(8.0, -1.0, 0.0)).norm() < 1e-5);
+        assert!((moved.max - na::Point3::new(12.0, 1.0, 0.0)).norm() < 1e-5);
+    }
+}
@@ -0,0 +1,396 @@
+use crate::{
+    gpu::Gpu,
+    model::{InstanceBuffer, InstanceRaw, ModelVertex, Vertex},
+    texture, ModelEntry,
+};
+
+/// Screen space inputs for SSR: linear depth in `[0, 1]` (0 at the camera,
+/// 1 at the far plane) and view space normals.
+pub struct GBuffer {
+    pipeline: wgpu::RenderPipeline,
+    linear_depth: texture::Texture,
+    normal: texture::Texture,
+    layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    width: u32,
+    height: u32,
+}
+
+impl GBuffer {
+    pub const LINEAR_DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R32Float;
+    pub const NORMAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+    pub fn new(gpu: &Gpu, camera_layout: &wgpu::BindGroupLayout, width: u32, height: u32) -> Self {
+        let device = &gpu.device;
+
+        let (linear_depth, normal) = Self::create_targets(gpu, width, height);
+
+        // R32Float is not filterable, so both targets are read with a
+        // non-filtering sampler.
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("GBuffer::layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::NonFiltering),
+                    count: None,
+                },
+            ],
+        });
+
+        let bind_group = Self::create_bind_group(device, &layout, &linear_depth, &normal);
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("gbuffer.wgsl"));
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("GBuffer::pipeline_layout"),
+            bind_group_layouts: &[camera_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("GBuffer::pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[ModelVertex::desc(), InstanceRaw::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[
+                    Some(wgpu::ColorTargetState {
+                        format: Self::LINEAR_DEPTH_FORMAT,
+                        blend: None,
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }),
+                    Some(wgpu::ColorTargetState {
+                        format: Self::NORMAL_FORMAT,
+                        blend: None,
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }),
+                ],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            // The scene pass has already filled the depth buffer, so we
+            // only test against it.
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: texture::Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        Self {
+            pipeline,
+            linear_depth,
+            normal,
+            layout,
+            bind_group,
+            width,
+            height,
+        }
+    }
+
+    fn create_targets(gpu: &Gpu, width: u32, height: u32) -> (texture::Texture, texture::Texture) {
+        let usage = wgpu::TextureUsages::TEXTURE_BINDING
+            | wgpu::TextureUsages::RENDER_ATTACHMENT
+            | wgpu::TextureUsages::COPY_SRC;
+
+        let linear_depth = texture::Texture::create_2d_texture(
+            gpu,
+            height,
+            width,
+            Self::LINEAR_DEPTH_FORMAT,
+            usage,
+            wgpu::FilterMode::Nearest,
+            Some("GBuffer::linear_depth"),
+        );
+        let normal = texture::Texture::create_2d_texture(
+            gpu,
+            height,
+            width,
+            Self::NORMAL_FORMAT,
+            usage,
+            wgpu::FilterMode::Nearest,
+            Some("GBuffer::normal"),
+        );
+
+        (linear_depth, normal)
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        linear_depth: &texture::Texture,
+        normal: &texture::Texture,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("GBuffer::bind_group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&linear_depth.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&normal.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&linear_depth.sampler),
+                },
+            ],
+        })
+    }
+
+    pub fn resize(&mut self, gpu: &Gpu, width: u32, height: u32) {
+        let (linear_depth, normal) = Self::create_targets(gpu, width, height);
+        self.bind_group =
+            Self::create_bind_group(&gpu.device, &self.layout, &linear_depth, &normal);
+        self.linear_depth = linear_depth;
+        self.normal = normal;
+        self.width = width;
+        self.height = height;
+    }
+
+    pub fn linear_depth_view(&self) -> &wgpu::TextureView {
+        &self.linear_depth.view
+    }
+
+    pub fn normal_view(&self) -> &wgpu::TextureView {
+        &self.normal.view
+    }
+
+    /// Layout of [`GBuffer::bind_group`] for passes consuming the G-buffer.
+    pub fn layout(&self) -> &wgpu::BindGroupLayout {
+        &self.layout
+    }
+
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    /// Renders `models` into the G-buffer, depth testing against the
+    /// already populated scene `depth` buffer.
+    pub fn process<'a>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        depth: &wgpu::TextureView,
+        camera_bind_group: &wgpu::BindGroup,
+        models: impl Iterator<Item = &'a ModelEntry>,
+    ) {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("GBuffer::process"),
+            color_attachments: &[
+                Some(wgpu::RenderPassColorAttachment {
+                    view: &self.linear_depth.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        // Nothing drawn means the far plane.
+                        load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                        store: wgpu::StoreOp::Store,
+                    },
+                }),
+                Some(wgpu::RenderPassColorAttachment {
+                    view: &self.normal.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                }),
+            ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, camera_bind_group, &[]);
+
+        for entry in models.filter(|entry| !entry.instances.is_empty()) {
+            pass.set_vertex_buffer(InstanceBuffer::SLOT, entry.instances.slice());
+            for mesh in &entry.model.meshes {
+                pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                pass.set_index_buffer(mesh.index_slice(), mesh.index_format);
+                pass.draw_indexed(
+                    0..mesh.num_elements,
+                    mesh.base_vertex,
+                    0..entry.instances.len(),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{CameraUniform, ProjectionKind, StaticCamera};
+    use crate::model::Model;
+    use wgpu::util::DeviceExt;
+
+    /// Enough of a half float decoder for unit vectors.
+    fn f16_to_f32(bits: u16) -> f32 {
+        let sign = if bits & 0x8000 == 0 { 1.0 } else { -1.0 };
+        let exponent = ((bits >> 10) & 0x1f) as i32;
+        let mantissa = (bits & 0x3ff) as f32 / 1024.0;
+        match exponent {
+            0 => sign * mantissa * 2f32.powi(-14),
+            _ => sign * (1.0 + mantissa) * 2f32.powi(exponent - 15),
+        }
+    }
+
+    #[test]
+    fn test_plane_linear_depth_and_normal() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping G-buffer test");
+            return Ok(());
+        };
+        let device = &gpu.device;
+        let camera_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        // Looking down -x, so the plane's world space +x normal faces the
+        // camera, +z in view space.
+        let camera = StaticCamera {
+            position: na::Point3::origin(),
+            target: na::Point3::new(-1.0, 0.0, 0.0),
+            up: na::Vector3::y(),
+            projection: ProjectionKind::Perspective {
+                fovy: std::f32::consts::FRAC_PI_2,
+                znear: 0.1,
+                zfar: 10.0,
+            },
+        };
+        let projection = crate::camera::Projection::with_kind(4.0, 4.0, camera.projection);
+        let mut camera_uniform = CameraUniform::new();
+        camera_uniform.update_view_projection(&projection, &camera);
+        let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&[camera_uniform]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &camera_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: camera_buffer.as_entire_binding(),
+            }],
+        });
+
+        // A plane at x = -5, half way to the far plane, covering the view.
+        let vertices = [[-10.0, -10.0], [-10.0, 10.0], [10.0, -10.0], [10.0, 10.0]].map(|[y, z]| {
+            ModelVertex {
+                position: [-5.0, y, z],
+                tex_coord: [0.0; 2],
+                normal: [1.0, 0.0, 0.0],
+                tangent: [0.0, 0.0, 1.0, 1.0],
+            }
+        });
+        // Wound both ways so back face culling keeps one of them.
+        let indices = [0, 1, 2, 2, 1, 3, 0, 2, 1, 2, 3, 1];
+        let model = Model {
+            meshes: crate::model::Mesh::pack(device, "plane", &[(&vertices, &indices, 0)]),
+            materials: Vec::new(),
+            skin: None,
+        };
+        let entry = ModelEntry::new(&gpu, model);
+
+        let depth = texture::Texture::create_depth_texture(device, &gpu.get_config(), 1, "depth");
+        let gbuffer = GBuffer::new(&gpu, &camera_layout, 4, 4);
+        let mut encoder = device.create_command_encoder(&Default::default());
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &depth.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        gbuffer.process(
+            &mut encoder,
+            &depth.view,
+            &camera_bind_group,
+            std::iter::once(&entry),
+        );
+        gpu.queue.submit([encoder.finish()]);
+
+        let linear_depth = gpu.read_texture_mip(&gbuffer.linear_depth.texture, 0)?;
+        for depth in bytemuck::pod_collect_to_vec::<u8, f32>(&linear_depth) {
+            assert!((depth - 0.5).abs() < 1e-3, "linear depth {depth}");
+        }
+        let normals = gpu.read_texture_mip(&gbuffer.normal.texture, 0)?;
+        let normals = bytemuck::pod_collect_to_vec::<u8, u16>(&normals);
+        for normal in normals.chunks_exact(4) {
+            let normal = normal
+                .iter()
+                .map(|&bits| f16_to_f32(bits))
+                .collect::<Vec<_>>();
+            for (value, expected) in normal.iter().zip([0.0, 0.0, 1.0, 1.0]) {
+                assert!((value - expected).abs() < 1e-3, "view normal {normal:?}");
+            }
+        }
+        Ok(())
+    }
+}
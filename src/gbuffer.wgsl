@@ -0,0 +1,80 @@
+struct Camera {
+    view_pos: vec4<f32>,
+    view: mat4x4<f32>,
+    view_proj: mat4x4<f32>,
+    inv_proj: mat4x4<f32>,
+    inv_view: mat4x4<f32>,
+}
+@group(0) @binding(0)
+var<uniform> camera: Camera;
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+
+    @location(9) normal_matrix_0: vec3<f32>,
+    @location(10) normal_matrix_1: vec3<f32>,
+    @location(11) normal_matrix_2: vec3<f32>,
+};
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) view_position: vec3<f32>,
+    @location(1) view_normal: vec3<f32>,
+}
+
+struct GBufferOutput {
+    @location(0) linear_depth: f32,
+    @location(1) view_normal: vec4<f32>,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let normal_matrix = mat3x3<f32>(
+        instance.normal_matrix_0,
+        instance.normal_matrix_1,
+        instance.normal_matrix_2,
+    );
+
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    let world_normal = normal_matrix * model.normal;
+
+    var out: VertexOutput;
+    out.view_position = (camera.view * world_position).xyz;
+    out.view_normal = (camera.view * vec4<f32>(world_normal, 0.0)).xyz;
+    out.clip_position = camera.view_proj * world_position;
+    return out;
+}
+
+// Distance to the far plane, recovered from the inverse projection so we
+// don't need a separate uniform for it.
+fn far_plane() -> f32 {
+    let far = camera.inv_proj * vec4<f32>(0.0, 0.0, 1.0, 1.0);
+    return -far.z / far.w;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> GBufferOutput {
+    var out: GBufferOutput;
+    // The camera looks down -z in view space.
+    out.linear_depth = clamp(-in.view_position.z / far_plane(), 0.0, 1.0);
+    out.view_normal = vec4<f32>(normalize(in.view_normal), 1.0);
+    return out;
+}
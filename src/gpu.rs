@@ -1,31 +1,600 @@
 use std::{
-    cell::OnceCell,
     collections::BTreeMap,
-    sync::{atomic::AtomicUsize, Arc, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::Duration,
 };
 
-use wgpu::TextureView;
+use wgpu::{
+    util::{DeviceExt, StagingBelt},
+    TextureView,
+};
+
+use crate::{
+    db::{Id, DB},
+    hdr,
+    pass::PassQueue,
+    pipeline::{PipelineBuilder, PipelineCache, PipelineId, ReloadFailure, ShaderWatcher},
+    texture,
+};
 use winit::window::Window;
 
-static CMD_ID: OnceLock<AtomicUsize> = OnceLock::new();
+/// Where a command sits in a [`CommandList`]: sorted by `order` first and by
+/// the order the commands were pushed in for equal `order`s.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CommandListIndex {
+    pub order: u32,
+    sequence: usize,
+}
+
+/// Work recorded during a frame, handed back in exactly the order it was
+/// pushed so passes that depend on each other (shadow -> scene -> post -> UI)
+/// are submitted correctly regardless of which thread recorded them.
+///
+/// Threads racing to push can't rely on push order, they pick an explicit
+/// `order` with [`CommandList::push_ordered`] instead, e.g. opaque geometry
+/// below transparent geometry. Work recorded in parallel can also take its
+/// index up front with [`CommandList::reserve`] and [`CommandList::insert`]
+/// later, keeping the order it was handed out in.
+pub struct CommandList<T> {
+    next_sequence: usize,
+    max_order: u32,
+    cmds: BTreeMap<CommandListIndex, T>,
+}
+
+impl<T> Default for CommandList<T> {
+    fn default() -> Self {
+        Self {
+            next_sequence: 0,
+            max_order: 0,
+            cmds: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T> CommandList<T> {
+    /// Appends `cmd` after everything pushed so far and returns its index.
+    pub fn push(&mut self, cmd: T) -> CommandListIndex {
+        self.push_ordered(self.max_order, cmd)
+    }
+
+    /// Inserts `cmd` after every command with an `order` up to its own and
+    /// before every command with a greater one, whenever those were pushed.
+    pub fn push_ordered(&mut self, order: u32, cmd: T) -> CommandListIndex {
+        let index = CommandListIndex {
+            order,
+            sequence: self.next_sequence,
+        };
+        self.next_sequence += 1;
+        self.max_order = self.max_order.max(order);
+        self.cmds.insert(index, cmd);
+        index
+    }
+
+    /// Index of the next command [`CommandList::push`] would append, without
+    /// pushing anything. Hand it to [`CommandList::insert`] once recorded.
+    pub fn reserve(&mut self) -> CommandListIndex {
+        let index = CommandListIndex {
+            order: self.max_order,
+            sequence: self.next_sequence,
+        };
+        self.next_sequence += 1;
+        index
+    }
+
+    /// Places `cmd` at an index from [`CommandList::reserve`], so it's
+    /// submitted in reservation order however late it's inserted.
+    pub fn insert(&mut self, index: CommandListIndex, cmd: T) {
+        self.max_order = self.max_order.max(index.order);
+        self.cmds.insert(index, cmd);
+    }
+
+    pub fn len(&self) -> usize {
+        self.cmds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cmds.is_empty()
+    }
+
+    /// Removes every command in submission order.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> {
+        self.max_order = 0;
+        std::mem::take(&mut self.cmds).into_values()
+    }
+}
+
+/// Size of a mip level and the row pitches used to copy it into a buffer.
+struct MipLayout {
+    size: wgpu::Extent3d,
+    bytes_per_row: u32,
+    padded_bytes_per_row: u32,
+}
+
+impl MipLayout {
+    fn new(
+        size: wgpu::Extent3d,
+        dimension: wgpu::TextureDimension,
+        format: wgpu::TextureFormat,
+        mip_level_count: u32,
+        mip: u32,
+    ) -> anyhow::Result<Self> {
+        if mip >= mip_level_count {
+            anyhow::bail!("Mip level {mip} out of range, the texture has {mip_level_count}");
+        }
+        let bytes_per_pixel = match (format.block_dimensions(), format.block_copy_size(None)) {
+            ((1, 1), Some(size)) => size,
+            _ => anyhow::bail!("Reading back {format:?} textures is not supported"),
+        };
+
+        let size = size.mip_level_size(mip, dimension);
+        let bytes_per_row = size.width * bytes_per_pixel;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = bytes_per_row.div_ceil(align) * align;
+
+        Ok(Self {
+            size,
+            bytes_per_row,
+            padded_bytes_per_row,
+        })
+    }
+}
+
+/// Chunk size of the staging belt until [`Gpu::set_staging_chunk_size`].
+pub const DEFAULT_STAGING_CHUNK_SIZE: wgpu::BufferAddress = 64 * 1024;
+
+/// Uploads staged with [`Gpu::write_uniform`], copied out of shared chunks by
+/// a single encoder instead of a `queue.write_buffer` each.
+struct Staging {
+    belt: StagingBelt,
+    encoder: Option<wgpu::CommandEncoder>,
+    chunk_size: wgpu::BufferAddress,
+}
+
+impl Staging {
+    fn new(chunk_size: wgpu::BufferAddress) -> Self {
+        Self {
+            belt: StagingBelt::new(chunk_size),
+            encoder: None,
+            chunk_size,
+        }
+    }
+}
+
+/// Handle to a buffer created with one of the `Gpu::create_*_buffer` helpers.
+pub type BufferId = Id;
+
+/// Index types [`Gpu::create_index_buffer`] accepts.
+pub trait Index: bytemuck::Pod {
+    const FORMAT: wgpu::IndexFormat;
+}
+
+impl Index for u16 {
+    const FORMAT: wgpu::IndexFormat = wgpu::IndexFormat::Uint16;
+}
+
+impl Index for u32 {
+    const FORMAT: wgpu::IndexFormat = wgpu::IndexFormat::Uint32;
+}
+
+struct BufferEntry {
+    buffer: Arc<wgpu::Buffer>,
+    index_format: Option<wgpu::IndexFormat>,
+}
+
+/// A buffer still mapped from its creation, filled through
+/// [`MappedBuffer::slice_mut`] and handed to the GPU with
+/// [`MappedBuffer::unmap`].
+pub struct MappedBuffer {
+    buffer: wgpu::Buffer,
+}
+
+impl MappedBuffer {
+    pub fn slice_mut(&mut self) -> wgpu::BufferViewMut<'_> {
+        self.buffer.slice(..).get_mapped_range_mut()
+    }
+
+    pub fn unmap(self) -> wgpu::Buffer {
+        self.buffer.unmap();
+        self.buffer
+    }
+}
+
+/// A color target read back to the CPU.
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    /// Whether `pixels` are sRGB encoded and need linearizing before doing
+    /// any math on them.
+    pub srgb: bool,
+    /// Tightly packed RGBA8 rows.
+    pub pixels: Vec<u8>,
+}
+
+impl Frame {
+    /// Takes tightly packed pixels of a `format` target, swizzling BGRA
+    /// surfaces into RGBA.
+    fn new(
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+        mut pixels: Vec<u8>,
+    ) -> anyhow::Result<Self> {
+        use wgpu::TextureFormat::*;
+        match format {
+            Rgba8Unorm | Rgba8UnormSrgb => {}
+            Bgra8Unorm | Bgra8UnormSrgb => {
+                for pixel in pixels.chunks_exact_mut(4) {
+                    pixel.swap(0, 2);
+                }
+            }
+            _ => anyhow::bail!("Capturing {format:?} frames is not supported"),
+        }
+        if pixels.len() != (width * height * 4) as usize {
+            anyhow::bail!("Frame data doesn't match its {width}x{height} size");
+        }
+        Ok(Self {
+            width,
+            height,
+            srgb: format.is_srgb(),
+            pixels,
+        })
+    }
+
+    pub fn into_image(self) -> image::RgbaImage {
+        // The size was checked when the frame was read.
+        image::RgbaImage::from_raw(self.width, self.height, self.pixels).unwrap()
+    }
+}
+
+/// Signaled when the submissions made before [`Gpu::fence`] complete, from
+/// the queue's completion callback rather than by polling for an idle queue.
+pub struct FrameFence {
+    completed: futures::channel::oneshot::Receiver<()>,
+    signaled: bool,
+}
+
+impl FrameFence {
+    /// Whether the work has completed, without waiting. Callbacks only run
+    /// while the device is polled, e.g. by [`Gpu::poll_async`].
+    pub fn is_signaled(&mut self) -> bool {
+        // The channel only hands out the signal once.
+        self.signaled |= matches!(self.completed.try_recv(), Ok(Some(())));
+        self.signaled
+    }
+
+    /// Polls `gpu` with its [`PollStrategy`] until the fence is signaled.
+    /// Fails if the device dropped the callback, e.g. because it was lost.
+    pub async fn wait(mut self, gpu: &Gpu) -> anyhow::Result<()> {
+        if self.signaled {
+            return Ok(());
+        }
+        let strategy = gpu.poll_strategy();
+        if strategy == PollStrategy::Block {
+            gpu.device.poll(wgpu::Maintain::Wait);
+        }
+        let mut result = Ok(None);
+        strategy
+            .poll_until(|| {
+                gpu.device.poll(wgpu::Maintain::Poll);
+                result = self.completed.try_recv();
+                !matches!(result, Ok(None))
+            })
+            .await;
+        result
+            .map(|_| ())
+            .map_err(|_| anyhow::anyhow!("The frame was dropped before it completed"))
+    }
+}
+
+/// How [`Gpu::poll_async`] waits for the GPU.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PollStrategy {
+    /// `device.poll(Wait)`, blocking the worker thread until the GPU is done.
+    Block,
+    /// `device.poll(Poll)` and yield to the runtime between polls.
+    #[default]
+    Yield,
+    /// `device.poll(Poll)` and sleep for the interval between polls, to
+    /// spin less when waiting on long running work.
+    Interval(Duration),
+}
+
+impl PollStrategy {
+    /// Calls `poll` until it returns true, letting the runtime run other
+    /// tasks in between.
+    async fn poll_until(self, mut poll: impl FnMut() -> bool) {
+        while !poll() {
+            match self {
+                Self::Block | Self::Yield => tokio::task::yield_now().await,
+                Self::Interval(interval) => tokio::time::sleep(interval).await,
+            }
+        }
+    }
+}
+
+/// What the device supports, gathered from its features, limits and
+/// downlevel flags so callers don't each have to know which one to ask.
+///
+/// On the web the device runs on WebGL2, where everything but
+/// `max_texture_dimension_2d` and `max_bind_groups` comes out `false` or
+/// zero. That costs the wireframe toggle, the GPU profiler and push
+/// constants, and compressed textures are decoded on the CPU. Without
+/// compute shaders Hi-Z culling, auto exposure, the compute builders in
+/// [`crate::pipeline`] and the equirectangular sky conversion don't work.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    pub push_constants: bool,
+    /// Zero without [`Capabilities::push_constants`].
+    pub max_push_constant_size: u32,
+    pub timestamp_queries: bool,
+    pub bc_textures: bool,
+    pub multiview: bool,
+    pub wireframe: bool,
+    pub compute_shaders: bool,
+    pub max_texture_dimension_2d: u32,
+    pub max_bind_groups: u32,
+}
+
+impl Capabilities {
+    fn new(
+        features: wgpu::Features,
+        limits: &wgpu::Limits,
+        downlevel: &wgpu::DownlevelCapabilities,
+    ) -> Self {
+        let push_constants = features.contains(wgpu::Features::PUSH_CONSTANTS);
+        Self {
+            push_constants,
+            max_push_constant_size: if push_constants {
+                limits.max_push_constant_size
+            } else {
+                0
+            },
+            timestamp_queries: features.contains(wgpu::Features::TIMESTAMP_QUERY),
+            bc_textures: features.contains(wgpu::Features::TEXTURE_COMPRESSION_BC),
+            multiview: features.contains(wgpu::Features::MULTIVIEW),
+            wireframe: features.contains(wgpu::Features::POLYGON_MODE_LINE),
+            compute_shaders: downlevel
+                .flags
+                .contains(wgpu::DownlevelFlags::COMPUTE_SHADERS),
+            max_texture_dimension_2d: limits.max_texture_dimension_2d,
+            max_bind_groups: limits.max_bind_groups,
+        }
+    }
+}
+
+/// E.g. "Vulkan / NVIDIA GeForce RTX 3070 (DiscreteGpu), push constants:
+/// yes, timestamp queries: no, ...".
+fn describe(info: &wgpu::AdapterInfo, capabilities: &Capabilities) -> String {
+    let yes_no = |supported| if supported { "yes" } else { "no" };
+    format!(
+        "{:?} / {} ({:?}), push constants: {}, timestamp queries: {}, BC textures: {}, \
+         multiview: {}, wireframe: {}, compute shaders: {}",
+        info.backend,
+        info.name,
+        info.device_type,
+        yes_no(capabilities.push_constants),
+        yes_no(capabilities.timestamp_queries),
+        yes_no(capabilities.bc_textures),
+        yes_no(capabilities.multiview),
+        yes_no(capabilities.wireframe),
+        yes_no(capabilities.compute_shaders),
+    )
+}
+
+/// Calls `acquire`, reconfiguring the surface and trying once more when it
+/// was lost or outdated, e.g. by a resize. A timeout gives `Ok(None)` since
+/// skipping the frame is all that can be done about it.
+fn acquire_with_retry<T>(
+    mut acquire: impl FnMut() -> Result<T, wgpu::SurfaceError>,
+    reconfigure: impl FnOnce(),
+) -> Result<Option<T>, wgpu::SurfaceError> {
+    let result = match acquire() {
+        Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+            reconfigure();
+            acquire()
+        }
+        result => result,
+    };
+    match result {
+        Ok(texture) => Ok(Some(texture)),
+        Err(wgpu::SurfaceError::Timeout) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Backends to create the instance with, WebGL2 on the web, which unlike
+/// WebGPU every browser has.
+fn backends() -> wgpu::Backends {
+    if cfg!(target_arch = "wasm32") {
+        wgpu::Backends::GL
+    } else {
+        wgpu::Backends::all()
+    }
+}
+
+/// Limits the device is requested with, WebGL2's downlevel defaults when
+/// `webgl2`. Texture sizes go up to what the adapter allows and push
+/// constants are only asked for when `features` has them.
+fn device_limits(adapter: &wgpu::Limits, features: wgpu::Features, webgl2: bool) -> wgpu::Limits {
+    let base = if webgl2 {
+        wgpu::Limits::downlevel_webgl2_defaults()
+    } else {
+        wgpu::Limits::default()
+    };
+    let max_push_constant_size = if features.contains(wgpu::Features::PUSH_CONSTANTS) {
+        adapter.max_push_constant_size
+    } else {
+        0
+    };
+    wgpu::Limits {
+        max_push_constant_size,
+        ..base.using_resolution(adapter.clone())
+    }
+}
+
+/// `requested` if the surface supports it, `Fifo` otherwise, which every
+/// surface does. The `Auto*` modes are resolved by wgpu and always allowed.
+fn choose_present_mode(
+    requested: wgpu::PresentMode,
+    supported: &[wgpu::PresentMode],
+) -> wgpu::PresentMode {
+    match requested {
+        wgpu::PresentMode::AutoVsync | wgpu::PresentMode::AutoNoVsync => requested,
+        _ if supported.contains(&requested) => requested,
+        _ => wgpu::PresentMode::Fifo,
+    }
+}
+
+/// How the compositor interprets the surface's pixels. wgpu has no color
+/// space metadata of its own, the surface format implies it.
+///
+/// Color textures are sRGB encoded and sampled through `*Srgb` formats, so
+/// shaders do their lighting math on linear values. Writing to an `*Srgb`
+/// surface encodes the result again, a linear 8 bit surface needs the
+/// shader to do it instead, see [`needs_srgb_encoding`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorSpace {
+    /// 8 bit sRGB, which every surface supports.
+    #[default]
+    Srgb,
+    /// Linear half floats with sRGB primaries going past 1.0 (scRGB), shown
+    /// as HDR by the compositors on Windows and macOS.
+    ExtendedSrgbLinear,
+}
+
+impl ColorSpace {
+    /// Color space a surface configured with `format` is presented in.
+    pub fn of(format: wgpu::TextureFormat) -> Self {
+        match format {
+            wgpu::TextureFormat::Rgba16Float => Self::ExtendedSrgbLinear,
+            _ => Self::Srgb,
+        }
+    }
+}
+
+/// Settings for creating a windowed [`Gpu`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GpuConfig {
+    /// Falls back to [`ColorSpace::Srgb`] when the surface lacks it.
+    pub color_space: ColorSpace,
+}
+
+/// Why [`Gpu::recreate`] failed.
+#[derive(Debug)]
+pub enum GpuError {
+    /// No adapter is available, e.g. while the driver is still resetting.
+    /// Worth retrying a little later.
+    NoAdapter,
+    RequestDevice(wgpu::RequestDeviceError),
+}
+
+impl std::fmt::Display for GpuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoAdapter => write!(f, "No adapter available to recreate the device on"),
+            Self::RequestDevice(err) => write!(f, "Recreating the device failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for GpuError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::NoAdapter => None,
+            Self::RequestDevice(err) => Some(err),
+        }
+    }
+}
+
+/// Flag set once `device` is lost, by the driver resetting or
+/// `Device::destroy`. Dropping the device doesn't count.
+fn watch_device_lost(device: &wgpu::Device) -> Arc<AtomicBool> {
+    let lost = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&lost);
+    device.set_device_lost_callback(move |reason, message| {
+        if matches!(
+            reason,
+            wgpu::DeviceLostReason::Dropped | wgpu::DeviceLostReason::ReplacedCallback
+        ) {
+            return;
+        }
+        log::error!("Device lost ({reason:?}): {message}");
+        flag.store(true, Ordering::SeqCst);
+    });
+    lost
+}
+
+/// Whether shaders writing linear colors to a `format` target have to apply
+/// the sRGB transfer function themselves, because the format stores values
+/// as they are but is displayed as sRGB.
+pub fn needs_srgb_encoding(format: wgpu::TextureFormat) -> bool {
+    !format.is_srgb() && ColorSpace::of(format) == ColorSpace::Srgb
+}
+
+/// First of the surface's `formats` presenting in `color_space`, `None` when
+/// the surface doesn't support it. `*Srgb` formats are preferred for
+/// [`ColorSpace::Srgb`], see [`needs_srgb_encoding`] for the others.
+fn choose_surface_format(
+    color_space: ColorSpace,
+    formats: &[wgpu::TextureFormat],
+) -> Option<wgpu::TextureFormat> {
+    formats.iter().copied().find(|format| match color_space {
+        ColorSpace::Srgb => format.is_srgb(),
+        ColorSpace::ExtendedSrgbLinear => *format == wgpu::TextureFormat::Rgba16Float,
+    })
+}
+
+/// Handle to a window added with [`Gpu::add_window`].
+pub type SurfaceId = Id;
+
+/// A window beyond the main one and the surface presenting to it.
+struct WindowSurface {
+    surface: Arc<wgpu::Surface>,
+    config: wgpu::SurfaceConfiguration,
+    /// Acquired by [`Gpu::window_view`] and presented by [`Gpu::finish`],
+    /// `None` when nothing was drawn to the window this frame.
+    current: Option<wgpu::SurfaceTexture>,
+    // The surface must not outlive its window.
+    _window: Arc<Window>,
+}
 
 pub struct Gpu {
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
-    pub surface: Arc<wgpu::Surface>,
+    /// `None` for a headless [`Gpu`], which only renders to textures.
+    pub surface: Option<Arc<wgpu::Surface>>,
     pub config: Arc<RwLock<wgpu::SurfaceConfiguration>>,
-    current_texture_view: RwLock<OnceCell<wgpu::SurfaceTexture>>,
-    cmds: RwLock<BTreeMap<usize, wgpu::CommandBuffer>>,
+    adapter: wgpu::Adapter,
+    instance: Arc<wgpu::Instance>,
+    windows: Arc<RwLock<DB<WindowSurface>>>,
+    msaa_samples: AtomicU32,
+    wireframe: AtomicBool,
+    culling: AtomicBool,
+    depth_prepass: AtomicBool,
+    current_texture_view: RwLock<OnceLock<wgpu::SurfaceTexture>>,
+    cmds: RwLock<CommandList<wgpu::CommandBuffer>>,
+    poll_strategy: RwLock<PollStrategy>,
+    pipelines: RwLock<PipelineCache>,
+    buffers: RwLock<DB<BufferEntry>>,
+    staging: Mutex<Staging>,
+    /// Set by the device lost callback, see [`Gpu::is_lost`].
+    lost: Arc<AtomicBool>,
 }
 
 impl Gpu {
     pub async fn new(window: Arc<Window>) -> Self {
-        CMD_ID.get_or_init(|| AtomicUsize::new(0));
+        Self::with_config(window, GpuConfig::default()).await
+    }
 
+    pub async fn with_config(window: Arc<Window>, gpu_config: GpuConfig) -> Self {
         let size = window.inner_size();
 
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::all(),
+            backends: backends(),
             ..Default::default()
         });
 
@@ -40,33 +609,36 @@ impl Gpu {
             .await
             .unwrap();
 
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    label: None,
-                    features: wgpu::Features::empty(),
-                    // WebGL doesn't support all of wgpu's features, so if
-                    // we're building for the web we'll have to disable some.
-                    limits: wgpu::Limits::default(),
-                },
-                None, // Trace path
-            )
-            .await
-            .unwrap();
+        let (device, queue) = Self::request_device(&adapter).await.unwrap();
 
         let surface_caps = surface.get_capabilities(&adapter);
         // Shader code in this tutorial assumes an Srgb surface texture. Using a different
         // one will result all the colors comming out darker. If you want to support non
         // Srgb surfaces, you'll need to account for that when drawing to the frame.
-        let surface_format = surface_caps
-            .formats
-            .iter()
-            .copied()
-            .find(|f| f.is_srgb())
-            .unwrap_or(surface_caps.formats[0]);
+        // Extended sRGB surfaces are linear like the shaders' output.
+        let surface_format = choose_surface_format(gpu_config.color_space, &surface_caps.formats)
+            .or_else(|| {
+                log::warn!(
+                    "The surface doesn't support {:?}, falling back to sRGB",
+                    gpu_config.color_space
+                );
+                choose_surface_format(ColorSpace::Srgb, &surface_caps.formats)
+            })
+            .unwrap_or_else(|| {
+                log::info!(
+                    "No sRGB surface format, shaders encode {:?} output themselves",
+                    surface_caps.formats[0]
+                );
+                surface_caps.formats[0]
+            });
+
+        // Copying out of the surface is what screenshots are made of, but
+        // not every platform allows it.
+        let usage = wgpu::TextureUsages::RENDER_ATTACHMENT
+            | (surface_caps.usages & wgpu::TextureUsages::COPY_SRC);
 
         let config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            usage,
             format: surface_format,
             width: size.width,
             height: size.height,
@@ -77,16 +649,204 @@ impl Gpu {
 
         surface.configure(&device, &config);
 
-        let config = Arc::new(RwLock::new(config));
+        Self::from_parts(
+            device,
+            queue,
+            Some(surface),
+            Arc::new(instance),
+            adapter,
+            config,
+        )
+    }
+
+    /// A [`Gpu`] without a window, for rendering to textures and tests.
+    ///
+    /// The configuration describes a `width` x `height` Rgba8UnormSrgb
+    /// target, so code sizing its targets from it works unchanged.
+    pub async fn new_headless(width: u32, height: u32) -> anyhow::Result<Self> {
+        let instance = Self::headless_instance();
 
-        Self {
+        let Some(adapter) = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+                force_fallback_adapter: false,
+            })
+            .await
+        else {
+            anyhow::bail!("No adapter available for headless rendering");
+        };
+
+        let (device, queue) = Self::request_device(&adapter).await?;
+
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            width,
+            height,
+            present_mode: wgpu::PresentMode::Fifo,
+            alpha_mode: wgpu::CompositeAlphaMode::Auto,
+            view_formats: vec![],
+        };
+
+        Ok(Self::from_parts(
+            device, queue, None, instance, adapter, config,
+        ))
+    }
+
+    /// A new instance for a headless [`Gpu`] that is never dropped. Dropping
+    /// a GL instance terminates the EGL display every GL instance in the
+    /// process shares, which breaks the other headless [`Gpu`]s still in
+    /// use. Sharing one instance between them doesn't work either, devices
+    /// of the same GL adapter fail when used from several threads at once.
+    fn headless_instance() -> Arc<wgpu::Instance> {
+        let instance = Arc::new(wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: backends(),
+            ..Default::default()
+        }));
+        std::mem::forget(Arc::clone(&instance));
+        instance
+    }
+
+    async fn request_device(
+        adapter: &wgpu::Adapter,
+    ) -> Result<(wgpu::Device, wgpu::Queue), wgpu::RequestDeviceError> {
+        // Optional features are enabled whenever the adapter has them,
+        // callers check `Gpu::capabilities` before relying on one.
+        let features = adapter.features()
+            & (wgpu::Features::TEXTURE_COMPRESSION_BC
+                | wgpu::Features::PUSH_CONSTANTS
+                | wgpu::Features::POLYGON_MODE_LINE
+                | wgpu::Features::TIMESTAMP_QUERY
+                | wgpu::Features::MULTIVIEW);
+
+        adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: None,
+                    features,
+                    // WebGL doesn't support all of wgpu's features, so if
+                    // we're building for the web we'll have to disable some.
+                    limits: device_limits(
+                        &adapter.limits(),
+                        features,
+                        cfg!(target_arch = "wasm32"),
+                    ),
+                },
+                None, // Trace path
+            )
+            .await
+    }
+
+    fn from_parts(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        surface: Option<Arc<wgpu::Surface>>,
+        instance: Arc<wgpu::Instance>,
+        adapter: wgpu::Adapter,
+        config: wgpu::SurfaceConfiguration,
+    ) -> Self {
+        let lost = watch_device_lost(&device);
+        let gpu = Self {
             device,
             queue,
             surface,
-            cmds: RwLock::new(BTreeMap::default()),
-            current_texture_view: RwLock::new(OnceCell::new()),
+            adapter,
+            instance,
+            windows: Arc::default(),
+            msaa_samples: AtomicU32::new(1),
+            wireframe: AtomicBool::new(false),
+            culling: AtomicBool::new(true),
+            depth_prepass: AtomicBool::new(false),
+            cmds: RwLock::new(CommandList::default()),
+            current_texture_view: RwLock::new(OnceLock::new()),
+            poll_strategy: RwLock::default(),
+            pipelines: RwLock::default(),
+            buffers: RwLock::default(),
+            staging: Mutex::new(Staging::new(DEFAULT_STAGING_CHUNK_SIZE)),
+            config: Arc::new(RwLock::new(config)),
+            lost,
+        };
+        log::info!("{}", gpu.describe());
+        gpu
+    }
+
+    /// Whether the device was lost, e.g. to a driver reset. Nothing drawn
+    /// shows up until it's replaced, see [`Gpu::recreate`].
+    pub fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::SeqCst)
+    }
+
+    /// A new [`Gpu`] replacing this one after its device was lost, with a
+    /// device from a fresh adapter. It presents to the same surfaces,
+    /// configured for the new device, and keeps this one's settings. The
+    /// pipeline cache and registered buffers start out empty. Everything
+    /// else created from the old device, such as the renderer's textures and
+    /// bind groups, has to be created again by its owner, see
+    /// [`crate::app::App`].
+    pub async fn recreate(&self) -> Result<Self, GpuError> {
+        let adapter = self
+            .instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: self.surface.as_deref(),
+                force_fallback_adapter: false,
+            })
+            .await
+            .ok_or(GpuError::NoAdapter)?;
+        let (device, queue) = Self::request_device(&adapter)
+            .await
+            .map_err(GpuError::RequestDevice)?;
+
+        let mut config = self.get_config().clone();
+        if let Some(surface) = &self.surface {
+            let caps = surface.get_capabilities(&adapter);
+            if !caps.formats.contains(&config.format) {
+                let format = choose_surface_format(ColorSpace::of(config.format), &caps.formats)
+                    .unwrap_or(caps.formats[0]);
+                log::warn!(
+                    "The new adapter doesn't support {:?}, switching to {format:?}",
+                    config.format
+                );
+                config.format = format;
+            }
+            surface.configure(&device, &config);
+        }
+
+        let mut gpu = Self::from_parts(
+            device,
+            queue,
+            self.surface.clone(),
+            Arc::clone(&self.instance),
+            adapter,
             config,
+        );
+        // Shared, so both agree on the surfaces until this one is dropped.
+        *self.config.write().unwrap() = gpu.get_config().clone();
+        gpu.config = Arc::clone(&self.config);
+        gpu.windows = Arc::clone(&self.windows);
+        for window in gpu.windows.write().unwrap().data.values_mut() {
+            // Acquired from the old device, never presented.
+            window.current = None;
+            window.config.format = gpu.surface_format();
+            window.surface.configure(&gpu.device, &window.config);
+        }
+
+        gpu.set_msaa(self.msaa_samples());
+        if self.wireframe() {
+            if let Err(err) = gpu.set_wireframe(true) {
+                log::warn!("{err:#}");
+            }
         }
+        gpu.set_culling(self.culling());
+        gpu.set_depth_prepass(self.depth_prepass());
+        gpu.set_poll_strategy(self.poll_strategy());
+        gpu.staging = Mutex::new(Staging::new(self.staging.lock().unwrap().chunk_size));
+        Ok(gpu)
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.surface.is_none()
     }
 
     pub fn get_config_read<T, F: FnOnce(&wgpu::SurfaceConfiguration) -> T>(&self, func: F) -> T {
@@ -101,38 +861,1554 @@ impl Gpu {
             })
     }
 
-    pub fn get_current_view(&self) -> TextureView {
-        let surface_tex = self.current_texture_view.read().unwrap();
-        let surface_tex = surface_tex.get_or_init(|| self.surface.get_current_texture().unwrap());
-        surface_tex
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default())
+    /// View of the surface texture being rendered this frame, acquired on
+    /// the first call after the last [`Gpu::finish`]. A lost or outdated
+    /// surface is reconfigured and acquired again once. `Ok(None)` means the
+    /// acquire timed out and the frame should be skipped.
+    pub fn get_current_view(&self) -> Result<Option<TextureView>, wgpu::SurfaceError> {
+        let create_view = |surface_tex: &wgpu::SurfaceTexture| {
+            surface_tex
+                .texture
+                .create_view(&wgpu::TextureViewDescriptor::default())
+        };
+        if let Some(surface_tex) = self.current_texture_view.read().unwrap().get() {
+            return Ok(Some(create_view(surface_tex)));
+        }
+
+        let surface = self
+            .surface
+            .as_ref()
+            .expect("A headless Gpu has no surface, render to a texture instead");
+        let mut current = self.current_texture_view.write().unwrap();
+        // Another thread may have acquired it while the lock was released.
+        if current.get().is_none() {
+            let acquired = acquire_with_retry(
+                || surface.get_current_texture(),
+                || surface.configure(&self.device, &self.get_config()),
+            )?;
+            let Some(surface_tex) = acquired else {
+                return Ok(None);
+            };
+            *current = OnceLock::from(surface_tex);
+        }
+        Ok(current.get().map(create_view))
     }
 
-    pub fn submit_cmd(&self, cmd: wgpu::CommandBuffer) {
-        let id = CMD_ID
-            .get()
-            .unwrap()
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        let mut cmds_write = self.cmds.write().unwrap();
-        cmds_write.insert(id, cmd);
+    /// Adds a surface for another `window`, e.g. a tool palette, sharing
+    /// the device with the main one. It's configured like the main surface
+    /// so the same pipelines can draw to it, failing when it lacks the main
+    /// surface's format.
+    pub fn add_window(&self, window: Arc<Window>) -> anyhow::Result<SurfaceId> {
+        let surface = Arc::new(unsafe { self.instance.create_surface(&window) }?);
+        let caps = surface.get_capabilities(&self.adapter);
+        let size = window.inner_size();
+        let config = wgpu::SurfaceConfiguration {
+            width: size.width.max(1),
+            height: size.height.max(1),
+            ..self.get_config().clone()
+        };
+        if !caps.formats.contains(&config.format) {
+            anyhow::bail!(
+                "The window's surface doesn't support the main surface's {:?}",
+                config.format
+            );
+        }
+        surface.configure(&self.device, &config);
+
+        Ok(self.windows.write().unwrap().insert(WindowSurface {
+            surface,
+            config,
+            current: None,
+            _window: window,
+        }))
     }
 
-    pub fn get_config(&self) -> RwLockReadGuard<wgpu::SurfaceConfiguration> {
-        self.config.read().unwrap()
+    /// Drops the window's surface, a frame acquired for it is discarded.
+    pub fn remove_window(&self, id: SurfaceId) {
+        self.windows.write().unwrap().data.remove(&id);
     }
 
-    pub fn get_config_mut(&self) -> RwLockWriteGuard<wgpu::SurfaceConfiguration> {
-        self.config.write().unwrap()
+    pub fn window_count(&self) -> usize {
+        self.windows.read().unwrap().data.len()
     }
 
-    pub fn finish(&self) {
-        let mut cmds_write = self.cmds.write().unwrap();
-        let cmds = std::mem::take(&mut *cmds_write);
-        let cmds = cmds.into_values().into_iter();
-        self.queue.submit(cmds);
-        let mut current_surface_tex = self.current_texture_view.write().unwrap();
-        let current_surface_tex = current_surface_tex.take().unwrap();
-        current_surface_tex.present();
+    /// Reconfigures the window's surface after it was resized.
+    pub fn window_update(&self, id: SurfaceId, width: u32, height: u32) -> anyhow::Result<()> {
+        let mut windows = self.windows.write().unwrap();
+        let Some(window) = windows.data.get_mut(&id) else {
+            anyhow::bail!("No window with id {id}");
+        };
+        if width > 0 && height > 0 {
+            window.config.width = width;
+            window.config.height = height;
+            window.surface.configure(&self.device, &window.config);
+        }
+        Ok(())
+    }
+
+    /// Size the window's surface is configured with.
+    pub fn window_size(&self, id: SurfaceId) -> Option<(u32, u32)> {
+        let windows = self.windows.read().unwrap();
+        windows
+            .data
+            .get(&id)
+            .map(|window| (window.config.width, window.config.height))
+    }
+
+    /// [`Gpu::get_current_view`] for a window added with
+    /// [`Gpu::add_window`]. Only windows acquired this way are presented by
+    /// the next [`Gpu::finish`].
+    pub fn window_view(&self, id: SurfaceId) -> anyhow::Result<Option<TextureView>> {
+        let mut windows = self.windows.write().unwrap();
+        let Some(window) = windows.data.get_mut(&id) else {
+            anyhow::bail!("No window with id {id}");
+        };
+        if window.current.is_none() {
+            let (surface, config) = (&window.surface, &window.config);
+            window.current = acquire_with_retry(
+                || surface.get_current_texture(),
+                || surface.configure(&self.device, config),
+            )?;
+        }
+        Ok(window.current.as_ref().map(|surface_tex| {
+            surface_tex
+                .texture
+                .create_view(&wgpu::TextureViewDescriptor::default())
+        }))
+    }
+
+    /// Whether render targets of `format` can be multisampled `count` times.
+    pub fn supports_sample_count(&self, format: wgpu::TextureFormat, count: u32) -> bool {
+        // Without adapter specific format features only the counts WebGPU
+        // guarantees are available.
+        if !self
+            .device
+            .features()
+            .contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES)
+        {
+            return count == 1 || count == 4;
+        }
+        self.adapter
+            .get_texture_format_features(format)
+            .flags
+            .sample_count_supported(count)
+    }
+
+    /// Sets the MSAA sample count used by the scene pass, falling back to no
+    /// multisampling if the count isn't supported. Returns the count in use.
+    ///
+    /// Only affects renderers created afterwards since pipelines bake it in.
+    pub fn set_msaa(&self, samples: u32) -> u32 {
+        let supported = [hdr::HdrPipeline::FORMAT, texture::Texture::DEPTH_FORMAT]
+            .into_iter()
+            .all(|format| self.supports_sample_count(format, samples));
+
+        let samples = if supported {
+            samples
+        } else {
+            log::warn!("MSAA x{samples} is not supported, disabling multisampling");
+            1
+        };
+        self.msaa_samples.store(samples, Ordering::Relaxed);
+        samples
+    }
+
+    pub fn msaa_samples(&self) -> u32 {
+        self.msaa_samples.load(Ordering::Relaxed)
+    }
+
+    /// The adapter the device was created on, e.g. to tell which backend
+    /// and GPU were picked.
+    pub fn adapter_info(&self) -> wgpu::AdapterInfo {
+        self.adapter.get_info()
+    }
+
+    /// The optional features enabled on the device.
+    pub fn features(&self) -> wgpu::Features {
+        self.device.features()
+    }
+
+    /// The limits the device was created with, see [`device_limits`].
+    pub fn limits(&self) -> wgpu::Limits {
+        self.device.limits()
+    }
+
+    /// Whether all of `features` are enabled on the device.
+    pub fn supports(&self, features: wgpu::Features) -> bool {
+        self.features().contains(features)
+    }
+
+    /// One line naming the backend, the adapter and the optional
+    /// capabilities, logged when the device is created.
+    pub fn describe(&self) -> String {
+        describe(&self.adapter_info(), &self.capabilities())
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::new(
+            self.device.features(),
+            &self.device.limits(),
+            &self.adapter.get_downlevel_capabilities(),
+        )
+    }
+
+    /// Format the surface was configured with, which the output pipelines
+    /// render to. An `*Srgb` one whenever the surface offers it.
+    pub fn surface_format(&self) -> wgpu::TextureFormat {
+        self.get_config().format
+    }
+
+    /// Color space the surface is presented in, see [`GpuConfig`].
+    pub fn color_space(&self) -> ColorSpace {
+        ColorSpace::of(self.get_config().format)
+    }
+
+    /// Present modes the surface supports, empty for a headless [`Gpu`].
+    pub fn present_modes(&self) -> Vec<wgpu::PresentMode> {
+        self.surface
+            .as_ref()
+            .map(|surface| surface.get_capabilities(&self.adapter).present_modes)
+            .unwrap_or_default()
+    }
+
+    pub fn present_mode(&self) -> wgpu::PresentMode {
+        self.get_config_read(|config| config.present_mode)
+    }
+
+    /// Switches between vsync (`Fifo`) and uncapped or low latency modes
+    /// (`Immediate`, `Mailbox`), falling back to `Fifo` when the surface
+    /// doesn't support `mode`. Returns the mode in use, which resizing keeps.
+    /// Call it between frames, not while one is being rendered.
+    pub fn set_present_mode(&self, requested: wgpu::PresentMode) -> wgpu::PresentMode {
+        let mode = match &self.surface {
+            Some(_) => choose_present_mode(requested, &self.present_modes()),
+            None => requested,
+        };
+        if mode != requested {
+            log::warn!("Present mode {requested:?} is not supported, using {mode:?}");
+        }
+        let mut config = self.get_config_mut();
+        config.present_mode = mode;
+        if let Some(surface) = &self.surface {
+            surface.configure(&self.device, &config);
+        }
+        mode
+    }
+
+    /// Draws the scene as lines with the renderer's line variant of its
+    /// pipeline, for checking how meshes were triangulated. Fails when the
+    /// device lacks [`wgpu::Features::POLYGON_MODE_LINE`].
+    pub fn set_wireframe(&self, enabled: bool) -> anyhow::Result<()> {
+        if enabled && !self.capabilities().wireframe {
+            anyhow::bail!("Wireframe rendering needs Features::POLYGON_MODE_LINE");
+        }
+        self.wireframe.store(enabled, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn wireframe(&self) -> bool {
+        self.wireframe.load(Ordering::Relaxed)
+    }
+
+    /// Skips drawing models outside the camera's frustum, on by default.
+    /// Turning it off helps telling culling bugs from missing geometry.
+    pub fn set_culling(&self, enabled: bool) {
+        self.culling.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn culling(&self) -> bool {
+        self.culling.load(Ordering::Relaxed)
+    }
+
+    /// Renders the depth of opaque meshes in a pass of its own first, so
+    /// the scene pass only shades the surface that ends up visible. Off by
+    /// default, it only pays off when overdraw meets expensive shading
+    /// since every opaque mesh is drawn twice.
+    pub fn set_depth_prepass(&self, enabled: bool) {
+        self.depth_prepass.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn depth_prepass(&self) -> bool {
+        self.depth_prepass.load(Ordering::Relaxed)
+    }
+
+    /// Queues `cmd` for the next [`Gpu::finish`], it will be submitted after
+    /// everything queued before it.
+    pub fn submit_cmd(&self, cmd: wgpu::CommandBuffer) {
+        let mut cmds_write = self.cmds.write().unwrap();
+        cmds_write.push(cmd);
+    }
+
+    /// Queues `cmd` for the next [`Gpu::finish`] at an explicit position, see
+    /// [`CommandList::push_ordered`].
+    pub fn submit_cmd_ordered(&self, order: u32, cmd: wgpu::CommandBuffer) -> CommandListIndex {
+        let mut cmds_write = self.cmds.write().unwrap();
+        cmds_write.push_ordered(order, cmd)
+    }
+
+    /// Encodes `passes`, merging the compatible ones, and queues them like
+    /// [`Gpu::submit_cmd`]. Returns how many render passes they took.
+    pub fn submit_passes(&self, passes: PassQueue) -> usize {
+        let mut encoder = self.create_cmd_encoder();
+        let count = passes.encode(&mut encoder);
+        self.submit_cmd(encoder.finish());
+        count
+    }
+
+    /// Reserves a slot for a command buffer that's about to be recorded,
+    /// e.g. before handing the recording to another thread.
+    pub fn reserve_cmd(&self) -> CommandListIndex {
+        self.cmds.write().unwrap().reserve()
+    }
+
+    /// Submits `cmd` in the slot from [`Gpu::reserve_cmd`].
+    pub fn submit_cmd_at(&self, index: CommandListIndex, cmd: wgpu::CommandBuffer) {
+        self.cmds.write().unwrap().insert(index, cmd);
+    }
+
+    pub fn poll_strategy(&self) -> PollStrategy {
+        *self.poll_strategy.read().unwrap()
+    }
+
+    pub fn set_poll_strategy(&self, strategy: PollStrategy) {
+        *self.poll_strategy.write().unwrap() = strategy;
+    }
+
+    /// Waits until the GPU has finished all submitted work, resolving pending
+    /// `map_async` callbacks, without blocking the runtime unless the poll
+    /// strategy is [`PollStrategy::Block`].
+    pub async fn poll_async(&self) {
+        match self.poll_strategy() {
+            PollStrategy::Block => {
+                self.device.poll(wgpu::Maintain::Wait);
+            }
+            strategy => {
+                strategy
+                    .poll_until(|| self.device.poll(wgpu::Maintain::Poll).is_queue_empty())
+                    .await
+            }
+        }
+    }
+
+    /// Builds a render pipeline or returns the identical one built before,
+    /// see [`PipelineCache::get_or_create`].
+    pub fn get_or_create_pipeline(&self, builder: PipelineBuilder) -> anyhow::Result<PipelineId> {
+        let mut pipelines = self.pipelines.write().unwrap();
+        pipelines.get_or_create(self, builder)
+    }
+
+    pub fn pipeline(&self, id: PipelineId) -> Option<Arc<wgpu::RenderPipeline>> {
+        self.pipelines.read().unwrap().get(id)
+    }
+
+    pub fn clear_pipeline_cache(&self) {
+        self.pipelines.write().unwrap().clear();
+    }
+
+    /// Swaps the pipeline behind `id`, draws fetching it afterwards use the
+    /// new one.
+    pub fn replace_pipeline(&self, id: PipelineId, pipeline: wgpu::RenderPipeline) {
+        self.pipelines.write().unwrap().replace(id, pipeline);
+    }
+
+    /// Rebuilds pipeline `id` with `rebuild` from the new source whenever the
+    /// WGSL file at `path` changes. Sources that don't compile are logged
+    /// and handled as `on_failure` says. Watching stops when the returned
+    /// watcher is dropped.
+    pub fn watch_shader(
+        self: &Arc<Self>,
+        path: impl AsRef<Path>,
+        id: PipelineId,
+        on_failure: ReloadFailure,
+        rebuild: impl Fn(&Gpu, &str) -> wgpu::RenderPipeline + Send + 'static,
+    ) -> anyhow::Result<ShaderWatcher> {
+        ShaderWatcher::new(Arc::downgrade(self), path.as_ref(), id, on_failure, rebuild)
+    }
+
+    fn insert_buffer(
+        &self,
+        label: &str,
+        contents: &[u8],
+        usage: wgpu::BufferUsages,
+        index_format: Option<wgpu::IndexFormat>,
+    ) -> BufferId {
+        let buffer = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents,
+                usage: usage | wgpu::BufferUsages::COPY_DST,
+            });
+        self.buffers.write().unwrap().insert(BufferEntry {
+            buffer: Arc::new(buffer),
+            index_format,
+        })
+    }
+
+    pub fn create_vertex_buffer<V: bytemuck::Pod>(&self, vertices: &[V]) -> BufferId {
+        self.insert_buffer(
+            "Gpu::vertex_buffer",
+            bytemuck::cast_slice(vertices),
+            wgpu::BufferUsages::VERTEX,
+            None,
+        )
+    }
+
+    /// Index buffer of `u16` or `u32` indices, see [`Gpu::index_format`].
+    pub fn create_index_buffer<I: Index>(&self, indices: &[I]) -> BufferId {
+        self.insert_buffer(
+            "Gpu::index_buffer",
+            bytemuck::cast_slice(indices),
+            wgpu::BufferUsages::INDEX,
+            Some(I::FORMAT),
+        )
+    }
+
+    pub fn create_uniform_buffer<T: bytemuck::Pod>(&self, value: &T) -> BufferId {
+        self.insert_buffer(
+            "Gpu::uniform_buffer",
+            bytemuck::bytes_of(value),
+            wgpu::BufferUsages::UNIFORM,
+            None,
+        )
+    }
+
+    /// Zeroed buffer for compute shaders to read and write, with `usage` on
+    /// top of `STORAGE | COPY_SRC | COPY_DST`. `size` is rounded up to
+    /// [`wgpu::COPY_BUFFER_ALIGNMENT`].
+    pub fn create_storage_buffer(
+        &self,
+        size: wgpu::BufferAddress,
+        usage: wgpu::BufferUsages,
+    ) -> BufferId {
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Gpu::storage_buffer"),
+            size: size.next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT),
+            usage: usage
+                | wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_SRC
+                | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        self.buffers.write().unwrap().insert(BufferEntry {
+            buffer: Arc::new(buffer),
+            index_format: None,
+        })
+    }
+
+    /// `None` once the buffer was removed.
+    pub fn buffer(&self, id: BufferId) -> Option<Arc<wgpu::Buffer>> {
+        let buffers = self.buffers.read().unwrap();
+        buffers.data.get(&id).map(|entry| entry.buffer.clone())
+    }
+
+    /// Format to bind an index buffer with, `None` for other buffers.
+    pub fn index_format(&self, id: BufferId) -> Option<wgpu::IndexFormat> {
+        let buffers = self.buffers.read().unwrap();
+        buffers.data.get(&id).and_then(|entry| entry.index_format)
+    }
+
+    /// Overwrites the start of the buffer with `data`.
+    pub fn write_buffer<T: bytemuck::Pod>(&self, id: BufferId, data: &[T]) -> anyhow::Result<()> {
+        let Some(buffer) = self.buffer(id) else {
+            anyhow::bail!("No buffer with id {id}");
+        };
+        let bytes = bytemuck::cast_slice(data);
+        if bytes.len() as wgpu::BufferAddress > buffer.size() {
+            anyhow::bail!(
+                "{} bytes don't fit buffer {id} of {} bytes",
+                bytes.len(),
+                buffer.size()
+            );
+        }
+        self.queue.write_buffer(&buffer, 0, bytes);
+        Ok(())
+    }
+
+    /// Drops the table's handle, the buffer lives on while it's still used.
+    pub fn remove_buffer(&self, id: BufferId) {
+        self.buffers.write().unwrap().data.remove(&id);
+    }
+
+    pub fn get_config(&self) -> RwLockReadGuard<'_, wgpu::SurfaceConfiguration> {
+        self.config.read().unwrap()
+    }
+
+    pub fn get_config_mut(&self) -> RwLockWriteGuard<'_, wgpu::SurfaceConfiguration> {
+        self.config.write().unwrap()
+    }
+
+    /// Loads an image and stores it block compressed as `format`, see
+    /// [`texture::Texture::from_bytes_compressed`].
+    pub fn load_texture_compressed(
+        &self,
+        bytes: &[u8],
+        format: wgpu::TextureFormat,
+    ) -> anyhow::Result<texture::Texture> {
+        texture::Texture::from_bytes_compressed(
+            &self.device,
+            &self.queue,
+            bytes,
+            format,
+            "Compressed texture",
+        )
+    }
+
+    /// Stages `value` to be copied into `buffer` at `offset` with the rest of
+    /// the frame's uploads, see [`Gpu::finish_staging`]. `buffer` needs
+    /// `COPY_DST` usage and `value` a size that's a multiple of 4.
+    pub fn write_uniform<T: bytemuck::Pod>(
+        &self,
+        buffer: &wgpu::Buffer,
+        offset: wgpu::BufferAddress,
+        value: &T,
+    ) {
+        let bytes = bytemuck::bytes_of(value);
+        let Some(size) = wgpu::BufferSize::new(bytes.len() as u64) else {
+            return;
+        };
+        let mut staging = self.staging.lock().unwrap();
+        let Staging { belt, encoder, .. } = &mut *staging;
+        let encoder = encoder.get_or_insert_with(|| {
+            self.device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Gpu::staging"),
+                })
+        });
+        belt.write_buffer(encoder, buffer, offset, size, &self.device)
+            .copy_from_slice(bytes);
+    }
+
+    /// Submits the uploads staged since the last call, ahead of everything
+    /// in the command list. [`Gpu::finish`] calls it before submitting the
+    /// frame, so it has to come before the frame is presented.
+    pub fn finish_staging(&self) {
+        let mut staging = self.staging.lock().unwrap();
+        if let Some(encoder) = staging.encoder.take() {
+            staging.belt.finish();
+            self.queue.submit([encoder.finish()]);
+        }
+    }
+
+    /// Makes the chunks of submitted uploads reusable once the GPU is done
+    /// with them, [`Gpu::finish`] calls it after submitting.
+    pub fn recall_staging(&self) {
+        self.staging.lock().unwrap().belt.recall();
+    }
+
+    /// Uploads larger than a chunk get a chunk of their own, so the size
+    /// should fit a frame's worth of uniforms.
+    pub fn set_staging_chunk_size(&self, chunk_size: wgpu::BufferAddress) {
+        self.finish_staging();
+        *self.staging.lock().unwrap() = Staging::new(chunk_size);
+    }
+
+    /// Clears every mip and layer of the color `texture` to `color`, queued
+    /// like [`Gpu::submit_cmd`]. `texture` needs `RENDER_ATTACHMENT` usage.
+    pub fn clear_texture(&self, texture: &wgpu::Texture, color: wgpu::Color) -> anyhow::Result<()> {
+        if texture.format().has_depth_aspect() || texture.format().has_stencil_aspect() {
+            anyhow::bail!(
+                "{:?} is not a color format, use Gpu::clear_depth",
+                texture.format()
+            );
+        }
+        self.clear_views(texture, |view, encoder| {
+            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Gpu::clear_texture"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(color),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+        })
+    }
+
+    /// Clears every mip and layer of the depth `texture` to `depth`, and its
+    /// stencil to 0 if it has one. Queued like [`Gpu::clear_texture`].
+    pub fn clear_depth(&self, texture: &wgpu::Texture, depth: f32) -> anyhow::Result<()> {
+        let format = texture.format();
+        if !format.has_depth_aspect() {
+            anyhow::bail!("{format:?} has no depth to clear");
+        }
+        self.clear_views(texture, |view, encoder| {
+            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Gpu::clear_depth"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(depth),
+                        store: wgpu::StoreOp::Store,
+                    }),
+                    stencil_ops: format.has_stencil_aspect().then_some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(0),
+                        store: wgpu::StoreOp::Store,
+                    }),
+                }),
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+        })
+    }
+
+    /// Runs `clear` on a single mip and layer view at a time, a render pass
+    /// only clearing what its attachments cover.
+    fn clear_views(
+        &self,
+        texture: &wgpu::Texture,
+        clear: impl Fn(&wgpu::TextureView, &mut wgpu::CommandEncoder),
+    ) -> anyhow::Result<()> {
+        if !texture
+            .usage()
+            .contains(wgpu::TextureUsages::RENDER_ATTACHMENT)
+        {
+            anyhow::bail!("Clearing a texture needs RENDER_ATTACHMENT usage");
+        }
+        let layers = match texture.dimension() {
+            wgpu::TextureDimension::D2 => texture.depth_or_array_layers(),
+            _ => 1,
+        };
+
+        let mut encoder = self.create_cmd_encoder();
+        for mip in 0..texture.mip_level_count() {
+            for layer in 0..layers {
+                let view = texture.create_view(&wgpu::TextureViewDescriptor {
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_mip_level: mip,
+                    mip_level_count: Some(1),
+                    base_array_layer: layer,
+                    array_layer_count: Some(1),
+                    ..Default::default()
+                });
+                clear(&view, &mut encoder);
+            }
+        }
+        self.submit_cmd(encoder.finish());
+        Ok(())
+    }
+
+    /// Creates a buffer mapped for writing right away, the fastest way to
+    /// upload its initial contents. `size` is rounded up to
+    /// [`wgpu::COPY_BUFFER_ALIGNMENT`].
+    ///
+    /// With `zeroed` the mapping is cleared before it's handed out, so bytes
+    /// left unwritten are guaranteed to be zero whatever the backend does.
+    pub fn create_mapped_buffer(
+        &self,
+        label: Option<&str>,
+        size: wgpu::BufferAddress,
+        usage: wgpu::BufferUsages,
+        zeroed: bool,
+    ) -> MappedBuffer {
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label,
+            size: size.next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT),
+            usage,
+            mapped_at_creation: true,
+        });
+        let mut mapped = MappedBuffer { buffer };
+        if zeroed {
+            mapped.slice_mut().fill(0);
+        }
+        mapped
+    }
+
+    /// Copies `buffer` back to the CPU, `buffer` needs `COPY_SRC` usage.
+    /// Blocks like [`Gpu::read_texture_mip`].
+    pub fn read_buffer(&self, buffer: &wgpu::Buffer) -> anyhow::Result<Vec<u8>> {
+        let staging = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Gpu::read_buffer"),
+            size: buffer.size(),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, buffer.size());
+        self.queue.submit([encoder.finish()]);
+
+        let slice = staging.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        receiver.recv()??;
+
+        let data = slice.get_mapped_range().to_vec();
+        staging.unmap();
+        Ok(data)
+    }
+
+    /// [`Gpu::read_buffer`] for a buffer from the `Gpu::create_*_buffer`
+    /// helpers, e.g. the output of a compute shader.
+    pub fn read_buffer_by_id(&self, id: BufferId) -> anyhow::Result<Vec<u8>> {
+        let Some(buffer) = self.buffer(id) else {
+            anyhow::bail!("No buffer with id {id}");
+        };
+        self.read_buffer(&buffer)
+    }
+
+    /// Copies mip level `mip` of `texture` back to the CPU with the rows
+    /// tightly packed. `texture` needs `COPY_SRC` usage.
+    ///
+    /// Blocks until the copy is done and doesn't wait for commands queued
+    /// with [`Gpu::submit_cmd`], it's meant for debugging and tests.
+    pub fn read_texture_mip(&self, texture: &wgpu::Texture, mip: u32) -> anyhow::Result<Vec<u8>> {
+        let layout = MipLayout::new(
+            texture.size(),
+            texture.dimension(),
+            texture.format(),
+            texture.mip_level_count(),
+            mip,
+        )?;
+        let rows = layout.size.height * layout.size.depth_or_array_layers;
+
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Gpu::read_texture_mip"),
+            size: (layout.padded_bytes_per_row * rows) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture,
+                mip_level: mip,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(layout.padded_bytes_per_row),
+                    rows_per_image: Some(layout.size.height),
+                },
+            },
+            layout.size,
+        );
+        self.queue.submit([encoder.finish()]);
+
+        let slice = buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        receiver.recv()??;
+
+        let data = slice.get_mapped_range();
+        let mut pixels = Vec::with_capacity((layout.bytes_per_row * rows) as usize);
+        for row in data.chunks(layout.padded_bytes_per_row as usize) {
+            pixels.extend_from_slice(&row[..layout.bytes_per_row as usize]);
+        }
+        drop(data);
+        buffer.unmap();
+
+        Ok(pixels)
+    }
+
+    /// Reads back the RGBA8 or BGRA8 color target `texture` with a copy of
+    /// its own, so it only sees work already submitted. Commands still in the
+    /// command list wait for [`Gpu::finish`] like the rest of the frame.
+    /// `texture` needs `COPY_SRC` usage.
+    pub fn read_texture(&self, texture: &wgpu::Texture) -> anyhow::Result<Frame> {
+        let pixels = self.read_texture_mip(texture, 0)?;
+        Frame::new(texture.format(), texture.width(), texture.height(), pixels)
+    }
+
+    /// Reads back the frame being rendered with [`Gpu::read_texture`]. Has to
+    /// be called once the frame is recorded, before [`Gpu::finish`] presents
+    /// it. Submits the frame's commands like [`Gpu::finish`] would.
+    pub fn read_surface(&self) -> anyhow::Result<Frame> {
+        self.finish_staging();
+        let mut cmds_write = self.cmds.write().unwrap();
+        self.queue.submit(cmds_write.drain());
+        drop(cmds_write);
+
+        let surface_tex = self.current_texture_view.read().unwrap();
+        let Some(surface_tex) = surface_tex.get() else {
+            anyhow::bail!("No frame has been rendered since the last present");
+        };
+        let texture = &surface_tex.texture;
+        if !texture.usage().contains(wgpu::TextureUsages::COPY_SRC) {
+            anyhow::bail!("The surface doesn't support copying frames out of it");
+        }
+        self.read_texture(texture)
+    }
+
+    /// [`Gpu::read_surface`] as an image.
+    pub fn capture_frame(&self) -> anyhow::Result<image::RgbaImage> {
+        Ok(self.read_surface()?.into_image())
+    }
+
+    /// Captures the current frame with [`Gpu::capture_frame`] and saves it,
+    /// the image format follows the extension of `path`.
+    pub fn save_screenshot(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        self.capture_frame()?.save(path)?;
+        Ok(())
+    }
+
+    /// Submits the frame and presents what was drawn to the surface and the
+    /// windows. Staged uploads are submitted first, then the command list in
+    /// [`CommandList`] order, so everything queued with [`Gpu::submit_cmd`]
+    /// before the call is part of the frame.
+    pub fn finish(&self) {
+        self.finish_staging();
+        let mut cmds_write = self.cmds.write().unwrap();
+        self.queue.submit(cmds_write.drain());
+        self.recall_staging();
+        let mut current_surface_tex = self.current_texture_view.write().unwrap();
+        // Nothing to present when everything went to textures.
+        if let Some(current_surface_tex) = current_surface_tex.take() {
+            current_surface_tex.present();
+        }
+        // Only the windows something was drawn to this frame.
+        let mut windows = self.windows.write().unwrap();
+        for window in windows.get_all_mut() {
+            if let Some(surface_tex) = window.current.take() {
+                surface_tex.present();
+            }
+        }
+    }
+
+    /// [`Gpu::finish`], resolving once the GPU has completed the frame, e.g.
+    /// to keep at most a few frames in flight or to throttle input polling.
+    ///
+    /// The frame is submitted and presented before the first await, in the
+    /// same order as with [`Gpu::finish`]. Commands queued while waiting go
+    /// into the next frame. Waits like [`FrameFence::wait`].
+    pub async fn finish_async(&self) -> anyhow::Result<()> {
+        self.finish();
+        self.fence().wait(self).await
+    }
+
+    /// A fence signaled once everything submitted to the queue so far has
+    /// completed on the GPU. Taken right after [`Gpu::finish`] it marks the
+    /// end of that frame, e.g. for a video encoder reading it back.
+    pub fn fence(&self) -> FrameFence {
+        let (done, completed) = futures::channel::oneshot::channel();
+        self.queue.on_submitted_work_done(move || {
+            let _ = done.send(());
+        });
+        FrameFence {
+            completed,
+            signaled: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose_present_mode() {
+        use wgpu::PresentMode;
+
+        let supported = [PresentMode::Fifo, PresentMode::Mailbox];
+        assert_eq!(
+            choose_present_mode(PresentMode::Mailbox, &supported),
+            PresentMode::Mailbox
+        );
+        assert_eq!(
+            choose_present_mode(PresentMode::Immediate, &supported),
+            PresentMode::Fifo
+        );
+        assert_eq!(
+            choose_present_mode(PresentMode::AutoNoVsync, &supported),
+            PresentMode::AutoNoVsync
+        );
+    }
+
+    #[test]
+    fn test_webgl2_device_limits() {
+        let adapter = wgpu::Limits {
+            max_texture_dimension_2d: 16384,
+            max_push_constant_size: 128,
+            ..Default::default()
+        };
+
+        let webgl2 = device_limits(&adapter, wgpu::Features::PUSH_CONSTANTS, true);
+        assert!(webgl2.check_limits(&wgpu::Limits {
+            max_push_constant_size: 128,
+            ..wgpu::Limits::downlevel_webgl2_defaults().using_resolution(adapter.clone())
+        }));
+        assert_eq!(webgl2.max_storage_buffers_per_shader_stage, 0);
+        assert_eq!(webgl2.max_compute_workgroups_per_dimension, 0);
+        assert_eq!(webgl2.max_texture_dimension_2d, 16384);
+        assert_eq!(webgl2.max_push_constant_size, 128);
+
+        let native = device_limits(&adapter, wgpu::Features::empty(), false);
+        assert_eq!(native.max_push_constant_size, 0);
+        assert!(native.max_storage_buffers_per_shader_stage > 0);
+    }
+
+    #[test]
+    fn test_unknown_windows() {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping window test");
+            return;
+        };
+        let id = Id(0);
+        assert_eq!(gpu.window_count(), 0);
+        assert!(gpu.window_view(id).is_err());
+        assert!(gpu.window_update(id, 8, 8).is_err());
+        assert_eq!(gpu.window_size(id), None);
+        gpu.remove_window(id);
+        // Nothing was acquired, so there's nothing to present either.
+        gpu.finish();
+    }
+
+    #[test]
+    fn test_choose_surface_format() {
+        use wgpu::TextureFormat;
+
+        let hdr = [
+            TextureFormat::Bgra8UnormSrgb,
+            TextureFormat::Bgra8Unorm,
+            TextureFormat::Rgba16Float,
+            TextureFormat::Rgb10a2Unorm,
+        ];
+        let format = choose_surface_format(ColorSpace::ExtendedSrgbLinear, &hdr);
+        assert_eq!(format, Some(TextureFormat::Rgba16Float));
+        assert_eq!(
+            ColorSpace::of(format.unwrap()),
+            ColorSpace::ExtendedSrgbLinear
+        );
+        assert_eq!(
+            choose_surface_format(ColorSpace::Srgb, &hdr),
+            Some(TextureFormat::Bgra8UnormSrgb)
+        );
+
+        let sdr = [TextureFormat::Bgra8Unorm, TextureFormat::Bgra8UnormSrgb];
+        assert_eq!(
+            choose_surface_format(ColorSpace::ExtendedSrgbLinear, &sdr),
+            None
+        );
+        assert_eq!(ColorSpace::of(sdr[1]), ColorSpace::Srgb);
+        // The sRGB variant wins wherever it's listed.
+        assert_eq!(
+            choose_surface_format(ColorSpace::Srgb, &sdr),
+            Some(TextureFormat::Bgra8UnormSrgb)
+        );
+        assert_eq!(
+            choose_surface_format(ColorSpace::Srgb, &[TextureFormat::Rgb10a2Unorm]),
+            None
+        );
+
+        assert!(!needs_srgb_encoding(TextureFormat::Bgra8UnormSrgb));
+        assert!(needs_srgb_encoding(TextureFormat::Bgra8Unorm));
+        assert!(needs_srgb_encoding(TextureFormat::Rgb10a2Unorm));
+        // Extended sRGB is linear on purpose.
+        assert!(!needs_srgb_encoding(TextureFormat::Rgba16Float));
+    }
+
+    #[test]
+    fn test_acquire_with_retry() {
+        use wgpu::SurfaceError;
+
+        // Recovers from a lost surface by reconfiguring it once.
+        let mut results = vec![Ok(1), Err(SurfaceError::Lost)];
+        let mut reconfigured = false;
+        let acquired = acquire_with_retry(|| results.pop().unwrap(), || reconfigured = true);
+        assert_eq!(acquired, Ok(Some(1)));
+        assert!(reconfigured);
+
+        // Only retried once.
+        let mut results = vec![Err(SurfaceError::Outdated), Err(SurfaceError::Outdated)];
+        let acquired = acquire_with_retry(|| results.pop().unwrap(), || {});
+        assert_eq!(acquired, Err::<Option<()>, _>(SurfaceError::Outdated));
+
+        assert_eq!(
+            acquire_with_retry(|| Err::<(), _>(SurfaceError::Timeout), || {}),
+            Ok(None)
+        );
+        assert_eq!(
+            acquire_with_retry(|| Err::<(), _>(SurfaceError::OutOfMemory), || {}),
+            Err(SurfaceError::OutOfMemory)
+        );
+    }
+
+    #[test]
+    fn test_capabilities_match_features() {
+        let limits = wgpu::Limits {
+            max_push_constant_size: 128,
+            ..Default::default()
+        };
+        let downlevel = wgpu::DownlevelCapabilities::default();
+        let none = Capabilities::new(wgpu::Features::empty(), &limits, &downlevel);
+        assert!(!none.push_constants && !none.timestamp_queries && !none.wireframe);
+        assert_eq!(none.max_push_constant_size, 0);
+
+        let features = wgpu::Features::PUSH_CONSTANTS | wgpu::Features::TEXTURE_COMPRESSION_BC;
+        let some = Capabilities::new(features, &limits, &downlevel);
+        assert!(some.push_constants && some.bc_textures && !some.multiview);
+        assert_eq!(some.max_push_constant_size, 128);
+
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping capabilities test");
+            return;
+        };
+        let capabilities = gpu.capabilities();
+        let features = gpu.device.features();
+        assert_eq!(
+            capabilities.push_constants,
+            features.contains(wgpu::Features::PUSH_CONSTANTS)
+        );
+        assert_eq!(
+            capabilities.timestamp_queries,
+            features.contains(wgpu::Features::TIMESTAMP_QUERY)
+        );
+        assert_eq!(
+            capabilities.bc_textures,
+            features.contains(wgpu::Features::TEXTURE_COMPRESSION_BC)
+        );
+        assert_eq!(
+            capabilities.multiview,
+            features.contains(wgpu::Features::MULTIVIEW)
+        );
+        assert_eq!(
+            capabilities.max_texture_dimension_2d,
+            gpu.device.limits().max_texture_dimension_2d
+        );
+    }
+
+    #[test]
+    fn test_command_list_order() {
+        let mut list = CommandList::default();
+        for pass in ["shadow", "scene", "post", "ui"] {
+            list.push(pass);
+        }
+        assert_eq!(list.len(), 4);
+
+        let order = list.drain().collect::<Vec<_>>();
+        assert_eq!(order, ["shadow", "scene", "post", "ui"]);
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn test_command_list_explicit_order() {
+        const OPAQUE: u32 = 1;
+        const TRANSPARENT: u32 = 2;
+
+        let list = Arc::new(RwLock::new(CommandList::default()));
+        let threads = [
+            (TRANSPARENT, "transparent"),
+            (OPAQUE, "opaque"),
+            (TRANSPARENT, "transparent"),
+            (OPAQUE, "opaque"),
+        ]
+        .map(|(order, pass)| {
+            let list = list.clone();
+            std::thread::spawn(move || {
+                list.write().unwrap().push_ordered(order, pass);
+            })
+        });
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut list = list.write().unwrap();
+        // Pushed without an order, it lands after everything so far.
+        list.push("ui");
+
+        let order = list.drain().collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            ["opaque", "opaque", "transparent", "transparent", "ui"]
+        );
+    }
+
+    #[test]
+    fn test_command_list_reserved_order() {
+        for _ in 0..8 {
+            let list = Arc::new(RwLock::new(CommandList::default()));
+            list.write().unwrap().push("shadow");
+
+            // Reserved in recording order, finished in reverse.
+            let threads = (0..4)
+                .map(|bundle| (bundle, list.write().unwrap().reserve()))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|(bundle, index)| {
+                    let list = list.clone();
+                    std::thread::spawn(move || {
+                        std::thread::sleep(Duration::from_millis(4 * (4 - bundle)));
+                        list.write()
+                            .unwrap()
+                            .insert(index, ["a", "b", "c", "d"][bundle as usize]);
+                    })
+                })
+                .collect::<Vec<_>>();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            list.write().unwrap().push("ui");
+
+            let order = list.write().unwrap().drain().collect::<Vec<_>>();
+            assert_eq!(order, ["shadow", "a", "b", "c", "d", "ui"]);
+        }
+    }
+
+    #[test]
+    fn test_mapped_buffer_round_trip() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping mapped buffer test");
+            return Ok(());
+        };
+        let data = (0..1024u32).collect::<Vec<_>>();
+        let size = std::mem::size_of_val(data.as_slice()) as wgpu::BufferAddress;
+
+        let mut mapped = gpu.create_mapped_buffer(None, size, wgpu::BufferUsages::COPY_SRC, false);
+        mapped
+            .slice_mut()
+            .copy_from_slice(bytemuck::cast_slice(&data));
+        let buffer = mapped.unmap();
+
+        let read = gpu.read_buffer(&buffer)?;
+        assert_eq!(bytemuck::cast_slice::<u8, u32>(&read), data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_zeroed_buffers_read_back_zeros() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping zeroed buffer test");
+            return Ok(());
+        };
+        // Left unwritten, only the zeroing decides what's in it.
+        let buffer = gpu
+            .create_mapped_buffer(None, 4096, wgpu::BufferUsages::COPY_SRC, true)
+            .unmap();
+        let read = gpu.read_buffer(&buffer)?;
+        assert_eq!(read.len(), 4096);
+        assert!(read.iter().all(|byte| *byte == 0));
+
+        let storage = gpu.create_storage_buffer(4096, wgpu::BufferUsages::empty());
+        assert!(gpu
+            .read_buffer_by_id(storage)?
+            .iter()
+            .all(|byte| *byte == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffer_helpers() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping buffer helper test");
+            return Ok(());
+        };
+        let vertices = gpu.create_vertex_buffer(&[[0.0f32; 3]; 4]);
+        let indices = gpu.create_index_buffer(&[0u16, 1, 2, 2, 3, 0]);
+        let uniform = gpu.create_uniform_buffer(&[1.0f32; 4]);
+
+        assert_eq!(gpu.buffer(vertices).unwrap().size(), 48);
+        assert_eq!(gpu.index_format(indices), Some(wgpu::IndexFormat::Uint16));
+        assert_eq!(gpu.index_format(vertices), None);
+
+        gpu.write_buffer(uniform, &[2.0f32; 4])?;
+        assert!(gpu.write_buffer(uniform, &[2.0f32; 8]).is_err());
+
+        gpu.remove_buffer(uniform);
+        assert!(gpu.buffer(uniform).is_none());
+        assert!(gpu.write_buffer(uniform, &[2.0f32; 4]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_staged_uniform_writes() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping staging test");
+            return Ok(());
+        };
+        const COUNT: usize = 500;
+        let stride = gpu.device.limits().min_uniform_buffer_offset_alignment as usize;
+        let buffer = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: (COUNT * stride) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        gpu.set_staging_chunk_size(4096);
+        for frame in 0..2u32 {
+            for index in 0..COUNT {
+                let value = [frame, index as u32, 0, 0];
+                gpu.write_uniform(&buffer, (index * stride) as wgpu::BufferAddress, &value);
+            }
+            gpu.finish();
+
+            let bytes = gpu.read_buffer(&buffer)?;
+            for index in [0, 1, COUNT / 2, COUNT - 1] {
+                let value: &[u32] = bytemuck::cast_slice(&bytes[index * stride..][..16]);
+                assert_eq!(value, [frame, index as u32, 0, 0]);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_clear_texture() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping clear test");
+            return Ok(());
+        };
+        let create = |format| {
+            gpu.device.create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size: wgpu::Extent3d {
+                    width: 8,
+                    height: 8,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 2,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+                view_formats: &[],
+            })
+        };
+
+        let color = create(wgpu::TextureFormat::Rgba8Unorm);
+        gpu.clear_texture(&color, wgpu::Color::RED)?;
+        // The clear waits in the command list, reading back doesn't submit
+        // it ahead of the rest of the frame.
+        let frame = gpu.read_texture(&color)?;
+        assert!(frame.pixels.iter().all(|byte| *byte == 0));
+        gpu.finish();
+        let frame = gpu.read_texture(&color)?;
+        assert!(frame
+            .pixels
+            .chunks(4)
+            .all(|pixel| pixel == [255, 0, 0, 255]));
+        let mip = gpu.read_texture_mip(&color, 1)?;
+        assert!(mip.chunks(4).all(|pixel| pixel == [255, 0, 0, 255]));
+
+        let depth = create(wgpu::TextureFormat::Depth32Float);
+        assert!(gpu.clear_texture(&depth, wgpu::Color::RED).is_err());
+        gpu.clear_depth(&depth, 0.25)?;
+        gpu.finish();
+        let flags = gpu.adapter.get_downlevel_capabilities().flags;
+        if !flags.contains(wgpu::DownlevelFlags::DEPTH_TEXTURE_AND_BUFFER_COPIES) {
+            eprintln!("Depth copies unsupported, not reading back the cleared depth");
+            return Ok(());
+        }
+        let texels = gpu.read_texture_mip(&depth, 0)?;
+        assert!(bytemuck::cast_slice::<u8, f32>(&texels)
+            .iter()
+            .all(|depth| *depth == 0.25));
+        Ok(())
+    }
+
+    #[test]
+    fn test_screenshot_round_trip() -> anyhow::Result<()> {
+        // A solid orange BGRA frame, as most surfaces hand them out.
+        let bgra = [0x20, 0x80, 0xff, 0xff].repeat(4 * 2);
+        let frame = Frame::new(wgpu::TextureFormat::Bgra8UnormSrgb, 4, 2, bgra)?;
+        assert!(frame.srgb);
+        let image = frame.into_image();
+
+        let path = std::env::temp_dir().join("void_test_screenshot_round_trip.png");
+        image.save(&path)?;
+        let reloaded = image::open(&path)?.to_rgba8();
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(reloaded.dimensions(), (4, 2));
+        assert!(reloaded.pixels().all(|p| p.0 == [0xff, 0x80, 0x20, 0xff]));
+        assert!(Frame::new(wgpu::TextureFormat::R32Float, 4, 2, vec![0; 32]).is_err());
+        assert!(Frame::new(wgpu::TextureFormat::Rgba8Unorm, 4, 2, vec![0; 4]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_poll_until_yields_to_runtime() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+
+        for strategy in [
+            PollStrategy::Yield,
+            PollStrategy::Interval(Duration::from_millis(1)),
+        ] {
+            runtime.block_on(async {
+                // Stands in for a map_async callback, which only fires while
+                // the single runtime thread is free to run it.
+                let mapped = Arc::new(AtomicU32::new(0));
+                let callback = {
+                    let mapped = mapped.clone();
+                    tokio::spawn(async move { mapped.store(1, Ordering::Relaxed) })
+                };
+
+                let wait = strategy.poll_until(|| mapped.load(Ordering::Relaxed) == 1);
+                tokio::time::timeout(Duration::from_secs(5), wait)
+                    .await
+                    .expect("polling blocked the runtime");
+                callback.await.unwrap();
+            });
+        }
+    }
+
+    #[test]
+    fn test_recreate_after_device_loss() -> anyhow::Result<()> {
+        let Ok(lost) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping device loss test");
+            return Ok(());
+        };
+        assert!(!lost.is_lost());
+        lost.set_culling(false);
+        lost.device.destroy();
+        lost.device.poll(wgpu::Maintain::Wait);
+        assert!(lost.is_lost());
+
+        let gpu = futures::executor::block_on(lost.recreate())?;
+        assert!(!gpu.culling());
+        drop(lost);
+        // Dropping the old device doesn't count as losing the new one.
+        assert!(!gpu.is_lost());
+        let buffer = gpu
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: &[1, 2, 3, 4],
+                usage: wgpu::BufferUsages::COPY_SRC,
+            });
+        assert_eq!(gpu.read_buffer(&buffer)?, [1, 2, 3, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe() {
+        let info = wgpu::AdapterInfo {
+            name: "NVIDIA GeForce RTX 3070".into(),
+            vendor: 0x10de,
+            device: 0,
+            device_type: wgpu::DeviceType::DiscreteGpu,
+            driver: String::new(),
+            driver_info: String::new(),
+            backend: wgpu::Backend::Dx12,
+        };
+        let capabilities = Capabilities::new(
+            wgpu::Features::TIMESTAMP_QUERY,
+            &wgpu::Limits::default(),
+            &wgpu::DownlevelCapabilities::default(),
+        );
+        let line = describe(&info, &capabilities);
+        assert!(line.starts_with("Dx12 / NVIDIA GeForce RTX 3070 (DiscreteGpu)"));
+        assert!(line.contains("timestamp queries: yes"));
+        assert!(line.contains("push constants: no"));
+
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping adapter info test");
+            return;
+        };
+        assert!(gpu.supports(wgpu::Features::empty()));
+        assert!(gpu.supports(gpu.features()));
+        assert!(!gpu.supports(wgpu::Features::all()));
+        assert!(gpu.describe().contains(&gpu.adapter_info().name));
+    }
+
+    #[test]
+    fn test_finish_async_resolves_after_the_frame() {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping async finish test");
+            return;
+        };
+        let texture = gpu.device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: 4,
+                height: 4,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+
+        for strategy in [PollStrategy::Block, PollStrategy::Yield] {
+            gpu.set_poll_strategy(strategy);
+            gpu.clear_texture(&texture, wgpu::Color::RED).unwrap();
+            assert!(!gpu.cmds.read().unwrap().is_empty());
+            runtime.block_on(async {
+                tokio::time::timeout(Duration::from_secs(5), gpu.finish_async())
+                    .await
+                    .expect("the frame never completed")
+                    .unwrap();
+            });
+            assert!(gpu.cmds.read().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_fence_signals_before_read_back() {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping frame fence test");
+            return;
+        };
+        let texture = gpu.device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: 4,
+                height: 4,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        gpu.clear_texture(&texture, wgpu::Color::GREEN).unwrap();
+        gpu.finish();
+
+        let mut fence = gpu.fence();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            gpu.poll_async().await;
+            assert!(fence.is_signaled());
+            tokio::time::timeout(Duration::from_secs(5), fence.wait(&gpu))
+                .await
+                .expect("the fence was never signaled")
+                .unwrap();
+        });
+
+        let frame = gpu.read_texture(&texture).unwrap();
+        assert!(frame
+            .pixels
+            .chunks(4)
+            .all(|pixel| pixel == [0, 255, 0, 255]));
+    }
+
+    #[test]
+    fn test_headless_triangle() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(64, 64)) else {
+            eprintln!("No adapter, skipping headless rendering test");
+            return Ok(());
+        };
+        let device = &gpu.device;
+        let format = gpu.get_config_read(|config| config.format);
+
+        let target = texture::Texture::create_2d_texture(
+            &gpu,
+            64,
+            64,
+            format,
+            wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            wgpu::FilterMode::Nearest,
+            Some("test_headless_triangle"),
+        );
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(
+                "
+                @vertex
+                fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+                    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+                    return vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+                }
+
+                @fragment
+                fn fs_main() -> @location(0) vec4<f32> {
+                    return vec4<f32>(1.0, 0.0, 0.0, 1.0);
+                }
+                "
+                .into(),
+            ),
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: None,
+            layout: None,
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(format.into())],
+            }),
+            primitive: Default::default(),
+            depth_stencil: None,
+            multisample: Default::default(),
+            multiview: None,
+        });
+
+        let mut encoder = gpu.create_cmd_encoder();
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &target.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        pass.set_pipeline(&pipeline);
+        pass.draw(0..3, 0..1);
+        drop(pass);
+        gpu.submit_cmd(encoder.finish());
+        gpu.finish();
+
+        let frame = gpu.read_texture(&target.texture)?;
+        assert_eq!((frame.width, frame.height), (64, 64));
+        let center = ((32 * 64 + 32) * 4) as usize;
+        assert_eq!(frame.pixels[center..center + 4], [255, 0, 0, 255]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mip_layout() -> anyhow::Result<()> {
+        let size = wgpu::Extent3d {
+            width: 64,
+            height: 32,
+            depth_or_array_layers: 1,
+        };
+        let (dimension, format) = (wgpu::TextureDimension::D2, wgpu::TextureFormat::Rgba8Unorm);
+
+        let layout = MipLayout::new(size, dimension, format, 7, 1)?;
+        assert_eq!((layout.size.width, layout.size.height), (32, 16));
+        assert_eq!(layout.bytes_per_row, 32 * 4);
+        assert_eq!(
+            layout.padded_bytes_per_row,
+            wgpu::COPY_BYTES_PER_ROW_ALIGNMENT
+        );
+
+        let layout = MipLayout::new(size, dimension, format, 7, 6)?;
+        assert_eq!((layout.size.width, layout.size.height), (1, 1));
+
+        assert!(MipLayout::new(size, dimension, format, 7, 7).is_err());
+        Ok(())
     }
 }
@@ -0,0 +1,599 @@
+use anyhow::{bail, Result};
+
+use crate::gpu::{CommandListIndex, Gpu};
+
+/// A texture of a [`RenderGraph`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TextureId(usize);
+
+/// What a texture holds before the first pass writing it draws.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextureInit {
+    /// What it held before the graph ran.
+    Load,
+    Clear(wgpu::Color),
+    ClearDepth(f32),
+    /// For depth formats with a stencil aspect, which the graph can't tell
+    /// from the view.
+    ClearDepthStencil(f32, u32),
+}
+
+struct GraphTexture<'a> {
+    label: &'a str,
+    view: &'a wgpu::TextureView,
+    init: TextureInit,
+    /// Whether its contents are used after the graph, otherwise they're
+    /// discarded once the last pass using it is done.
+    keep: bool,
+}
+
+/// The textures a pass touches, which is all scheduling looks at.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Access {
+    writes: Vec<TextureId>,
+    reads: Vec<TextureId>,
+}
+
+type Record<'a> = Box<dyn FnOnce(&mut wgpu::CommandEncoder, &PassTargets<'a>) + 'a>;
+
+struct GraphPass<'a> {
+    label: &'a str,
+    colors: Vec<TextureId>,
+    /// Per color, the texture it's resolved into.
+    resolves: Vec<Option<TextureId>>,
+    depth: Option<TextureId>,
+    reads: Vec<TextureId>,
+    record: Record<'a>,
+}
+
+impl GraphPass<'_> {
+    fn access(&self) -> Access {
+        Access {
+            writes: self
+                .colors
+                .iter()
+                .chain(self.resolves.iter().flatten())
+                .chain(&self.depth)
+                .copied()
+                .collect(),
+            reads: self.reads.clone(),
+        }
+    }
+}
+
+/// The attachments of a pass, with the load and store operations the graph
+/// picked for them.
+pub struct PassTargets<'a> {
+    label: &'a str,
+    color_attachments: Vec<Option<wgpu::RenderPassColorAttachment<'a>>>,
+    depth_stencil_attachment: Option<wgpu::RenderPassDepthStencilAttachment<'a>>,
+}
+
+impl<'a> PassTargets<'a> {
+    /// Begins the render pass over the pass's attachments. Passes that only
+    /// copy or dispatch don't have to, but then nothing is cleared either.
+    pub fn begin<'p>(&'p self, encoder: &'p mut wgpu::CommandEncoder) -> wgpu::RenderPass<'p> {
+        self.begin_timed(encoder, None)
+    }
+
+    /// Like [`PassTargets::begin`], also writing `timestamp_writes` when the
+    /// pass begins and ends.
+    pub fn begin_timed<'p>(
+        &'p self,
+        encoder: &'p mut wgpu::CommandEncoder,
+        timestamp_writes: Option<wgpu::RenderPassTimestampWrites<'p>>,
+    ) -> wgpu::RenderPass<'p> {
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(self.label),
+            color_attachments: &self.color_attachments,
+            depth_stencil_attachment: self.depth_stencil_attachment.clone(),
+            timestamp_writes,
+            occlusion_query_set: None,
+        })
+    }
+}
+
+/// Passes declared with the textures they draw into and sample, run in an
+/// order where every texture is written before it's read, e.g. a shadow map
+/// before the scene pass sampling it, whatever order they were added in.
+///
+/// The graph picks the load and store operations: the first pass writing a
+/// texture applies its [`TextureInit`] and later ones load, and a texture is
+/// only stored while a later pass or the caller still needs it. wgpu tracks
+/// how each texture is used and inserts the barriers between passes itself.
+///
+/// Each pass is recorded into its own command encoder and queued with
+/// [`Gpu::reserve_cmd`] slots in the order the graph picked.
+#[derive(Default)]
+pub struct RenderGraph<'a> {
+    textures: Vec<GraphTexture<'a>>,
+    passes: Vec<GraphPass<'a>>,
+}
+
+impl<'a> RenderGraph<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a texture whose contents are used after the graph, such as the
+    /// surface or a target read back later.
+    pub fn import(
+        &mut self,
+        label: &'a str,
+        view: &'a wgpu::TextureView,
+        init: TextureInit,
+    ) -> TextureId {
+        self.add_texture(label, view, init, true)
+    }
+
+    /// Adds a texture only the graph's passes use, such as a depth buffer.
+    /// It isn't stored past the last pass using it.
+    pub fn transient(
+        &mut self,
+        label: &'a str,
+        view: &'a wgpu::TextureView,
+        init: TextureInit,
+    ) -> TextureId {
+        self.add_texture(label, view, init, false)
+    }
+
+    fn add_texture(
+        &mut self,
+        label: &'a str,
+        view: &'a wgpu::TextureView,
+        init: TextureInit,
+        keep: bool,
+    ) -> TextureId {
+        self.textures.push(GraphTexture {
+            label,
+            view,
+            init,
+            keep,
+        });
+        TextureId(self.textures.len() - 1)
+    }
+
+    /// Declares a pass, added to the graph by [`PassBuilder::record`].
+    pub fn add_pass<'g>(&'g mut self, label: &'a str) -> PassBuilder<'g, 'a> {
+        PassBuilder {
+            graph: self,
+            label,
+            colors: Vec::new(),
+            resolves: Vec::new(),
+            depth: None,
+            reads: Vec::new(),
+        }
+    }
+
+    /// Records every pass in dependency order and queues the command buffers
+    /// for the next [`Gpu::finish`], returning where each was queued in that
+    /// order. Fails without recording anything when the passes depend on
+    /// each other in a cycle.
+    pub fn execute(self, gpu: &Gpu) -> Result<Vec<CommandListIndex>> {
+        let accesses = self
+            .passes
+            .iter()
+            .map(GraphPass::access)
+            .collect::<Vec<_>>();
+        let order = match schedule(&accesses) {
+            Ok(order) => order,
+            Err(pass) => bail!(
+                "Render pass {:?} is part of a dependency cycle",
+                self.passes[pass].label
+            ),
+        };
+        let textures = self
+            .textures
+            .iter()
+            .map(|texture| (texture.init, texture.keep))
+            .collect::<Vec<_>>();
+        let ops = attachment_ops(&textures, &accesses, &order);
+
+        let indices = order.iter().map(|_| gpu.reserve_cmd()).collect::<Vec<_>>();
+        let mut passes = self.passes.into_iter().map(Some).collect::<Vec<_>>();
+        for (&declared, &index) in order.iter().zip(&indices) {
+            let pass = passes[declared].take().unwrap();
+            let targets = PassTargets {
+                label: pass.label,
+                color_attachments: pass
+                    .colors
+                    .iter()
+                    .zip(&pass.resolves)
+                    .map(|(id, resolve)| {
+                        Some(wgpu::RenderPassColorAttachment {
+                            view: self.textures[id.0].view,
+                            resolve_target: resolve.map(|resolve| self.textures[resolve.0].view),
+                            ops: ops.color(declared, *id),
+                        })
+                    })
+                    .collect(),
+                depth_stencil_attachment: pass.depth.map(|id| {
+                    let (depth_ops, stencil_ops) = ops.depth_stencil(declared, id);
+                    wgpu::RenderPassDepthStencilAttachment {
+                        view: self.textures[id.0].view,
+                        depth_ops: Some(depth_ops),
+                        stencil_ops,
+                    }
+                }),
+            };
+
+            let mut encoder = gpu
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some(pass.label),
+                });
+            (pass.record)(&mut encoder, &targets);
+            gpu.submit_cmd_at(index, encoder.finish());
+        }
+        Ok(indices)
+    }
+}
+
+/// A pass being declared, see [`RenderGraph::add_pass`].
+pub struct PassBuilder<'g, 'a> {
+    graph: &'g mut RenderGraph<'a>,
+    label: &'a str,
+    colors: Vec<TextureId>,
+    resolves: Vec<Option<TextureId>>,
+    depth: Option<TextureId>,
+    reads: Vec<TextureId>,
+}
+
+impl<'g, 'a> PassBuilder<'g, 'a> {
+    /// Draws into `texture` at the next color `@location`.
+    pub fn color(mut self, texture: TextureId) -> Self {
+        self.colors.push(texture);
+        self.resolves.push(None);
+        self
+    }
+
+    /// Draws into the multisampled `texture` at the next color `@location`,
+    /// resolved into `resolve_target` at the end of the pass.
+    pub fn color_resolved(mut self, texture: TextureId, resolve_target: TextureId) -> Self {
+        self.colors.push(texture);
+        self.resolves.push(Some(resolve_target));
+        self
+    }
+
+    pub fn depth(mut self, texture: TextureId) -> Self {
+        self.depth = Some(texture);
+        self
+    }
+
+    /// Samples or copies from `texture`, the pass runs after every pass
+    /// writing it.
+    pub fn read(mut self, texture: TextureId) -> Self {
+        self.reads.push(texture);
+        self
+    }
+
+    /// Adds the pass, `record` encodes it when the graph executes. It begins
+    /// the render pass with [`PassTargets::begin`].
+    pub fn record(
+        self,
+        record: impl FnOnce(&mut wgpu::CommandEncoder, &PassTargets<'a>) + 'a,
+    ) -> Result<()> {
+        let draws_into = |texture: &TextureId| {
+            self.colors.contains(texture)
+                || self.resolves.contains(&Some(*texture))
+                || self.depth == Some(*texture)
+        };
+        if let Some(texture) = self.reads.iter().find(|read| draws_into(read)) {
+            bail!(
+                "Render pass {:?} reads {:?}, which it also draws into",
+                self.label,
+                self.graph.textures[texture.0].label
+            );
+        }
+        self.graph.passes.push(GraphPass {
+            label: self.label,
+            colors: self.colors,
+            resolves: self.resolves,
+            depth: self.depth,
+            reads: self.reads,
+            record: Box::new(record),
+        });
+        Ok(())
+    }
+}
+
+/// An order of the passes where every texture is read after all the passes
+/// writing it, and the passes writing the same texture keep the order they
+/// were added in. Passes are otherwise left in the order they were added.
+/// Fails with a pass of a dependency cycle.
+fn schedule(passes: &[Access]) -> Result<Vec<usize>, usize> {
+    // `before[b]` has every pass that has to run before `b`.
+    let mut before = vec![Vec::new(); passes.len()];
+    for (b, pass) in passes.iter().enumerate() {
+        for (a, other) in passes.iter().enumerate() {
+            let writes_read = pass.reads.iter().any(|read| other.writes.contains(read));
+            let writes_earlier =
+                a < b && pass.writes.iter().any(|write| other.writes.contains(write));
+            if a != b && (writes_read || writes_earlier) {
+                before[b].push(a);
+            }
+        }
+    }
+
+    let mut order = Vec::with_capacity(passes.len());
+    let mut scheduled = vec![false; passes.len()];
+    while order.len() < passes.len() {
+        let next = (0..passes.len())
+            .find(|&pass| !scheduled[pass] && before[pass].iter().all(|a| scheduled[*a]));
+        let Some(next) = next else {
+            return Err((0..passes.len()).find(|pass| !scheduled[*pass]).unwrap());
+        };
+        scheduled[next] = true;
+        order.push(next);
+    }
+    Ok(order)
+}
+
+/// `(load, store)` of every texture, per pass in declaration order.
+struct AttachmentOps(Vec<Vec<(TextureInit, wgpu::StoreOp)>>);
+
+impl AttachmentOps {
+    fn color(&self, pass: usize, texture: TextureId) -> wgpu::Operations<wgpu::Color> {
+        let (init, store) = self.0[texture.0][pass];
+        let load = match init {
+            TextureInit::Clear(color) => wgpu::LoadOp::Clear(color),
+            _ => wgpu::LoadOp::Load,
+        };
+        wgpu::Operations { load, store }
+    }
+
+    fn depth_stencil(
+        &self,
+        pass: usize,
+        texture: TextureId,
+    ) -> (wgpu::Operations<f32>, Option<wgpu::Operations<u32>>) {
+        let (init, store) = self.0[texture.0][pass];
+        match init {
+            TextureInit::ClearDepth(depth) => (
+                wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(depth),
+                    store,
+                },
+                None,
+            ),
+            TextureInit::ClearDepthStencil(depth, stencil) => (
+                wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(depth),
+                    store,
+                },
+                Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(stencil),
+                    store,
+                }),
+            ),
+            TextureInit::Load | TextureInit::Clear(_) => (
+                wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store,
+                },
+                None,
+            ),
+        }
+    }
+}
+
+/// Per texture, given its init and whether it's kept, how each pass writing
+/// it loads and stores it when run in `order`: the first applies the init and
+/// the others load, and it's stored unless no later pass uses it and it isn't
+/// kept.
+fn attachment_ops(
+    textures: &[(TextureInit, bool)],
+    passes: &[Access],
+    order: &[usize],
+) -> AttachmentOps {
+    let ops = textures
+        .iter()
+        .enumerate()
+        .map(|(texture, &(texture_init, keep))| {
+            let id = TextureId(texture);
+            let uses = |pass: &usize| {
+                passes[*pass].writes.contains(&id) || passes[*pass].reads.contains(&id)
+            };
+            let last_use = order.iter().rposition(uses);
+            let first_write = order
+                .iter()
+                .position(|pass| passes[*pass].writes.contains(&id));
+
+            let mut ops = vec![(TextureInit::Load, wgpu::StoreOp::Store); passes.len()];
+            for (position, &pass) in order.iter().enumerate() {
+                let init = if Some(position) == first_write {
+                    texture_init
+                } else {
+                    TextureInit::Load
+                };
+                let store = if keep || Some(position) < last_use {
+                    wgpu::StoreOp::Store
+                } else {
+                    wgpu::StoreOp::Discard
+                };
+                ops[pass] = (init, store);
+            }
+            ops
+        })
+        .collect();
+    AttachmentOps(ops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture;
+
+    fn access(writes: &[usize], reads: &[usize]) -> Access {
+        Access {
+            writes: writes.iter().copied().map(TextureId).collect(),
+            reads: reads.iter().copied().map(TextureId).collect(),
+        }
+    }
+
+    #[test]
+    fn test_schedule_writes_before_reads() {
+        // The scene samples the shadow map, but was added first.
+        let scene = access(&[1, 2], &[0]);
+        let shadow = access(&[0], &[]);
+        let post = access(&[3], &[1]);
+        assert_eq!(schedule(&[post, scene, shadow]), Ok(vec![2, 1, 0]));
+
+        // Writers of the same texture keep their order.
+        let opaque = access(&[1], &[]);
+        let transparent = access(&[1], &[]);
+        assert_eq!(schedule(&[opaque, transparent]), Ok(vec![0, 1]));
+
+        let a = access(&[0], &[1]);
+        let b = access(&[1], &[0]);
+        assert_eq!(schedule(&[a, b]), Err(0));
+    }
+
+    #[test]
+    fn test_load_and_store_ops() {
+        let textures = [
+            (TextureInit::Clear(wgpu::Color::BLACK), true),
+            (TextureInit::ClearDepth(1.0), false),
+        ];
+        let opaque = access(&[0, 1], &[]);
+        let transparent = access(&[0, 1], &[]);
+        let ui = access(&[0], &[]);
+        let ops = attachment_ops(&textures, &[ui, opaque, transparent], &[1, 2, 0]);
+
+        assert_eq!(
+            ops.color(1, TextureId(0)).load,
+            wgpu::LoadOp::Clear(wgpu::Color::BLACK)
+        );
+        let (depth_ops, stencil_ops) = ops.depth_stencil(1, TextureId(1));
+        assert_eq!(depth_ops.load, wgpu::LoadOp::Clear(1.0));
+        assert_eq!(depth_ops.store, wgpu::StoreOp::Store);
+        assert_eq!(stencil_ops, None);
+
+        // Nothing needs the depth after the transparent pass.
+        let (depth_ops, _) = ops.depth_stencil(2, TextureId(1));
+        assert_eq!(depth_ops.load, wgpu::LoadOp::Load);
+        assert_eq!(depth_ops.store, wgpu::StoreOp::Discard);
+
+        let ui = ops.color(0, TextureId(0));
+        assert_eq!(ui.load, wgpu::LoadOp::Load);
+        assert_eq!(ui.store, wgpu::StoreOp::Store);
+    }
+
+    #[test]
+    fn test_execute_in_dependency_order() -> Result<()> {
+        let Ok(gpu) = futures::executor::block_on(crate::gpu::Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping render graph test");
+            return Ok(());
+        };
+        let format = wgpu::TextureFormat::Rgba8Unorm;
+        let intermediate = texture::Texture::create_render_target(
+            &gpu.device,
+            4,
+            4,
+            format,
+            wgpu::TextureUsages::COPY_SRC,
+        );
+        let readback = texture::Texture::create_render_target(
+            &gpu.device,
+            4,
+            4,
+            format,
+            wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::COPY_SRC,
+        );
+
+        let mut graph = RenderGraph::new();
+        let red = graph.transient(
+            "intermediate",
+            &intermediate.view,
+            TextureInit::Clear(wgpu::Color::RED),
+        );
+        // Added before the pass filling what it copies.
+        graph.add_pass("copy").read(red).record(|encoder, _| {
+            encoder.copy_texture_to_texture(
+                intermediate.texture.as_image_copy(),
+                readback.texture.as_image_copy(),
+                intermediate.size,
+            );
+        })?;
+        graph
+            .add_pass("fill")
+            .color(red)
+            .record(|encoder, targets| {
+                targets.begin(encoder);
+            })?;
+        assert!(graph
+            .add_pass("feedback")
+            .color(red)
+            .read(red)
+            .record(|_, _| {})
+            .is_err());
+
+        // The rejected pass isn't recorded.
+        let indices = graph.execute(&gpu)?;
+        assert_eq!(indices.len(), 2);
+        assert!(indices.windows(2).all(|pair| pair[0] < pair[1]));
+        gpu.finish();
+        let frame = gpu.read_texture(&readback.texture)?;
+        assert!(frame
+            .pixels
+            .chunks(4)
+            .all(|pixel| pixel == [255, 0, 0, 255]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_multisampled_color() -> Result<()> {
+        let Ok(gpu) = futures::executor::block_on(crate::gpu::Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping render graph resolve test");
+            return Ok(());
+        };
+        let format = wgpu::TextureFormat::Rgba8Unorm;
+        let msaa = gpu.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("msaa"),
+            size: wgpu::Extent3d {
+                width: 4,
+                height: 4,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 4,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+        let msaa_view = msaa.create_view(&Default::default());
+        let target = texture::Texture::create_render_target(
+            &gpu.device,
+            4,
+            4,
+            format,
+            wgpu::TextureUsages::COPY_SRC,
+        );
+
+        let mut graph = RenderGraph::new();
+        let samples = graph.transient("msaa", &msaa_view, TextureInit::Clear(wgpu::Color::GREEN));
+        let resolved = graph.import("target", &target.view, TextureInit::Load);
+        graph
+            .add_pass("resolve")
+            .color_resolved(samples, resolved)
+            .record(|encoder, targets| {
+                targets.begin(encoder);
+            })?;
+        assert!(graph
+            .add_pass("feedback")
+            .color_resolved(samples, resolved)
+            .read(resolved)
+            .record(|_, _| {})
+            .is_err());
+
+        graph.execute(&gpu)?;
+        gpu.finish();
+        let frame = gpu.read_texture(&target.texture)?;
+        assert!(frame
+            .pixels
+            .chunks(4)
+            .all(|pixel| pixel == [0, 255, 0, 255]));
+        Ok(())
+    }
+}
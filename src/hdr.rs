@@ -1,6 +1,10 @@
-use wgpu::{util::RenderEncoder, Operations};
+use wgpu::{util::DeviceExt, Operations};
 
-use crate::{create_render_pipeline, gpu::Gpu, texture};
+use crate::{
+    gpu::{needs_srgb_encoding, Gpu},
+    pipeline::PipelineBuilder,
+    texture,
+};
 
 pub struct HdrPipeline {
     pipeline: wgpu::RenderPipeline,
@@ -10,9 +14,12 @@ pub struct HdrPipeline {
     height: u32,
     format: wgpu::TextureFormat,
     layout: wgpu::BindGroupLayout,
+    exposure_buffer: wgpu::Buffer,
 }
 
 impl HdrPipeline {
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
     pub fn new(gpu: &Gpu) -> Self {
         let config = gpu.get_config();
         let width = config.width;
@@ -20,7 +27,7 @@ impl HdrPipeline {
 
         let device = &gpu.device;
 
-        let format = wgpu::TextureFormat::Rgba16Float;
+        let format = Self::FORMAT;
 
         let texture = texture::Texture::create_2d_texture(
             gpu,
@@ -28,7 +35,7 @@ impl HdrPipeline {
             height,
             format,
             wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
-            wgpu::FilterMode::Nearest,
+            wgpu::FilterMode::Linear,
             Some("Hdr::texture"),
         );
 
@@ -51,24 +58,28 @@ impl HdrPipeline {
                     ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
         });
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Hdr::bind_group"),
-            layout: &layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
-                },
-            ],
+        // Padded to 16 bytes for uniform layout rules.
+        let exposure_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Hdr::exposure"),
+            contents: bytemuck::cast_slice(&[1.0f32, 0.0, 0.0, 0.0]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
+        let bind_group = Self::create_bind_group(device, &layout, &texture, &exposure_buffer);
+
         let shader = wgpu::include_wgsl!("hdr.wgsl");
 
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
@@ -76,15 +87,15 @@ impl HdrPipeline {
             bind_group_layouts: &[&layout],
             push_constant_ranges: &[],
         });
-        let pipeline = create_render_pipeline(
-            gpu,
-            &pipeline_layout,
-            config.format.add_srgb_suffix(),
-            None,
-            &[],
-            wgpu::PrimitiveTopology::TriangleList,
-            shader,
-        );
+        let defines: &[&str] = if needs_srgb_encoding(config.format) {
+            &["ENCODE_SRGB"]
+        } else {
+            &[]
+        };
+        let pipeline = PipelineBuilder::new(&pipeline_layout, config.format, shader)
+            .defines(defines)
+            .expect("hdr.wgsl's #ifdef blocks are balanced")
+            .build(gpu);
 
         Self {
             pipeline,
@@ -94,36 +105,43 @@ impl HdrPipeline {
             height,
             format,
             layout,
+            exposure_buffer,
         }
     }
 
-    pub fn resize(&mut self, gpu: &Gpu, width: u32, height: u32) {
-        let device = &gpu.device;
-
-        self.texture = texture::Texture::create_2d_texture(
-            gpu,
-            height,
-            width,
-            wgpu::TextureFormat::Rgba16Float,
-            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
-            wgpu::FilterMode::Nearest,
-            Some("Hdr::texture"),
-        );
-
-        self.bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        texture: &texture::Texture,
+        exposure_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("Hdr::bind_group"),
-            layout: &self.layout,
+            layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&self.texture.view),
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&self.texture.sampler),
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: exposure_buffer.as_entire_binding(),
                 },
             ],
-        });
+        })
+    }
+
+    pub fn resize(&mut self, gpu: &Gpu, width: u32, height: u32) {
+        let device = &gpu.device;
+
+        self.texture.resize(device, width, height);
+
+        self.bind_group =
+            Self::create_bind_group(device, &self.layout, &self.texture, &self.exposure_buffer);
 
         self.width = width;
         self.height = height;
@@ -133,10 +151,32 @@ impl HdrPipeline {
         &self.texture.view
     }
 
+    pub fn texture(&self) -> &wgpu::Texture {
+        &self.texture.texture
+    }
+
     pub fn format(&self) -> wgpu::TextureFormat {
         self.format
     }
 
+    /// Multiplier applied before tone mapping, 1 by default.
+    pub fn set_exposure(&self, queue: &wgpu::Queue, exposure: f32) {
+        queue.write_buffer(&self.exposure_buffer, 0, bytemuck::cast_slice(&[exposure]));
+    }
+
+    /// Copies the exposure [`AutoExposure`](crate::exposure::AutoExposure)
+    /// computed on the GPU, so it never has to be read back.
+    pub fn copy_exposure(&self, encoder: &mut wgpu::CommandEncoder, state: &wgpu::Buffer) {
+        let offset = std::mem::offset_of!(crate::exposure::ExposureState, exposure);
+        encoder.copy_buffer_to_buffer(
+            state,
+            offset as wgpu::BufferAddress,
+            &self.exposure_buffer,
+            0,
+            std::mem::size_of::<f32>() as wgpu::BufferAddress,
+        );
+    }
+
     pub fn process(&self, encoder: &mut wgpu::CommandEncoder, output: &wgpu::TextureView) {
         let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: Some("Hdr::process"),
@@ -158,3 +198,30 @@ impl HdrPipeline {
         pass.draw(0..3, 0..1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::preprocess;
+
+    #[test]
+    fn test_shader_variants_compile() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping hdr shader test");
+            return Ok(());
+        };
+        let source = include_str!("hdr.wgsl");
+        for defines in [&[][..], &["ENCODE_SRGB"]] {
+            gpu.device.push_error_scope(wgpu::ErrorFilter::Validation);
+            gpu.device
+                .create_shader_module(wgpu::ShaderModuleDescriptor {
+                    label: None,
+                    source: wgpu::ShaderSource::Wgsl(preprocess(source, defines)?.into()),
+                });
+            if let Some(err) = futures::executor::block_on(gpu.device.pop_error_scope()) {
+                anyhow::bail!("hdr.wgsl with {defines:?}: {err}");
+            }
+        }
+        Ok(())
+    }
+}
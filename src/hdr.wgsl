@@ -48,9 +48,31 @@ var hdr_image: texture_2d<f32>;
 @binding(1)
 var hdr_sampler: sampler;
 
+struct Exposure {
+    value: f32,
+}
+
+@group(0)
+@binding(2)
+var<uniform> exposure: Exposure;
+
+#ifdef ENCODE_SRGB
+// The sRGB transfer function, for linear surfaces that are displayed as
+// sRGB but don't encode what's written to them.
+fn linear_to_srgb(linear: vec3<f32>) -> vec3<f32> {
+    let low = linear * 12.92;
+    let high = 1.055 * pow(linear, vec3(1.0 / 2.4)) - 0.055;
+    return select(high, low, linear <= vec3(0.0031308));
+}
+#endif
+
 @fragment
 fn fs_main(vs: VertexOutput) -> @location(0) vec4<f32> {
     let hdr = textureSample(hdr_image, hdr_sampler, vs.uv);
-    let sdr = aces_tone_map(hdr.rgb);
+    let sdr = aces_tone_map(hdr.rgb * exposure.value);
+#ifdef ENCODE_SRGB
+    return vec4(linear_to_srgb(sdr), hdr.a);
+#else
     return vec4(sdr, hdr.a);
+#endif
 }
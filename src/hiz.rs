@@ -0,0 +1,601 @@
+use std::mem;
+
+use bytemuck::Zeroable;
+use wgpu::util::{DeviceExt, DrawIndexedIndirectArgs};
+
+use crate::{
+    gpu::Gpu,
+    model::{InstanceBuffer, InstanceRaw, Model},
+    texture,
+};
+
+const WORKGROUP_SIZE_2D: u32 = 8;
+const WORKGROUP_SIZE_CULL: u32 = 64;
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct CullParams {
+    view_proj: [[f32; 4]; 4],
+    bounds: [f32; 4],
+    instance_count: u32,
+    mesh_count: u32,
+    /// Mip levels of the pyramid, textureNumLevels isn't available on GL.
+    level_count: u32,
+    // Uniform structs are padded to 16 bytes.
+    _padding: u32,
+}
+
+/// Hierarchical Z pyramid built from the scene depth buffer, used to cull
+/// instances hidden behind what was drawn on the GPU.
+///
+/// Level 0 has the size of the depth buffer and every following level half
+/// of the one before, each texel holding the farthest depth below it.
+pub struct HiZPass {
+    pyramid: wgpu::Texture,
+    view: wgpu::TextureView,
+    level_views: Vec<wgpu::TextureView>,
+    copy_layout: wgpu::BindGroupLayout,
+    copy_pipeline: wgpu::ComputePipeline,
+    downsample_layout: wgpu::BindGroupLayout,
+    downsample_pipeline: wgpu::ComputePipeline,
+    downsample_bind_groups: Vec<wgpu::BindGroup>,
+    cull_layout: wgpu::BindGroupLayout,
+    cull_pipeline: wgpu::ComputePipeline,
+    /// Whether the pyramid holds a depth buffer yet, a fresh one would cull
+    /// everything.
+    built: bool,
+}
+
+fn texture_entry(binding: u32, sample_type: wgpu::TextureSampleType) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Texture {
+            sample_type,
+            view_dimension: wgpu::TextureViewDimension::D2,
+            multisampled: false,
+        },
+        count: None,
+    }
+}
+
+fn storage_texture_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::StorageTexture {
+            access: wgpu::StorageTextureAccess::WriteOnly,
+            format: HiZPass::FORMAT,
+            view_dimension: wgpu::TextureViewDimension::D2,
+        },
+        count: None,
+    }
+}
+
+fn buffer_entry(binding: u32, ty: wgpu::BufferBindingType) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+impl HiZPass {
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R32Float;
+
+    pub fn new(gpu: &Gpu, width: u32, height: u32) -> Self {
+        let device = &gpu.device;
+        let shader = device.create_shader_module(wgpu::include_wgsl!("hiz.wgsl"));
+
+        // R32Float can't be filtered, so the pyramid is only ever read with
+        // textureLoad.
+        let unfilterable = wgpu::TextureSampleType::Float { filterable: false };
+        let copy_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("HiZPass::copy_layout"),
+            entries: &[
+                // Bound as a float texture, GL can't textureLoad depth ones.
+                texture_entry(0, unfilterable),
+                storage_texture_entry(1),
+            ],
+        });
+        let downsample_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("HiZPass::downsample_layout"),
+            entries: &[texture_entry(0, unfilterable), storage_texture_entry(1)],
+        });
+        let cull_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("HiZPass::cull_layout"),
+            entries: &[
+                texture_entry(0, unfilterable),
+                buffer_entry(1, wgpu::BufferBindingType::Uniform),
+                buffer_entry(2, wgpu::BufferBindingType::Storage { read_only: true }),
+                buffer_entry(3, wgpu::BufferBindingType::Storage { read_only: false }),
+                buffer_entry(4, wgpu::BufferBindingType::Storage { read_only: false }),
+            ],
+        });
+
+        let create_pipeline = |entry_point, layout| {
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(entry_point),
+                bind_group_layouts: &[layout],
+                push_constant_ranges: &[],
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point,
+            })
+        };
+        let copy_pipeline = create_pipeline("copy_depth", &copy_layout);
+        let downsample_pipeline = create_pipeline("downsample", &downsample_layout);
+        let cull_pipeline = create_pipeline("cull", &cull_layout);
+
+        let (pyramid, view, level_views) = Self::create_pyramid(device, width, height);
+        let downsample_bind_groups =
+            Self::create_downsample_bind_groups(device, &downsample_layout, &level_views);
+
+        Self {
+            pyramid,
+            view,
+            level_views,
+            copy_layout,
+            copy_pipeline,
+            downsample_layout,
+            downsample_pipeline,
+            downsample_bind_groups,
+            cull_layout,
+            cull_pipeline,
+            built: false,
+        }
+    }
+
+    /// Size of every level of the pyramid for a `width` x `height` depth
+    /// buffer, from level 0 down to 1x1.
+    pub fn level_sizes(width: u32, height: u32) -> Vec<(u32, u32)> {
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+        (0..texture::Texture::mip_level_count(width, height))
+            .map(|level| {
+                let size = size.mip_level_size(level, wgpu::TextureDimension::D2);
+                (size.width, size.height)
+            })
+            .collect()
+    }
+
+    fn create_pyramid(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+    ) -> (wgpu::Texture, wgpu::TextureView, Vec<wgpu::TextureView>) {
+        let pyramid = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("HiZPass::pyramid"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: texture::Texture::mip_level_count(width, height),
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::FORMAT,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+
+        let view = pyramid.create_view(&wgpu::TextureViewDescriptor::default());
+        let level_views = (0..pyramid.mip_level_count())
+            .map(|level| {
+                pyramid.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some("HiZPass::level"),
+                    base_mip_level: level,
+                    mip_level_count: Some(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        (pyramid, view, level_views)
+    }
+
+    fn create_downsample_bind_groups(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        level_views: &[wgpu::TextureView],
+    ) -> Vec<wgpu::BindGroup> {
+        level_views
+            .windows(2)
+            .map(|levels| {
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("HiZPass::downsample"),
+                    layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: wgpu::BindingResource::TextureView(&levels[0]),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: wgpu::BindingResource::TextureView(&levels[1]),
+                        },
+                    ],
+                })
+            })
+            .collect()
+    }
+
+    pub fn resize(&mut self, gpu: &Gpu, width: u32, height: u32) {
+        let (pyramid, view, level_views) = Self::create_pyramid(&gpu.device, width, height);
+        self.downsample_bind_groups =
+            Self::create_downsample_bind_groups(&gpu.device, &self.downsample_layout, &level_views);
+        self.pyramid = pyramid;
+        self.view = view;
+        self.level_views = level_views;
+        self.built = false;
+    }
+
+    /// Whether [`HiZPass::build`] filled the pyramid since it was created or
+    /// resized, culling against it before then drops every instance.
+    pub fn is_built(&self) -> bool {
+        self.built
+    }
+
+    /// View of the whole pyramid, every level readable with `textureLoad`.
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.view
+    }
+
+    /// Rebuilds the pyramid from `depth`, which must be a single sampled
+    /// depth buffer of the pyramid's size.
+    pub fn build(
+        &mut self,
+        gpu: &Gpu,
+        encoder: &mut wgpu::CommandEncoder,
+        depth: &wgpu::TextureView,
+    ) {
+        self.built = true;
+        let copy_bind_group = gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("HiZPass::copy_depth"),
+            layout: &self.copy_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(depth),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&self.level_views[0]),
+                },
+            ],
+        });
+
+        let size = self.pyramid.size();
+        let sizes = Self::level_sizes(size.width, size.height);
+        let workgroups = |(width, height): (u32, u32)| {
+            (
+                width.div_ceil(WORKGROUP_SIZE_2D),
+                height.div_ceil(WORKGROUP_SIZE_2D),
+            )
+        };
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("HiZPass::build"),
+            timestamp_writes: None,
+        });
+
+        pass.set_pipeline(&self.copy_pipeline);
+        pass.set_bind_group(0, &copy_bind_group, &[]);
+        let (x, y) = workgroups(sizes[0]);
+        pass.dispatch_workgroups(x, y, 1);
+
+        pass.set_pipeline(&self.downsample_pipeline);
+        for (bind_group, size) in self.downsample_bind_groups.iter().zip(&sizes[1..]) {
+            pass.set_bind_group(0, bind_group, &[]);
+            let (x, y) = workgroups(*size);
+            pass.dispatch_workgroups(x, y, 1);
+        }
+    }
+
+    /// Writes the instances of `target` whose bounding sphere is not hidden
+    /// behind the pyramid into `out`, along with one indirect draw per mesh.
+    ///
+    /// The indirect arguments are reset in `encoder`, so `out` can be culled
+    /// again, e.g. for another view, before the commands are submitted.
+    pub fn cull(
+        &self,
+        gpu: &Gpu,
+        encoder: &mut wgpu::CommandEncoder,
+        target: CullTarget,
+        view_proj: na::Matrix4<f32>,
+        out: &mut CulledInstances,
+    ) {
+        let device = &gpu.device;
+        let CullTarget {
+            model,
+            instances,
+            bounds,
+        } = target;
+        out.reserve(device, instances.len(), model.meshes.len() as u32);
+
+        let args = model
+            .meshes
+            .iter()
+            .flat_map(|mesh| {
+                DrawIndexedIndirectArgs {
+                    index_count: mesh.num_elements,
+                    instance_count: 0,
+                    first_index: 0,
+                    base_vertex: mesh.base_vertex,
+                    first_instance: 0,
+                }
+                .as_bytes()
+                .to_vec()
+            })
+            .collect::<Vec<_>>();
+        Self::copy_to(device, encoder, &args, &out.args);
+        // Nothing to cull, the reset arguments draw no instances.
+        if instances.is_empty() {
+            return;
+        }
+
+        let params = CullParams {
+            view_proj: view_proj.into(),
+            bounds,
+            instance_count: instances.len(),
+            mesh_count: model.meshes.len() as u32,
+            level_count: self.pyramid.mip_level_count(),
+            _padding: 0,
+        };
+        Self::copy_to(device, encoder, bytemuck::bytes_of(&params), &out.params);
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("HiZPass::cull"),
+            layout: &self.cull_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&self.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: out.params.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: instances.buffer().as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: out.instances.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: out.args.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("HiZPass::cull"),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.cull_pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(instances.len().div_ceil(WORKGROUP_SIZE_CULL), 1, 1);
+    }
+
+    /// Writes `data` to the start of `buffer` in order with the rest of
+    /// `encoder`, unlike a queue write which lands before all of it.
+    fn copy_to(
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        data: &[u8],
+        buffer: &wgpu::Buffer,
+    ) {
+        let staging = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("HiZPass::cull staging"),
+            contents: data,
+            usage: wgpu::BufferUsages::COPY_SRC,
+        });
+        encoder.copy_buffer_to_buffer(&staging, 0, buffer, 0, staging.size());
+    }
+}
+
+/// A model for [`HiZPass::cull`] and the instances it's drawn with.
+pub struct CullTarget<'a> {
+    pub model: &'a Model,
+    pub instances: &'a InstanceBuffer,
+    /// Model space bounding sphere, `[x, y, z, radius]`.
+    pub bounds: [f32; 4],
+}
+
+/// Output of [`HiZPass::cull`]: the visible instances, bound like an
+/// [`InstanceBuffer`], and one `DrawIndexedIndirectArgs` per mesh.
+pub struct CulledInstances {
+    instances: wgpu::Buffer,
+    args: wgpu::Buffer,
+    params: wgpu::Buffer,
+    capacity: u32,
+    mesh_count: u32,
+}
+
+impl CulledInstances {
+    pub const ARGS_STRIDE: wgpu::BufferAddress =
+        mem::size_of::<DrawIndexedIndirectArgs>() as wgpu::BufferAddress;
+
+    pub fn new(device: &wgpu::Device, capacity: u32, mesh_count: u32) -> Self {
+        let capacity = capacity.max(1);
+        let mesh_count = mesh_count.max(1);
+
+        let instances = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("CulledInstances::instances"),
+            size: capacity as u64 * mem::size_of::<InstanceRaw>() as u64,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let args = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("CulledInstances::args"),
+            size: mesh_count as u64 * Self::ARGS_STRIDE,
+            usage: wgpu::BufferUsages::INDIRECT
+                | wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let params = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("CulledInstances::params"),
+            contents: bytemuck::bytes_of(&CullParams::zeroed()),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        Self {
+            instances,
+            args,
+            params,
+            capacity,
+            mesh_count,
+        }
+    }
+
+    fn reserve(&mut self, device: &wgpu::Device, capacity: u32, mesh_count: u32) {
+        if capacity > self.capacity || mesh_count > self.mesh_count {
+            *self = Self::new(
+                device,
+                capacity.max(self.capacity),
+                mesh_count.max(self.mesh_count),
+            );
+        }
+    }
+
+    pub fn instances(&self) -> wgpu::BufferSlice<'_> {
+        self.instances.slice(..)
+    }
+
+    /// Indirect arguments of mesh `i` start at `i * ARGS_STRIDE`.
+    pub fn args(&self) -> &wgpu::Buffer {
+        &self.args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Instance, Mesh, ModelVertex};
+
+    #[test]
+    fn test_level_sizes_halve() {
+        let sizes = HiZPass::level_sizes(640, 480);
+        assert_eq!(sizes.len(), 10);
+        assert_eq!(sizes[0], (640, 480));
+        assert_eq!(sizes.last(), Some(&(1, 1)));
+        for levels in sizes.windows(2) {
+            let ((width, height), (next_width, next_height)) = (levels[0], levels[1]);
+            assert_eq!(next_width, (width / 2).max(1));
+            assert_eq!(next_height, (height / 2).max(1));
+        }
+    }
+
+    #[test]
+    fn test_cull_params_layout() {
+        // Matches the WGSL struct: mat4 + vec4 + three u32, rounded up to 16.
+        assert_eq!(mem::size_of::<CullParams>(), 96);
+    }
+
+    #[test]
+    fn test_occluded_instances_are_not_drawn() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(8, 8)) else {
+            eprintln!("No adapter, skipping Hi-Z culling test");
+            return Ok(());
+        };
+        let device = &gpu.device;
+
+        // A wall at depth 0.5 covering the whole view.
+        let depth = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: 8,
+                height: 8,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: texture::Texture::DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let depth_view = depth.create_view(&Default::default());
+        let mut hiz = HiZPass::new(&gpu, 8, 8);
+        assert!(!hiz.is_built());
+        let mut encoder = device.create_command_encoder(&Default::default());
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(0.5),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        hiz.build(&gpu, &mut encoder, &depth_view);
+        gpu.queue.submit([encoder.finish()]);
+        assert!(hiz.is_built());
+
+        let vertices =
+            [[0.0, 0.0, 0.0], [0.1, 0.0, 0.0], [0.0, 0.1, 0.0]].map(|position| ModelVertex {
+                position,
+                tex_coord: [0.0; 2],
+                normal: [0.0, 0.0, 1.0],
+                tangent: [1.0, 0.0, 0.0, 1.0],
+            });
+        let model = Model {
+            meshes: Mesh::pack(device, "triangle", &[(&vertices, &[0, 1, 2], 0)]),
+            materials: Vec::new(),
+            skin: None,
+        };
+        // With an identity view projection the z translation is the depth.
+        let at = |z: f32| Instance {
+            isometry: na::Isometry3::translation(0.0, 0.0, z),
+        };
+        let mut culled = CulledInstances::new(device, 1, 1);
+        let mut drawn_instances = |instances: &[Instance]| -> anyhow::Result<u32> {
+            let instances = InstanceBuffer::new(device, instances);
+            let mut encoder = device.create_command_encoder(&Default::default());
+            let target = CullTarget {
+                model: &model,
+                instances: &instances,
+                bounds: [0.0, 0.0, 0.0, 0.1],
+            };
+            hiz.cull(
+                &gpu,
+                &mut encoder,
+                target,
+                na::Matrix4::identity(),
+                &mut culled,
+            );
+            gpu.queue.submit([encoder.finish()]);
+            // instance_count follows index_count in DrawIndexedIndirectArgs.
+            let args = gpu.read_buffer(culled.args())?;
+            Ok(bytemuck::pod_read_unaligned(&args[4..8]))
+        };
+
+        assert_eq!(drawn_instances(&[at(0.2), at(0.8)])?, 1);
+        assert_eq!(drawn_instances(&[at(0.8)])?, 0);
+        assert_eq!(drawn_instances(&[at(0.2)])?, 1);
+        // Dropping every instance doesn't keep drawing the last ones.
+        assert_eq!(drawn_instances(&[])?, 0);
+        Ok(())
+    }
+}
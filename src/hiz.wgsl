@@ -0,0 +1,195 @@
+// Hierarchical Z: every texel of a level holds the farthest depth of the
+// texels it covers in the level below, so a single load answers "is
+// anything in this area closer than X".
+
+@group(0)
+@binding(0)
+var depth: texture_2d<f32>;
+
+@group(0)
+@binding(1)
+var dst: texture_storage_2d<r32float, write>;
+
+@compute
+@workgroup_size(8, 8, 1)
+fn copy_depth(
+    @builtin(global_invocation_id)
+    gid: vec3<u32>,
+) {
+    let size = textureDimensions(dst);
+    if gid.x >= size.x || gid.y >= size.y {
+        return;
+    }
+    let d = textureLoad(depth, gid.xy, 0).r;
+    textureStore(dst, gid.xy, vec4<f32>(d, 0.0, 0.0, 0.0));
+}
+
+@group(0)
+@binding(0)
+var src: texture_2d<f32>;
+
+@compute
+@workgroup_size(8, 8, 1)
+fn downsample(
+    @builtin(global_invocation_id)
+    gid: vec3<u32>,
+) {
+    let size = textureDimensions(dst);
+    if gid.x >= size.x || gid.y >= size.y {
+        return;
+    }
+    let src_size = textureDimensions(src);
+    let base = gid.xy * 2u;
+
+    // Odd sized levels fold their last row and column into the last texel
+    // of the next level, so the footprint grows to 3 texels there.
+    var extent = vec2<u32>(2u, 2u);
+    if (src_size.x & 1u) == 1u && gid.x == size.x - 1u {
+        extent.x = 3u;
+    }
+    if (src_size.y & 1u) == 1u && gid.y == size.y - 1u {
+        extent.y = 3u;
+    }
+
+    var far = 0.0;
+    for (var y = 0u; y < extent.y; y++) {
+        for (var x = 0u; x < extent.x; x++) {
+            let coords = min(base + vec2<u32>(x, y), src_size - 1u);
+            far = max(far, textureLoad(src, coords, 0).r);
+        }
+    }
+    textureStore(dst, gid.xy, vec4<f32>(far, 0.0, 0.0, 0.0));
+}
+
+struct CullParams {
+    view_proj: mat4x4<f32>,
+    // Model space bounding sphere, xyz center and w radius.
+    bounds: vec4<f32>,
+    instance_count: u32,
+    mesh_count: u32,
+    level_count: u32,
+}
+
+// Same layout as wgpu::util::DrawIndexedIndirectArgs.
+struct DrawArgs {
+    index_count: u32,
+    instance_count: atomic<u32>,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+}
+
+// InstanceRaw is a mat4 followed by a mat3 without padding, which doesn't
+// match any WGSL struct layout, so the instances are copied as floats.
+const INSTANCE_FLOATS: u32 = 25u;
+
+@group(0)
+@binding(0)
+var pyramid: texture_2d<f32>;
+
+@group(0)
+@binding(1)
+var<uniform> params: CullParams;
+
+@group(0)
+@binding(2)
+var<storage, read> instances_in: array<f32>;
+
+@group(0)
+@binding(3)
+var<storage, read_write> instances_out: array<f32>;
+
+@group(0)
+@binding(4)
+var<storage, read_write> args: array<DrawArgs>;
+
+fn instance_matrix(index: u32) -> mat4x4<f32> {
+    let o = index * INSTANCE_FLOATS;
+    return mat4x4<f32>(
+        vec4<f32>(instances_in[o + 0u], instances_in[o + 1u], instances_in[o + 2u], instances_in[o + 3u]),
+        vec4<f32>(instances_in[o + 4u], instances_in[o + 5u], instances_in[o + 6u], instances_in[o + 7u]),
+        vec4<f32>(instances_in[o + 8u], instances_in[o + 9u], instances_in[o + 10u], instances_in[o + 11u]),
+        vec4<f32>(instances_in[o + 12u], instances_in[o + 13u], instances_in[o + 14u], instances_in[o + 15u]),
+    );
+}
+
+fn is_visible(model: mat4x4<f32>) -> bool {
+    let center = (model * vec4<f32>(params.bounds.xyz, 1.0)).xyz;
+    let scale = max(length(model[0].xyz), max(length(model[1].xyz), length(model[2].xyz)));
+    let radius = params.bounds.w * scale;
+
+    // Screen space rectangle and nearest depth of the sphere's bounding box.
+    var uv_min = vec2<f32>(1.0);
+    var uv_max = vec2<f32>(0.0);
+    var nearest = 1.0;
+    for (var i = 0u; i < 8u; i++) {
+        let corner = center + radius * vec3<f32>(
+            select(-1.0, 1.0, (i & 1u) != 0u),
+            select(-1.0, 1.0, (i & 2u) != 0u),
+            select(-1.0, 1.0, (i & 4u) != 0u),
+        );
+        let clip = params.view_proj * vec4<f32>(corner, 1.0);
+        // Crossing the camera plane, the projection is meaningless.
+        if clip.w <= 0.0 {
+            return true;
+        }
+        let ndc = clip.xyz / clip.w;
+        let uv = vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+        uv_min = min(uv_min, uv);
+        uv_max = max(uv_max, uv);
+        nearest = min(nearest, ndc.z);
+    }
+
+    // Outside the frustum.
+    if any(uv_max < vec2<f32>(0.0)) || any(uv_min > vec2<f32>(1.0)) || nearest > 1.0 {
+        return false;
+    }
+    uv_min = clamp(uv_min, vec2<f32>(0.0), vec2<f32>(1.0));
+    uv_max = clamp(uv_max, vec2<f32>(0.0), vec2<f32>(1.0));
+
+    // Pick the level where the rectangle is at most a texel wide, wherever
+    // it lands its four corners cover it.
+    let base_size = vec2<f32>(textureDimensions(pyramid, 0));
+    let extent = (uv_max - uv_min) * base_size;
+    let levels = params.level_count;
+    let wanted = u32(ceil(log2(max(max(extent.x, extent.y), 1.0))));
+    let level = i32(min(wanted, levels - 1u));
+
+    let size = textureDimensions(pyramid, level);
+    let texel_min = min(vec2<u32>(uv_min * vec2<f32>(size)), size - 1u);
+    let texel_max = min(vec2<u32>(uv_max * vec2<f32>(size)), size - 1u);
+    let far = max(
+        max(textureLoad(pyramid, texel_min, level).r, textureLoad(pyramid, texel_max, level).r),
+        max(
+            textureLoad(pyramid, vec2<u32>(texel_min.x, texel_max.y), level).r,
+            textureLoad(pyramid, vec2<u32>(texel_max.x, texel_min.y), level).r,
+        ),
+    );
+
+    return nearest <= far;
+}
+
+@compute
+@workgroup_size(64, 1, 1)
+fn cull(
+    @builtin(global_invocation_id)
+    gid: vec3<u32>,
+) {
+    let index = gid.x;
+    if index >= params.instance_count || !is_visible(instance_matrix(index)) {
+        return;
+    }
+
+    // Every mesh of the model draws the same instances, the first mesh's
+    // counter hands out the slots.
+    let slot = atomicAdd(&args[0].instance_count, 1u);
+    for (var mesh = 1u; mesh < params.mesh_count; mesh++) {
+        atomicAdd(&args[mesh].instance_count, 1u);
+    }
+
+    let offset_in = index * INSTANCE_FLOATS;
+    let offset_out = slot * INSTANCE_FLOATS;
+    for (var i = 0u; i < INSTANCE_FLOATS; i++) {
+        instances_out[offset_out + i] = instances_in[offset_in + i];
+    }
+}
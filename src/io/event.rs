@@ -0,0 +1,435 @@
+use std::hash::{Hash, Hasher};
+use tokio::sync::{broadcast, mpsc};
+use winit::event::{Event, WindowEvent};
+
+/// A window event as the app loop sees it, see [`NativeEvent::from_event`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum NativeEvent {
+    /// The window's new size in physical pixels.
+    Resize {
+        width: u32,
+        height: u32,
+    },
+    CloseRequested,
+    /// Time to draw the next frame.
+    Redraw,
+    /// Everything else, e.g. keyboard and mouse input.
+    Input(WindowEvent),
+}
+
+impl NativeEvent {
+    /// The window event in `event`, `None` for the event loop's own events.
+    pub fn from_event<T>(event: Event<T>) -> Option<Self> {
+        match event {
+            Event::WindowEvent { event, .. } => Some(event.into()),
+            _ => None,
+        }
+    }
+}
+
+impl From<WindowEvent> for NativeEvent {
+    fn from(event: WindowEvent) -> Self {
+        match event {
+            WindowEvent::Resized(size) => Self::Resize {
+                width: size.width,
+                height: size.height,
+            },
+            WindowEvent::CloseRequested => Self::CloseRequested,
+            WindowEvent::RedrawRequested => Self::Redraw,
+            event => Self::Input(event),
+        }
+    }
+}
+
+// `WindowEvent` is neither `Eq` nor `Hash` because of its floats, which are
+// never NaN in the events winit sends.
+impl Eq for NativeEvent {}
+
+impl Hash for NativeEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Self::Resize { width, height } => (width, height).hash(state),
+            // Only the kind of input, equal events still hash the same.
+            Self::Input(event) => std::mem::discriminant(event).hash(state),
+            Self::CloseRequested | Self::Redraw => {}
+        }
+    }
+}
+
+/// Why [`EventReceiver`] didn't return an event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventError {
+    /// The receiver fell so far behind that the bus dropped its `n` oldest
+    /// events. Receiving again continues with the oldest one still kept.
+    Lagged(u64),
+    /// Every [`EventBus`], or every [`ChannelSender`], is gone and all
+    /// events were received.
+    Closed,
+}
+
+impl std::fmt::Display for EventError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Lagged(n) => write!(f, "Receiver lagged behind, {n} events were dropped"),
+            Self::Closed => write!(f, "Event bus closed"),
+        }
+    }
+}
+
+impl std::error::Error for EventError {}
+
+/// Delivers every event to all of its subscribers, e.g. the same window
+/// event to the renderer, the camera controller and a logger.
+///
+/// Each subscriber sees the events sent after it subscribed. The bus keeps
+/// at most `capacity` events a subscriber hasn't received yet, a slower one
+/// gets [`EventError::Lagged`] instead of holding everyone else up.
+#[derive(Clone)]
+pub struct EventBus<T> {
+    sender: broadcast::Sender<T>,
+}
+
+impl<T: Clone> EventBus<T> {
+    /// Panics if `capacity` is zero.
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    /// Sends `event` to every subscriber, returns how many there are. With
+    /// none the event is dropped.
+    pub fn send(&self, event: T) -> usize {
+        self.sender.send(event).unwrap_or(0)
+    }
+
+    pub fn subscribe(&self) -> EventReceiver<T> {
+        EventReceiver {
+            receiver: self.sender.subscribe(),
+        }
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+}
+
+/// One subscriber of an [`EventBus`].
+pub struct EventReceiver<T> {
+    receiver: broadcast::Receiver<T>,
+}
+
+impl<T: Clone> EventReceiver<T> {
+    /// Waits for the next event.
+    pub async fn recv(&mut self) -> Result<T, EventError> {
+        self.receiver.recv().await.map_err(|err| match err {
+            broadcast::error::RecvError::Lagged(n) => EventError::Lagged(n),
+            broadcast::error::RecvError::Closed => EventError::Closed,
+        })
+    }
+
+    /// Blocks the thread until the next event arrives, `None` once the bus
+    /// is closed. Events a lagging receiver missed are skipped with a
+    /// warning, [`EventReceiver::recv`] reports them instead.
+    ///
+    /// Panics when called from async code, which has to use
+    /// [`EventReceiver::recv`].
+    pub fn recv_blocking(&mut self) -> Option<T> {
+        loop {
+            match self.receiver.blocking_recv() {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    log::warn!("Event receiver lagged behind, skipped {n} events")
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// The next event if there is one, without waiting.
+    pub fn try_recv(&mut self) -> Result<Option<T>, EventError> {
+        match self.receiver.try_recv() {
+            Ok(event) => Ok(Some(event)),
+            Err(broadcast::error::TryRecvError::Empty) => Ok(None),
+            Err(broadcast::error::TryRecvError::Lagged(n)) => Err(EventError::Lagged(n)),
+            Err(broadcast::error::TryRecvError::Closed) => Err(EventError::Closed),
+        }
+    }
+}
+
+/// Hands each event to a single [`ChannelReceiver`], e.g. finished work to
+/// the frame loop. `send` waits while `capacity` events are queued, so a
+/// slow receiver holds the senders up instead of letting events pile up.
+///
+/// Panics if `capacity` is zero.
+pub fn create_bounded_channel<T>(capacity: usize) -> (ChannelSender<T>, ChannelReceiver<T>) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    (
+        ChannelSender {
+            sender: SenderKind::Bounded(sender),
+        },
+        ChannelReceiver {
+            receiver: ReceiverKind::Bounded(receiver),
+        },
+    )
+}
+
+/// [`create_bounded_channel`] without a capacity, sending never waits and
+/// events queue up until they're received.
+pub fn create_unbounded_channel<T>() -> (ChannelSender<T>, ChannelReceiver<T>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    (
+        ChannelSender {
+            sender: SenderKind::Unbounded(sender),
+        },
+        ChannelReceiver {
+            receiver: ReceiverKind::Unbounded(receiver),
+        },
+    )
+}
+
+enum SenderKind<T> {
+    Unbounded(mpsc::UnboundedSender<T>),
+    Bounded(mpsc::Sender<T>),
+}
+
+enum ReceiverKind<T> {
+    Unbounded(mpsc::UnboundedReceiver<T>),
+    Bounded(mpsc::Receiver<T>),
+}
+
+/// Sending half of a channel, see [`create_bounded_channel`].
+pub struct ChannelSender<T> {
+    sender: SenderKind<T>,
+}
+
+// Derived it would need `T: Clone`.
+impl<T> Clone for ChannelSender<T> {
+    fn clone(&self) -> Self {
+        let sender = match &self.sender {
+            SenderKind::Unbounded(sender) => SenderKind::Unbounded(sender.clone()),
+            SenderKind::Bounded(sender) => SenderKind::Bounded(sender.clone()),
+        };
+        Self { sender }
+    }
+}
+
+impl<T> ChannelSender<T> {
+    /// Sends `event`, waiting while a bounded channel is full without
+    /// holding up a runtime thread. Fails with `event` once the receiver is
+    /// dropped.
+    pub async fn send(&self, event: T) -> Result<(), T> {
+        match &self.sender {
+            SenderKind::Unbounded(sender) => sender.send(event).map_err(|err| err.0),
+            SenderKind::Bounded(sender) => sender.send(event).await.map_err(|err| err.0),
+        }
+    }
+
+    /// [`ChannelSender::send`] that blocks the thread while a bounded channel
+    /// is full.
+    ///
+    /// Panics when called from async code, which has to use
+    /// [`ChannelSender::send`].
+    pub fn send_blocking(&self, event: T) -> Result<(), T> {
+        match &self.sender {
+            SenderKind::Unbounded(sender) => sender.send(event).map_err(|err| err.0),
+            SenderKind::Bounded(sender) => sender.blocking_send(event).map_err(|err| err.0),
+        }
+    }
+}
+
+/// Receiving half of a channel, see [`create_bounded_channel`].
+pub struct ChannelReceiver<T> {
+    receiver: ReceiverKind<T>,
+}
+
+impl<T> ChannelReceiver<T> {
+    /// Waits for the next event.
+    pub async fn recv(&mut self) -> Result<T, EventError> {
+        let event = match &mut self.receiver {
+            ReceiverKind::Unbounded(receiver) => receiver.recv().await,
+            ReceiverKind::Bounded(receiver) => receiver.recv().await,
+        };
+        event.ok_or(EventError::Closed)
+    }
+
+    /// Blocks the thread until the next event arrives, `None` once the
+    /// channel is closed.
+    ///
+    /// Panics when called from async code, which has to use
+    /// [`ChannelReceiver::recv`].
+    pub fn recv_blocking(&mut self) -> Option<T> {
+        match &mut self.receiver {
+            ReceiverKind::Unbounded(receiver) => receiver.blocking_recv(),
+            ReceiverKind::Bounded(receiver) => receiver.blocking_recv(),
+        }
+    }
+
+    /// The next event if there is one, without waiting.
+    pub fn try_recv(&mut self) -> Result<Option<T>, EventError> {
+        let event = match &mut self.receiver {
+            ReceiverKind::Unbounded(receiver) => receiver.try_recv(),
+            ReceiverKind::Bounded(receiver) => receiver.try_recv(),
+        };
+        match event {
+            Ok(event) => Ok(Some(event)),
+            Err(mpsc::error::TryRecvError::Empty) => Ok(None),
+            Err(mpsc::error::TryRecvError::Disconnected) => Err(EventError::Closed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_subscriber_gets_every_event() {
+        let bus = EventBus::new(8);
+        let mut renderer = bus.subscribe();
+        let mut logger = bus.subscribe();
+
+        assert_eq!(bus.send("resized"), 2);
+        assert_eq!(bus.send("redraw"), 2);
+        for receiver in [&mut renderer, &mut logger] {
+            assert_eq!(receiver.try_recv(), Ok(Some("resized")));
+            assert_eq!(futures::executor::block_on(receiver.recv()), Ok("redraw"));
+            assert_eq!(receiver.try_recv(), Ok(None));
+        }
+
+        drop(bus);
+        assert_eq!(renderer.try_recv(), Err(EventError::Closed));
+    }
+
+    #[test]
+    fn test_lagged_subscriber_reports_dropped_events() {
+        let bus = EventBus::new(2);
+        let mut slow = bus.subscribe();
+        for i in 0..5 {
+            bus.send(i);
+        }
+
+        assert_eq!(slow.try_recv(), Err(EventError::Lagged(3)));
+        assert_eq!(slow.try_recv(), Ok(Some(3)));
+        assert_eq!(slow.try_recv(), Ok(Some(4)));
+        assert_eq!(slow.try_recv(), Ok(None));
+
+        // Events sent before subscribing or without subscribers are lost.
+        let late = EventBus::<i32>::new(2);
+        assert_eq!(late.send(0), 0);
+        assert_eq!(late.subscribe().try_recv(), Ok(None));
+    }
+
+    #[test]
+    fn test_recv_blocking_waits_for_events() {
+        let bus = EventBus::new(2);
+        let mut receiver = bus.subscribe();
+        let consumer = std::thread::spawn(move || {
+            let mut events = Vec::new();
+            while let Some(event) = receiver.recv_blocking() {
+                events.push(event);
+            }
+            events
+        });
+
+        // One at a time so the consumer can't lag behind.
+        for i in 0..4 {
+            bus.send(i);
+            while !bus.sender.is_empty() {
+                std::thread::yield_now();
+            }
+        }
+        drop(bus);
+        assert_eq!(consumer.join().unwrap(), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_bounded_send_waits_for_room() {
+        let (sender, mut receiver) = create_bounded_channel(1);
+
+        assert_eq!(futures::executor::block_on(sender.send("first")), Ok(()));
+        let mut second = Box::pin(sender.send("second"));
+        assert!(futures::FutureExt::now_or_never(second.as_mut()).is_none());
+        assert_eq!(receiver.try_recv(), Ok(Some("first")));
+        assert_eq!(futures::executor::block_on(second), Ok(()));
+        assert_eq!(receiver.try_recv(), Ok(Some("second")));
+
+        drop(receiver);
+        assert_eq!(
+            futures::executor::block_on(sender.send("third")),
+            Err("third")
+        );
+        assert_eq!(sender.send_blocking("fourth"), Err("fourth"));
+    }
+
+    #[test]
+    fn test_send_blocking_waits_for_room() {
+        let (sender, mut receiver) = create_bounded_channel(1);
+        let producer = std::thread::spawn(move || {
+            for i in 0..4 {
+                sender.send_blocking(i).unwrap();
+            }
+        });
+
+        let events = std::iter::from_fn(|| receiver.recv_blocking()).collect::<Vec<_>>();
+        producer.join().unwrap();
+        assert_eq!(events, [0, 1, 2, 3]);
+        assert_eq!(receiver.try_recv(), Err(EventError::Closed));
+    }
+
+    #[test]
+    fn test_unbounded_send_never_waits() {
+        let (sender, mut receiver) = create_unbounded_channel();
+        for i in 0..100 {
+            assert_eq!(sender.send_blocking(i), Ok(()));
+        }
+        assert_eq!(futures::executor::block_on(sender.send(100)), Ok(()));
+
+        drop(sender);
+        let events = std::iter::from_fn(|| receiver.try_recv().ok().flatten()).collect::<Vec<_>>();
+        assert_eq!(events, (0..=100).collect::<Vec<_>>());
+        // Received everything after the sender is gone, the channel closes.
+        assert_eq!(receiver.try_recv(), Err(EventError::Closed));
+        assert_eq!(
+            futures::executor::block_on(receiver.recv()),
+            Err(EventError::Closed)
+        );
+    }
+
+    #[test]
+    fn test_window_events_are_translated() {
+        let resized = WindowEvent::Resized(winit::dpi::PhysicalSize::new(800, 600));
+        assert_eq!(
+            NativeEvent::from(resized),
+            NativeEvent::Resize {
+                width: 800,
+                height: 600
+            }
+        );
+        assert_eq!(
+            NativeEvent::from(WindowEvent::CloseRequested),
+            NativeEvent::CloseRequested
+        );
+        assert_eq!(
+            NativeEvent::from(WindowEvent::RedrawRequested),
+            NativeEvent::Redraw
+        );
+        assert_eq!(
+            NativeEvent::from(WindowEvent::Focused(true)),
+            NativeEvent::Input(WindowEvent::Focused(true))
+        );
+        assert_eq!(NativeEvent::from_event(Event::<()>::AboutToWait), None);
+
+        // Usable as keys, input included.
+        let events = [
+            NativeEvent::Redraw,
+            NativeEvent::Input(WindowEvent::Focused(true)),
+            NativeEvent::Input(WindowEvent::Focused(false)),
+            NativeEvent::Redraw,
+        ]
+        .into_iter()
+        .collect::<std::collections::HashSet<_>>();
+        assert_eq!(events.len(), 3);
+        assert!(events.contains(&NativeEvent::Input(WindowEvent::Focused(false))));
+    }
+}
@@ -0,0 +1,543 @@
+use crate::io::fs::{generate_normals, generate_tangents};
+use crate::{gpu::Gpu, model, skin, texture};
+
+use crate::ktx2::Ktx2;
+use anyhow::{bail, Result};
+use nalgebra as na;
+use rayon::prelude::*;
+use std::{collections::HashMap, path::Path};
+
+/// Loads every mesh primitive of a `.gltf`/`.glb` file as a [`model::Mesh`]
+/// and every glTF material as a [`model::Material`].
+///
+/// Primitives sharing a glTF material share the material index, primitives
+/// without one use a default white material.
+///
+/// The first skin becomes the model's [`skin::Skin`], animated by the first
+/// animation, and primitives with joints are skinned by it.
+pub fn load_gltf(gpu: &Gpu, path: &Path) -> Result<model::Model> {
+    let (device, queue) = (&gpu.device, &gpu.queue);
+    let ::gltf::Gltf { document, blob } = ::gltf::Gltf::open(path)?;
+    let buffers = ::gltf::import_buffers(&document, path.parent(), blob)?;
+
+    // Decoding dominates loading textured scenes, so every image is decoded
+    // up front on the rayon pool and only uploaded afterwards.
+    let images = document
+        .images()
+        .map(|image| read_image(path, &buffers, &image))
+        .collect::<Result<Vec<_>>>()?;
+    let images = images
+        .into_par_iter()
+        .map(|bytes| bytes.map(decode_image).transpose())
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut materials = Vec::new();
+    for material in document.materials() {
+        let name = material.name().unwrap_or("glTF material").to_string();
+        let pbr = material.pbr_metallic_roughness();
+        // `None` for materials without the texture and for images that
+        // couldn't be read.
+        let upload = |texture: Option<::gltf::Texture>, kind: &str, srgb| {
+            let Some(texture) = texture else {
+                return Ok(None);
+            };
+            let Some(image) = images[texture.source().index()].as_ref() else {
+                return Ok(None);
+            };
+            let label = format!("{name} {kind}");
+            upload_image(gpu, image, &label, srgb)?
+                .with_sampler(device, &sampler_desc(&texture.sampler()))
+                .map(Some)
+        };
+
+        let base_color = pbr.base_color_texture().map(|info| info.texture());
+        let diffuse_texture = match upload(base_color, "base color", true)? {
+            Some(texture) => texture,
+            None => texture::Texture::default_texture(device, queue)?,
+        };
+        // The other maps hold data rather than colors.
+        let maps = model::MaterialMaps {
+            normal: upload(
+                material.normal_texture().map(|info| info.texture()),
+                "normal map",
+                false,
+            )?,
+            metallic_roughness: upload(
+                pbr.metallic_roughness_texture().map(|info| info.texture()),
+                "metallic-roughness map",
+                false,
+            )?,
+            occlusion: upload(
+                material.occlusion_texture().map(|info| info.texture()),
+                "occlusion map",
+                false,
+            )?,
+        };
+
+        let mut material = model::Material::with_maps(gpu, &name, diffuse_texture, maps);
+        let uniform = material
+            .uniform
+            .with_metallic_roughness(pbr.metallic_factor(), pbr.roughness_factor());
+        material.set_uniform(queue, uniform);
+        materials.push(material);
+    }
+
+    let default_material = materials.len();
+    let mut needs_default_material = false;
+
+    let skin = document
+        .skins()
+        .next()
+        .map(|skin| load_skin(gpu, &document, &buffers, &skin))
+        .transpose()?;
+
+    let mut meshes = Vec::new();
+    for mesh in document.meshes() {
+        for primitive in mesh.primitives() {
+            let mode = primitive.mode();
+            if mode != ::gltf::mesh::Mode::Triangles {
+                bail!(
+                    "Unsupported primitive mode {mode:?} in mesh {:?}, only triangles are supported",
+                    mesh.name()
+                );
+            }
+
+            // The reader follows the accessor strides, so interleaved and
+            // separate vertex attributes are handled the same way.
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+            let Some(positions) = reader.read_positions() else {
+                bail!("Mesh {:?} has a primitive without positions", mesh.name());
+            };
+            let mut vertices = positions
+                .map(|position| model::ModelVertex {
+                    position,
+                    tex_coord: [0.0; 2],
+                    normal: [0.0; 3],
+                    tangent: [0.0; 4],
+                })
+                .collect::<Vec<_>>();
+
+            if let Some(tex_coords) = reader.read_tex_coords(0) {
+                for (vertex, tex_coord) in vertices.iter_mut().zip(tex_coords.into_f32()) {
+                    vertex.tex_coord = tex_coord;
+                }
+            }
+
+            let indices = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+                None => (0..vertices.len() as u32).collect(),
+            };
+
+            match reader.read_normals() {
+                Some(normals) => {
+                    for (vertex, normal) in vertices.iter_mut().zip(normals) {
+                        vertex.normal = normal;
+                    }
+                }
+                None => generate_normals(&mut vertices, &indices),
+            }
+
+            match reader.read_tangents() {
+                Some(tangents) => {
+                    for (vertex, tangent) in vertices.iter_mut().zip(tangents) {
+                        vertex.tangent = tangent;
+                    }
+                }
+                None => generate_tangents(&mut vertices, &indices),
+            }
+
+            let material = primitive.material().index().unwrap_or_else(|| {
+                needs_default_material = true;
+                default_material
+            });
+
+            let name = mesh.name().unwrap_or("glTF mesh");
+            let mut mesh = model::Mesh::new(device, name, &vertices, &indices, material);
+            if skin.is_some() {
+                let joints = match (reader.read_joints(0), reader.read_weights(0)) {
+                    (Some(joints), Some(weights)) => joints
+                        .into_u16()
+                        .zip(weights.into_f32())
+                        .map(|(joints, weights)| skin::JointVertex {
+                            joints: joints.map(u32::from),
+                            weights,
+                        })
+                        .collect::<Vec<_>>(),
+                    // The whole model is drawn skinned, no weights leave
+                    // these vertices where they are.
+                    _ => vec![skin::JointVertex::default(); vertices.len()],
+                };
+                mesh = mesh.with_joints(device, &joints);
+            }
+            meshes.push(mesh);
+        }
+    }
+
+    if needs_default_material {
+        let diffuse_texture = texture::Texture::default_texture(device, queue)?;
+        materials.push(model::Material::new(
+            gpu,
+            "Default texture",
+            diffuse_texture,
+        ));
+    }
+
+    Ok(model::Model {
+        meshes,
+        materials,
+        skin,
+    })
+}
+
+/// The joints of `skin`, the parent of each being the nearest ancestor node
+/// that's also a joint. Transforms of other nodes in between are ignored.
+fn load_skin(
+    gpu: &Gpu,
+    document: &::gltf::Document,
+    buffers: &[::gltf::buffer::Data],
+    skin: &::gltf::Skin,
+) -> Result<skin::Skin> {
+    let nodes = skin.joints().collect::<Vec<_>>();
+    let joint_of = nodes
+        .iter()
+        .enumerate()
+        .map(|(joint, node)| (node.index(), joint))
+        .collect::<HashMap<_, _>>();
+    let parent_of = document
+        .nodes()
+        .flat_map(|node| {
+            node.children()
+                .map(move |child| (child.index(), node.index()))
+        })
+        .collect::<HashMap<_, _>>();
+    let joint_parent = |mut node| loop {
+        node = *parent_of.get(&node)?;
+        if let Some(joint) = joint_of.get(&node) {
+            return Some(*joint);
+        }
+    };
+
+    let joints = nodes
+        .iter()
+        .map(|node| {
+            let (translation, rotation, scale) = node.transform().decomposed();
+            skin::Joint {
+                name: node.name().unwrap_or("glTF joint").to_string(),
+                parent: joint_parent(node.index()),
+                rest: skin::JointTransform {
+                    translation: translation.into(),
+                    rotation: quaternion(rotation),
+                    scale: scale.into(),
+                },
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let reader = skin.reader(|buffer| Some(&buffers[buffer.index()]));
+    let inverse_bind_matrices = match reader.read_inverse_bind_matrices() {
+        // Both are column major.
+        Some(matrices) => matrices.map(na::Matrix4::from).collect(),
+        None => vec![na::Matrix4::identity(); joints.len()],
+    };
+
+    let animation = document
+        .animations()
+        .next()
+        .map(|animation| load_animation(&animation, buffers, &joint_of))
+        .transpose()?;
+
+    skin::Skin::new(
+        gpu,
+        skin::Skeleton {
+            joints,
+            inverse_bind_matrices,
+        },
+        animation,
+    )
+}
+
+/// The channels of `animation` moving joints, `joint_of` maps node indices
+/// to joints. Morph target weights aren't supported and cubic splines are
+/// interpolated linearly between their keyframes.
+fn load_animation(
+    animation: &::gltf::Animation,
+    buffers: &[::gltf::buffer::Data],
+    joint_of: &HashMap<usize, usize>,
+) -> Result<skin::SkeletalAnimation> {
+    use ::gltf::animation::{util::ReadOutputs, Interpolation};
+
+    let mut channels = Vec::new();
+    for channel in animation.channels() {
+        let Some(&joint) = joint_of.get(&channel.target().node().index()) else {
+            continue;
+        };
+        let reader = channel.reader(|buffer| Some(&buffers[buffer.index()]));
+        let (Some(times), Some(outputs)) = (reader.read_inputs(), reader.read_outputs()) else {
+            bail!(
+                "Animation {:?} has a channel without keyframes",
+                animation.name()
+            );
+        };
+
+        let gltf_interpolation = channel.sampler().interpolation();
+        // Cubic spline keyframes are an in tangent, the value and an out
+        // tangent.
+        fn values<T>(values: impl Iterator<Item = T>, interpolation: Interpolation) -> Vec<T> {
+            match interpolation {
+                Interpolation::CubicSpline => values.skip(1).step_by(3).collect(),
+                Interpolation::Linear | Interpolation::Step => values.collect(),
+            }
+        }
+        let values = match outputs {
+            ReadOutputs::Translations(translations) => skin::ChannelValues::Translation(values(
+                translations.map(na::Vector3::from),
+                gltf_interpolation,
+            )),
+            ReadOutputs::Rotations(rotations) => skin::ChannelValues::Rotation(values(
+                rotations.into_f32().map(quaternion),
+                gltf_interpolation,
+            )),
+            ReadOutputs::Scales(scales) => skin::ChannelValues::Scale(values(
+                scales.map(na::Vector3::from),
+                gltf_interpolation,
+            )),
+            ReadOutputs::MorphTargetWeights(_) => continue,
+        };
+        let interpolation = match gltf_interpolation {
+            Interpolation::Step => skin::Interpolation::Step,
+            Interpolation::Linear | Interpolation::CubicSpline => skin::Interpolation::Linear,
+        };
+
+        channels.push(skin::Channel {
+            joint,
+            interpolation,
+            times: times.collect(),
+            values,
+        });
+    }
+
+    Ok(skin::SkeletalAnimation {
+        name: animation.name().unwrap_or("glTF animation").to_string(),
+        channels,
+    })
+}
+
+/// A glTF `[x, y, z, w]` rotation.
+fn quaternion([x, y, z, w]: [f32; 4]) -> na::UnitQuaternion<f32> {
+    na::UnitQuaternion::from_quaternion(na::Quaternion::new(w, x, y, z))
+}
+
+/// The glTF sampler, with linear filtering where it leaves the filters to
+/// the renderer.
+fn sampler_desc(sampler: &::gltf::texture::Sampler) -> texture::SamplerDesc {
+    use ::gltf::texture::{MagFilter, MinFilter, WrappingMode};
+    use wgpu::{AddressMode, FilterMode};
+
+    let address_mode = |mode| match mode {
+        WrappingMode::ClampToEdge => AddressMode::ClampToEdge,
+        WrappingMode::MirroredRepeat => AddressMode::MirrorRepeat,
+        WrappingMode::Repeat => AddressMode::Repeat,
+    };
+    let mag_filter = match sampler.mag_filter() {
+        Some(MagFilter::Nearest) => FilterMode::Nearest,
+        Some(MagFilter::Linear) | None => FilterMode::Linear,
+    };
+    let (min_filter, mipmap_filter) = match sampler.min_filter() {
+        Some(MinFilter::Nearest | MinFilter::NearestMipmapNearest) => {
+            (FilterMode::Nearest, FilterMode::Nearest)
+        }
+        Some(MinFilter::NearestMipmapLinear) => (FilterMode::Nearest, FilterMode::Linear),
+        Some(MinFilter::LinearMipmapNearest) => (FilterMode::Linear, FilterMode::Nearest),
+        Some(MinFilter::Linear | MinFilter::LinearMipmapLinear) | None => {
+            (FilterMode::Linear, FilterMode::Linear)
+        }
+    };
+    texture::SamplerDesc {
+        mag_filter,
+        min_filter,
+        mipmap_filter,
+        address_mode_u: address_mode(sampler.wrap_s()),
+        address_mode_v: address_mode(sampler.wrap_t()),
+        ..Default::default()
+    }
+}
+
+/// An image of the glTF file, decoded unless the GPU takes it as is.
+enum DecodedImage {
+    Image(image::DynamicImage),
+    Ktx2(Vec<u8>),
+}
+
+/// The encoded bytes of `image`, `None` for the unsupported data URIs.
+fn read_image(
+    path: &Path,
+    buffers: &[::gltf::buffer::Data],
+    image: &::gltf::Image,
+) -> Result<Option<Vec<u8>>> {
+    match image.source() {
+        ::gltf::image::Source::View { view, .. } => {
+            let buffer = &buffers[view.buffer().index()];
+            Ok(Some(
+                buffer[view.offset()..view.offset() + view.length()].to_vec(),
+            ))
+        }
+        ::gltf::image::Source::Uri { uri, .. } if uri.starts_with("data:") => {
+            log::warn!("Embedded data URI images are not supported, using a default texture");
+            Ok(None)
+        }
+        ::gltf::image::Source::Uri { uri, .. } => {
+            let image_path = path.parent().unwrap_or(Path::new("")).join(uri);
+            Ok(Some(std::fs::read(image_path)?))
+        }
+    }
+}
+
+fn decode_image(bytes: Vec<u8>) -> Result<DecodedImage> {
+    if Ktx2::is_ktx2(&bytes) {
+        return Ok(DecodedImage::Ktx2(bytes));
+    }
+    Ok(DecodedImage::Image(image::load_from_memory(&bytes)?))
+}
+
+/// Uploads `image`, decoding it from sRGB on sampling when `srgb` is set.
+/// KTX2 images keep the format they were stored in.
+fn upload_image(
+    gpu: &Gpu,
+    image: &DecodedImage,
+    label: &str,
+    srgb: bool,
+) -> Result<texture::Texture> {
+    let (device, queue) = (&gpu.device, &gpu.queue);
+    match image {
+        DecodedImage::Image(image) if srgb => {
+            texture::Texture::from_image(device, queue, image, Some(label))
+        }
+        DecodedImage::Image(image) => {
+            texture::Texture::from_image_linear(device, queue, image, Some(label))
+        }
+        DecodedImage::Ktx2(bytes) => texture::Texture::from_bytes(device, queue, bytes, label),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A triangle skinned to two joints, the child listed first, and an
+    /// animation turning the root.
+    fn write_skinned(dir: &Path) -> Result<std::path::PathBuf> {
+        let positions: [[f32; 3]; 3] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+        let joints: [[u16; 4]; 3] = [[1, 0, 0, 0], [1, 0, 0, 0], [0, 1, 0, 0]];
+        let weights: [[f32; 4]; 3] = [
+            [1.0, 0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0, 0.0],
+            [0.5, 0.5, 0.0, 0.0],
+        ];
+        let inverse_binds: [[[f32; 4]; 4]; 2] = [
+            na::Matrix4::new_translation(&na::Vector3::new(0.0, -2.0, 0.0)).into(),
+            na::Matrix4::new_translation(&na::Vector3::new(0.0, -1.0, 0.0)).into(),
+        ];
+        let times: [f32; 2] = [0.0, 1.0];
+        let rotations: [[f32; 4]; 2] = [[0.0, 0.0, 0.0, 1.0], [0.0, 0.0, 1.0, 0.0]];
+
+        let mut buffer = Vec::new();
+        let mut views = Vec::new();
+        for bytes in [
+            bytemuck::cast_slice::<_, u8>(&positions),
+            bytemuck::cast_slice(&joints),
+            bytemuck::cast_slice(&weights),
+            bytemuck::cast_slice(&inverse_binds),
+            bytemuck::cast_slice(&times),
+            bytemuck::cast_slice(&rotations),
+        ] {
+            views.push(format!(
+                r#"{{ "buffer": 0, "byteOffset": {}, "byteLength": {} }}"#,
+                buffer.len(),
+                bytes.len()
+            ));
+            buffer.extend_from_slice(bytes);
+        }
+        std::fs::write(dir.join("skinned.bin"), &buffer)?;
+
+        let gltf = format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "buffers": [{{ "uri": "skinned.bin", "byteLength": {} }}],
+                "bufferViews": [{}],
+                "accessors": [
+                    {{
+                        "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                        "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0]
+                    }},
+                    {{ "bufferView": 1, "componentType": 5123, "count": 3, "type": "VEC4" }},
+                    {{ "bufferView": 2, "componentType": 5126, "count": 3, "type": "VEC4" }},
+                    {{ "bufferView": 3, "componentType": 5126, "count": 2, "type": "MAT4" }},
+                    {{
+                        "bufferView": 4, "componentType": 5126, "count": 2, "type": "SCALAR",
+                        "min": [0.0], "max": [1.0]
+                    }},
+                    {{ "bufferView": 5, "componentType": 5126, "count": 2, "type": "VEC4" }}
+                ],
+                "meshes": [{{
+                    "name": "skinned",
+                    "primitives": [{{
+                        "attributes": {{ "POSITION": 0, "JOINTS_0": 1, "WEIGHTS_0": 2 }}
+                    }}]
+                }}],
+                "nodes": [
+                    {{ "mesh": 0, "skin": 0 }},
+                    {{ "name": "root", "translation": [0.0, 1.0, 0.0], "children": [2] }},
+                    {{ "name": "tip", "translation": [0.0, 1.0, 0.0] }}
+                ],
+                "skins": [{{ "joints": [2, 1], "inverseBindMatrices": 3 }}],
+                "animations": [{{
+                    "name": "turn",
+                    "channels": [{{ "sampler": 0, "target": {{ "node": 1, "path": "rotation" }} }}],
+                    "samplers": [{{ "input": 4, "output": 5 }}]
+                }}]
+            }}"#,
+            buffer.len(),
+            views.join(", ")
+        );
+        let path = dir.join("skinned.gltf");
+        std::fs::write(&path, gltf)?;
+        Ok(path)
+    }
+
+    #[test]
+    fn test_load_skin_and_animation() -> Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping glTF skin test");
+            return Ok(());
+        };
+        let dir = std::env::temp_dir().join(format!("void-skin-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let model = load_gltf(&gpu, &write_skinned(&dir)?);
+        std::fs::remove_dir_all(&dir)?;
+        let model = model?;
+
+        assert!(model.meshes[0].joints.is_some());
+        let skin = model.skin.as_ref().unwrap();
+        let joints = &skin.skeleton.joints;
+        assert_eq!(joints.len(), 2);
+        assert_eq!(
+            (joints[0].name.as_str(), joints[0].parent),
+            ("tip", Some(1))
+        );
+        assert_eq!((joints[1].name.as_str(), joints[1].parent), ("root", None));
+
+        // Inverse binds of the rest pose leave the vertices in place.
+        for matrix in skin.skeleton.joint_matrices(&skin.skeleton.rest_pose()) {
+            assert!((matrix - na::Matrix4::identity()).abs().max() < 1e-6);
+        }
+
+        let animation = skin.animation.as_ref().unwrap();
+        assert_eq!(animation.name, "turn");
+        assert_eq!(animation.duration(), 1.0);
+        assert_eq!(animation.channels.len(), 1);
+        assert_eq!(animation.channels[0].joint, 1);
+        model.animate(&gpu.queue, 0.5);
+        Ok(())
+    }
+}
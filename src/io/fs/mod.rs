@@ -2,9 +2,12 @@ use crate::model;
 use anyhow::Result;
 use std::{ffi::OsStr, path::PathBuf};
 
+mod gltf;
 mod obj;
 mod stl;
 
+pub use self::gltf::load_gltf;
+pub use obj::load_obj;
 use obj::*;
 use stl::*;
 
@@ -58,6 +61,72 @@ impl IMeshFile for MeshFile {
     }
 }
 
+/// Fills in smooth vertex normals by accumulating the area weighted face
+/// normals of every triangle sharing a vertex.
+pub fn generate_normals(vertices: &mut [model::ModelVertex], indices: &[u32]) {
+    let position = |i: u32| nalgebra::Vector3::from(vertices[i as usize].position);
+    let mut normals = vec![nalgebra::Vector3::<f32>::zeros(); vertices.len()];
+
+    for triangle in indices.chunks_exact(3) {
+        let (a, b, c) = (triangle[0], triangle[1], triangle[2]);
+        // Not normalized, the cross product length weighs larger faces more.
+        let face_normal = (position(b) - position(a)).cross(&(position(c) - position(a)));
+        for i in triangle {
+            normals[*i as usize] += face_normal;
+        }
+    }
+
+    for (vertex, normal) in vertices.iter_mut().zip(normals) {
+        vertex.normal = normal
+            .try_normalize(f32::EPSILON)
+            .unwrap_or_default()
+            .into();
+    }
+}
+
+/// Fills in tangents following the texture's `u` axis, orthogonal to the
+/// normals which have to be set already. The handedness in `w` is negative
+/// where the UVs are mirrored, which flips the reconstructed bitangent.
+pub fn generate_tangents(vertices: &mut [model::ModelVertex], indices: &[u32]) {
+    use nalgebra::{Vector2, Vector3};
+
+    let position = |i: u32| Vector3::from(vertices[i as usize].position);
+    let tex_coord = |i: u32| Vector2::from(vertices[i as usize].tex_coord);
+    let mut tangents = vec![Vector3::<f32>::zeros(); vertices.len()];
+    let mut bitangents = vec![Vector3::<f32>::zeros(); vertices.len()];
+
+    for triangle in indices.chunks_exact(3) {
+        let (a, b, c) = (triangle[0], triangle[1], triangle[2]);
+        let (edge1, edge2) = (position(b) - position(a), position(c) - position(a));
+        let (uv1, uv2) = (tex_coord(b) - tex_coord(a), tex_coord(c) - tex_coord(a));
+        let det = uv1.x * uv2.y - uv2.x * uv1.y;
+        // Collapsed UVs give no direction to follow.
+        if det.abs() <= f32::EPSILON {
+            continue;
+        }
+        // Not normalized, like the normals larger faces weigh more.
+        let tangent = (edge1 * uv2.y - edge2 * uv1.y) / det;
+        let bitangent = (edge2 * uv1.x - edge1 * uv2.x) / det;
+        for i in triangle {
+            tangents[*i as usize] += tangent;
+            bitangents[*i as usize] += bitangent;
+        }
+    }
+
+    for ((vertex, tangent), bitangent) in vertices.iter_mut().zip(tangents).zip(bitangents) {
+        let normal = Vector3::from(vertex.normal);
+        let tangent = (tangent - normal * normal.dot(&tangent))
+            .try_normalize(f32::EPSILON)
+            .unwrap_or_default();
+        let handedness = if normal.cross(&tangent).dot(&bitangent) < 0.0 {
+            -1.0
+        } else {
+            1.0
+        };
+        vertex.tangent = [tangent.x, tangent.y, tangent.z, handedness];
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -66,6 +135,49 @@ mod test {
 
     static MODEL_PATH: &'static str = "./models";
 
+    #[test]
+    fn test_generate_normals() {
+        let mut vertices = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]].map(|position| {
+            model::ModelVertex {
+                position,
+                tex_coord: [0.0; 2],
+                normal: [0.0; 3],
+                tangent: [0.0; 4],
+            }
+        });
+        generate_normals(&mut vertices, &[0, 1, 2]);
+        for vertex in vertices {
+            assert_eq!(vertex.normal, [0.0, 0.0, 1.0]);
+        }
+    }
+
+    /// A unit quad facing +z, `u` running along +x or, mirrored, along -x.
+    fn quad(mirrored: bool) -> [model::ModelVertex; 4] {
+        [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]].map(|[x, y]| model::ModelVertex {
+            position: [x, y, 0.0],
+            tex_coord: [if mirrored { 1.0 - x } else { x }, 1.0 - y],
+            normal: [0.0, 0.0, 1.0],
+            tangent: [0.0; 4],
+        })
+    }
+
+    #[test]
+    fn test_generate_tangents_handedness() {
+        let indices = [0, 1, 2, 0, 2, 3];
+
+        let mut vertices = quad(false);
+        generate_tangents(&mut vertices, &indices);
+        for vertex in vertices {
+            assert_eq!(vertex.tangent, [1.0, 0.0, 0.0, -1.0]);
+        }
+
+        let mut mirrored = quad(true);
+        generate_tangents(&mut mirrored, &indices);
+        for vertex in mirrored {
+            assert_eq!(vertex.tangent, [-1.0, 0.0, 0.0, 1.0]);
+        }
+    }
+
     #[test]
     fn test_mesh_file() -> Result<()> {
         let stl_path = PathBuf::from_str(&MODEL_PATH).unwrap().join("test.stl");
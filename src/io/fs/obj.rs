@@ -1,11 +1,10 @@
+use crate::io::fs::{generate_normals, generate_tangents};
 use crate::texture;
 use crate::{gpu::Gpu, model};
 use anyhow::Result;
 use cfg_if::cfg_if;
-use std::io::{BufReader, Cursor};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tobj;
-use wgpu::util::DeviceExt;
 
 pub async fn load_texture(
     file_name: &str,
@@ -63,96 +62,103 @@ impl ObjFile {
     }
 }
 
-async fn get_models(
-    gpu: &Gpu,
-    file_name: &str,
-    layout: &wgpu::BindGroupLayout,
-) -> Result<model::Model> {
+/// Loads an OBJ file and the MTL libraries it references.
+///
+/// Faces are triangulated and missing normals are generated. A missing or
+/// broken MTL only costs the textures, every mesh then uses a default white
+/// material.
+pub fn load_obj(gpu: &Gpu, path: &Path) -> Result<model::Model> {
     let (device, queue) = (&gpu.device, &gpu.queue);
-    let obj_text = load_string(file_name).await?;
-    let obj_cursor = Cursor::new(obj_text);
-    let mut obj_reader = BufReader::new(obj_cursor);
+    let file_name = path.display().to_string();
+    let base_dir = path.parent().unwrap_or(Path::new(""));
 
-    let (models, obj_materials) = tobj::load_obj_buf_async(
-        &mut obj_reader,
+    let (models, obj_materials) = tobj::load_obj(
+        path,
         &tobj::LoadOptions {
             single_index: true,
             triangulate: true,
             ..Default::default()
         },
-        |p| async move {
-            let mat_tex = load_string(&p).await.unwrap();
-            tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_tex)))
-        },
-    )
-    .await?;
+    )?;
 
-    let mut materials = Vec::new();
+    let obj_materials = obj_materials.unwrap_or_else(|e| {
+        log::warn!("Failed to load materials of {file_name}: {e}, using a default material");
+        Vec::new()
+    });
 
-    for m in obj_materials? {
-        let diffuse_texture = load_texture(&m.diffuse_texture, device, queue).await?;
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                },
-            ],
-        });
-
-        materials.push(model::Material {
-            bind_group,
-            diffuse_texture,
-            name: m.name,
-        });
+    let mut materials = Vec::new();
+    for m in obj_materials {
+        let diffuse_texture = if m.diffuse_texture.is_empty() {
+            texture::Texture::default_texture(device, queue)?
+        } else {
+            let bytes = std::fs::read(base_dir.join(&m.diffuse_texture))?;
+            // MTL maps repeat unless a `-clamp on` option says otherwise,
+            // which tobj doesn't keep.
+            texture::Texture::from_bytes_with_sampler(
+                device,
+                queue,
+                &bytes,
+                &m.diffuse_texture,
+                &texture::SamplerDesc::repeat(),
+            )?
+        };
+        materials.push(model::Material::new(gpu, &m.name, diffuse_texture));
     }
 
+    // Meshes referencing a material that failed to load, or none at all,
+    // fall back to the last material which is always the default one.
+    let default_material = materials.len();
+    let diffuse_texture = texture::Texture::default_texture(device, queue)?;
+    materials.push(model::Material::new(
+        gpu,
+        "Default texture",
+        diffuse_texture,
+    ));
+
     let meshes = models
         .into_iter()
         .map(|m| {
-            let vertices = (0..m.mesh.positions.len() / 3)
+            let mesh = &m.mesh;
+            let mut vertices = (0..mesh.positions.len() / 3)
                 .map(|i| model::ModelVertex {
                     position: [
-                        m.mesh.positions[i * 3],
-                        m.mesh.positions[i * 3 + 1],
-                        m.mesh.positions[i * 3 + 2],
-                    ],
-                    tex_coord: [m.mesh.texcoords[i * 2], 1.0 - m.mesh.texcoords[i * 2 + 1]],
-                    normal: [
-                        m.mesh.normals[i * 3],
-                        m.mesh.normals[i * 3 + 1],
-                        m.mesh.normals[i * 3 + 2],
+                        mesh.positions[i * 3],
+                        mesh.positions[i * 3 + 1],
+                        mesh.positions[i * 3 + 2],
                     ],
+                    tex_coord: match mesh.texcoords.is_empty() {
+                        true => [0.0; 2],
+                        false => [mesh.texcoords[i * 2], 1.0 - mesh.texcoords[i * 2 + 1]],
+                    },
+                    normal: match mesh.normals.is_empty() {
+                        true => [0.0; 3],
+                        false => [
+                            mesh.normals[i * 3],
+                            mesh.normals[i * 3 + 1],
+                            mesh.normals[i * 3 + 2],
+                        ],
+                    },
+                    tangent: [0.0; 4],
                 })
                 .collect::<Vec<_>>();
 
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", file_name)),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsages::VERTEX,
-            });
-
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", file_name)),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsages::INDEX,
-            });
-
-            model::Mesh {
-                name: file_name.to_string(),
-                vertex_buffer,
-                index_buffer,
-                material: m.mesh.material_id.unwrap_or(0),
-                num_elements: m.mesh.indices.len() as u32,
+            if mesh.normals.is_empty() {
+                generate_normals(&mut vertices, &mesh.indices);
             }
+            generate_tangents(&mut vertices, &mesh.indices);
+
+            let material = mesh
+                .material_id
+                .filter(|id| *id < default_material)
+                .unwrap_or(default_material);
+
+            model::Mesh::new(device, &m.name, &vertices, &mesh.indices, material)
         })
         .collect::<Vec<_>>();
 
-    Ok(model::Model { meshes, materials })
+    Ok(model::Model {
+        meshes,
+        materials,
+        skin: None,
+    })
 }
@@ -46,6 +46,7 @@ impl IMeshFile for StlFile {
                     position: mesh_vertices[idx].into(),
                     normal: face.normal.into(),
                     tex_coord: self.get_uv(&mesh_vertices[idx].into()),
+                    tangent: [0.0; 4],
                 };
 
                 model_vertices.push(vertex);
@@ -0,0 +1,117 @@
+use std::collections::{HashMap, HashSet};
+
+use winit::{
+    event::{ElementState, KeyEvent, MouseButton},
+    keyboard::{KeyCode, PhysicalKey},
+};
+
+/// Physical input that can be bound to an [`Action`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Input {
+    Key(KeyCode),
+    Mouse(MouseButton),
+}
+
+/// Logical actions controllers react to instead of raw keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    MoveForward,
+    MoveBackward,
+    MoveLeft,
+    MoveRight,
+    /// Held while dragging rotates the camera.
+    Rotate,
+}
+
+/// Maps inputs to actions and tracks which inputs are held, several inputs
+/// may trigger the same action.
+pub struct InputMap {
+    bindings: HashMap<Input, Action>,
+    pressed: HashSet<Input>,
+}
+
+impl Default for InputMap {
+    /// WASD movement and left mouse drag rotation.
+    fn default() -> Self {
+        let mut map = Self::empty();
+        map.bind(Input::Key(KeyCode::KeyW), Action::MoveForward);
+        map.bind(Input::Key(KeyCode::KeyS), Action::MoveBackward);
+        map.bind(Input::Key(KeyCode::KeyA), Action::MoveLeft);
+        map.bind(Input::Key(KeyCode::KeyD), Action::MoveRight);
+        map.bind(Input::Mouse(MouseButton::Left), Action::Rotate);
+        map
+    }
+}
+
+impl InputMap {
+    pub fn empty() -> Self {
+        Self {
+            bindings: HashMap::new(),
+            pressed: HashSet::new(),
+        }
+    }
+
+    /// Binds `input` to `action`, replacing what `input` was bound to.
+    pub fn bind(&mut self, input: Input, action: Action) {
+        self.bindings.insert(input, action);
+    }
+
+    pub fn unbind(&mut self, input: Input) {
+        self.bindings.remove(&input);
+        self.pressed.remove(&input);
+    }
+
+    /// Records the new state of `input`, returns whether it is bound.
+    pub fn set_pressed(&mut self, input: Input, pressed: bool) -> bool {
+        if !self.bindings.contains_key(&input) {
+            return false;
+        }
+        if pressed {
+            self.pressed.insert(input);
+        } else {
+            self.pressed.remove(&input);
+        }
+        true
+    }
+
+    pub fn process_key(&mut self, key_event: &KeyEvent) -> bool {
+        match key_event.physical_key {
+            PhysicalKey::Code(key_code) => {
+                self.set_pressed(Input::Key(key_code), key_event.state.is_pressed())
+            }
+            PhysicalKey::Unidentified(_) => false,
+        }
+    }
+
+    pub fn process_mouse_button(&mut self, button: MouseButton, state: ElementState) -> bool {
+        self.set_pressed(Input::Mouse(button), state.is_pressed())
+    }
+
+    /// Whether any input bound to `action` is held.
+    pub fn is_active(&self, action: Action) -> bool {
+        self.pressed
+            .iter()
+            .any(|input| self.bindings.get(input) == Some(&action))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rebind_move_forward() {
+        let mut map = InputMap::default();
+        map.unbind(Input::Key(KeyCode::KeyW));
+        map.bind(Input::Key(KeyCode::KeyI), Action::MoveForward);
+
+        assert!(!map.set_pressed(Input::Key(KeyCode::KeyW), true));
+        assert!(!map.is_active(Action::MoveForward));
+
+        assert!(map.set_pressed(Input::Key(KeyCode::KeyI), true));
+        assert!(map.is_active(Action::MoveForward));
+
+        map.set_pressed(Input::Key(KeyCode::KeyI), false);
+        assert!(!map.is_active(Action::MoveForward));
+    }
+}
@@ -1,43 +1,198 @@
 use egui::epaint::Shadow;
-use egui::{Context, Visuals};
+use egui::{
+    Context, DeferredViewportUiCallback, FullOutput, ViewportBuilder, ViewportCommand,
+    ViewportEvent, ViewportId, ViewportIdMap, ViewportInfo, ViewportOutput, Visuals,
+};
 use egui_wgpu::renderer::ScreenDescriptor;
 use egui_wgpu::Renderer;
 
-use crate::gpu::Gpu;
-use crate::model;
+use crate::gpu::{Gpu, SurfaceId};
+use crate::graph::{RenderGraph, TextureInit};
 use crate::resource;
 use crate::texture;
 use crate::ModelEntry;
 use crate::Resources;
 
-use wgpu::util::DeviceExt;
-
 use egui_winit::State;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::PathBuf;
 use std::sync::Arc;
 use wgpu::TextureFormat;
 use winit::event::{KeyEvent, WindowEvent};
-use winit::window::Window;
+use winit::event_loop::EventLoopWindowTarget;
+use winit::window::{Window, WindowId};
 
+pub mod event;
 pub mod fs;
+pub mod input;
 
 pub trait Controller {
     fn process_events(&self, ctx: &KeyEvent);
+    /// Everything but keyboard input, e.g. mouse drag and scroll.
+    fn process_window_event(&self, _event: &WindowEvent) {}
 }
 
 pub trait Ui {
     fn render_ui(&mut self, context: &Context);
 }
 
+/// Handle to a UI pushed with [`GuiRenderer::push_ui`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct UiId(usize);
+
+/// Renders every UI in order. A UI that panics is logged and removed, the
+/// rest still make it into the frame.
+fn render_uis(uis: &mut Vec<(UiId, Box<dyn Ui>)>, context: &Context) {
+    uis.retain_mut(|(id, ui)| {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| ui.render_ui(context)));
+        if result.is_err() {
+            log::error!("UI {id:?} panicked and was removed");
+        }
+        result.is_ok()
+    });
+}
+
+/// A window egui asked for while viewports aren't embedded, see
+/// [`GuiRenderer::set_detached_viewports`].
+pub struct Viewport {
+    pub parent: ViewportId,
+    /// Title, size and the rest of the window attributes.
+    pub builder: ViewportBuilder,
+    /// Draws the viewport's content, `None` for immediate viewports.
+    pub ui: Option<Arc<DeferredViewportUiCallback>>,
+    /// Commands for the viewport's window, oldest first: those egui sent and
+    /// those catching it up with changes to `builder`. Applied and cleared
+    /// by [`GuiRenderer::update_viewport_windows`].
+    pub commands: Vec<ViewportCommand>,
+    /// Whether `builder` changed in a way only recreating the window can
+    /// apply, e.g. its close button.
+    pub recreate: bool,
+    window: Option<ViewportWindow>,
+}
+
+impl Viewport {
+    /// The window the viewport is shown in, `None` until
+    /// [`GuiRenderer::update_viewport_windows`] opens it.
+    pub fn window(&self) -> Option<&Arc<Window>> {
+        self.window.as_ref().map(|window| &window.window)
+    }
+}
+
+/// The window of a detached [`Viewport`], with its own surface and input.
+struct ViewportWindow {
+    window: Arc<Window>,
+    surface: SurfaceId,
+    state: State,
+    info: ViewportInfo,
+    gpu: Arc<Gpu>,
+}
+
+impl ViewportWindow {
+    fn new(
+        gpu: &Arc<Gpu>,
+        context: &Context,
+        event_loop: &EventLoopWindowTarget<()>,
+        id: ViewportId,
+        builder: &ViewportBuilder,
+    ) -> anyhow::Result<Self> {
+        let window = Arc::new(egui_winit::create_window(context, event_loop, builder)?);
+        let surface = gpu.add_window(Arc::clone(&window))?;
+        let state = State::new(
+            context.clone(),
+            id,
+            &window,
+            Some(window.scale_factor() as f32),
+            None,
+        );
+        Ok(Self {
+            window,
+            surface,
+            state,
+            info: ViewportInfo::default(),
+            gpu: Arc::clone(gpu),
+        })
+    }
+}
+
+impl Drop for ViewportWindow {
+    fn drop(&mut self) {
+        self.gpu.remove_window(self.surface);
+    }
+}
+
+/// Viewports that appeared or went away during a frame.
+#[derive(Debug, Default, PartialEq)]
+pub struct ViewportChanges {
+    pub opened: Vec<ViewportId>,
+    pub closed: Vec<ViewportId>,
+}
+
+/// Brings `viewports` in line with what egui output this frame. Viewports
+/// egui stopped showing or asked to close are removed.
+fn sync_viewports(
+    viewports: &mut HashMap<ViewportId, Viewport>,
+    output: ViewportIdMap<ViewportOutput>,
+) -> ViewportChanges {
+    let mut changes = ViewportChanges::default();
+    let mut shown = Vec::with_capacity(output.len());
+
+    for (id, output) in output {
+        if id == ViewportId::ROOT || output.commands.contains(&ViewportCommand::Close) {
+            continue;
+        }
+        shown.push(id);
+        match viewports.get_mut(&id) {
+            Some(viewport) => {
+                let (commands, recreate) = viewport.builder.patch(output.builder);
+                viewport.commands.extend(commands);
+                viewport.commands.extend(output.commands);
+                viewport.recreate |= recreate;
+                viewport.ui = output.viewport_ui_cb;
+            }
+            None => {
+                viewports.insert(
+                    id,
+                    Viewport {
+                        parent: output.parent,
+                        builder: output.builder,
+                        ui: output.viewport_ui_cb,
+                        commands: output.commands,
+                        recreate: false,
+                        window: None,
+                    },
+                );
+                changes.opened.push(id);
+            }
+        }
+    }
+
+    viewports.retain(|id, _| {
+        let keep = shown.contains(id);
+        if !keep {
+            changes.closed.push(*id);
+        }
+        keep
+    });
+    changes
+}
+
 pub struct IoEngine<T: Controller> {
     camera_controller: T,
     resources: Arc<Resources>,
     window: Arc<Window>,
     gui: GuiRenderer,
     gpu: Arc<Gpu>,
+    assets: resource::AssetLoader,
 }
 
 impl<T: Controller> IoEngine<T> {
+    /// Finished models waiting for [`IoEngine::collect_loaded_models`]
+    /// before further loads wait for room, e.g. while a frame is stuck on the
+    /// GPU. Waiting loads hold their model but no runtime thread, see
+    /// [`resource::AssetLoader::bounded`].
+    const LOADED_MODEL_CAPACITY: usize = 4;
+
     pub fn new(
         gpu: Arc<Gpu>,
         resources: Arc<Resources>,
@@ -45,75 +200,145 @@ impl<T: Controller> IoEngine<T> {
         gui: GuiRenderer,
         camera_controller: T,
     ) -> Self {
+        let assets = Self::asset_loader(&gpu);
         Self {
             camera_controller,
             resources,
             gui,
             gpu,
             window,
+            assets,
         }
     }
 
+    fn asset_loader(gpu: &Arc<Gpu>) -> resource::AssetLoader {
+        // Needs to be created from within the runtime the models load on.
+        resource::AssetLoader::bounded(
+            Arc::clone(gpu),
+            tokio::runtime::Handle::current(),
+            Self::LOADED_MODEL_CAPACITY,
+        )
+    }
+
     pub fn render(&mut self) {
         self.gui.render_ui();
     }
 
+    pub fn gui_mut(&mut self) -> &mut GuiRenderer {
+        &mut self.gui
+    }
+
+    /// Moves loading and the GUI over to `gpu` once the device was lost.
+    /// Models still loading are dropped, they'd be on the lost device.
+    pub fn on_device_recreated(&mut self, gpu: Arc<Gpu>) {
+        self.assets = Self::asset_loader(&gpu);
+        self.gui.on_device_recreated(Arc::clone(&gpu));
+        self.gpu = gpu;
+    }
+
+    /// Adds the models dropped files finished loading into since the last
+    /// call.
+    pub fn collect_loaded_models(&mut self) {
+        while let Some(loaded) = self.assets.try_recv() {
+            match loaded.model {
+                Ok(model) => {
+                    let mut model_db = self.resources.model_db.write().unwrap();
+                    model_db
+                        .insert(ModelEntry::new(&self.gpu, model).with_source(loaded.path.clone()));
+                    log::info!("Added Model {}", loaded.path.display());
+                }
+                Err(msg) => log::error!("{}: {msg}", loaded.path.display()),
+            }
+        }
+    }
+
     pub fn handle_event(&mut self, event: &WindowEvent) {
         use WindowEvent::*;
         match event {
-            DroppedFile(path) => match futures::executor::block_on(self.handle_file_drop(path)) {
-                Ok(()) => log::info!("Added Model"),
-                Err(msg) => log::error!("{msg}"),
-            },
+            DroppedFile(path) => {
+                if let Err(msg) = self.handle_file_drop(path) {
+                    log::error!("{msg}");
+                }
+            }
             KeyboardInput { event, .. } => {
                 self.camera_controller.process_events(&event);
             }
-            _ => {}
+            event => self.camera_controller.process_window_event(event),
         };
 
         self.gui.handle_input(&self.window, event);
     }
 
-    async fn handle_file_drop(&mut self, path: &PathBuf) -> anyhow::Result<()> {
+    /// Starts loading the dropped file, or every file of a dropped
+    /// directory, see [`IoEngine::collect_loaded_models`].
+    fn handle_file_drop(&mut self, path: &PathBuf) -> anyhow::Result<()> {
         if path.is_dir() {
             let dir = std::fs::read_dir(path)?;
 
             for entry in dir {
                 let entry = entry?;
-                let path = entry.path();
-
-                self.add_model(&path).await?;
+                self.add_model(&entry.path());
             }
 
             return Ok(());
         }
 
-        self.add_model(&path).await?;
+        self.add_model(path);
 
         Ok(())
     }
 
-    pub async fn add_model(&mut self, path: &PathBuf) -> anyhow::Result<()> {
-        let model = resource::load_model(path.to_path_buf(), &self.gpu).await?;
-        let mut model_db = self.resources.model_db.write().unwrap();
-        let device = &self.gpu.device;
-        let instances = vec![model::Instance::default()];
-
-        let instance_data = instances
-            .iter()
-            .map(model::Instance::to_raw)
-            .collect::<Vec<_>>();
-        let model_entry = ModelEntry {
-            instances,
-            instance_buffer: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Model instance"),
-                contents: bytemuck::cast_slice(&instance_data),
-                usage: wgpu::BufferUsages::VERTEX,
-            }),
-            model,
-        };
-        model_db.insert(model_entry);
-        Ok(())
+    pub fn add_model(&mut self, path: &PathBuf) {
+        self.assets.load(path);
+    }
+}
+
+/// What the egui renderer's pipeline is built for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct RendererConfig {
+    color_format: TextureFormat,
+    depth_format: Option<TextureFormat>,
+    msaa_samples: u32,
+}
+
+impl RendererConfig {
+    fn create_renderer(&self, device: &wgpu::Device) -> Renderer {
+        Renderer::new(
+            device,
+            self.color_format,
+            self.depth_format,
+            self.msaa_samples,
+        )
+    }
+
+    /// [`RendererConfig::create_renderer`] for replacing the renderer
+    /// `context` drew with so far. egui only sends updates of the font atlas
+    /// once it's uploaded, so the new renderer gets all of it right away.
+    /// Textures loaded by UIs aren't kept by egui and can't be restored.
+    fn recreate_renderer(&self, gpu: &Gpu, context: &Context) -> Renderer {
+        let mut renderer = self.create_renderer(&gpu.device);
+        let font = egui::TextureId::default();
+        let options = context
+            .tex_manager()
+            .read()
+            .meta(font)
+            .map(|meta| meta.options);
+        // Before the first frame there is no atlas yet, egui sends it whole.
+        if let Some(options) = options {
+            let atlas = context.fonts(|fonts| fonts.image());
+            let delta = egui::epaint::ImageDelta::full(atlas, options);
+            renderer.update_texture(&gpu.device, &gpu.queue, font, &delta);
+        }
+        renderer
+    }
+
+    /// The config for drawing to `format` targets, `None` if that's what it
+    /// already draws to.
+    fn with_color_format(&self, format: TextureFormat) -> Option<Self> {
+        (format != self.color_format).then_some(Self {
+            color_format: format,
+            ..*self
+        })
     }
 }
 
@@ -122,8 +347,12 @@ pub struct GuiRenderer {
     gpu: Arc<Gpu>,
     state: State,
     renderer: Renderer,
+    renderer_config: RendererConfig,
     window: Arc<Window>,
-    ui: Box<dyn Ui>,
+    uis: Vec<(UiId, Box<dyn Ui>)>,
+    next_ui_id: usize,
+    clear_color: Option<wgpu::Color>,
+    viewports: HashMap<ViewportId, Viewport>,
 }
 
 impl GuiRenderer {
@@ -150,80 +379,654 @@ impl GuiRenderer {
 
         egui_context.set_visuals(visuals);
 
-        let egui_state = egui_winit::State::new(egui_context.clone(), id, &window, None, None);
+        // Lays out the first frame for the monitor the window opens on,
+        // `handle_input` follows it to others.
+        let egui_state = egui_winit::State::new(
+            egui_context.clone(),
+            id,
+            &window,
+            Some(window.scale_factor() as f32),
+            None,
+        );
 
-        // egui_state.set_pixels_per_point(window.scale_factor() as f32);
-        let egui_renderer = egui_wgpu::Renderer::new(
-            &device,
-            output_color_format,
-            output_depth_format,
+        let renderer_config = RendererConfig {
+            color_format: output_color_format,
+            depth_format: output_depth_format,
             msaa_samples,
-        );
+        };
+        let egui_renderer = renderer_config.create_renderer(device);
 
         GuiRenderer {
             context: egui_context,
             state: egui_state,
             renderer: egui_renderer,
-            ui: Box::new(ui),
+            renderer_config,
+            uis: vec![(UiId(0), Box::new(ui))],
+            next_ui_id: 1,
             gpu,
             window,
+            clear_color: None,
+            viewports: HashMap::new(),
         }
     }
 
+    /// Adds `ui` on top of the UIs pushed before it.
+    pub fn push_ui(&mut self, ui: impl Ui + 'static) -> UiId {
+        let id = UiId(self.next_ui_id);
+        self.next_ui_id += 1;
+        self.uis.push((id, Box::new(ui)));
+        id
+    }
+
+    pub fn remove_ui(&mut self, id: UiId) -> Option<Box<dyn Ui>> {
+        let index = self.uis.iter().position(|(ui_id, _)| *ui_id == id)?;
+        Some(self.uis.remove(index).1)
+    }
+
+    /// `Some(color)` clears the surface before drawing the UI, for apps
+    /// without a scene pass. `None` draws on top of the previous contents.
+    pub fn set_clear_color(&mut self, color: Option<wgpu::Color>) {
+        self.clear_color = color;
+    }
+
+    /// `true` makes egui hand out viewports as separate windows, tracked in
+    /// [`GuiRenderer::viewports`], instead of drawing them as windows inside
+    /// the main one. Only deferred viewports get a window, opened by
+    /// [`GuiRenderer::update_viewport_windows`] and drawn along with the
+    /// main window.
+    pub fn set_detached_viewports(&mut self, detached: bool) {
+        self.context.set_embed_viewports(!detached);
+    }
+
+    /// Secondary viewports egui currently shows, empty unless viewports are
+    /// detached.
+    pub fn viewports(&self) -> &HashMap<ViewportId, Viewport> {
+        &self.viewports
+    }
+
+    /// Opens a window for every deferred viewport that lacks one, reopens
+    /// those that need [`Viewport::recreate`] and applies the
+    /// [`Viewport::commands`] to the rest. Windows of closed viewports are
+    /// gone with them.
+    pub fn update_viewport_windows(&mut self, event_loop: &EventLoopWindowTarget<()>) {
+        for (id, viewport) in &mut self.viewports {
+            if viewport.ui.is_none() {
+                continue;
+            }
+            if std::mem::take(&mut viewport.recreate) {
+                viewport.window = None;
+            }
+            match &mut viewport.window {
+                Some(window) => egui_winit::process_viewport_commands(
+                    &self.context,
+                    &mut window.info,
+                    viewport.commands.drain(..),
+                    &window.window,
+                    window.window.has_focus(),
+                    &mut false,
+                ),
+                None => {
+                    // The builder already has what the commands would change.
+                    viewport.commands.clear();
+                    match ViewportWindow::new(
+                        &self.gpu,
+                        &self.context,
+                        event_loop,
+                        *id,
+                        &viewport.builder,
+                    ) {
+                        Ok(window) => {
+                            viewport.window = Some(window);
+                            // It's drawn with the main window's next frame.
+                            self.window.request_redraw();
+                        }
+                        Err(err) => log::error!("Failed to open viewport {id:?}: {err:#}"),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Passes `event` to the viewport shown in the window `window_id`,
+    /// returns `false` when no viewport is.
+    pub fn handle_viewport_event(&mut self, window_id: WindowId, event: &WindowEvent) -> bool {
+        let Some(window) = self
+            .viewports
+            .values_mut()
+            .filter_map(|viewport| viewport.window.as_mut())
+            .find(|window| window.window.id() == window_id)
+        else {
+            return false;
+        };
+        match event {
+            WindowEvent::Resized(size) => {
+                if let Err(err) = self
+                    .gpu
+                    .window_update(window.surface, size.width, size.height)
+                {
+                    log::error!("{err:#}");
+                }
+            }
+            // The UI decides whether the viewport goes away, see
+            // `ViewportInfo::close_requested`.
+            WindowEvent::CloseRequested => window.info.events.push(ViewportEvent::Close),
+            // Viewports are drawn with the main window.
+            WindowEvent::RedrawRequested => return true,
+            event => {
+                let _ = window.state.on_window_event(&window.window, event);
+            }
+        }
+        self.window.request_redraw();
+        true
+    }
+
+    /// Rebuilds the egui renderer when the surface is reconfigured to a
+    /// different `format`, returns whether it did. The font atlas moves over
+    /// to the new renderer, textures loaded by UIs have to be reloaded.
+    pub fn on_surface_reconfigured(&mut self, format: TextureFormat) -> bool {
+        let Some(config) = self.renderer_config.with_color_format(format) else {
+            return false;
+        };
+        self.renderer = config.recreate_renderer(&self.gpu, &self.context);
+        self.renderer_config = config;
+        true
+    }
+
+    /// Draws with `gpu` from now on, once the device was lost. Like
+    /// [`GuiRenderer::on_surface_reconfigured`] only the font atlas moves
+    /// over, textures loaded by UIs have to be reloaded.
+    pub fn on_device_recreated(&mut self, gpu: Arc<Gpu>) {
+        self.renderer_config = self
+            .renderer_config
+            .with_color_format(gpu.surface_format())
+            .unwrap_or(self.renderer_config);
+        self.renderer = self.renderer_config.recreate_renderer(&gpu, &self.context);
+        // Their surfaces moved over with the rest of the gpu's windows.
+        for window in self
+            .viewports
+            .values_mut()
+            .filter_map(|v| v.window.as_mut())
+        {
+            window.gpu = Arc::clone(&gpu);
+        }
+        self.gpu = gpu;
+    }
+
     pub fn handle_input(&mut self, window: &Window, event: &WindowEvent) {
+        // Also moves the state to the new pixels per point when the window
+        // changes scale factor, e.g. when dragged to a monitor with a
+        // different DPI.
         let _ = self.state.on_window_event(window, event);
+        if let WindowEvent::ScaleFactorChanged { scale_factor, .. } = event {
+            log::debug!("UI scale factor changed to {scale_factor}");
+            // The surface is resized to the new physical size right after,
+            // the UI has to be laid out again at the new scale to match.
+            window.request_redraw();
+        }
     }
 
     pub fn render_ui(&mut self) {
+        // Recovering from a lost device can switch the surface to another
+        // format.
+        self.on_surface_reconfigured(self.gpu.surface_format());
         let window = &self.window;
-        let mut encoder = self.gpu.create_cmd_encoder();
         let config = self.gpu.get_config();
-        let window_surface_view = self.gpu.get_current_view();
+        // The scene pass already reported why there's no frame.
+        let Ok(Some(window_surface_view)) = self.gpu.get_current_view() else {
+            return;
+        };
         let raw_input = self.state.take_egui_input(&window);
-        let full_output = self
+        let mut full_output = self
             .context
-            .run(raw_input, |_ui| self.ui.render_ui(&self.context));
+            .run(raw_input, |context| render_uis(&mut self.uis, context));
 
         self.state
-            .handle_platform_output(&window, full_output.platform_output);
+            .handle_platform_output(&window, std::mem::take(&mut full_output.platform_output));
 
-        let tris = self
-            .context
-            .tessellate(full_output.shapes, full_output.pixels_per_point);
-        for (id, image_delta) in &full_output.textures_delta.set {
-            self.renderer
-                .update_texture(&self.gpu.device, &self.gpu.queue, *id, &image_delta);
+        let changes = sync_viewports(
+            &mut self.viewports,
+            std::mem::take(&mut full_output.viewport_output),
+        );
+        if changes != ViewportChanges::default() {
+            log::debug!("Viewports changed: {changes:?}");
+        }
+
+        if let Err(err) = paint(
+            &self.gpu,
+            &mut self.renderer,
+            &self.context,
+            &window_surface_view,
+            self.clear_color,
+            [config.width, config.height],
+            full_output,
+        ) {
+            log::error!("Failed to draw the UI: {err:#}");
         }
+
+        for (id, viewport) in &mut self.viewports {
+            let (Some(ui), Some(window)) = (&viewport.ui, &mut viewport.window) else {
+                continue;
+            };
+            let Some((width, height)) = self.gpu.window_size(window.surface) else {
+                continue;
+            };
+            let view = match self.gpu.window_view(window.surface) {
+                Ok(Some(view)) => view,
+                Ok(None) => continue,
+                Err(err) => {
+                    log::error!("Failed to get the frame of viewport {id:?}: {err:#}");
+                    continue;
+                }
+            };
+            egui_winit::update_viewport_info(&mut window.info, &self.context, &window.window);
+            let mut raw_input = window.state.take_egui_input(&window.window);
+            raw_input.viewports.insert(*id, window.info.clone());
+            window.info.events.clear();
+
+            match render_viewport(
+                &self.gpu,
+                &mut self.renderer,
+                &self.context,
+                raw_input,
+                &**ui,
+                &view,
+                [width, height],
+            ) {
+                Ok((platform_output, commands)) => {
+                    window
+                        .state
+                        .handle_platform_output(&window.window, platform_output);
+                    viewport.commands.extend(commands);
+                }
+                Err(err) => log::error!("Failed to draw viewport {id:?}: {err:#}"),
+            }
+        }
+    }
+}
+
+/// Tessellates the shapes of a frame and draws them over `view`, which is
+/// `size_in_pixels` large, with the textures the frame changed.
+fn paint(
+    gpu: &Gpu,
+    renderer: &mut Renderer,
+    context: &Context,
+    view: &wgpu::TextureView,
+    clear_color: Option<wgpu::Color>,
+    size_in_pixels: [u32; 2],
+    full_output: FullOutput,
+) -> anyhow::Result<()> {
+    let tris = context.tessellate(full_output.shapes, full_output.pixels_per_point);
+    for (id, image_delta) in &full_output.textures_delta.set {
+        renderer.update_texture(&gpu.device, &gpu.queue, *id, image_delta);
+    }
+    // The scale the shapes were tessellated at, the window's scale factor
+    // when the input was taken times egui's zoom. Reading the window again
+    // could disagree mid drag between monitors.
+    let screen_descriptor = ScreenDescriptor {
+        size_in_pixels,
+        pixels_per_point: full_output.pixels_per_point,
+    };
+    let drawn = draw_gui(gpu, renderer, view, clear_color, &tris, &screen_descriptor);
+    for id in &full_output.textures_delta.free {
+        renderer.free_texture(id)
+    }
+    drawn
+}
+
+/// Runs the `ui` of the detached viewport `raw_input` is for and draws it
+/// into `view`, cleared to black as there's no scene under it. Returns what
+/// the viewport's window should do: the platform output and the commands
+/// the viewport sent itself.
+fn render_viewport(
+    gpu: &Gpu,
+    renderer: &mut Renderer,
+    context: &Context,
+    raw_input: egui::RawInput,
+    ui: &DeferredViewportUiCallback,
+    view: &wgpu::TextureView,
+    size_in_pixels: [u32; 2],
+) -> anyhow::Result<(egui::PlatformOutput, Vec<ViewportCommand>)> {
+    let id = raw_input.viewport_id;
+    let mut full_output = context.run(raw_input, ui);
+    let platform_output = std::mem::take(&mut full_output.platform_output);
+    let commands = full_output
+        .viewport_output
+        .remove(&id)
+        .map(|output| output.commands)
+        .unwrap_or_default();
+    paint(
+        gpu,
+        renderer,
+        context,
+        view,
+        Some(wgpu::Color::BLACK),
+        size_in_pixels,
+        full_output,
+    )?;
+    Ok((platform_output, commands))
+}
+
+/// Draws the tessellated UI over `view` in a [`RenderGraph`] pass, which
+/// clears it to `clear_color` first when there is one.
+fn draw_gui(
+    gpu: &Gpu,
+    renderer: &mut Renderer,
+    view: &wgpu::TextureView,
+    clear_color: Option<wgpu::Color>,
+    tris: &[egui::ClippedPrimitive],
+    screen_descriptor: &ScreenDescriptor,
+) -> anyhow::Result<()> {
+    let mut encoder = gpu.create_cmd_encoder();
+    renderer.update_buffers(
+        &gpu.device,
+        &gpu.queue,
+        &mut encoder,
+        tris,
+        screen_descriptor,
+    );
+    gpu.submit_cmd(encoder.finish());
+
+    let renderer = &*renderer;
+    let mut graph = RenderGraph::new();
+    let init = match clear_color {
+        Some(color) => TextureInit::Clear(color),
+        None => TextureInit::Load,
+    };
+    let surface = graph.import("Surface", view, init);
+    graph
+        .add_pass("egui main render pass")
+        .color(surface)
+        .record(|encoder, targets| {
+            renderer.render(&mut targets.begin(encoder), tris, screen_descriptor);
+        })?;
+    graph.execute(gpu)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingUi(Arc<AtomicUsize>);
+
+    impl Ui for CountingUi {
+        fn render_ui(&mut self, _context: &Context) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    struct PanickingUi;
+
+    impl Ui for PanickingUi {
+        fn render_ui(&mut self, _context: &Context) {
+            panic!("broken UI");
+        }
+    }
+
+    #[test]
+    fn test_renderer_config_follows_surface_format() {
+        let config = RendererConfig {
+            color_format: TextureFormat::Bgra8UnormSrgb,
+            depth_format: None,
+            msaa_samples: 1,
+        };
+        assert_eq!(
+            config.with_color_format(TextureFormat::Bgra8UnormSrgb),
+            None
+        );
+
+        let rebuilt = config.with_color_format(TextureFormat::Bgra8Unorm).unwrap();
+        assert_eq!(rebuilt.color_format, TextureFormat::Bgra8Unorm);
+        assert_eq!(rebuilt.msaa_samples, config.msaa_samples);
+    }
+
+    #[test]
+    fn test_clear_color_clears_under_the_ui() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping GUI clear color test");
+            return Ok(());
+        };
+        let format = TextureFormat::Rgba8Unorm;
+        let target = texture::Texture::create_render_target(
+            &gpu.device,
+            4,
+            4,
+            format,
+            wgpu::TextureUsages::COPY_SRC,
+        );
+        let mut renderer = RendererConfig {
+            color_format: format,
+            depth_format: None,
+            msaa_samples: 1,
+        }
+        .create_renderer(&gpu.device);
         let screen_descriptor = ScreenDescriptor {
-            size_in_pixels: [config.width, config.height],
-            pixels_per_point: window.scale_factor() as f32,
+            size_in_pixels: [4, 4],
+            pixels_per_point: 1.0,
+        };
+        let mut draw = |clear_color| -> anyhow::Result<Vec<u8>> {
+            draw_gui(
+                &gpu,
+                &mut renderer,
+                &target.view,
+                clear_color,
+                &[],
+                &screen_descriptor,
+            )?;
+            gpu.finish();
+            Ok(gpu.read_texture(&target.texture)?.pixels)
+        };
+
+        let cleared = draw(Some(wgpu::Color::RED))?;
+        assert!(cleared.chunks(4).all(|pixel| pixel == [255, 0, 0, 255]));
+        // Without one the UI goes over what was there.
+        assert_eq!(draw(None)?, cleared);
+        let cleared = draw(Some(wgpu::Color::BLUE))?;
+        assert!(cleared.chunks(4).all(|pixel| pixel == [0, 0, 255, 255]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuilt_renderer_keeps_the_font_atlas() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping GUI renderer rebuild test");
+            return Ok(());
+        };
+        let (width, height) = (64, 32);
+        let context = Context::default();
+        let run = || {
+            let input = egui::RawInput {
+                screen_rect: Some(egui::Rect::from_min_size(
+                    egui::Pos2::ZERO,
+                    egui::vec2(width as f32, height as f32),
+                )),
+                ..Default::default()
+            };
+            // Text without a background, only drawn with the atlas.
+            context.run(input, |context| {
+                egui::Area::new("text").show(context, |ui| ui.label("MMMM"));
+            })
+        };
+        let config = RendererConfig {
+            color_format: TextureFormat::Rgba8UnormSrgb,
+            depth_format: None,
+            msaa_samples: 1,
         };
-        self.renderer.update_buffers(
-            &self.gpu.device,
-            &self.gpu.queue,
-            &mut encoder,
+        let mut renderer = config.create_renderer(&gpu.device);
+        for (id, delta) in &run().textures_delta.set {
+            renderer.update_texture(&gpu.device, &gpu.queue, *id, delta);
+        }
+
+        // The surface switched formats, egui won't send the atlas again.
+        let config = config.with_color_format(TextureFormat::Rgba8Unorm).unwrap();
+        let mut renderer = config.recreate_renderer(&gpu, &context);
+        let output = run();
+        assert!(output.textures_delta.set.is_empty());
+
+        let target = texture::Texture::create_render_target(
+            &gpu.device,
+            width,
+            height,
+            config.color_format,
+            wgpu::TextureUsages::COPY_SRC,
+        );
+        let tris = context.tessellate(output.shapes, output.pixels_per_point);
+        let screen_descriptor = ScreenDescriptor {
+            size_in_pixels: [width, height],
+            pixels_per_point: output.pixels_per_point,
+        };
+        draw_gui(
+            &gpu,
+            &mut renderer,
+            &target.view,
+            Some(wgpu::Color::BLACK),
             &tris,
             &screen_descriptor,
+        )?;
+        gpu.finish();
+        let pixels = gpu.read_texture(&target.texture)?.pixels;
+        assert!(
+            pixels.chunks(4).any(|pixel| pixel[..3] != [0, 0, 0]),
+            "the label wasn't drawn"
         );
-        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &window_surface_view,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Load,
-                    store: wgpu::StoreOp::Store,
-                },
-            })],
-            depth_stencil_attachment: None,
-            label: Some("egui main render pass"),
-            timestamp_writes: None,
-            occlusion_query_set: None,
+        Ok(())
+    }
+
+    #[test]
+    fn test_panicking_ui_is_removed() {
+        let rendered = Arc::new(AtomicUsize::new(0));
+        let mut uis: Vec<(UiId, Box<dyn Ui>)> = vec![
+            (UiId(0), Box::new(CountingUi(rendered.clone()))),
+            (UiId(1), Box::new(PanickingUi)),
+            (UiId(2), Box::new(CountingUi(rendered.clone()))),
+        ];
+
+        let context = Context::default();
+        let _ = context.run(Default::default(), |context| render_uis(&mut uis, context));
+
+        assert_eq!(rendered.load(Ordering::Relaxed), 2);
+        let ids = uis.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        assert_eq!(ids, [UiId(0), UiId(2)]);
+    }
+
+    #[test]
+    fn test_detached_viewport_is_drawn() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping detached viewport test");
+            return Ok(());
+        };
+        let (width, height) = (8, 8);
+        let context = Context::default();
+        context.set_embed_viewports(false);
+        let id = ViewportId::from_hash_of("inspector");
+        let output = context.run(Default::default(), |context| {
+            context.show_viewport_deferred(id, ViewportBuilder::default(), |context, _| {
+                egui::CentralPanel::default()
+                    .frame(egui::Frame::none().fill(egui::Color32::RED))
+                    .show(context, |_| {});
+                context.send_viewport_cmd(ViewportCommand::Title("Red".to_string()));
+            });
         });
-        self.renderer.render(&mut rpass, &tris, &screen_descriptor);
-        drop(rpass);
-        for x in &full_output.textures_delta.free {
-            self.renderer.free_texture(x)
+        let mut viewports = HashMap::new();
+        sync_viewports(&mut viewports, output.viewport_output);
+        let ui = viewports[&id].ui.clone().unwrap();
+
+        let format = TextureFormat::Rgba8Unorm;
+        let target = texture::Texture::create_2d_texture(
+            &gpu,
+            height,
+            width,
+            format,
+            wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            wgpu::FilterMode::Nearest,
+            None,
+        );
+        let mut renderer = RendererConfig {
+            color_format: format,
+            depth_format: None,
+            msaa_samples: 1,
+        }
+        .create_renderer(&gpu.device);
+        // The main window's frame uploads the font atlas.
+        for (id, image_delta) in &output.textures_delta.set {
+            renderer.update_texture(&gpu.device, &gpu.queue, *id, image_delta);
         }
-        self.gpu.submit_cmd(encoder.finish());
+        let raw_input = egui::RawInput {
+            viewport_id: id,
+            viewports: std::iter::once((id, ViewportInfo::default())).collect(),
+            screen_rect: Some(egui::Rect::from_min_size(
+                egui::Pos2::ZERO,
+                egui::vec2(width as f32, height as f32),
+            )),
+            ..Default::default()
+        };
+        let (_, commands) = render_viewport(
+            &gpu,
+            &mut renderer,
+            &context,
+            raw_input,
+            &*ui,
+            &target.view,
+            [width, height],
+        )?;
+        gpu.finish();
+
+        assert_eq!(commands, [ViewportCommand::Title("Red".to_string())]);
+        let pixels = gpu.read_texture(&target.texture)?.pixels;
+        let center = ((height / 2 * width + width / 2) * 4) as usize;
+        assert_eq!(pixels[center..center + 4], [255, 0, 0, 255]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_detached_viewport_is_tracked() {
+        let context = Context::default();
+        context.set_embed_viewports(false);
+        let id = ViewportId::from_hash_of("inspector");
+        let show = |context: &Context| {
+            context.show_viewport_deferred(
+                id,
+                ViewportBuilder::default().with_title("Inspector"),
+                |_, _| {},
+            );
+        };
+
+        let mut viewports = HashMap::new();
+        let output = context.run(Default::default(), show);
+        let changes = sync_viewports(&mut viewports, output.viewport_output);
+        assert_eq!(changes.opened, [id]);
+        assert_eq!(viewports[&id].parent, ViewportId::ROOT);
+        assert_eq!(viewports[&id].builder.title.as_deref(), Some("Inspector"));
+        assert!(viewports[&id].ui.is_some());
+
+        let output = context.run(Default::default(), show);
+        let changes = sync_viewports(&mut viewports, output.viewport_output);
+        assert_eq!(changes, ViewportChanges::default());
+        assert!(viewports[&id].commands.is_empty());
+        assert!(!viewports[&id].recreate);
+
+        // Changes to the builder reach the window as commands, or by
+        // recreating it.
+        let output = context.run(Default::default(), |context| {
+            context.show_viewport_deferred(
+                id,
+                ViewportBuilder::default()
+                    .with_title("Scene")
+                    .with_close_button(false),
+                |_, _| {},
+            );
+        });
+        sync_viewports(&mut viewports, output.viewport_output);
+        assert_eq!(
+            viewports[&id].commands,
+            [ViewportCommand::Title("Scene".to_string())]
+        );
+        assert!(viewports[&id].recreate);
+
+        let output = context.run(Default::default(), |_| {});
+        let changes = sync_viewports(&mut viewports, output.viewport_output);
+        assert_eq!(changes.closed, [id]);
+        assert!(viewports.is_empty());
     }
 }
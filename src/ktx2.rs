@@ -0,0 +1,145 @@
+//! Minimal reader for KTX2 containers holding block compressed 2D textures.
+//!
+//! Only what's needed to hand the mip levels to wgpu as they are: no
+//! supercompression, no cube maps or arrays and no Basis Universal.
+
+use anyhow::*;
+
+const IDENTIFIER: [u8; 12] = [
+    0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+];
+/// Identifier, nine u32 header fields and the index of the data format
+/// descriptor, key/value data and supercompression global data.
+const HEADER_SIZE: usize = 80;
+/// Byte offset, byte length and uncompressed byte length.
+const LEVEL_INDEX_ENTRY_SIZE: usize = 24;
+
+pub struct Ktx2<'a> {
+    /// The `VkFormat` the data is stored in.
+    pub vk_format: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Level 0 is the full size image.
+    pub levels: Vec<&'a [u8]>,
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+impl<'a> Ktx2<'a> {
+    pub fn is_ktx2(bytes: &[u8]) -> bool {
+        bytes.starts_with(&IDENTIFIER)
+    }
+
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE || !Self::is_ktx2(bytes) {
+            bail!("Not a KTX2 file");
+        }
+
+        let vk_format = read_u32(bytes, 12);
+        let width = read_u32(bytes, 20);
+        let height = read_u32(bytes, 24);
+        let depth = read_u32(bytes, 28);
+        let layer_count = read_u32(bytes, 32);
+        let face_count = read_u32(bytes, 36);
+        // Zero asks the loader to generate the mips, there's still one level.
+        let level_count = read_u32(bytes, 40).max(1) as usize;
+        let supercompression = read_u32(bytes, 44);
+
+        if depth > 1 || layer_count > 1 || face_count != 1 {
+            bail!("Only single layer 2D KTX2 textures are supported");
+        }
+        if supercompression != 0 {
+            bail!("KTX2 supercompression scheme {supercompression} is not supported");
+        }
+
+        let index_end = HEADER_SIZE + level_count * LEVEL_INDEX_ENTRY_SIZE;
+        if bytes.len() < index_end {
+            bail!("KTX2 level index is truncated");
+        }
+
+        let levels = (0..level_count)
+            .map(|level| {
+                let entry = HEADER_SIZE + level * LEVEL_INDEX_ENTRY_SIZE;
+                let offset = read_u64(bytes, entry) as usize;
+                let length = read_u64(bytes, entry + 8) as usize;
+                match offset.checked_add(length) {
+                    Some(end) if end <= bytes.len() => Ok(&bytes[offset..end]),
+                    _ => bail!("KTX2 mip level {level} is out of bounds"),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            vk_format,
+            width,
+            height,
+            levels,
+        })
+    }
+
+    /// The wgpu format matching [`Self::vk_format`], `None` for anything but
+    /// BC1, BC3 and BC7.
+    pub fn format(&self) -> Option<wgpu::TextureFormat> {
+        use wgpu::TextureFormat::*;
+        // VK_FORMAT_BC1_RGB_* has no alpha, sampling it as RGBA reads 1.
+        match self.vk_format {
+            131 | 133 => Some(Bc1RgbaUnorm),
+            132 | 134 => Some(Bc1RgbaUnormSrgb),
+            137 => Some(Bc3RgbaUnorm),
+            138 => Some(Bc3RgbaUnormSrgb),
+            145 => Some(Bc7RgbaUnorm),
+            146 => Some(Bc7RgbaUnormSrgb),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(vk_format: u32, width: u32, height: u32, levels: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = IDENTIFIER.to_vec();
+        for field in [vk_format, 1, width, height, 0, 0, 1, levels.len() as u32, 0] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes.resize(HEADER_SIZE, 0);
+
+        let mut offset = HEADER_SIZE + levels.len() * LEVEL_INDEX_ENTRY_SIZE;
+        for level in levels {
+            for field in [offset, level.len(), level.len()] {
+                bytes.extend_from_slice(&(field as u64).to_le_bytes());
+            }
+            offset += level.len();
+        }
+        for level in levels {
+            bytes.extend_from_slice(level);
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_parse_levels() {
+        let levels = vec![vec![1; 32], vec![2; 8], vec![3; 8]];
+        let bytes = header(145, 8, 8, &levels);
+        let ktx2 = Ktx2::parse(&bytes).unwrap();
+
+        assert_eq!(ktx2.format(), Some(wgpu::TextureFormat::Bc7RgbaUnorm));
+        assert_eq!((ktx2.width, ktx2.height), (8, 8));
+        assert_eq!(ktx2.levels, levels);
+    }
+
+    #[test]
+    fn test_parse_rejects_truncated_level() {
+        let mut bytes = header(131, 4, 4, &[vec![0; 8]]);
+        bytes.pop();
+        assert!(Ktx2::parse(&bytes).is_err());
+        assert!(Ktx2::parse(b"not a ktx2 file").is_err());
+    }
+}
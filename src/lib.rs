@@ -1,90 +1,65 @@
 extern crate nalgebra as na;
 
+pub mod animation;
 pub mod app;
+mod bcn;
+mod bundle;
 mod camera;
 mod db;
+mod debug;
+mod decimate;
+mod exposure;
+mod frustum;
+mod gbuffer;
 pub mod gpu;
+mod graph;
 mod gui;
 mod hdr;
+mod hiz;
 mod io;
+mod ktx2;
 mod light;
 mod model;
+mod pass;
+mod picking;
+pub mod pipeline;
+mod profiler;
 mod resource;
+mod shadow;
+mod skin;
+mod sprite;
+mod text;
 mod texture;
+mod uniform;
+mod upload;
+mod viewport;
 
 use crate::db::Id;
 use crate::model::{InstanceRaw, ModelVertex, Vertex};
+pub use io::event::{
+    create_bounded_channel, create_unbounded_channel, ChannelReceiver, ChannelSender, EventError,
+    EventReceiver, NativeEvent,
+};
+pub use model::{ColorVertex, Instance};
 
-use camera::{CameraController, CameraUniform, Projection, StaticCamera};
+use camera::{CameraController, CameraUniform, ICamera, Projection, StaticCamera};
 use db::DB;
 use gpu::Gpu;
 use io::Controller;
 use light::LightUniform;
-use model::DrawLight;
 use model::DrawModel;
-use std::sync::{Arc, RwLock};
-use texture::Texture;
+use pipeline::{BlendPreset, PipelineBuilder};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
 use wgpu::util::{DeviceExt, RenderEncoder};
-use winit::{event::*, window::Window};
-
-fn create_render_pipeline(
-    gpu: &Gpu,
-    layout: &wgpu::PipelineLayout,
-    color_format: wgpu::TextureFormat,
-    depth_format: Option<wgpu::TextureFormat>,
-    vertex_layouts: &[wgpu::VertexBufferLayout],
-    topology: wgpu::PrimitiveTopology, // NEW!
-    shader: wgpu::ShaderModuleDescriptor,
-) -> wgpu::RenderPipeline {
-    let device = &gpu.device;
-    let shader = device.create_shader_module(shader);
-
-    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-        label: Some(&format!("{:?}", shader)),
-        layout: Some(layout),
-        vertex: wgpu::VertexState {
-            module: &shader,
-            entry_point: "vs_main",
-            buffers: vertex_layouts,
-        },
-        fragment: Some(wgpu::FragmentState {
-            module: &shader,
-            entry_point: "fs_main",
-            targets: &[Some(wgpu::ColorTargetState {
-                format: color_format,
-                blend: None,
-                write_mask: wgpu::ColorWrites::ALL,
-            })],
-        }),
-        primitive: wgpu::PrimitiveState {
-            topology, // NEW!
-            strip_index_format: None,
-            front_face: wgpu::FrontFace::Ccw,
-            cull_mode: Some(wgpu::Face::Back),
-            // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
-            polygon_mode: wgpu::PolygonMode::Fill,
-            // Requires Features::DEPTH_CLIP_CONTROL
-            unclipped_depth: false,
-            // Requires Features::CONSERVATIVE_RASTERIZATION
-            conservative: false,
-        },
-        depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
-            format,
-            depth_write_enabled: true,
-            depth_compare: wgpu::CompareFunction::LessEqual, // UDPATED!
-            stencil: wgpu::StencilState::default(),
-            bias: wgpu::DepthBiasState::default(),
-        }),
-        multisample: wgpu::MultisampleState {
-            count: 1,
-            mask: !0,
-            alpha_to_coverage_enabled: false,
-        },
-        // If the pipeline will be used with a multiview render pass, this
-        // indicates how many array layers the attachments will have.
-        multiview: None,
-    })
-}
+use winit::{dpi::PhysicalSize, event::*, window::Window};
 
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
@@ -94,8 +69,15 @@ impl Controller for Arc<RwLock<CameraController>> {
         let mut camera_write = self.write().unwrap();
         camera_write.process_key(ctx);
     }
+
+    fn process_window_event(&self, event: &WindowEvent) {
+        self.write().unwrap().handle_window_event(event);
+    }
 }
 
+/// Key of a model in the [`ModelDB`], what [`Renderer::pick`] returns.
+pub type ModelId = Id;
+
 type ModelDB = DB<ModelEntry>;
 type BindGroupDB = DB<BindGroupEntry>;
 type PipelineDB = DB<PipelineEntry>;
@@ -105,10 +87,169 @@ enum PipelineEntry {
     Compute(wgpu::ComputePipeline),
 }
 
-struct ModelEntry {
+/// A model in the scene with the instances it's drawn with, see
+/// [`app::App::with_model`].
+pub struct ModelEntry {
     model: model::Model,
-    instances: Vec<model::Instance>,
-    instance_buffer: wgpu::Buffer,
+    instances: model::InstanceBuffer,
+    /// Center of the instances, what blended meshes are sorted by.
+    position: na::Point3<f32>,
+    /// World space bounds of every instance, what the frustum culls.
+    aabb: frustum::Aabb,
+    material_animators: Vec<animation::MaterialAnimator>,
+    /// Seconds into the skeletal animation, see [`model::Model::animate`].
+    skin_time: f32,
+    /// See [`ModelEntry::set_procedural_instances`].
+    procedural_instances: Option<u32>,
+    /// Material index each overridden mesh is drawn with, see
+    /// [`ModelEntry::set_material_override`].
+    material_overrides: HashMap<usize, usize>,
+    /// Unique among every entry, and taken again whenever what the static
+    /// scene recorded of it changes, see [`ModelEntry::touch`].
+    revision: u64,
+    /// The file the model was loaded from, see [`ModelEntry::reload`].
+    source: Option<PathBuf>,
+}
+
+impl ModelEntry {
+    /// Next [`ModelEntry::revision`], shared by every entry so one replacing
+    /// another never reuses its revision.
+    fn next_revision() -> u64 {
+        static NEXT_REVISION: AtomicU64 = AtomicU64::new(0);
+        NEXT_REVISION.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Makes the static scene record this entry again.
+    fn touch(&mut self) {
+        self.revision = Self::next_revision();
+    }
+
+    fn new(gpu: &Gpu, model: model::Model) -> Self {
+        if let Err(err) = model.validate() {
+            log::warn!("{err}, drawing it with the fallback material");
+        }
+        let instances = model::InstanceBuffer::new(&gpu.device, &[model::Instance::default()]);
+        Self {
+            aabb: model.aabb(),
+            model,
+            instances,
+            position: na::Point3::origin(),
+            material_animators: Vec::new(),
+            skin_time: 0.0,
+            procedural_instances: None,
+            material_overrides: HashMap::new(),
+            revision: Self::next_revision(),
+            source: None,
+        }
+    }
+
+    fn with_source(mut self, path: PathBuf) -> Self {
+        self.source = Some(path);
+        self
+    }
+
+    /// The entry with its model loaded again from its file onto `gpu`, e.g.
+    /// after the device it was on was lost. Instances, material overrides
+    /// and animations carry over. Fails for models that weren't loaded from
+    /// a file.
+    async fn reload(self, gpu: &Gpu) -> anyhow::Result<Self> {
+        let Some(source) = self.source.clone() else {
+            anyhow::bail!("The model wasn't loaded from a file");
+        };
+        let model = resource::load_model(source, gpu).await?;
+        Ok(Self {
+            model,
+            instances: self.instances.recreate(&gpu.device),
+            revision: Self::next_revision(),
+            ..self
+        })
+    }
+
+    /// Whether every mesh has the material it refers to. Malformed models
+    /// are still drawn, with the fallback of [`model::Model::material`].
+    pub fn validate(&self) -> Result<(), model::DrawError> {
+        self.model.validate()
+    }
+
+    /// Draws mesh number `mesh` with the model's material number `material`
+    /// instead of its own from the next frame on, e.g. a team color authored
+    /// next to the default one. `None` goes back to the mesh's own material.
+    pub fn set_material_override(
+        &mut self,
+        mesh: usize,
+        material: Option<usize>,
+    ) -> anyhow::Result<()> {
+        let (meshes, materials) = (self.model.meshes.len(), self.model.materials.len());
+        if mesh >= meshes {
+            anyhow::bail!("Mesh {mesh} of a model with {meshes} meshes");
+        }
+        match material {
+            Some(material) if material >= materials => {
+                anyhow::bail!("Material {material} of a model with {materials} materials")
+            }
+            Some(material) => self.material_overrides.insert(mesh, material),
+            None => self.material_overrides.remove(&mesh),
+        };
+        self.touch();
+        Ok(())
+    }
+
+    /// Animates one of the model's materials from the next frame on.
+    pub fn add_material_animator(&mut self, animator: animation::MaterialAnimator) {
+        self.material_animators.push(animator);
+    }
+
+    /// Advances every material animation and uploads the animated uniforms.
+    fn animate_materials(&mut self, gpu: &Gpu, dt: Duration) {
+        for animator in &mut self.material_animators {
+            animator.update(&gpu.queue, &self.model.materials, dt);
+        }
+    }
+
+    /// Advances the skeletal animation and uploads the joint matrices.
+    fn animate_skin(&mut self, gpu: &Gpu, dt: Duration) {
+        self.skin_time += dt.as_secs_f32();
+        self.model.animate(&gpu.queue, self.skin_time);
+    }
+
+    /// Replaces the transforms every mesh of the model is drawn with. The
+    /// instance buffer is reused while they fit and reallocated otherwise.
+    pub fn set_instances(&mut self, gpu: &Gpu, instances: &[Instance]) {
+        self.instances.upload(&gpu.device, &gpu.queue, instances);
+        self.touch();
+        let sum = instances
+            .iter()
+            .fold(na::Vector3::zeros(), |sum, instance| {
+                sum + instance.isometry.translation.vector
+            });
+        self.position = (sum / instances.len().max(1) as f32).into();
+
+        let aabb = self.model.aabb();
+        self.aabb = instances
+            .iter()
+            .map(|instance| aabb.transform(&instance.isometry))
+            .reduce(|a, b| a.union(&b))
+            .unwrap_or(aabb);
+    }
+
+    /// Whether [`hiz::HiZPass::cull`] can pick the instances to draw, its
+    /// indirect draws don't tell opaque and blended meshes apart.
+    fn occlusion_cullable(&self) -> bool {
+        !self.instances.is_empty()
+            && self
+                .model
+                .materials
+                .iter()
+                .all(|material| material.alpha_mode == model::AlphaMode::Opaque)
+    }
+
+    /// `Some(count)` draws the model `count` times on the grid procedural.wgsl
+    /// derives from the instance index instead of with the instances, e.g.
+    /// for crowds without uploading a transform each. Only the scene pass
+    /// draws them, unlit and without frustum culling or shadows.
+    pub fn set_procedural_instances(&mut self, count: Option<u32>) {
+        self.procedural_instances = count;
+    }
 }
 
 struct BindGroupEntry {
@@ -130,30 +271,188 @@ impl Resources {
             model_db: RwLock::default(),
         }
     }
+
+    /// Loads every model again onto `gpu` under the id it had, see
+    /// [`ModelEntry::reload`]. Models that fail to load are dropped.
+    async fn reload_models(&self, gpu: &Gpu) {
+        let entries = std::mem::take(&mut self.model_db.write().unwrap().data);
+        let mut reloaded = HashMap::with_capacity(entries.len());
+        for (id, entry) in entries {
+            match entry.reload(gpu).await {
+                Ok(entry) => {
+                    reloaded.insert(id, entry);
+                }
+                Err(err) => log::error!("Dropped model {id:?}: {err:#}"),
+            }
+        }
+        self.model_db.write().unwrap().data = reloaded;
+    }
 }
 
-struct Renderer {
+pub struct Renderer {
     gpu: Arc<Gpu>,
     window: Arc<Window>,
     camera_controller: Arc<RwLock<CameraController>>,
     size: winit::dpi::PhysicalSize<u32>,
     render_pipeline: wgpu::RenderPipeline,
+    /// Line variant of `render_pipeline`, `None` without line polygon support.
+    wireframe_pipeline: Option<wgpu::RenderPipeline>,
+    /// Alpha blended variant of `render_pipeline` for [`model::AlphaMode::Blend`].
+    transparent_pipeline: wgpu::RenderPipeline,
+    /// Variants of `render_pipeline` and `transparent_pipeline` for models
+    /// with a [`skin::Skin`], which bind its joint matrices to group 3.
+    skinned_pipeline: wgpu::RenderPipeline,
+    skinned_transparent_pipeline: wgpu::RenderPipeline,
+    /// Depth only variants of `render_pipeline` and `skinned_pipeline` for
+    /// the pre-pass, see [`Gpu::set_depth_prepass`].
+    prepass_pipeline: wgpu::RenderPipeline,
+    skinned_prepass_pipeline: wgpu::RenderPipeline,
+    /// Variants of `render_pipeline` and `skinned_pipeline` that only shade
+    /// fragments at the depth the pre-pass left.
+    equal_depth_pipeline: wgpu::RenderPipeline,
+    skinned_equal_depth_pipeline: wgpu::RenderPipeline,
     camera: Arc<RwLock<StaticCamera>>,
     camera_uniform: CameraUniform,
     camera_buffer: wgpu::Buffer,
     camera_bind_group: Id,
-    depth_texture: Option<texture::Texture>,
+    /// Bound next to the camera, see [`uniform::FrameUniform`].
+    frame_uniform: uniform::FrameUniformBuffer,
+    depth_texture: texture::DepthTexture,
+    msaa_texture: Option<texture::Texture>,
+    sample_count: u32,
     light_uniform: LightUniform,
     light_buffer: wgpu::Buffer,
+    light_bind_group_layout: wgpu::BindGroupLayout,
     light_bind_group: wgpu::BindGroup,
-    light_render_pipeline: wgpu::RenderPipeline,
+    /// The shadow map bound with the light, 1x1 while the light casts none.
+    shadow: shadow::ShadowPass,
+    shadow_settings: Option<light::ShadowSettings>,
     hdr: hdr::HdrPipeline,
     bind_group_db: BindGroupDB,
     envoronment_bind_group: wgpu::BindGroup,
     sky_pipeline: wgpu::RenderPipeline,
+    procedural_pipeline: wgpu::RenderPipeline,
+    clear_color: Option<wgpu::Color>,
+    gbuffer: Option<gbuffer::GBuffer>,
+    hiz: Option<hiz::HiZPass>,
+    /// Instances [`hiz::HiZPass::cull`] kept this frame, one per occlusion
+    /// culled model in draw order.
+    culled: Vec<hiz::CulledInstances>,
+    auto_exposure: Option<exposure::AutoExposure>,
+    profiler: profiler::Profiler,
+    /// Lines drawn over the scene this frame, see [`Renderer::debug_mut`].
+    debug: debug::DebugRenderer,
+    /// Text drawn over the finished frame, see [`Renderer::text_mut`].
+    text: text::TextRenderer,
+    /// Billboards drawn with the scene this frame, see
+    /// [`Renderer::sprites_mut`].
+    sprites: sprite::SpriteBatch,
+    /// See [`Renderer::add_colored_mesh`].
+    colored_meshes: Vec<model::ColoredMesh>,
+    vertex_color_material: model::VertexColorMaterial,
+    /// Created on the first [`Renderer::pick`].
+    picking: Option<picking::PickingPass>,
+    /// Opaque draws of every model, recorded once while the scene is static,
+    /// keyed by whether they draw in wireframe, whether they follow a depth
+    /// pre-pass and the revision of every model.
+    static_scene: Option<bundle::BundleCache<(bool, bool, Vec<u64>)>>,
+    /// The camera the static scene is recorded with, so the bundle doesn't
+    /// depend on which camera draws it.
+    static_camera_slot: bundle::CameraSlot,
+    /// Texture and buffer writes spread over frames, flushed before the
+    /// scene is drawn.
+    uploads: upload::UploadScheduler,
+    render_scale: f32,
+    /// In surface pixels, see [`Renderer::set_viewport`].
+    viewport: Option<viewport::Viewport>,
+    scissor_rect: Option<viewport::ScissorRect>,
+    frame_budget: Option<Duration>,
+    last_update: Instant,
+    frame_delta: Duration,
+}
+
+/// Binds `pipeline` to draw `model`, or `skinned` and the joint matrices
+/// when the model has a [`skin::Skin`].
+fn set_scene_pipeline<'a>(
+    encoder: &mut impl RenderEncoder<'a>,
+    model: &'a model::Model,
+    pipeline: &'a wgpu::RenderPipeline,
+    skinned: &'a wgpu::RenderPipeline,
+) {
+    match &model.skin {
+        Some(skin) => {
+            encoder.set_pipeline(skinned);
+            encoder.set_bind_group(3, skin.bind_group(), &[]);
+        }
+        None => encoder.set_pipeline(pipeline),
+    }
+}
+
+/// Draws the opaque meshes of `models`. Those [`ModelEntry::occlusion_cullable`]
+/// are drawn with the instances `culled` kept for them, in the same order,
+/// when there is `culled`.
+fn draw_opaque<'a>(
+    render_pass: &mut wgpu::RenderPass<'a>,
+    models: &[&'a ModelEntry],
+    mut culled: Option<std::slice::Iter<'a, hiz::CulledInstances>>,
+    pipeline: &'a wgpu::RenderPipeline,
+    skinned: &'a wgpu::RenderPipeline,
+    camera_bind_group: &'a wgpu::BindGroup,
+    light_bind_group: &'a wgpu::BindGroup,
+) {
+    for entry in models {
+        set_scene_pipeline(render_pass, &entry.model, pipeline, skinned);
+        let culled = culled
+            .as_mut()
+            .filter(|_| entry.occlusion_cullable())
+            .and_then(|culled| culled.next());
+        match culled {
+            Some(culled) => render_pass.draw_model_indirect(
+                &entry.model,
+                culled,
+                &entry.material_overrides,
+                camera_bind_group,
+                light_bind_group,
+            ),
+            None => render_pass.draw_meshes_instanced(
+                &entry.model,
+                &entry.instances,
+                &entry.material_overrides,
+                model::AlphaMode::Opaque,
+                camera_bind_group,
+                light_bind_group,
+            ),
+        }
+    }
+}
+
+/// Model space bounding sphere of `model`, `[x, y, z, radius]`.
+fn bounding_sphere(model: &model::Model) -> [f32; 4] {
+    let aabb = model.aabb();
+    let center = aabb.center();
+    let radius = (aabb.max - aabb.min).norm() / 2.0;
+    [center.x, center.y, center.z, radius]
+}
+
+/// Lowest scale [`Renderer::update_render_scale`] drops the resolution to.
+const MIN_RENDER_SCALE: f32 = 0.25;
+
+/// Size of the offscreen targets when rendering a `size` surface at `scale`.
+fn scaled_size(size: PhysicalSize<u32>, scale: f32) -> PhysicalSize<u32> {
+    PhysicalSize::new(
+        ((size.width as f32 * scale).round() as u32).max(1),
+        ((size.height as f32 * scale).round() as u32).max(1),
+    )
 }
 
 impl Renderer {
+    /// Copyable depth without stencil, which picks the
+    /// [`texture::Texture::DEPTH_FORMAT`] every scene pipeline draws with.
+    const DEPTH_REQUIREMENTS: texture::DepthRequirements = texture::DepthRequirements {
+        stencil: false,
+        readback: true,
+    };
+
     async fn new(
         window: Arc<Window>,
         gpu: Arc<Gpu>,
@@ -164,30 +463,7 @@ impl Renderer {
 
         let size = window.inner_size();
 
-        let texture_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: Some("Texture Bind Group Layout"),
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        // This should match the filterable field of the
-                        // corresponding Texture entry above.
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                        count: None,
-                    },
-                ],
-            });
+        let texture_bind_group_layout = texture::Texture::get_bind_group_layout(&gpu);
 
         let mut camera_uniform = CameraUniform::new();
         let camera = static_camera.read().unwrap();
@@ -198,41 +474,61 @@ impl Renderer {
         let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Camera Buffer"),
             contents: bytemuck::cast_slice(&[camera_uniform]),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            // COPY_SRC for the static scene's camera slot.
+            usage: wgpu::BufferUsages::UNIFORM
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
         });
 
+        let frame_uniform = uniform::FrameUniformBuffer::new(&gpu);
+
         let camera_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
                     },
-                    count: None,
-                }],
+                    uniform::FrameUniform::layout_entry(
+                        wgpu::ShaderStages::VERTEX
+                            | wgpu::ShaderStages::FRAGMENT
+                            | wgpu::ShaderStages::COMPUTE,
+                    ),
+                ],
                 label: Some("camera_bind_group_layout"),
             });
 
         let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: &camera_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: camera_buffer.as_entire_binding(),
-            }],
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: camera_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: uniform::FrameUniform::BINDING,
+                    resource: frame_uniform.binding(),
+                },
+            ],
             label: Some("camera_bind_group"),
         });
+        let static_camera_slot = bundle::CameraSlot::new(
+            device,
+            &camera_bind_group_layout,
+            camera_buffer.size(),
+            &[wgpu::BindGroupEntry {
+                binding: uniform::FrameUniform::BINDING,
+                resource: frame_uniform.binding(),
+            }],
+        );
 
-        let light_uniform = light::LightUniform {
-            position: [2.0, 2.0, 2.0],
-            _padding: 0,
-            color: [1.0, 1.0, 1.0],
-            _paddding: 0,
-        };
-
-        let hdr = hdr::HdrPipeline::new(&gpu);
+        let light_uniform = LightUniform::from(light::Light::point([2.0; 3], [1.0; 3]));
 
         let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Light V8"),
@@ -240,53 +536,22 @@ impl Renderer {
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let light_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: Some("Light Bind Group Layout"),
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    count: None,
-                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                }],
-            });
-
-        let light_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Light Bind Group"),
-            layout: &light_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: light_buffer.as_entire_binding(),
-            }],
-        });
-
-        let depth_texture = None;
-
-        // lib.rs
-        let light_render_pipeline = {
-            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Light Pipeline Layout"),
-                bind_group_layouts: &[&camera_bind_group_layout, &light_bind_group_layout],
-                push_constant_ranges: &[],
-            });
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Light Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("light.wgsl").into()),
-            };
-            create_render_pipeline(
-                &gpu,
-                &layout,
-                hdr.format(),
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[ModelVertex::desc()],
-                wgpu::PrimitiveTopology::TriangleList,
-                shader,
-            )
-        };
+        let light_bind_group_layout = light::bind_group_layout(device);
+        let shadow = shadow::ShadowPass::new(&gpu, 1);
+        let light_bind_group =
+            Self::create_light_bind_group(&gpu, &light_bind_group_layout, &light_buffer, &shadow);
+
+        let sample_count = gpu.msaa_samples();
+        let depth_texture = texture::DepthTexture::with_requirements(
+            device,
+            &gpu.get_config(),
+            sample_count,
+            Self::DEPTH_REQUIREMENTS,
+            "depth_texture",
+        )
+        .expect("depth readback without stencil needs no features");
+        debug_assert_eq!(depth_texture.format(), texture::Texture::DEPTH_FORMAT);
+        let msaa_texture = Self::create_msaa_texture(&gpu, &gpu.get_config(), sample_count);
 
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
@@ -353,32 +618,128 @@ impl Renderer {
                 push_constant_ranges: &[],
             });
             let shader = wgpu::include_wgsl!("sky.wgsl");
-            create_render_pipeline(
-                &gpu,
-                &layout,
-                hdr.format(),
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[],
-                wgpu::PrimitiveTopology::TriangleList,
-                shader,
-            )
+            PipelineBuilder::new(&layout, hdr.format(), shader)
+                .depth_format(Some(texture::Texture::DEPTH_FORMAT))
+                .sample_count(sample_count)
+                .build(&gpu)
         };
 
-        let render_pipeline = {
+        let procedural_pipeline = Self::create_procedural_pipeline(
+            &gpu,
+            &camera_bind_group_layout,
+            hdr.format(),
+            Some(texture::Texture::DEPTH_FORMAT),
+            sample_count,
+        );
+
+        let skin_layout = skin::Skin::layout(device);
+        let skinned_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Skinned Render Pipeline Layout"),
+                bind_group_layouts: &[
+                    &texture_bind_group_layout,
+                    &camera_bind_group_layout,
+                    &light_bind_group_layout,
+                    &skin_layout,
+                ],
+                push_constant_ranges: &[],
+            });
+
+        // Without color targets for the depth pre-pass, with the same vertex
+        // stage so the depth matches the scene pass exactly.
+        let scene_pipeline_builder = |skinned, depth_only| {
+            let (layout, defines, vertex_layouts): (_, &[&str], &[_]) = if skinned {
+                (
+                    &skinned_pipeline_layout,
+                    &["SKINNED"],
+                    &[
+                        model::ModelVertex::desc(),
+                        InstanceRaw::desc(),
+                        skin::JointVertex::desc(),
+                    ],
+                )
+            } else {
+                (
+                    &render_pipeline_layout,
+                    &[],
+                    &[model::ModelVertex::desc(), InstanceRaw::desc()],
+                )
+            };
             let shader = wgpu::ShaderModuleDescriptor {
                 label: Some("Normal Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
+                source: wgpu::ShaderSource::Wgsl(
+                    concat!(
+                        include_str!("sample_level.wgsl"),
+                        include_str!("shader.wgsl")
+                    )
+                    .into(),
+                ),
             };
-            create_render_pipeline(
-                &gpu,
-                &render_pipeline_layout,
-                hdr.format(),
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[model::ModelVertex::desc(), InstanceRaw::desc()],
-                wgpu::PrimitiveTopology::TriangleList,
-                shader,
-            )
+            let builder = if depth_only {
+                PipelineBuilder::depth_only(layout, texture::Texture::DEPTH_FORMAT, shader)
+            } else {
+                PipelineBuilder::new(layout, hdr.format(), shader)
+                    .depth_format(Some(texture::Texture::DEPTH_FORMAT))
+            };
+            builder
+                .defines(defines)
+                .expect("shader.wgsl's #ifdef blocks are balanced")
+                .vertex_layouts(vertex_layouts)
+                .sample_count(sample_count)
+        };
+        let scene_pipeline = |polygon_mode, blend, skinned| {
+            scene_pipeline_builder(skinned, false)
+                .polygon_mode(polygon_mode)
+                .blend(blend)
+                .depth_write(blend == BlendPreset::Replace)
+                .build(&gpu)
+        };
+        let render_pipeline = scene_pipeline(wgpu::PolygonMode::Fill, BlendPreset::Replace, false);
+        let wireframe_pipeline = device
+            .features()
+            .contains(wgpu::Features::POLYGON_MODE_LINE)
+            .then(|| scene_pipeline(wgpu::PolygonMode::Line, BlendPreset::Replace, false));
+        let transparent_pipeline =
+            scene_pipeline(wgpu::PolygonMode::Fill, BlendPreset::AlphaBlend, false);
+        let skinned_pipeline = scene_pipeline(wgpu::PolygonMode::Fill, BlendPreset::Replace, true);
+        let skinned_transparent_pipeline =
+            scene_pipeline(wgpu::PolygonMode::Fill, BlendPreset::AlphaBlend, true);
+        let prepass_pipeline = scene_pipeline_builder(false, true).build(&gpu);
+        let skinned_prepass_pipeline = scene_pipeline_builder(true, true).build(&gpu);
+        let equal_depth_pipeline = |skinned| {
+            scene_pipeline_builder(skinned, false)
+                .depth_compare(wgpu::CompareFunction::Equal)
+                .depth_write(false)
+                .build(&gpu)
         };
+        let skinned_equal_depth_pipeline = equal_depth_pipeline(true);
+        let equal_depth_pipeline = equal_depth_pipeline(false);
+
+        let profiler = profiler::Profiler::new(&gpu);
+        let debug = debug::DebugRenderer::new(
+            &gpu,
+            &camera_bind_group_layout,
+            hdr.format(),
+            Some(texture::Texture::DEPTH_FORMAT),
+            sample_count,
+        );
+        let vertex_color_material = model::VertexColorMaterial::new(
+            &gpu,
+            &camera_bind_group_layout,
+            hdr.format(),
+            Some(texture::Texture::DEPTH_FORMAT),
+            sample_count,
+        );
+
+        let text = text::TextRenderer::new(&gpu, text::DEFAULT_FONT, gpu.surface_format())
+            .expect("the bundled font parses");
+        let sprites = sprite::SpriteBatch::new(
+            &gpu,
+            hdr.format(),
+            Some(texture::Texture::DEPTH_FORMAT),
+            sample_count,
+            BlendPreset::AlphaBlend,
+        );
 
         let mut bind_group_db = BindGroupDB::default();
 
@@ -391,28 +752,384 @@ impl Renderer {
             envoronment_bind_group: environment_bind_group,
             gpu,
             depth_texture,
+            msaa_texture,
+            sample_count,
             hdr,
             size,
             render_pipeline,
+            wireframe_pipeline,
+            transparent_pipeline,
+            skinned_pipeline,
+            skinned_transparent_pipeline,
+            prepass_pipeline,
+            skinned_prepass_pipeline,
+            equal_depth_pipeline,
+            skinned_equal_depth_pipeline,
             window,
             camera: static_camera,
             camera_uniform,
             camera_bind_group,
+            frame_uniform,
             camera_buffer,
             light_buffer,
             light_uniform,
+            light_bind_group_layout,
             light_bind_group,
-            light_render_pipeline,
+            shadow,
+            shadow_settings: None,
             camera_controller,
             bind_group_db,
             sky_pipeline,
+            procedural_pipeline,
+            clear_color: Some(wgpu::Color::BLACK),
+            gbuffer: None,
+            hiz: None,
+            culled: Vec::new(),
+            auto_exposure: None,
+            profiler,
+            debug,
+            text,
+            sprites,
+            colored_meshes: Vec::new(),
+            vertex_color_material,
+            picking: None,
+            static_scene: None,
+            static_camera_slot,
+            uploads: upload::UploadScheduler::default(),
+            render_scale: 1.0,
+            viewport: None,
+            scissor_rect: None,
+            frame_budget: None,
+            last_update: Instant::now(),
+            frame_delta: Duration::ZERO,
         }
     }
 
+    fn create_light_bind_group(
+        gpu: &Gpu,
+        layout: &wgpu::BindGroupLayout,
+        light_buffer: &wgpu::Buffer,
+        shadow: &shadow::ShadowPass,
+    ) -> wgpu::BindGroup {
+        let [shadow_uniform, shadow_map, shadow_sampler] = shadow.bind_group_entries();
+        gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Light Bind Group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: light_buffer.as_entire_binding(),
+                },
+                shadow_uniform,
+                shadow_map,
+                shadow_sampler,
+            ],
+        })
+    }
+
+    /// Draws the meshes of [`ModelEntry::set_procedural_instances`] with the
+    /// camera bound to group 0.
+    fn create_procedural_pipeline(
+        gpu: &Gpu,
+        camera_layout: &wgpu::BindGroupLayout,
+        color_format: wgpu::TextureFormat,
+        depth_format: Option<wgpu::TextureFormat>,
+        sample_count: u32,
+    ) -> wgpu::RenderPipeline {
+        let layout = gpu
+            .device
+            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Procedural Pipeline Layout"),
+                bind_group_layouts: &[camera_layout],
+                push_constant_ranges: &[],
+            });
+        let shader = wgpu::include_wgsl!("procedural.wgsl");
+        PipelineBuilder::new(&layout, color_format, shader)
+            .depth_format(depth_format)
+            .vertex_layouts(&[ModelVertex::desc()])
+            .sample_count(sample_count)
+            .build(gpu)
+    }
+
+    /// Multisampled color target resolved into the HDR texture, `None` when
+    /// MSAA is off.
+    fn create_msaa_texture(
+        gpu: &Gpu,
+        config: &wgpu::SurfaceConfiguration,
+        sample_count: u32,
+    ) -> Option<texture::Texture> {
+        (sample_count > 1).then(|| {
+            texture::Texture::create_multisampled_texture(
+                &gpu.device,
+                config,
+                hdr::HdrPipeline::FORMAT,
+                sample_count,
+                "msaa_texture",
+            )
+        })
+    }
+
+    /// A renderer for the same window drawing with `gpu`, e.g. once the
+    /// device this one drew with was lost. The cameras, the light and the
+    /// other settings carry over. Debug lines, text, sprites and colored
+    /// meshes live on the old device and have to be added again.
+    async fn recreate(&self, gpu: Arc<Gpu>) -> Self {
+        let mut renderer = Self::new(
+            Arc::clone(&self.window),
+            gpu,
+            Arc::clone(&self.camera_controller),
+            Arc::clone(&self.camera),
+        )
+        .await;
+        renderer.set_light_uniform(self.light_uniform, self.shadow_settings);
+        renderer.set_clear_color(self.clear_color);
+        renderer.set_render_scale(self.render_scale);
+        renderer.set_viewport(self.viewport);
+        renderer.set_scissor_rect(self.scissor_rect);
+        renderer.set_frame_budget(self.frame_budget);
+        renderer.set_gbuffer_enabled(self.gbuffer.is_some());
+        renderer.set_occlusion_culling_enabled(self.hiz.is_some());
+        renderer.set_auto_exposure_enabled(self.auto_exposure.is_some());
+        renderer.set_static_scene_enabled(self.static_scene.is_some());
+        renderer
+    }
+
     pub fn window(&self) -> &Window {
         &self.window
     }
 
+    /// `Some(color)` clears the frame to `color` before drawing, `None` keeps
+    /// the previous contents.
+    pub fn set_clear_color(&mut self, color: Option<wgpu::Color>) {
+        self.clear_color = color;
+    }
+
+    /// Toggles the G-buffer (linear depth and view space normals) consumed
+    /// by screen space effects such as SSR.
+    pub fn set_gbuffer_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.gbuffer = None;
+        } else if self.sample_count > 1 {
+            log::warn!("The G-buffer does not support MSAA, leaving it disabled");
+        } else if self.gbuffer.is_none() {
+            let camera_entry = self.bind_group_db.get(self.camera_bind_group);
+            let size = scaled_size(self.size, self.render_scale);
+            self.gbuffer = Some(gbuffer::GBuffer::new(
+                &self.gpu,
+                &camera_entry.layout,
+                size.width,
+                size.height,
+            ));
+        }
+    }
+
+    pub fn gbuffer(&self) -> Option<&gbuffer::GBuffer> {
+        self.gbuffer.as_ref()
+    }
+
+    /// Toggles building the Hi-Z pyramid from the depth buffer after the
+    /// scene pass, which [`hiz::HiZPass::cull`] tests the instances of
+    /// opaque models against before the next frame draws them. Culling uses
+    /// the previous frame's depth, so what comes into view from behind an
+    /// occluder shows up a frame late. The static scene isn't culled.
+    pub fn set_occlusion_culling_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.hiz = None;
+        } else if self.sample_count > 1 {
+            log::warn!("Hi-Z occlusion culling does not support MSAA, leaving it disabled");
+        } else if self.hiz.is_none() {
+            let size = scaled_size(self.size, self.render_scale);
+            self.hiz = Some(hiz::HiZPass::new(&self.gpu, size.width, size.height));
+        }
+    }
+
+    /// Pyramid of the last rendered frame, `None` unless occlusion culling
+    /// is enabled.
+    pub fn hiz(&self) -> Option<&hiz::HiZPass> {
+        self.hiz.as_ref()
+    }
+
+    /// Changes how the camera projects the scene from the next update on,
+    /// e.g. to [`camera::ProjectionKind::Orthographic`] for a top down view.
+    pub fn set_projection(&mut self, projection: camera::ProjectionKind) {
+        self.camera.write().unwrap().projection = projection;
+    }
+
+    /// Runs `f` with the controller that moves the camera, e.g. to switch
+    /// to [`camera::CameraMode::Fly`] or rebind its keys.
+    pub fn with_camera_controller<R>(&self, f: impl FnOnce(&mut CameraController) -> R) -> R {
+        f(&mut self.camera_controller.write().unwrap())
+    }
+
+    /// Replaces the scene light, uploaded with the next update. Its
+    /// [`light::ShadowSettings`] take effect from the next frame.
+    pub fn set_light(&mut self, light: light::Light) {
+        self.set_light_uniform(light.into(), light.shadow);
+    }
+
+    fn set_light_uniform(
+        &mut self,
+        light_uniform: LightUniform,
+        shadow_settings: Option<light::ShadowSettings>,
+    ) {
+        self.light_uniform = light_uniform;
+        self.gpu
+            .write_uniform(&self.light_buffer, 0, &self.light_uniform);
+
+        self.shadow_settings = shadow_settings;
+        let map_size = match (light_uniform.directional != 0, shadow_settings) {
+            (true, Some(settings)) => settings.map_size,
+            _ => 1,
+        };
+        if map_size != self.shadow.map_size() {
+            self.shadow.resize(&self.gpu, map_size);
+            self.light_bind_group = Self::create_light_bind_group(
+                &self.gpu,
+                &self.light_bind_group_layout,
+                &self.light_buffer,
+                &self.shadow,
+            );
+            // The static scene bundle binds the old light bind group.
+            self.invalidate_static_scene();
+        }
+    }
+
+    /// Toggles adapting the exposure to the scene's average luminance,
+    /// disabling it goes back to an exposure of 1.
+    pub fn set_auto_exposure_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.auto_exposure = None;
+            self.hdr.set_exposure(&self.gpu.queue, 1.0);
+        } else if self.auto_exposure.is_none() {
+            self.auto_exposure = Some(exposure::AutoExposure::new(&self.gpu));
+        }
+    }
+
+    /// `None` unless auto exposure is enabled, e.g. to change the adaptation
+    /// speed.
+    pub fn auto_exposure_mut(&mut self) -> Option<&mut exposure::AutoExposure> {
+        self.auto_exposure.as_mut()
+    }
+
+    /// Adds a triangle list colored per vertex, e.g. voxel terrain, drawn
+    /// unlit with the scene once per instance until
+    /// [`Renderer::clear_colored_meshes`].
+    pub fn add_colored_mesh(&mut self, vertices: &[ColorVertex], instances: &[Instance]) {
+        self.colored_meshes.push(model::ColoredMesh::new(
+            &self.gpu.device,
+            vertices,
+            instances,
+        ));
+    }
+
+    pub fn clear_colored_meshes(&mut self) {
+        self.colored_meshes.clear();
+    }
+
+    /// Toggles recording the opaque draws once into a render bundle replayed
+    /// every frame instead of encoding them again. Frustum culling no longer
+    /// applies to them.
+    pub fn set_static_scene_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.static_scene = None;
+        } else if self.static_scene.is_none() {
+            self.static_scene = Some(bundle::BundleCache::default());
+        }
+    }
+
+    /// Records the static scene again on the next frame. Adding, removing or
+    /// replacing models, changing their instances or materials and toggling
+    /// wireframe do this on their own.
+    pub fn invalidate_static_scene(&mut self) {
+        if let Some(static_scene) = &mut self.static_scene {
+            static_scene.invalidate();
+        }
+    }
+
+    /// How often the static scene was recorded since it was enabled, `None`
+    /// while it's disabled. Growing every frame means something keeps
+    /// invalidating it.
+    pub fn static_scene_recordings(&self) -> Option<usize> {
+        self.static_scene
+            .as_ref()
+            .map(|static_scene| static_scene.recordings())
+    }
+
+    /// Lines and boxes to draw over the next frame, e.g. the bounds of every
+    /// model to see what frustum culling works with. They're cleared once
+    /// the frame is submitted.
+    pub fn debug_mut(&mut self) -> &mut debug::DebugRenderer {
+        &mut self.debug
+    }
+
+    /// Text to draw over this frame, in pixels from the top left of the
+    /// window. Cleared once the frame is rendered.
+    pub fn text_mut(&mut self) -> &mut text::TextRenderer {
+        &mut self.text
+    }
+
+    /// Camera facing sprites to draw with the scene this frame, in world
+    /// units, e.g. particles. Textures are added to the batch once, the
+    /// sprites are cleared once the frame is submitted.
+    pub fn sprites_mut(&mut self) -> &mut sprite::SpriteBatch {
+        &mut self.sprites
+    }
+
+    /// The model under `position` in the window as of the current camera and
+    /// instances, `None` over the background. `models` are what was drawn,
+    /// with the ids they're stored under. Logical positions are scaled by the
+    /// window's scale factor, so cursor positions from winit work either way.
+    pub fn pick<'a>(
+        &mut self,
+        models: impl Iterator<Item = (ModelId, &'a ModelEntry)>,
+        position: impl Into<winit::dpi::Position>,
+    ) -> anyhow::Result<Option<ModelId>> {
+        let size = self.render_size();
+        let position = position
+            .into()
+            .to_physical::<f64>(self.window.scale_factor());
+        let Some(pixel) = picking::framebuffer_pixel(position, self.render_scale, size) else {
+            return Ok(None);
+        };
+
+        let camera_entry = self.bind_group_db.get(self.camera_bind_group);
+        let picking = self.picking.get_or_insert_with(|| {
+            picking::PickingPass::new(&self.gpu, &camera_entry.layout, size.width, size.height)
+        });
+        if picking.size() != size {
+            picking.resize(&self.gpu, size.width, size.height);
+        }
+        let camera_bind_group = camera_entry.bind_group.as_ref().unwrap();
+        picking.pick(&self.gpu, camera_bind_group, models, pixel)
+    }
+
+    /// What shaders read from [`uniform::FrameUniform`] this frame.
+    pub fn frame_uniform(&self) -> &uniform::FrameUniform {
+        self.frame_uniform.uniform()
+    }
+
+    /// Queue for uploads that don't have to land this frame.
+    pub fn uploads_mut(&mut self) -> &mut upload::UploadScheduler {
+        &mut self.uploads
+    }
+
+    /// Caps the bytes of queued uploads written each frame.
+    pub fn set_upload_budget(&mut self, bytes: u64) {
+        self.uploads.set_budget(bytes);
+    }
+
+    /// GPU time of the scene pass a few frames ago, `None` without
+    /// [`wgpu::Features::TIMESTAMP_QUERY`].
+    pub fn gpu_frame_ms(&self) -> Option<f64> {
+        self.profiler.last_frame_ms()
+    }
+
+    /// The scene pass and the frame's markers on the GPU timeline over the
+    /// last frames read back, see [`profiler::GpuTrace::to_chrome_json`].
+    pub fn gpu_trace(&self) -> &profiler::GpuTrace {
+        self.profiler.trace()
+    }
+
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
@@ -420,19 +1137,87 @@ impl Renderer {
             let device = &self.gpu.device;
 
             let mut config_write = self.gpu.get_config_mut();
-            let surface = &self.gpu.surface;
 
             config_write.width = new_size.width;
             config_write.height = new_size.height;
 
-            surface.configure(device, &config_write);
-            self.depth_texture = Some(texture::Texture::create_depth_texture(
-                &device,
-                &config_write,
-                "depth_texture",
-            ));
-            self.hdr
-                .resize(&self.gpu, self.size.width, self.size.height);
+            if let Some(surface) = &self.gpu.surface {
+                surface.configure(device, &config_write);
+            }
+            drop(config_write);
+            self.resize_targets();
+        }
+    }
+
+    /// Scale of the offscreen targets relative to the surface, the HDR pass
+    /// upscales the result to the surface.
+    pub fn render_scale(&self) -> f32 {
+        self.render_scale
+    }
+
+    pub fn set_render_scale(&mut self, scale: f32) {
+        let scale = scale.clamp(MIN_RENDER_SCALE, 1.0);
+        if scale != self.render_scale {
+            self.render_scale = scale;
+            self.resize_targets();
+        }
+    }
+
+    /// Draws the scene into part of the window only, in window pixels,
+    /// `None` for all of it. Rendering again with another viewport and
+    /// camera and the clear color off makes a split screen. Parts outside
+    /// the window are cut off.
+    pub fn set_viewport(&mut self, viewport: Option<viewport::Viewport>) {
+        self.viewport = viewport;
+    }
+
+    /// Keeps the scene's fragments inside `rect`, in window pixels, `None`
+    /// to keep them all. Parts outside the window are cut off.
+    pub fn set_scissor_rect(&mut self, rect: Option<viewport::ScissorRect>) {
+        self.scissor_rect = rect;
+    }
+
+    /// Size of the offscreen targets the scene is rendered into.
+    pub fn render_size(&self) -> PhysicalSize<u32> {
+        scaled_size(self.size, self.render_scale)
+    }
+
+    /// `Some(budget)` lets [`Renderer::update_render_scale`] trade resolution
+    /// for frame time, `None` keeps the current scale.
+    pub fn set_frame_budget(&mut self, budget: Option<Duration>) {
+        self.frame_budget = budget;
+    }
+
+    /// Lowers the render scale when `frame_time` goes over the frame budget
+    /// and slowly raises it back once there is headroom.
+    pub fn update_render_scale(&mut self, frame_time: Duration) {
+        let Some(budget) = self.frame_budget else {
+            return;
+        };
+        // The dead band keeps the scale from oscillating around the budget.
+        if frame_time > budget.mul_f32(1.1) {
+            self.set_render_scale(self.render_scale * 0.9);
+        } else if frame_time < budget.mul_f32(0.8) {
+            self.set_render_scale(self.render_scale * 1.05);
+        }
+    }
+
+    fn resize_targets(&mut self) {
+        let size = self.render_size();
+        let mut config = self.gpu.get_config().clone();
+        config.width = size.width;
+        config.height = size.height;
+
+        self.depth_texture.resize(&self.gpu.device, &config);
+        if let Some(msaa) = &mut self.msaa_texture {
+            msaa.resize(&self.gpu.device, size.width, size.height);
+        }
+        self.hdr.resize(&self.gpu, size.width, size.height);
+        if let Some(gbuffer) = &mut self.gbuffer {
+            gbuffer.resize(&self.gpu, size.width, size.height);
+        }
+        if let Some(hiz) = &mut self.hiz {
+            hiz.resize(&self.gpu, size.width, size.height);
         }
     }
 
@@ -442,116 +1227,839 @@ impl Renderer {
         false
     }
 
-    fn update(&mut self) {
+    /// Updates the camera and light, returning the time since the last update.
+    fn update(&mut self) -> Duration {
+        let now = Instant::now();
+        let dt = now - self.last_update;
+        self.last_update = now;
+        self.frame_delta = dt;
+
         let mut camera = self.camera.write().unwrap();
         self.camera_controller
             .write()
             .unwrap()
-            .update_camera(&mut *camera);
+            .update_camera(&mut *camera, dt);
 
         let (width, height) = self
             .gpu
             .get_config_read(|config| (config.width as f32, config.height as f32));
 
-        let projection = Projection::with_aspect(width, height);
+        let projection = Projection::with_kind(width, height, camera.projection);
 
         self.camera_uniform
             .update_view_projection(&projection, &mut *camera);
 
         // Update the light
-        let old_position: nalgebra::Vector3<_> = self.light_uniform.position.into();
-        let isom = na::Isometry3::new(old_position, *na::Vector3::y_axis());
+        if self.light_uniform.directional == 0 {
+            let old_position: nalgebra::Vector3<_> = self.light_uniform.position.into();
+            let isom = na::Isometry3::new(old_position, *na::Vector3::y_axis());
 
-        let queue = &self.gpu.queue;
+            self.light_uniform.position = isom.translation.into();
+        }
+        self.gpu
+            .write_uniform(&self.light_buffer, 0, &self.light_uniform);
+        self.gpu
+            .write_uniform(&self.camera_buffer, 0, &self.camera_uniform);
+        self.frame_uniform.advance(&self.gpu, dt);
 
-        self.light_uniform.position = isom.translation.into();
-        queue.write_buffer(
-            &self.light_buffer,
-            0,
-            bytemuck::cast_slice(&[self.light_uniform]),
-        );
-        queue.write_buffer(
-            &self.camera_buffer,
-            0,
-            bytemuck::cast_slice(&[self.camera_uniform]),
-        );
+        dt
     }
 
+    /// Renders into the main window. Failing to get its texture returns the
+    /// [`wgpu::SurfaceError`], e.g. to reconfigure a lost surface.
     pub fn render_models<'a>(
         &mut self,
         models: impl Iterator<Item = &'a ModelEntry>,
-    ) -> Result<(), wgpu::SurfaceError> {
-        let view = self.gpu.get_current_view();
-        let device = &self.gpu.device;
+    ) -> anyhow::Result<()> {
+        let Some(view) = self.gpu.get_current_view()? else {
+            log::warn!("Surface timeout, skipping the frame");
+            return Ok(());
+        };
+        self.render_models_to(models, &view)
+    }
+
+    /// Renders into a window added with [`Gpu::add_window`], at the main
+    /// window's resolution and aspect scaled to fit it.
+    pub fn render_models_to_window<'a>(
+        &mut self,
+        id: gpu::SurfaceId,
+        models: impl Iterator<Item = &'a ModelEntry>,
+    ) -> anyhow::Result<()> {
+        let Some(view) = self.gpu.window_view(id)? else {
+            log::warn!("Surface timeout, skipping the frame of window {id}");
+            return Ok(());
+        };
+        self.render_models_to(models, &view)
+    }
+
+    /// Renders into `target` instead of the surface, e.g. for render to
+    /// texture effects. `target` has to be a render attachment in the
+    /// surface format and is filled completely.
+    pub fn render_to_texture<'a>(
+        &mut self,
+        models: impl Iterator<Item = &'a ModelEntry>,
+        target: &texture::Texture,
+    ) -> anyhow::Result<()> {
+        let format = self.gpu.surface_format();
+        if target.texture.format() != format {
+            anyhow::bail!(
+                "Render target is {:?}, the output pipelines are built for {format:?}",
+                target.texture.format()
+            );
+        }
+        if !target
+            .texture
+            .usage()
+            .contains(wgpu::TextureUsages::RENDER_ATTACHMENT)
+        {
+            anyhow::bail!("Render target needs RENDER_ATTACHMENT usage");
+        }
+        self.render_models_to(models, &target.view)
+    }
+
+    fn render_models_to<'a>(
+        &mut self,
+        models: impl Iterator<Item = &'a ModelEntry>,
+        view: &wgpu::TextureView,
+    ) -> anyhow::Result<()> {
+        self.uploads.flush(&self.gpu);
+        self.debug.upload(&self.gpu);
+        self.text.upload(&self.gpu);
+        let camera_view = self.camera.read().unwrap().build_view_matrix();
+        self.sprites.set_view(sprite::SpriteView::billboard(
+            &self.camera_uniform.view_proj(),
+            &camera_view,
+        ));
+        self.sprites.upload(&self.gpu);
 
         let camera_bind_group_entry = self.bind_group_db.get(self.camera_bind_group);
         let camera_bind_group = camera_bind_group_entry.bind_group.as_ref().unwrap();
 
-        let config = self.gpu.get_config();
+        let depth_tex = &self.depth_texture;
+        let frustum = frustum::Frustum::from_view_proj(&self.camera_uniform.view_proj());
+        // Drawn on their own at the end of the opaque scene.
+        let (procedural, all_models): (Vec<_>, Vec<_>) =
+            models.partition(|entry| entry.procedural_instances.is_some());
+        let models = all_models
+            .iter()
+            .copied()
+            .filter(|entry| !self.gpu.culling() || frustum.intersects(&entry.aabb))
+            .collect::<Vec<_>>();
+
+        let wireframe = self.wireframe_pipeline.is_some() && self.gpu.wireframe();
+        // Lines don't cover the triangles the pre-pass fills.
+        let depth_prepass = self.gpu.depth_prepass() && !wireframe;
+        let (scene_pipeline, skinned_scene_pipeline) = match &self.wireframe_pipeline {
+            _ if depth_prepass => (
+                &self.equal_depth_pipeline,
+                &self.skinned_equal_depth_pipeline,
+            ),
+            Some(pipeline) if wireframe => (pipeline, &self.skinned_pipeline),
+            _ => (&self.render_pipeline, &self.skinned_pipeline),
+        };
+        let static_scene = self.static_scene.as_mut().map(|static_scene| {
+            let desc = wgpu::RenderBundleEncoderDescriptor {
+                label: Some("Static Scene Bundle"),
+                color_formats: &[Some(self.hdr.format())],
+                depth_stencil: Some(wgpu::RenderBundleDepthStencil {
+                    format: texture::Texture::DEPTH_FORMAT,
+                    depth_read_only: false,
+                    stencil_read_only: true,
+                }),
+                sample_count: self.sample_count,
+                multiview: None,
+            };
+            let revisions = all_models.iter().map(|entry| entry.revision).collect();
+            let key = (wireframe, depth_prepass, revisions);
+            static_scene.get_or_record(key, &self.gpu.device, &desc, |bundle| {
+                for entry in &all_models {
+                    set_scene_pipeline(
+                        bundle,
+                        &entry.model,
+                        scene_pipeline,
+                        skinned_scene_pipeline,
+                    );
+                    bundle.draw_meshes_instanced(
+                        &entry.model,
+                        &entry.instances,
+                        &entry.material_overrides,
+                        model::AlphaMode::Opaque,
+                        self.static_camera_slot.bind_group(),
+                        &self.light_bind_group,
+                    );
+                }
+            })
+        });
 
-        if let None = self.depth_texture {
-            self.depth_texture = Some(Texture::create_depth_texture(
-                device,
-                &config,
-                "Depth Texture",
-            ));
+        let mut encoder = self.gpu.create_cmd_encoder();
+        self.profiler.insert_marker(&mut encoder, "Frame start");
+        if static_scene.is_some() {
+            self.static_camera_slot
+                .select(&mut encoder, &self.camera_buffer);
         }
 
-        let depth_tex = self.depth_texture.as_ref().unwrap();
+        // Offscreen models still cast shadows into view.
+        let bounds = all_models
+            .iter()
+            .map(|entry| entry.aabb)
+            .reduce(|bounds, aabb| bounds.union(&aabb));
+        let casts_shadows = bounds.is_some_and(|bounds| {
+            self.shadow.update(
+                &self.gpu,
+                &self.light_uniform,
+                self.shadow_settings,
+                &bounds,
+            )
+        });
+
+        // Against the previous frame's pyramid, this frame's depth isn't
+        // drawn yet.
+        let hiz = self
+            .hiz
+            .as_ref()
+            .filter(|hiz| hiz.is_built() && static_scene.is_none());
+        if let Some(hiz) = hiz {
+            let view_proj = self.camera_uniform.view_proj();
+            let cullable = models.iter().filter(|entry| entry.occlusion_cullable());
+            for (i, entry) in cullable.enumerate() {
+                if i == self.culled.len() {
+                    self.culled.push(hiz::CulledInstances::new(
+                        &self.gpu.device,
+                        entry.instances.len(),
+                        entry.model.meshes.len() as u32,
+                    ));
+                }
+                let target = hiz::CullTarget {
+                    model: &entry.model,
+                    instances: &entry.instances,
+                    bounds: bounding_sphere(&entry.model),
+                };
+                hiz.cull(
+                    &self.gpu,
+                    &mut encoder,
+                    target,
+                    view_proj,
+                    &mut self.culled[i],
+                );
+            }
+        }
+        let culled = || hiz.map(|_| self.culled.iter());
+        // Queued ahead of the graph's passes, which draw what it culled.
+        self.gpu.submit_cmd(encoder.finish());
+
+        let size = scaled_size(self.size, self.render_scale);
+        let viewport = self.viewport.map(|viewport| {
+            viewport
+                .scaled(self.render_scale)
+                .clamped(size.width, size.height)
+        });
+        let scissor_rect = self.scissor_rect.map(|rect| {
+            rect.scaled(self.render_scale)
+                .clamped(size.width, size.height)
+        });
+        let clip = |render_pass: &mut wgpu::RenderPass<'_>| {
+            match viewport {
+                Some(Some(viewport)) => viewport.apply(render_pass),
+                // Nothing of it is on screen.
+                Some(None) => render_pass.set_scissor_rect(0, 0, 0, 0),
+                None => {}
+            }
+            if let Some(rect) = scissor_rect {
+                rect.apply(render_pass);
+            }
+        };
+
+        let eye = self.camera.read().unwrap().position;
+        let mut graph = graph::RenderGraph::new();
+        let shadow_map = graph.transient(
+            "Shadow Map",
+            &self.shadow.map().view,
+            graph::TextureInit::ClearDepth(1.0),
+        );
+        let depth_init = match depth_tex.format().has_stencil_aspect() {
+            true => graph::TextureInit::ClearDepthStencil(1.0, 0),
+            false => graph::TextureInit::ClearDepth(1.0),
+        };
+        // The G-buffer and the Hi-Z pyramid are built from it afterwards.
+        let depth = graph.import("Depth", depth_tex.view(), depth_init);
+        let color_init = match self.clear_color {
+            Some(color) => graph::TextureInit::Clear(color),
+            None => graph::TextureInit::Load,
+        };
+        let hdr = graph.import("HDR", self.hdr.view(), color_init);
+        let msaa = self
+            .msaa_texture
+            .as_ref()
+            .map(|msaa| graph.transient("MSAA", &msaa.view, color_init));
+
+        if casts_shadows {
+            graph
+                .add_pass("Shadow Pass")
+                .depth(shadow_map)
+                .record(|encoder, targets| {
+                    self.shadow
+                        .render(&mut targets.begin(encoder), all_models.iter().copied());
+                })?;
+        }
+
+        if depth_prepass {
+            graph
+                .add_pass("Depth Pre-pass")
+                .depth(depth)
+                .record(|encoder, targets| {
+                    let mut render_pass = targets.begin(encoder);
+                    clip(&mut render_pass);
+                    draw_opaque(
+                        &mut render_pass,
+                        &models,
+                        culled(),
+                        &self.prepass_pipeline,
+                        &self.skinned_prepass_pipeline,
+                        camera_bind_group,
+                        &self.light_bind_group,
+                    );
+                })?;
+        }
+
+        let scene_pass = graph.add_pass("Render Pass").read(shadow_map).depth(depth);
+        let scene_pass = match msaa {
+            // The samples are resolved into the HDR texture at the end of the
+            // pass, there's no need to keep them around.
+            Some(msaa) => scene_pass.color_resolved(msaa, hdr),
+            None => scene_pass.color(hdr),
+        };
+        scene_pass.record(|encoder, targets| {
+            let mut render_pass = targets.begin_timed(encoder, self.profiler.timestamp_writes());
+            clip(&mut render_pass);
+
+            match static_scene {
+                Some(bundle) => render_pass.execute_bundles(std::iter::once(bundle)),
+                None => draw_opaque(
+                    &mut render_pass,
+                    &models,
+                    culled(),
+                    scene_pipeline,
+                    skinned_scene_pipeline,
+                    camera_bind_group,
+                    &self.light_bind_group,
+                ),
+            }
+
+            if !procedural.is_empty() {
+                render_pass.set_pipeline(&self.procedural_pipeline);
+            }
+            for entry in &procedural {
+                let count = entry.procedural_instances.unwrap_or_default();
+                for mesh in &entry.model.meshes {
+                    render_pass.draw_procedural_instanced(mesh, count, camera_bind_group);
+                }
+            }
+
+            for mesh in &self.colored_meshes {
+                mesh.draw(
+                    &mut render_pass,
+                    &self.vertex_color_material,
+                    camera_bind_group,
+                );
+            }
+
+            render_pass.set_pipeline(&self.sky_pipeline);
+            render_pass.set_bind_group(0, &camera_bind_group, &[]);
+            render_pass.set_bind_group(1, &self.envoronment_bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+
+            // Blended meshes go over everything else, farthest first.
+            let mut transparent = models
+                .iter()
+                .filter(|entry| {
+                    entry
+                        .model
+                        .materials
+                        .iter()
+                        .any(|material| material.alpha_mode == model::AlphaMode::Blend)
+                })
+                .collect::<Vec<_>>();
+            model::sort_back_to_front(&mut transparent, &eye, |entry| entry.position);
+
+            for entry in transparent {
+                set_scene_pipeline(
+                    &mut render_pass,
+                    &entry.model,
+                    &self.transparent_pipeline,
+                    &self.skinned_transparent_pipeline,
+                );
+                render_pass.draw_meshes_instanced(
+                    &entry.model,
+                    &entry.instances,
+                    &entry.material_overrides,
+                    model::AlphaMode::Blend,
+                    camera_bind_group,
+                    &self.light_bind_group,
+                )
+            }
+
+            self.sprites.draw(&mut render_pass);
+            self.debug.draw(&mut render_pass, camera_bind_group);
+        })?;
+        graph.execute(&self.gpu)?;
 
         let mut encoder = self.gpu.create_cmd_encoder();
+        self.profiler.insert_marker(&mut encoder, "Scene done");
+
+        if let Some(gbuffer) = &self.gbuffer {
+            gbuffer.process(
+                &mut encoder,
+                depth_tex.view(),
+                camera_bind_group,
+                models.iter().copied(),
+            );
+        }
+
+        if let Some(hiz) = &mut self.hiz {
+            hiz.build(&self.gpu, &mut encoder, depth_tex.view());
+        }
+
+        if let Some(auto_exposure) = &self.auto_exposure {
+            auto_exposure.process(
+                &self.gpu,
+                &mut encoder,
+                self.hdr.texture(),
+                self.frame_delta,
+            );
+            self.hdr
+                .copy_exposure(&mut encoder, auto_exposure.state_buffer());
+        }
 
+        self.hdr.process(&mut encoder, view);
+        self.text.render(&mut encoder, view);
+        self.profiler.resolve(&mut encoder);
+
+        self.gpu.submit_cmd(encoder.finish());
+        self.debug.clear();
+        self.text.clear();
+        self.sprites.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scaled_size() {
+        let size = PhysicalSize::new(1920, 1080);
+        assert_eq!(scaled_size(size, 1.0), size);
+        assert_eq!(scaled_size(size, 0.5), PhysicalSize::new(960, 540));
+        assert_eq!(
+            scaled_size(PhysicalSize::new(1, 1), 0.5),
+            PhysicalSize::new(1, 1)
+        );
+    }
+
+    #[test]
+    fn test_material_animator_updates_uniform() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping material animator test");
+            return Ok(());
+        };
+        let texture = texture::Texture::create_2d_texture(
+            &gpu,
+            1,
+            1,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            wgpu::TextureUsages::TEXTURE_BINDING,
+            wgpu::FilterMode::Linear,
+            None,
+        );
+        let model = model::Model {
+            meshes: Vec::new(),
+            materials: vec![model::Material::new(&gpu, "glow", texture)],
+            skin: None,
+        };
+        let mut entry = ModelEntry::new(&gpu, model);
+        entry.add_material_animator(animation::MaterialAnimator::new(0).with_keyframes(
+            animation::MaterialParam::EmissiveIntensity,
+            vec![
+                animation::Keyframe {
+                    time: 0.0,
+                    value: 0.0,
+                },
+                animation::Keyframe {
+                    time: 1.0,
+                    value: 2.0,
+                },
+            ],
+        ));
+
+        entry.animate_materials(&gpu, Duration::from_millis(500));
+        let material = &entry.model.materials[0];
+        let bytes = gpu.read_buffer(&material.uniform_buffer)?;
+        let uniform: model::MaterialUniform = bytemuck::pod_read_unaligned(&bytes);
+        assert!((uniform.emissive_intensity - 1.0).abs() < 1e-6);
+        // The animation starts from the material's own uniform every frame.
+        assert_eq!(material.uniform.emissive_intensity, 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_material_override_is_drawn() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping material override test");
+            return Ok(());
+        };
+        let device = &gpu.device;
+        // Fills the mesh with its material's diffuse color.
+        let shader = wgpu::ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(
+                "
+                @group(0) @binding(0) var t_diffuse: texture_2d<f32>;
+                @group(0) @binding(1) var s_diffuse: sampler;
+
+                @vertex
+                fn vs_main(@location(0) position: vec3<f32>) -> @builtin(position) vec4<f32> {
+                    return vec4<f32>(position, 1.0);
+                }
+
+                @fragment
+                fn fs_main() -> @location(0) vec4<f32> {
+                    return textureSample(t_diffuse, s_diffuse, vec2<f32>(0.5));
+                }
+                "
+                .into(),
+            ),
+        };
+        let uniform_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        // Stands in for the camera and the light, the shader reads neither.
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: &[0; 16],
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &uniform_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            }],
+        });
+        let material_layout = texture::Texture::get_bind_group_layout(&gpu);
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&material_layout, &uniform_layout, &uniform_layout],
+            push_constant_ranges: &[],
+        });
+        let format = wgpu::TextureFormat::Rgba8Unorm;
+        let pipeline = PipelineBuilder::new(&layout, format, shader)
+            .vertex_layouts(&[ModelVertex::desc(), InstanceRaw::desc()])
+            .try_build(&gpu)?;
+
+        // The left and the right half of the target, both with material 0.
+        let half = |x: f32| {
+            [[x, -1.0], [x + 1.0, -1.0], [x, 1.0], [x + 1.0, 1.0]].map(|[x, y]| ModelVertex {
+                position: [x, y, 0.0],
+                tex_coord: [0.0; 2],
+                normal: [0.0, 0.0, 1.0],
+                tangent: [1.0, 0.0, 0.0, 1.0],
+            })
+        };
+        let (left, right) = (half(-1.0), half(0.0));
+        let indices = [0, 1, 2, 2, 1, 3];
+        let meshes = model::Mesh::pack(
+            device,
+            "halves",
+            &[(&left, &indices, 0), (&right, &indices, 0)],
+        );
+        let material = |name, color| -> anyhow::Result<model::Material> {
+            let texture = texture::Texture::from_color(device, &gpu.queue, color, name)?;
+            Ok(model::Material::new(&gpu, name, texture))
+        };
+        let model = model::Model {
+            meshes,
+            materials: vec![
+                material("white", [255; 4])?,
+                material("red", [255, 0, 0, 255])?,
+            ],
+            skin: None,
+        };
+        let mut entry = ModelEntry::new(&gpu, model);
+        assert!(entry.set_material_override(2, Some(1)).is_err());
+        assert!(entry.set_material_override(1, Some(2)).is_err());
+        entry.set_material_override(1, Some(1))?;
+
+        // A row of 2 pixels, the height comes first.
+        let target = texture::Texture::create_2d_texture(
+            &gpu,
+            1,
+            2,
+            format,
+            wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            wgpu::FilterMode::Nearest,
+            None,
+        );
+        let mut encoder = device.create_command_encoder(&Default::default());
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass"),
+                label: None,
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: self.hdr.view(),
+                    view: &target.view,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
                         store: wgpu::StoreOp::Store,
                     },
                 })],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &depth_tex.view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: wgpu::StoreOp::Store,
-                    }),
-                    stencil_ops: None,
-                }),
+                depth_stencil_attachment: None,
                 occlusion_query_set: None,
                 timestamp_writes: None,
             });
+            draw_opaque(
+                &mut render_pass,
+                &[&entry],
+                None,
+                &pipeline,
+                &pipeline,
+                &uniform_bind_group,
+                &uniform_bind_group,
+            );
+        }
+        gpu.queue.submit([encoder.finish()]);
 
-            for entry in models {
-                let model = &entry.model;
-                let instances = &entry.instances;
-                let instane_buffer = &entry.instance_buffer;
+        // Only the overridden right half is red.
+        let pixels = gpu.read_texture(&target.texture)?.pixels;
+        assert_eq!(pixels, [255, 255, 255, 255, 255, 0, 0, 255]);
+        Ok(())
+    }
 
-                //render_pass.set_pipeline(&self.light_render_pipeline);
-                //render_pass.draw_light_model(model, camera_bind_group, &self.light_bind_group);
+    #[test]
+    fn test_set_instances_grows_the_buffer() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping instance upload test");
+            return Ok(());
+        };
+        let model = model::Model {
+            meshes: Vec::new(),
+            materials: Vec::new(),
+            skin: None,
+        };
+        let mut entry = ModelEntry::new(&gpu, model);
+        let size = entry.instances.buffer().size();
+        let at = |x: f32| Instance {
+            isometry: na::Isometry3::translation(x, 0.0, 0.0),
+        };
+        // The x translation of every instance in the buffer.
+        let uploaded = |entry: &ModelEntry| -> anyhow::Result<Vec<f32>> {
+            let bytes = gpu.read_buffer(entry.instances.buffer())?;
+            let floats = bytemuck::pod_collect_to_vec::<u8, f32>(&bytes);
+            let stride = std::mem::size_of::<InstanceRaw>() / 4;
+            Ok(floats.chunks(stride).map(|raw| raw[12]).collect())
+        };
 
-                render_pass.set_pipeline(&self.render_pipeline);
-                render_pass.set_vertex_buffer(1, instane_buffer.slice(..));
+        // Still fits, written in place.
+        entry.set_instances(&gpu, &[at(3.0)]);
+        assert_eq!(entry.instances.buffer().size(), size);
+        assert_eq!(uploaded(&entry)?, [3.0]);
+
+        let xs = (0..16).map(|i| i as f32).collect::<Vec<_>>();
+        entry.set_instances(&gpu, &xs.iter().map(|&x| at(x)).collect::<Vec<_>>());
+        assert!(entry.instances.buffer().size() > size);
+        assert_eq!(entry.instances.len(), 16);
+        assert_eq!(uploaded(&entry)?, xs);
+        assert_eq!(entry.position, na::Point3::new(7.5, 0.0, 0.0));
+        Ok(())
+    }
 
-                render_pass.draw_model_instanced(
-                    model,
-                    0..instances.len() as u32,
-                    camera_bind_group,
-                    &self.light_bind_group,
-                )
-            }
+    #[test]
+    fn test_models_are_reloaded_after_device_loss() -> anyhow::Result<()> {
+        let Ok(lost) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping model reload test");
+            return Ok(());
+        };
+        let path = PathBuf::from("res/cube.obj");
+        let model = futures::executor::block_on(resource::load_model(path.clone(), &lost))?;
+        let mut cube = ModelEntry::new(&lost, model).with_source(path);
+        let at = |x: f32| Instance {
+            isometry: na::Isometry3::translation(x, 0.0, 0.0),
+        };
+        cube.set_instances(&lost, &[at(0.0), at(1.0), at(2.0)]);
+        cube.instances
+            .set_visibility(&lost.queue, &[true, false, true]);
+        cube.set_material_override(0, Some(0))?;
+        let empty = model::Model {
+            meshes: Vec::new(),
+            materials: Vec::new(),
+            skin: None,
+        };
+        let resources = Resources::new();
+        let (cube_id, empty_id) = {
+            let mut model_db = resources.model_db.write().unwrap();
+            let cube_id = model_db.insert(cube);
+            (cube_id, model_db.insert(ModelEntry::new(&lost, empty)))
+        };
+        lost.device.destroy();
+        lost.device.poll(wgpu::Maintain::Wait);
+
+        let gpu = futures::executor::block_on(lost.recreate())?;
+        futures::executor::block_on(resources.reload_models(&gpu));
+        drop(lost);
+
+        let mut model_db = resources.model_db.write().unwrap();
+        // Without a file there's nothing to load it from.
+        assert!(model_db.get_mut(empty_id).is_none());
+        let cube = model_db.get_mut(cube_id).unwrap();
+        assert_eq!(cube.material_overrides[&0], 0);
+        assert_eq!(cube.instances.len(), 2);
+        let bytes = gpu.read_buffer(cube.instances.buffer())?;
+        let floats = bytemuck::pod_collect_to_vec::<u8, f32>(&bytes);
+        let stride = std::mem::size_of::<InstanceRaw>() / 4;
+        let xs = floats.chunks(stride).map(|raw| raw[12]).collect::<Vec<_>>();
+        // The hidden instance stays in the buffer, behind the visible ones.
+        assert_eq!(xs[..2], [0.0, 2.0]);
+        assert_eq!(xs.len(), 3);
+        Ok(())
+    }
 
-            render_pass.set_pipeline(&self.sky_pipeline);
-            render_pass.set_bind_group(0, &camera_bind_group, &[]);
-            render_pass.set_bind_group(1, &self.envoronment_bind_group, &[]);
-            render_pass.draw(0..3, 0..1);
-        }
+    #[test]
+    fn test_revision_changes_with_what_is_drawn() {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping model revision test");
+            return;
+        };
+        let empty = || model::Model {
+            meshes: Vec::new(),
+            materials: Vec::new(),
+            skin: None,
+        };
+        let mut entry = ModelEntry::new(&gpu, empty());
+        // A model replacing another one is recorded again, even though the
+        // count stays the same.
+        let replacement = ModelEntry::new(&gpu, empty());
+        assert_ne!(entry.revision, replacement.revision);
+
+        let revision = entry.revision;
+        assert!(entry.set_material_override(0, Some(0)).is_err());
+        assert_eq!(entry.revision, revision);
+        entry.set_instances(&gpu, &vec![Instance::default(); 2]);
+        assert_ne!(entry.revision, revision);
+    }
 
-        self.hdr.process(&mut encoder, &view);
+    #[test]
+    fn test_procedural_instances_fill_the_grid() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(8, 8)) else {
+            eprintln!("No adapter, skipping procedural instancing test");
+            return Ok(());
+        };
+        let device = &gpu.device;
+        let camera_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        // Looks down on the xz plane, one unit per pixel of the 8x8 target.
+        let view_proj = na::Matrix4::new(
+            0.25, 0.0, 0.0, -1.0, //
+            0.0, 0.0, 0.25, -1.0, //
+            0.0, 0.0, 0.0, 0.5, //
+            0.0, 0.0, 0.0, 1.0,
+        );
+        let mut camera = [0.0f32; std::mem::size_of::<CameraUniform>() / 4];
+        camera[20..36].copy_from_slice(view_proj.as_slice());
+        let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&camera),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &camera_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: camera_buffer.as_entire_binding(),
+            }],
+        });
 
-        self.gpu.submit_cmd(encoder.finish());
+        // A unit square facing up, wound both ways so culling keeps it.
+        let vertices = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0],
+            [1.0, 0.0, 1.0],
+        ]
+        .map(|position| ModelVertex {
+            position,
+            tex_coord: [0.0; 2],
+            normal: [0.0, 1.0, 0.0],
+            tangent: [1.0, 0.0, 0.0, 1.0],
+        });
+        let indices = [0, 1, 2, 2, 1, 3, 0, 2, 1, 2, 3, 1];
+        let meshes = model::Mesh::pack(device, "square", &[(&vertices, &indices, 0)]);
+
+        let format = wgpu::TextureFormat::Rgba8Unorm;
+        let pipeline = Renderer::create_procedural_pipeline(&gpu, &camera_layout, format, None, 1);
+        let target = texture::Texture::create_2d_texture(
+            &gpu,
+            8,
+            8,
+            format,
+            wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            wgpu::FilterMode::Nearest,
+            None,
+        );
+        let mut encoder = device.create_command_encoder(&Default::default());
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &target.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+            render_pass.set_pipeline(&pipeline);
+            render_pass.draw_procedural_instanced(&meshes[0], 16, &camera_bind_group);
+        }
+        gpu.queue.submit([encoder.finish()]);
+
+        // 4 squares per row 2 units apart, the first row at the bottom.
+        let pixels = gpu.read_texture(&target.texture)?.pixels;
+        let lit = |x: u32, y: u32| pixels[(y * 8 + x) as usize * 4 + 1] > 0;
+        for instance in 0..16 {
+            let (x, y) = (instance % 4 * 2, 7 - instance / 4 * 2);
+            assert!(
+                lit(x, y),
+                "instance {instance} at pixel ({x}, {y}) is missing"
+            );
+        }
+        let lit_count = (0..64).filter(|i| lit(i % 8, i / 8)).count();
+        assert_eq!(lit_count, 16);
         Ok(())
     }
 }
@@ -4,5 +4,166 @@ pub struct LightUniform {
     pub position: [f32; 3],
     pub _padding: u32,
     pub color: [f32; 3],
-    pub _paddding: u32,
+    /// Fraction of `color` reaching every surface whatever its normal.
+    pub ambient: f32,
+    /// Direction the light travels in, used instead of `position` by
+    /// directional lights.
+    pub direction: [f32; 3],
+    /// 1 for a directional light, 0 for a point light.
+    pub directional: u32,
+}
+
+/// Where the light of a [`Light`] comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightKind {
+    Point {
+        position: [f32; 3],
+    },
+    /// Infinitely far away like the sun, every surface is lit from the same
+    /// direction.
+    Directional {
+        direction: [f32; 3],
+    },
+}
+
+/// How a light casts shadows, see [`crate::shadow::ShadowPass`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShadowSettings {
+    /// Subtracted from a fragment's depth as seen from the light before it's
+    /// compared with the shadow map, in the light's `0..1` depth range. Too
+    /// low and surfaces shadow themselves in stripes (acne), too high and
+    /// shadows come loose from their casters (peter-panning).
+    pub bias: f32,
+    /// How far fragments are moved along their normal before the lookup, in
+    /// world units. Takes care of the acne on surfaces at grazing angles to
+    /// the light, where a depth bias would have to be large.
+    pub normal_bias: f32,
+    /// Width and height of the shadow map in texels.
+    pub map_size: u32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            bias: 0.002,
+            normal_bias: 0.02,
+            map_size: 2048,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Light {
+    pub kind: LightKind,
+    pub color: [f32; 3],
+    pub ambient: f32,
+    /// `None` lets light through everything. Only directional lights cast
+    /// shadows so far.
+    pub shadow: Option<ShadowSettings>,
+}
+
+impl Light {
+    pub const DEFAULT_AMBIENT: f32 = 0.1;
+
+    pub fn point(position: [f32; 3], color: [f32; 3]) -> Self {
+        Self {
+            kind: LightKind::Point { position },
+            color,
+            ambient: Self::DEFAULT_AMBIENT,
+            shadow: None,
+        }
+    }
+
+    pub fn directional(direction: [f32; 3], color: [f32; 3]) -> Self {
+        Self {
+            kind: LightKind::Directional { direction },
+            color,
+            ambient: Self::DEFAULT_AMBIENT,
+            shadow: Some(ShadowSettings::default()),
+        }
+    }
+
+    pub fn with_shadow(self, shadow: Option<ShadowSettings>) -> Self {
+        Self { shadow, ..self }
+    }
+}
+
+/// Layout of the light bind group at group 2 of the scene shaders: the
+/// [`LightUniform`], then the [`crate::shadow::ShadowPass`]'s uniform, map
+/// and comparison sampler.
+pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    let uniform = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        count: None,
+        visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+    };
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("Light Bind Group Layout"),
+        entries: &[
+            uniform(0),
+            uniform(1),
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                count: None,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Depth,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                count: None,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+            },
+        ],
+    })
+}
+
+impl From<Light> for LightUniform {
+    fn from(light: Light) -> Self {
+        let (position, direction, directional) = match light.kind {
+            LightKind::Point { position } => (position, [0.0, -1.0, 0.0], 0),
+            LightKind::Directional { direction } => {
+                let direction = nalgebra::Vector3::from(direction)
+                    .try_normalize(f32::EPSILON)
+                    .unwrap_or(-nalgebra::Vector3::y());
+                ([0.0; 3], direction.into(), 1)
+            }
+        };
+        Self {
+            position,
+            _padding: 0,
+            color: light.color,
+            ambient: light.ambient,
+            direction,
+            directional,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directional_light_uniform() {
+        let uniform = LightUniform::from(Light::directional([0.0, -2.0, 0.0], [1.0; 3]));
+        assert_eq!(uniform.direction, [0.0, -1.0, 0.0]);
+        assert_eq!(uniform.directional, 1);
+        assert_eq!(uniform.ambient, Light::DEFAULT_AMBIENT);
+
+        let uniform = LightUniform::from(Light::point([2.0; 3], [1.0; 3]));
+        assert_eq!(uniform.position, [2.0; 3]);
+        assert_eq!(uniform.directional, 0);
+        // Matches the WGSL struct, vec3s are aligned to 16 bytes.
+        assert_eq!(std::mem::size_of::<LightUniform>(), 48);
+    }
 }
@@ -1,33 +1,491 @@
 use na::*;
 use nalgebra as na;
-use std::{mem, ops::Range};
+use std::{collections::HashMap, mem, ops::Range, path::Path, sync::Arc};
 
-use crate::texture;
+use crate::{
+    decimate::decimate,
+    frustum::Aabb,
+    gpu::Gpu,
+    hiz::CulledInstances,
+    skin::{JointVertex, Skin},
+    texture,
+};
+use wgpu::util::{DeviceExt, RenderEncoder};
 
 pub trait Vertex {
     fn desc() -> wgpu::VertexBufferLayout<'static>;
 }
 
+/// Per material shading parameters, bound next to the diffuse texture.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MaterialUniform {
+    /// Multiplied with the diffuse texture.
+    pub base_color: [f32; 4],
+    pub emissive: [f32; 3],
+    pub emissive_intensity: f32,
+    /// Mip level the diffuse texture is always sampled at, negative to let
+    /// the GPU pick one. See [`MaterialUniform::with_mip_level`].
+    pub mip_level: f32,
+    /// Multiplied with the blue channel of the metallic-roughness map.
+    pub metallic: f32,
+    /// Multiplied with the green channel of the metallic-roughness map.
+    pub roughness: f32,
+    // Uniform structs are padded to 16 bytes.
+    _padding: f32,
+}
+
+impl Default for MaterialUniform {
+    fn default() -> Self {
+        Self {
+            base_color: [1.0; 4],
+            emissive: [1.0; 3],
+            emissive_intensity: 0.0,
+            mip_level: -1.0,
+            metallic: 0.0,
+            // The highlight the shader had before materials were rough.
+            roughness: 0.5,
+            _padding: 0.0,
+        }
+    }
+}
+
+impl MaterialUniform {
+    /// `Some(level)` pins the diffuse texture to mip `level`, e.g. for UI
+    /// textures that shouldn't blur at grazing angles.
+    pub fn with_mip_level(self, level: Option<f32>) -> Self {
+        Self {
+            mip_level: level.map_or(-1.0, |level| level.max(0.0)),
+            ..self
+        }
+    }
+
+    /// The glTF metallic and roughness factors.
+    pub fn with_metallic_roughness(self, metallic: f32, roughness: f32) -> Self {
+        Self {
+            metallic: metallic.clamp(0.0, 1.0),
+            roughness: roughness.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+}
+
+/// How a material's alpha combines with what's behind it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum AlphaMode {
+    /// Alpha is ignored and the surface hides everything behind it.
+    #[default]
+    Opaque,
+    /// Blended over what's behind, drawn after opaque surfaces from back to
+    /// front.
+    Blend,
+}
+
+/// The PBR maps of a [`Material`] besides the diffuse texture. Missing maps
+/// are replaced by 1x1 textures that leave the shading unchanged.
+#[derive(Default)]
+pub struct MaterialMaps {
+    /// Tangent space normals, flat when missing.
+    pub normal: Option<texture::Texture>,
+    /// Roughness in green and metalness in blue like glTF, white when
+    /// missing so only the [`MaterialUniform`] factors apply.
+    pub metallic_roughness: Option<texture::Texture>,
+    /// Ambient occlusion in red, white when missing.
+    pub occlusion: Option<texture::Texture>,
+}
+
 pub struct Material {
     pub name: String,
+    pub alpha_mode: AlphaMode,
     pub bind_group: wgpu::BindGroup,
     pub diffuse_texture: texture::Texture,
+    pub normal_texture: texture::Texture,
+    pub metallic_roughness_texture: texture::Texture,
+    pub occlusion_texture: texture::Texture,
+    pub uniform: MaterialUniform,
+    pub uniform_buffer: wgpu::Buffer,
+}
+
+impl Material {
+    /// Straight up in tangent space.
+    const FLAT_NORMAL: [u8; 4] = [128, 128, 255, 255];
+
+    /// A material with only a diffuse texture.
+    pub fn new(gpu: &Gpu, name: &str, diffuse_texture: texture::Texture) -> Self {
+        Self::with_maps(gpu, name, diffuse_texture, MaterialMaps::default())
+    }
+
+    pub fn with_maps(
+        gpu: &Gpu,
+        name: &str,
+        diffuse_texture: texture::Texture,
+        maps: MaterialMaps,
+    ) -> Self {
+        let (device, queue) = (&gpu.device, &gpu.queue);
+        let fallback = |map: Option<texture::Texture>, color, label| {
+            map.unwrap_or_else(|| {
+                texture::Texture::from_color(device, queue, color, label)
+                    .expect("1x1 textures are always valid")
+            })
+        };
+        let normal_texture = fallback(maps.normal, Self::FLAT_NORMAL, "flat normal map");
+        let metallic_roughness_texture = fallback(
+            maps.metallic_roughness,
+            [255; 4],
+            "white metallic-roughness map",
+        );
+        let occlusion_texture = fallback(maps.occlusion, [255; 4], "white occlusion map");
+
+        let uniform = MaterialUniform::default();
+        let uniform_buffer = gpu
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{:?} Material Buffer", name)),
+                contents: bytemuck::cast_slice(&[uniform]),
+                usage: wgpu::BufferUsages::UNIFORM
+                    | wgpu::BufferUsages::COPY_DST
+                    | wgpu::BufferUsages::COPY_SRC,
+            });
+        let bind_group = texture::Texture::load(
+            gpu,
+            &diffuse_texture,
+            [
+                &normal_texture,
+                &metallic_roughness_texture,
+                &occlusion_texture,
+            ],
+            &uniform_buffer,
+        );
+
+        Self {
+            name: name.to_string(),
+            alpha_mode: AlphaMode::default(),
+            bind_group,
+            diffuse_texture,
+            normal_texture,
+            metallic_roughness_texture,
+            occlusion_texture,
+            uniform,
+            uniform_buffer,
+        }
+    }
+
+    /// Stores `uniform` and uploads it for the next frame.
+    pub fn set_uniform(&mut self, queue: &wgpu::Queue, uniform: MaterialUniform) {
+        self.uniform = uniform;
+        self.write_uniform(queue, &uniform);
+    }
+
+    /// Uploads `uniform` without changing the material's own parameters, the
+    /// way animations override them frame by frame.
+    pub fn write_uniform(&self, queue: &wgpu::Queue, uniform: &MaterialUniform) {
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[*uniform]));
+    }
 }
 
 pub struct Mesh {
     pub name: String,
-    pub vertex_buffer: wgpu::Buffer,
-    pub index_buffer: wgpu::Buffer,
+    /// Shared with the other meshes [`Mesh::pack`]ed with this one.
+    pub vertex_buffer: Arc<wgpu::Buffer>,
+    pub index_buffer: Arc<wgpu::Buffer>,
+    /// The bytes of `index_buffer` holding this mesh's indices.
+    pub index_range: Range<wgpu::BufferAddress>,
+    pub index_format: wgpu::IndexFormat,
     pub num_elements: u32,
+    /// Added to every index, where the mesh's vertices start in
+    /// `vertex_buffer`.
+    pub base_vertex: i32,
     pub material: usize,
+    /// Bounds of the vertices in model space.
+    pub aabb: Aabb,
+    /// Per vertex [`JointVertex`]es of skinned meshes, bound next to the
+    /// vertices when drawing.
+    pub joints: Option<wgpu::Buffer>,
+}
+
+/// Where each of the meshes packed by [`Mesh::pack`] lives in the shared
+/// buffers: the byte range of its 32 bit indices and its base vertex.
+fn pack_layout(
+    sizes: impl Iterator<Item = (usize, usize)>,
+) -> Vec<(Range<wgpu::BufferAddress>, i32)> {
+    let index_size = mem::size_of::<u32>() as wgpu::BufferAddress;
+    let (mut vertex_offset, mut index_offset) = (0, 0);
+    sizes
+        .map(|(vertex_count, index_count)| {
+            let index_end = index_offset + index_count as wgpu::BufferAddress * index_size;
+            let layout = (index_offset..index_end, vertex_offset as i32);
+            vertex_offset += vertex_count;
+            index_offset = index_end;
+            layout
+        })
+        .collect()
+}
+
+impl Mesh {
+    /// Uploads `vertices` and `indices`, storing the indices as 16 bit when
+    /// the mesh is small enough. The buffers can be read back for
+    /// [`Mesh::decimate`].
+    pub fn new(
+        device: &wgpu::Device,
+        name: &str,
+        vertices: &[ModelVertex],
+        indices: &[u32],
+        material: usize,
+    ) -> Self {
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Vertex Buffer", name)),
+            contents: bytemuck::cast_slice(vertices),
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        // Meshes small enough for 16 bit indices get them, anything larger
+        // would wrap around and render garbage.
+        let index_format = Self::index_format_for(vertices.len());
+        let index_data = match index_format {
+            wgpu::IndexFormat::Uint16 => {
+                let indices = indices.iter().map(|&i| i as u16).collect::<Vec<_>>();
+                bytemuck::cast_slice(&indices).to_vec()
+            }
+            wgpu::IndexFormat::Uint32 => bytemuck::cast_slice(indices).to_vec(),
+        };
+
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Index Buffer", name)),
+            contents: &index_data,
+            usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_SRC,
+        });
+
+        Self {
+            name: name.to_string(),
+            vertex_buffer: Arc::new(vertex_buffer),
+            index_buffer: Arc::new(index_buffer),
+            index_range: 0..index_data.len() as wgpu::BufferAddress,
+            index_format,
+            num_elements: indices.len() as u32,
+            base_vertex: 0,
+            material,
+            aabb: Self::vertex_bounds(vertices),
+            joints: None,
+        }
+    }
+
+    /// Skins the mesh, `joints` has an entry per vertex. Only for meshes
+    /// from [`Mesh::new`], packed meshes would need them packed the same way.
+    pub fn with_joints(mut self, device: &wgpu::Device, joints: &[JointVertex]) -> Self {
+        self.joints = Some(
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{:?} Joint Buffer", self.name)),
+                contents: bytemuck::cast_slice(joints),
+                usage: wgpu::BufferUsages::VERTEX,
+            }),
+        );
+        self
+    }
+
+    /// Uploads all `parts`, vertices and indices with the material index,
+    /// into one vertex and one index buffer shared by the returned meshes.
+    /// Each mesh binds its own slice of the indices, which stay relative to
+    /// its own vertices.
+    pub fn pack(
+        device: &wgpu::Device,
+        name: &str,
+        parts: &[(&[ModelVertex], &[u32], usize)],
+    ) -> Vec<Self> {
+        let vertices = parts
+            .iter()
+            .flat_map(|(vertices, _, _)| vertices.iter().copied())
+            .collect::<Vec<_>>();
+        let indices = parts
+            .iter()
+            .flat_map(|(_, indices, _)| indices.iter().copied())
+            .collect::<Vec<_>>();
+
+        let vertex_buffer = Arc::new(device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{:?} Packed Vertex Buffer", name)),
+                contents: bytemuck::cast_slice(&vertices),
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_SRC,
+            },
+        ));
+        let index_buffer = Arc::new(
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{:?} Packed Index Buffer", name)),
+                contents: bytemuck::cast_slice(&indices),
+                usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_SRC,
+            }),
+        );
+
+        let layout = pack_layout(
+            parts
+                .iter()
+                .map(|(vertices, indices, _)| (vertices.len(), indices.len())),
+        );
+        parts
+            .iter()
+            .zip(layout)
+            .map(
+                |((vertices, indices, material), (index_range, base_vertex))| Self {
+                    name: name.to_string(),
+                    vertex_buffer: vertex_buffer.clone(),
+                    index_buffer: index_buffer.clone(),
+                    index_range,
+                    index_format: wgpu::IndexFormat::Uint32,
+                    num_elements: indices.len() as u32,
+                    base_vertex,
+                    material: *material,
+                    aabb: Self::vertex_bounds(vertices),
+                    joints: None,
+                },
+            )
+            .collect()
+    }
+
+    fn vertex_bounds(vertices: &[ModelVertex]) -> Aabb {
+        Aabb::from_points(vertices.iter().map(|vertex| vertex.position.into()))
+    }
+
+    /// The mesh's indices, to bind with its [`Mesh::index_format`].
+    pub fn index_slice(&self) -> wgpu::BufferSlice<'_> {
+        self.index_buffer.slice(self.index_range.clone())
+    }
+
+    /// A simplified copy with at most `target_ratio` of the triangles, e.g.
+    /// for a lower level of detail, see [`decimate`]. The geometry is read
+    /// back from the GPU, so this is meant for load time rather than frames.
+    pub fn decimate(&self, gpu: &Gpu, target_ratio: f32) -> anyhow::Result<Mesh> {
+        let vertices = gpu
+            .read_buffer(&self.vertex_buffer)?
+            .chunks_exact(mem::size_of::<ModelVertex>())
+            .map(bytemuck::pod_read_unaligned)
+            .collect::<Vec<ModelVertex>>();
+        let index_bytes = gpu.read_buffer(&self.index_buffer)?;
+        let index_bytes =
+            &index_bytes[self.index_range.start as usize..self.index_range.end as usize];
+        let indices = match self.index_format {
+            wgpu::IndexFormat::Uint16 => index_bytes
+                .chunks_exact(2)
+                .map(|index| bytemuck::pod_read_unaligned::<u16>(index) as u32)
+                .collect::<Vec<_>>(),
+            wgpu::IndexFormat::Uint32 => index_bytes
+                .chunks_exact(4)
+                .map(bytemuck::pod_read_unaligned)
+                .collect(),
+        };
+        // Packed meshes share the vertices, the indices are relative to the
+        // mesh's own.
+        let indices = indices
+            .into_iter()
+            .map(|index| index.wrapping_add_signed(self.base_vertex))
+            .collect::<Vec<_>>();
+
+        let (vertices, indices) = decimate(&vertices, &indices, target_ratio);
+        Ok(Mesh::new(
+            &gpu.device,
+            &self.name,
+            &vertices,
+            &indices,
+            self.material,
+        ))
+    }
+
+    /// Picks the smallest index format able to address `vertex_count` vertices.
+    pub fn index_format_for(vertex_count: usize) -> wgpu::IndexFormat {
+        if vertex_count <= u16::MAX as usize + 1 {
+            wgpu::IndexFormat::Uint16
+        } else {
+            wgpu::IndexFormat::Uint32
+        }
+    }
 }
 
 pub struct Model {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
+    /// Skeleton the meshes follow, models with one are drawn with the
+    /// skinned pipelines and need [`Mesh::joints`] on every mesh.
+    pub skin: Option<Skin>,
 }
 
-#[derive(Default)]
+impl Model {
+    /// Bounds of every mesh in model space.
+    pub fn aabb(&self) -> Aabb {
+        self.meshes
+            .iter()
+            .map(|mesh| mesh.aabb)
+            .reduce(|a, b| a.union(&b))
+            .unwrap_or(Aabb::from_points([]))
+    }
+
+    /// Loads a `.gltf` or `.glb` file, see [`crate::io::fs::load_gltf`].
+    pub fn from_gltf(gpu: &Gpu, path: &Path) -> anyhow::Result<Self> {
+        crate::io::fs::load_gltf(gpu, path)
+    }
+
+    /// Loads an OBJ file, see [`crate::io::fs::load_obj`].
+    pub fn from_obj(gpu: &Gpu, obj_path: &Path) -> anyhow::Result<Self> {
+        crate::io::fs::load_obj(gpu, obj_path)
+    }
+
+    /// The material mesh number `mesh` is drawn with, see [`resolve_material`]
+    /// for meshes that refer to one the model doesn't have.
+    pub fn material(&self, mesh: usize) -> Option<&Material> {
+        resolve_material(&self.materials, mesh, self.meshes[mesh].material)
+    }
+
+    /// Fails on the first mesh that refers to a material the model doesn't
+    /// have. Drawing such a model still works, see [`Model::material`].
+    pub fn validate(&self) -> Result<(), DrawError> {
+        for (i, mesh) in self.meshes.iter().enumerate() {
+            if mesh.material >= self.materials.len() {
+                return Err(DrawError::MissingMaterial {
+                    mesh: i,
+                    material: mesh.material,
+                    materials: self.materials.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Poses the skeleton `time` seconds into its animation, see
+    /// [`Skin::animate`]. Does nothing for models without a skin.
+    pub fn animate(&self, queue: &wgpu::Queue, time: f32) {
+        if let Some(skin) = &self.skin {
+            skin.animate(queue, time);
+        }
+    }
+}
+
+/// A malformed [`Model`], see [`Model::validate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DrawError {
+    /// Mesh number `mesh` refers to `material` but the model only has
+    /// `materials` of them.
+    MissingMaterial {
+        mesh: usize,
+        material: usize,
+        materials: usize,
+    },
+}
+
+impl std::fmt::Display for DrawError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingMaterial {
+                mesh,
+                material,
+                materials,
+            } => write!(
+                f,
+                "Mesh {mesh} refers to material {material} of a model with {materials} materials"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DrawError {}
+
+#[derive(Clone, Debug, Default)]
 pub struct Instance {
     pub isometry: Isometry3<f32>,
 }
@@ -51,6 +509,113 @@ impl Instance {
     }
 }
 
+/// GPU copy of the per instance transforms, bound to vertex buffer slot
+/// [`InstanceBuffer::SLOT`] when drawing.
+pub struct InstanceBuffer {
+    buffer: wgpu::Buffer,
+    /// Every instance, visible or not, so visibility can change without
+    /// uploading them again.
+    instances: Vec<InstanceRaw>,
+    /// What the last [`InstanceBuffer::set_visibility`] was given.
+    visible: Vec<bool>,
+    /// Instances drawn, the visible ones packed at the front of `buffer`.
+    len: u32,
+}
+
+/// The instances whose `visible` entry isn't `false`, in order. Instances
+/// past the end of `visible` are kept.
+fn compact_visible(instances: &[InstanceRaw], visible: &[bool]) -> Vec<InstanceRaw> {
+    instances
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| visible.get(*i).copied().unwrap_or(true))
+        .map(|(_, instance)| *instance)
+        .collect()
+}
+
+impl InstanceBuffer {
+    pub const SLOT: u32 = 1;
+
+    pub fn new(device: &wgpu::Device, instances: &[Instance]) -> Self {
+        let data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
+        Self {
+            buffer: Self::create_buffer(device, &data),
+            len: data.len() as u32,
+            instances: data,
+            visible: Vec::new(),
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device, data: &[InstanceRaw]) -> wgpu::Buffer {
+        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Model instance"),
+            contents: bytemuck::cast_slice(data),
+            // STORAGE lets GPU culling read the instances.
+            usage: wgpu::BufferUsages::VERTEX
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC
+                | wgpu::BufferUsages::STORAGE,
+        })
+    }
+
+    /// The same instances, visible as they were, in a new buffer on
+    /// `device`, e.g. after the old one's device was lost.
+    pub fn recreate(&self, device: &wgpu::Device) -> Self {
+        let mut data = compact_visible(&self.instances, &self.visible);
+        // Room for every instance, in case more become visible.
+        data.resize(self.instances.len(), bytemuck::Zeroable::zeroed());
+        Self {
+            buffer: Self::create_buffer(device, &data),
+            instances: self.instances.clone(),
+            visible: self.visible.clone(),
+            len: self.len,
+        }
+    }
+
+    /// Replaces the instances, reusing the buffer when they still fit. All
+    /// of them are visible again.
+    pub fn upload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, instances: &[Instance]) {
+        let data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
+        let bytes: &[u8] = bytemuck::cast_slice(&data);
+        if bytes.len() as wgpu::BufferAddress > self.buffer.size() {
+            *self = Self::new(device, instances);
+            return;
+        }
+        queue.write_buffer(&self.buffer, 0, bytes);
+        self.len = data.len() as u32;
+        self.instances = data;
+        self.visible.clear();
+    }
+
+    /// Hides the instances whose `visible` entry is `false` by packing the
+    /// others at the front of the buffer, so draws only cover those. Cheaper
+    /// than culling when the game already knows what's hidden, e.g. dead
+    /// units in a crowd. Never reallocates.
+    pub fn set_visibility(&mut self, queue: &wgpu::Queue, visible: &[bool]) {
+        let data = compact_visible(&self.instances, visible);
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&data));
+        self.len = data.len() as u32;
+        self.visible = visible.to_vec();
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    pub fn slice(&self) -> wgpu::BufferSlice<'_> {
+        let size = self.len as wgpu::BufferAddress * mem::size_of::<InstanceRaw>() as u64;
+        self.buffer.slice(..size)
+    }
+}
+
 impl Vertex for InstanceRaw {
     fn desc() -> wgpu::VertexBufferLayout<'static> {
         use std::mem;
@@ -112,6 +677,9 @@ pub struct ModelVertex {
     pub position: [f32; 3],
     pub tex_coord: [f32; 2],
     pub normal: [f32; 3],
+    /// Tangent along increasing `u`, `w` is the handedness the bitangent is
+    /// reconstructed with, `cross(normal, tangent.xyz) * tangent.w`.
+    pub tangent: [f32; 4],
 }
 
 impl Vertex for ModelVertex {
@@ -127,7 +695,7 @@ impl Vertex for ModelVertex {
                     shader_location: 0,
                 },
                 wgpu::VertexAttribute {
-                    format: wgpu::VertexFormat::Float32x3,
+                    format: wgpu::VertexFormat::Float32x2,
                     offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
                     shader_location: 1,
                 },
@@ -136,12 +704,194 @@ impl Vertex for ModelVertex {
                     offset: mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
                     shader_location: 2,
                 },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32x4,
+                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                },
             ],
         }
     }
 }
 
+/// Vertex of a mesh colored per vertex instead of textured, drawn with a
+/// [`VertexColorMaterial`].
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ColorVertex {
+    pub position: [f32; 3],
+    pub color: [f32; 4],
+}
+
+impl Vertex for ColorVertex {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<ColorVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32x3,
+                    offset: 0,
+                    shader_location: 0,
+                },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32x4,
+                    offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                },
+            ],
+        }
+    }
+}
+
+/// Unlit material outputting the interpolated [`ColorVertex::color`], for
+/// meshes authored with vertex colors such as voxel terrain.
+pub struct VertexColorMaterial {
+    pipeline: wgpu::RenderPipeline,
+}
+
+impl VertexColorMaterial {
+    /// `camera_layout` is the layout of the camera bind group, bound to
+    /// group 0 when drawing.
+    pub fn new(
+        gpu: &Gpu,
+        camera_layout: &wgpu::BindGroupLayout,
+        color_format: wgpu::TextureFormat,
+        depth_format: Option<wgpu::TextureFormat>,
+        sample_count: u32,
+    ) -> Self {
+        let layout = gpu
+            .device
+            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("VertexColorMaterial::layout"),
+                bind_group_layouts: &[camera_layout],
+                push_constant_ranges: &[],
+            });
+        let pipeline = crate::pipeline::PipelineBuilder::new(
+            &layout,
+            color_format,
+            wgpu::include_wgsl!("vertex_color.wgsl"),
+        )
+        .depth_format(depth_format)
+        .vertex_layouts(&[ColorVertex::desc(), InstanceRaw::desc()])
+        .sample_count(sample_count)
+        .build(gpu);
+
+        Self { pipeline }
+    }
+
+    /// Draws the first `vertex_count` vertices of `vertices`, a
+    /// [`ColorVertex`] triangle list, once per instance.
+    pub fn draw<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        vertices: &'a wgpu::Buffer,
+        vertex_count: u32,
+        instances: &'a InstanceBuffer,
+        camera_bind_group: &'a wgpu::BindGroup,
+    ) {
+        if instances.is_empty() {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, vertices.slice(..));
+        render_pass.set_vertex_buffer(InstanceBuffer::SLOT, instances.slice());
+        render_pass.draw(0..vertex_count, 0..instances.len());
+    }
+}
+
+/// A [`ColorVertex`] triangle list and the instances it's drawn with, see
+/// [`crate::Renderer::add_colored_mesh`].
+pub struct ColoredMesh {
+    vertices: wgpu::Buffer,
+    vertex_count: u32,
+    instances: InstanceBuffer,
+}
+
+impl ColoredMesh {
+    pub fn new(device: &wgpu::Device, vertices: &[ColorVertex], instances: &[Instance]) -> Self {
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Colored Mesh Vertex Buffer"),
+            contents: bytemuck::cast_slice(vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        Self {
+            vertices: buffer,
+            vertex_count: vertices.len() as u32,
+            instances: InstanceBuffer::new(device, instances),
+        }
+    }
+
+    pub fn draw<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        material: &'a VertexColorMaterial,
+        camera_bind_group: &'a wgpu::BindGroup,
+    ) {
+        material.draw(
+            render_pass,
+            &self.vertices,
+            self.vertex_count,
+            &self.instances,
+            camera_bind_group,
+        );
+    }
+}
+
 // model.rs
+/// `materials[material]` for mesh number `mesh`. Out of range indices fall
+/// back to the last material, which the loaders make the default one, and
+/// log a warning instead of panicking. `None` if there are no materials to
+/// fall back to, the mesh can't be drawn then.
+fn resolve_material<M>(materials: &[M], mesh: usize, material: usize) -> Option<&M> {
+    if let Some(material) = materials.get(material) {
+        return Some(material);
+    }
+    match materials.last() {
+        Some(fallback) => {
+            log::warn!(
+                "Mesh {mesh} refers to missing material {material}, drawing it with material {}",
+                materials.len() - 1
+            );
+            Some(fallback)
+        }
+        None => {
+            log::warn!(
+                "Mesh {mesh} refers to material {material} of a model without any, skipping it"
+            );
+            None
+        }
+    }
+}
+
+/// The material mesh number `mesh` is drawn with, the one its entry in
+/// `overrides` points to if there is one and `materials[material]`
+/// otherwise. Falls back like [`resolve_material`].
+fn material_for<'m, M>(
+    materials: &'m [M],
+    mesh: usize,
+    material: usize,
+    overrides: &HashMap<usize, usize>,
+) -> Option<&'m M> {
+    let material = overrides.get(&mesh).copied().unwrap_or(material);
+    resolve_material(materials, mesh, material)
+}
+
+/// Sorts `draws` farthest from `eye` first, the order blended surfaces have
+/// to be drawn in to composite correctly.
+pub fn sort_back_to_front<T>(
+    draws: &mut [T],
+    eye: &Point3<f32>,
+    position: impl Fn(&T) -> Point3<f32>,
+) {
+    draws.sort_by(|a, b| {
+        let a = na::distance_squared(eye, &position(a));
+        let b = na::distance_squared(eye, &position(b));
+        b.total_cmp(&a)
+    });
+}
+
 pub trait DrawModel<'a> {
     fn draw_mesh(
         &mut self,
@@ -165,18 +915,57 @@ pub trait DrawModel<'a> {
         camera_bind_group: &'a wgpu::BindGroup,
         light_bind_group: &'a wgpu::BindGroup,
     );
+    /// Draws every instance in `instances`, binding it to
+    /// [`InstanceBuffer::SLOT`].
     fn draw_model_instanced(
         &mut self,
         model: &'a Model,
-        instances: Range<u32>,
+        instances: &'a InstanceBuffer,
+        camera_bind_group: &'a wgpu::BindGroup,
+        light_bind_group: &'a wgpu::BindGroup,
+    );
+
+    /// [`DrawModel::draw_model_instanced`] limited to the meshes whose
+    /// material has `alpha_mode`, so opaque and blended meshes can be drawn
+    /// with different pipelines. The meshes whose index is in `overrides`
+    /// are drawn with the model's material at that index instead of their
+    /// own.
+    fn draw_meshes_instanced(
+        &mut self,
+        model: &'a Model,
+        instances: &'a InstanceBuffer,
+        overrides: &HashMap<usize, usize>,
+        alpha_mode: AlphaMode,
         camera_bind_group: &'a wgpu::BindGroup,
         light_bind_group: &'a wgpu::BindGroup,
     );
+
+    /// Draws the instances that survived [`crate::hiz::HiZPass::cull`], the
+    /// instance counts come from `culled`'s indirect arguments. Materials
+    /// are overridden like in [`DrawModel::draw_meshes_instanced`].
+    fn draw_model_indirect(
+        &mut self,
+        model: &'a Model,
+        culled: &'a CulledInstances,
+        overrides: &HashMap<usize, usize>,
+        camera_bind_group: &'a wgpu::BindGroup,
+        light_bind_group: &'a wgpu::BindGroup,
+    );
+
+    /// Draws `count` instances of `mesh` without an instance buffer, the
+    /// shader is expected to derive each transform from `instance_index`.
+    fn draw_procedural_instanced(
+        &mut self,
+        mesh: &'a Mesh,
+        count: u32,
+        camera_bind_group: &'a wgpu::BindGroup,
+    );
 }
 
-impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
+// Render passes as well as render bundles, see [`crate::bundle::BundleCache`].
+impl<'b, E> DrawModel<'b> for E
 where
-    'b: 'a,
+    E: RenderEncoder<'b>,
 {
     fn draw_mesh(
         &mut self,
@@ -197,11 +986,14 @@ where
         light_bind_group: &'b wgpu::BindGroup,
     ) {
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        if let Some(joints) = &mesh.joints {
+            self.set_vertex_buffer(JointVertex::SLOT, joints.slice(..));
+        }
+        self.set_index_buffer(mesh.index_slice(), mesh.index_format);
         self.set_bind_group(0, &material.bind_group, &[]);
         self.set_bind_group(1, camera_bind_group, &[]);
         self.set_bind_group(2, light_bind_group, &[]);
-        self.draw_indexed(0..mesh.num_elements, 0, instances);
+        self.draw_indexed(0..mesh.num_elements, mesh.base_vertex, instances);
     }
 
     fn draw_model(
@@ -210,109 +1002,481 @@ where
         camera_bind_group: &'b wgpu::BindGroup,
         light_bind_group: &'b wgpu::BindGroup,
     ) {
-        self.draw_model_instanced(model, 0..1, camera_bind_group, light_bind_group);
+        for (i, mesh) in model.meshes.iter().enumerate() {
+            if let Some(material) = model.material(i) {
+                self.draw_mesh(mesh, material, camera_bind_group, light_bind_group);
+            }
+        }
     }
 
     fn draw_model_instanced(
         &mut self,
         model: &'b Model,
-        instances: Range<u32>,
+        instances: &'b InstanceBuffer,
         camera_bind_group: &'b wgpu::BindGroup,
         light_bind_group: &'b wgpu::BindGroup,
     ) {
-        for mesh in &model.meshes {
-            let material = &model.materials[mesh.material];
+        if instances.is_empty() || model.meshes.is_empty() {
+            return;
+        }
+        self.set_vertex_buffer(InstanceBuffer::SLOT, instances.slice());
+        for (i, mesh) in model.meshes.iter().enumerate() {
+            let Some(material) = model.material(i) else {
+                continue;
+            };
             self.draw_mesh_instanced(
                 mesh,
                 material,
-                instances.clone(),
+                0..instances.len(),
                 camera_bind_group,
                 light_bind_group,
             );
         }
     }
-}
 
-// model.rs
-pub trait DrawLight<'a> {
-    fn draw_light_mesh(
+    fn draw_meshes_instanced(
         &mut self,
-        mesh: &'a Mesh,
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    );
-    fn draw_light_mesh_instanced(
-        &mut self,
-        mesh: &'a Mesh,
-        instances: Range<u32>,
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    );
-
-    fn draw_light_model(
-        &mut self,
-        model: &'a Model,
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    );
-    fn draw_light_model_instanced(
-        &mut self,
-        model: &'a Model,
-        instances: Range<u32>,
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    );
-}
+        model: &'b Model,
+        instances: &'b InstanceBuffer,
+        overrides: &HashMap<usize, usize>,
+        alpha_mode: AlphaMode,
+        camera_bind_group: &'b wgpu::BindGroup,
+        light_bind_group: &'b wgpu::BindGroup,
+    ) {
+        if instances.is_empty() || model.meshes.is_empty() {
+            return;
+        }
+        self.set_vertex_buffer(InstanceBuffer::SLOT, instances.slice());
+        for (i, mesh) in model.meshes.iter().enumerate() {
+            let Some(material) = material_for(&model.materials, i, mesh.material, overrides) else {
+                continue;
+            };
+            if material.alpha_mode != alpha_mode {
+                continue;
+            }
+            self.draw_mesh_instanced(
+                mesh,
+                material,
+                0..instances.len(),
+                camera_bind_group,
+                light_bind_group,
+            );
+        }
+    }
 
-impl<'a, 'b> DrawLight<'b> for wgpu::RenderPass<'a>
-where
-    'b: 'a,
-{
-    fn draw_light_mesh(
+    fn draw_model_indirect(
         &mut self,
-        mesh: &'b Mesh,
+        model: &'b Model,
+        culled: &'b CulledInstances,
+        overrides: &HashMap<usize, usize>,
         camera_bind_group: &'b wgpu::BindGroup,
         light_bind_group: &'b wgpu::BindGroup,
     ) {
-        self.draw_light_mesh_instanced(mesh, 0..1, camera_bind_group, light_bind_group);
+        if model.meshes.is_empty() {
+            return;
+        }
+        self.set_vertex_buffer(InstanceBuffer::SLOT, culled.instances());
+        for (i, mesh) in model.meshes.iter().enumerate() {
+            let Some(material) = material_for(&model.materials, i, mesh.material, overrides) else {
+                continue;
+            };
+            self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+            if let Some(joints) = &mesh.joints {
+                self.set_vertex_buffer(JointVertex::SLOT, joints.slice(..));
+            }
+            self.set_index_buffer(mesh.index_slice(), mesh.index_format);
+            self.set_bind_group(0, &material.bind_group, &[]);
+            self.set_bind_group(1, camera_bind_group, &[]);
+            self.set_bind_group(2, light_bind_group, &[]);
+            self.draw_indexed_indirect(culled.args(), i as u64 * CulledInstances::ARGS_STRIDE);
+        }
     }
 
-    fn draw_light_mesh_instanced(
+    fn draw_procedural_instanced(
         &mut self,
         mesh: &'b Mesh,
-        instances: Range<u32>,
+        count: u32,
         camera_bind_group: &'b wgpu::BindGroup,
-        light_bind_group: &'b wgpu::BindGroup,
     ) {
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        self.set_index_buffer(mesh.index_slice(), mesh.index_format);
         self.set_bind_group(0, camera_bind_group, &[]);
-        self.set_bind_group(1, light_bind_group, &[]);
-        self.draw_indexed(0..mesh.num_elements, 0, instances);
+        self.draw_indexed(0..mesh.num_elements, mesh.base_vertex, 0..count);
     }
+}
 
-    fn draw_light_model(
-        &mut self,
-        model: &'b Model,
-        camera_bind_group: &'b wgpu::BindGroup,
-        light_bind_group: &'b wgpu::BindGroup,
-    ) {
-        self.draw_light_model_instanced(model, 0..1, camera_bind_group, light_bind_group);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_format_for() {
+        assert_eq!(Mesh::index_format_for(3), wgpu::IndexFormat::Uint16);
+        assert_eq!(Mesh::index_format_for(65_536), wgpu::IndexFormat::Uint16);
+        assert_eq!(Mesh::index_format_for(100_000), wgpu::IndexFormat::Uint32);
     }
-    fn draw_light_model_instanced(
-        &mut self,
-        model: &'b Model,
-        instances: Range<u32>,
-        camera_bind_group: &'b wgpu::BindGroup,
-        light_bind_group: &'b wgpu::BindGroup,
-    ) {
-        for mesh in &model.meshes {
-            self.draw_light_mesh_instanced(
-                mesh,
-                instances.clone(),
-                camera_bind_group,
-                light_bind_group,
-            );
+
+    #[test]
+    fn test_pack_layout() {
+        // A triangle followed by a quad in the same buffers.
+        let layout = pack_layout([(3, 3), (4, 6)].into_iter());
+
+        assert_eq!(layout[0], (0..12, 0));
+        // The quad's indices start after the triangle's and address its
+        // vertices from the fourth one on.
+        assert_eq!(layout[1], (12..36, 3));
+    }
+
+    #[test]
+    fn test_default_shader_binds_material_maps() {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping material maps test");
+            return;
+        };
+        let device = &gpu.device;
+        let texture =
+            texture::Texture::from_color(device, &gpu.queue, [255; 4], "diffuse").unwrap();
+        let material = Material::new(&gpu, "plain", texture);
+        // Single texture materials still get all maps bound.
+        for map in [
+            &material.normal_texture,
+            &material.metallic_roughness_texture,
+            &material.occlusion_texture,
+        ] {
+            assert_eq!((map.size.width, map.size.height), (1, 1));
+            assert_eq!(map.texture.format(), wgpu::TextureFormat::Rgba8Unorm);
+        }
+
+        let uniform_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let material_layout = texture::Texture::get_bind_group_layout(&gpu);
+        let light_layout = crate::light::bind_group_layout(device);
+        let skin_layout = Skin::layout(device);
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&material_layout, &uniform_layout, &light_layout],
+            push_constant_ranges: &[],
+        });
+        let skinned_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[
+                &material_layout,
+                &uniform_layout,
+                &light_layout,
+                &skin_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+
+        // The skinned variant too, which reads the joints from group 3 and
+        // vertex buffer slot 2.
+        let variants: [(_, &[&str], &[_]); 2] = [
+            (&layout, &[], &[ModelVertex::desc(), InstanceRaw::desc()]),
+            (
+                &skinned_layout,
+                &["SKINNED"],
+                &[
+                    ModelVertex::desc(),
+                    InstanceRaw::desc(),
+                    JointVertex::desc(),
+                ],
+            ),
+        ];
+        for (layout, defines, vertex_layouts) in variants {
+            device.push_error_scope(wgpu::ErrorFilter::Validation);
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl(
+                    concat!(
+                        include_str!("sample_level.wgsl"),
+                        include_str!("shader.wgsl")
+                    )
+                    .into(),
+                ),
+            };
+            crate::pipeline::PipelineBuilder::new(layout, wgpu::TextureFormat::Rgba16Float, shader)
+                .defines(defines)
+                .unwrap()
+                .vertex_layouts(vertex_layouts)
+                .try_build(&gpu)
+                .unwrap();
+            let error = futures::executor::block_on(device.pop_error_scope());
+            assert!(error.is_none(), "{defines:?}: {error:?}");
+        }
+    }
+
+    #[test]
+    fn test_vertex_colors_are_interpolated() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping vertex color test");
+            return Ok(());
+        };
+        let device = &gpu.device;
+        let camera_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        // Identity matrices, the positions are in clip space.
+        let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&[crate::camera::CameraUniform::new()]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &camera_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: camera_buffer.as_entire_binding(),
+            }],
+        });
+
+        // Centered on the middle pixel of the 3x3 target, where each vertex
+        // contributes a third.
+        let vertices = [
+            ColorVertex {
+                position: [-1.0, -1.0, 0.5],
+                color: [1.0, 0.0, 0.0, 1.0],
+            },
+            ColorVertex {
+                position: [1.0, -1.0, 0.5],
+                color: [0.0, 1.0, 0.0, 1.0],
+            },
+            ColorVertex {
+                position: [0.0, 2.0, 0.5],
+                color: [0.0, 0.0, 1.0, 1.0],
+            },
+        ];
+        let mesh = ColoredMesh::new(device, &vertices, &[Instance::default()]);
+
+        let format = wgpu::TextureFormat::Rgba8Unorm;
+        let material = VertexColorMaterial::new(&gpu, &camera_layout, format, None, 1);
+        let target = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: 3,
+                height: 3,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = target.create_view(&Default::default());
+
+        let mut encoder = device.create_command_encoder(&Default::default());
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+            mesh.draw(&mut render_pass, &material, &camera_bind_group);
+        }
+        gpu.queue.submit([encoder.finish()]);
+
+        let frame = gpu.read_texture(&target)?;
+        let center = &frame.pixels[4 * 4..4 * 4 + 4];
+        for channel in &center[..3] {
+            assert!(channel.abs_diff(85) <= 3, "center pixel {center:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort_back_to_front() {
+        let eye = Point3::origin();
+        let mut draws = [
+            ("near", Point3::new(0.0, 0.0, -1.0)),
+            ("far", Point3::new(0.0, 0.0, -10.0)),
+            ("behind", Point3::new(0.0, 0.0, 5.0)),
+        ];
+        sort_back_to_front(&mut draws, &eye, |(_, position)| *position);
+
+        let order = draws.map(|(name, _)| name);
+        assert_eq!(order, ["far", "behind", "near"]);
+    }
+
+    #[test]
+    fn test_material_override() {
+        // Two meshes sharing material 0, the second one overridden.
+        let materials = ["base", "team color"];
+        let overrides = HashMap::from([(1, 1)]);
+
+        assert_eq!(material_for(&materials, 0, 0, &overrides), Some(&"base"));
+        assert_eq!(
+            material_for(&materials, 1, 0, &overrides),
+            Some(&"team color")
+        );
+        // Overrides past the materials fall back like the meshes' own.
+        let overrides = HashMap::from([(1, 7)]);
+        assert_eq!(
+            material_for(&materials, 1, 0, &overrides),
+            Some(&"team color")
+        );
+    }
+
+    #[test]
+    fn test_missing_material_falls_back() {
+        let materials = ["red", "default"];
+        assert_eq!(resolve_material(&materials, 0, 1), Some(&"default"));
+        assert_eq!(resolve_material(&materials, 0, 7), Some(&"default"));
+        assert_eq!(resolve_material::<&str>(&[], 0, 0), None);
+    }
+
+    #[test]
+    fn test_broken_model_draws_without_panicking() {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping broken model test");
+            return;
+        };
+        let vertices =
+            [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]].map(|position| ModelVertex {
+                position,
+                tex_coord: [0.0; 2],
+                normal: [0.0, 0.0, 1.0],
+                tangent: [1.0, 0.0, 0.0, 1.0],
+            });
+        // The second mesh refers past the only material.
+        let meshes = Mesh::pack(
+            &gpu.device,
+            "broken",
+            &[(&vertices, &[0, 1, 2], 0), (&vertices, &[0, 1, 2], 3)],
+        );
+        let texture = texture::Texture::create_2d_texture(
+            &gpu,
+            1,
+            1,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            wgpu::TextureUsages::TEXTURE_BINDING,
+            wgpu::FilterMode::Linear,
+            None,
+        );
+        let model = Model {
+            meshes,
+            materials: vec![Material::new(&gpu, "only", texture)],
+            skin: None,
+        };
+        assert_eq!(
+            model.validate(),
+            Err(DrawError::MissingMaterial {
+                mesh: 1,
+                material: 3,
+                materials: 1,
+            })
+        );
+        assert!(std::ptr::eq(
+            model.material(1).unwrap(),
+            &model.materials[0]
+        ));
+
+        // Both meshes are drawn, the second with the fallback.
+        assert!((0..model.meshes.len()).all(|i| model.material(i).is_some()));
+
+        // Without any materials nothing is drawn, and empty models are fine.
+        let no_materials = Model {
+            materials: Vec::new(),
+            ..model
+        };
+        assert!(no_materials.material(0).is_none());
+
+        let empty = Model {
+            meshes: Vec::new(),
+            materials: Vec::new(),
+            skin: None,
+        };
+        assert_eq!(empty.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_hidden_instances_are_not_drawn() {
+        let instances = (0..5)
+            .map(|i| Instance {
+                isometry: na::Isometry3::translation(i as f32, 0.0, 0.0),
+            })
+            .collect::<Vec<_>>();
+        let visible = [true, false, true, false, true];
+
+        let raw = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
+        let compacted = compact_visible(&raw, &visible);
+        let xs = compacted
+            .iter()
+            .map(|instance| instance.model[3][0])
+            .collect::<Vec<_>>();
+        assert_eq!(xs, [0.0, 2.0, 4.0]);
+        assert_eq!(compact_visible(&raw, &[false]).len(), 4);
+
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping instance visibility test");
+            return;
+        };
+        let mut buffer = InstanceBuffer::new(&gpu.device, &instances);
+        let size = buffer.buffer().size();
+        buffer.set_visibility(&gpu.queue, &visible);
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.buffer().size(), size);
+
+        buffer.set_visibility(&gpu.queue, &[]);
+        assert_eq!(buffer.len(), 5);
+    }
+
+    #[test]
+    fn test_instance_layout() {
+        let layout = InstanceRaw::desc();
+        assert_eq!(layout.step_mode, wgpu::VertexStepMode::Instance);
+        assert_eq!(
+            layout.array_stride,
+            mem::size_of::<InstanceRaw>() as wgpu::BufferAddress
+        );
+
+        // A mat4 model matrix as 4 vec4 columns followed by a mat3 normal
+        // matrix as 3 vec3 columns, on consecutive locations.
+        let expected = [
+            (5, 0, wgpu::VertexFormat::Float32x4),
+            (6, 16, wgpu::VertexFormat::Float32x4),
+            (7, 32, wgpu::VertexFormat::Float32x4),
+            (8, 48, wgpu::VertexFormat::Float32x4),
+            (9, 64, wgpu::VertexFormat::Float32x3),
+            (10, 76, wgpu::VertexFormat::Float32x3),
+            (11, 88, wgpu::VertexFormat::Float32x3),
+        ];
+        assert_eq!(layout.attributes.len(), expected.len());
+        for (attribute, (location, offset, format)) in layout.attributes.iter().zip(expected) {
+            assert_eq!(attribute.shader_location, location);
+            assert_eq!(attribute.offset, offset);
+            assert_eq!(attribute.format, format);
         }
     }
 }
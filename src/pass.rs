@@ -0,0 +1,238 @@
+struct QueuedPass<'a> {
+    label: Option<&'a str>,
+    color_attachments: Vec<Option<wgpu::RenderPassColorAttachment<'a>>>,
+    depth_stencil_attachment: Option<wgpu::RenderPassDepthStencilAttachment<'a>>,
+    bundles: Vec<&'a wgpu::RenderBundle>,
+}
+
+/// Render passes queued up and encoded together, so that consecutive passes
+/// drawing into the same attachments share one render pass instead of each
+/// storing and loading the framebuffer again.
+///
+/// A pass is merged into the one before it when it has the same color,
+/// resolve and depth views, loads every attachment and the previous pass
+/// stores every attachment. Drawing both into one pass then gives the same
+/// result, a pass clearing an attachment always starts a new render pass.
+///
+/// A pass draws by executing render bundles, since a render pass can't
+/// outlive the encoder borrow it was begun with. See
+/// [`crate::bundle::BundleCache`] for keeping them across frames.
+#[derive(Default)]
+pub struct PassQueue<'a> {
+    passes: Vec<QueuedPass<'a>>,
+}
+
+impl<'a> PassQueue<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a pass over the attachments executing `bundles`, which have to
+    /// be recorded for the attachments' formats and sample count.
+    pub fn push(
+        &mut self,
+        label: Option<&'a str>,
+        color_attachments: &[Option<wgpu::RenderPassColorAttachment<'a>>],
+        depth_stencil_attachment: Option<wgpu::RenderPassDepthStencilAttachment<'a>>,
+        bundles: impl IntoIterator<Item = &'a wgpu::RenderBundle>,
+    ) {
+        self.passes.push(QueuedPass {
+            label,
+            color_attachments: color_attachments.to_vec(),
+            depth_stencil_attachment,
+            bundles: bundles.into_iter().collect(),
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.passes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.passes.is_empty()
+    }
+
+    /// Encodes the queued passes in order, returning how many render passes
+    /// they took after merging.
+    pub fn encode(self, encoder: &mut wgpu::CommandEncoder) -> usize {
+        let mut runs: Vec<Vec<QueuedPass<'a>>> = Vec::new();
+        for pass in self.passes {
+            match runs.last_mut() {
+                Some(run) if can_merge(run.last().unwrap(), &pass) => run.push(pass),
+                _ => runs.push(vec![pass]),
+            }
+        }
+
+        let count = runs.len();
+        for run in runs {
+            // Loads as the first pass of the run does and stores as the last.
+            let last = run.last().unwrap();
+            let mut color_attachments = run[0].color_attachments.clone();
+            for (first, last) in color_attachments.iter_mut().zip(&last.color_attachments) {
+                if let (Some(first), Some(last)) = (first, last) {
+                    first.ops.store = last.ops.store;
+                }
+            }
+            let mut depth_stencil_attachment = run[0].depth_stencil_attachment.clone();
+            if let (Some(first), Some(last)) = (
+                &mut depth_stencil_attachment,
+                &last.depth_stencil_attachment,
+            ) {
+                if let (Some(first), Some(last)) = (&mut first.depth_ops, &last.depth_ops) {
+                    first.store = last.store;
+                }
+                if let (Some(first), Some(last)) = (&mut first.stencil_ops, &last.stencil_ops) {
+                    first.store = last.store;
+                }
+            }
+
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: run[0].label,
+                color_attachments: &color_attachments,
+                depth_stencil_attachment,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            render_pass.execute_bundles(run.iter().flat_map(|pass| pass.bundles.iter().copied()));
+        }
+        count
+    }
+}
+
+/// Whether `next` can draw into the render pass `prev` is drawn into.
+fn can_merge(prev: &QueuedPass, next: &QueuedPass) -> bool {
+    fn continues<V>(prev: &wgpu::Operations<V>, next: &wgpu::Operations<V>) -> bool {
+        prev.store == wgpu::StoreOp::Store && matches!(next.load, wgpu::LoadOp::Load)
+    }
+    fn same_view(a: &wgpu::TextureView, b: &wgpu::TextureView) -> bool {
+        a.global_id() == b.global_id()
+    }
+
+    let colors_continue = prev.color_attachments.len() == next.color_attachments.len()
+        && prev
+            .color_attachments
+            .iter()
+            .zip(&next.color_attachments)
+            .all(|attachments| match attachments {
+                (None, None) => true,
+                (Some(prev), Some(next)) => {
+                    same_view(prev.view, next.view)
+                        && match (prev.resolve_target, next.resolve_target) {
+                            (None, None) => true,
+                            (Some(prev), Some(next)) => same_view(prev, next),
+                            _ => false,
+                        }
+                        && continues(&prev.ops, &next.ops)
+                }
+                _ => false,
+            });
+    let depth_continues = match (
+        &prev.depth_stencil_attachment,
+        &next.depth_stencil_attachment,
+    ) {
+        (None, None) => true,
+        (Some(prev), Some(next)) => {
+            same_view(prev.view, next.view)
+                && match (&prev.depth_ops, &next.depth_ops) {
+                    (None, None) => true,
+                    (Some(prev), Some(next)) => continues(prev, next),
+                    _ => false,
+                }
+                && match (&prev.stencil_ops, &next.stencil_ops) {
+                    (None, None) => true,
+                    (Some(prev), Some(next)) => continues(prev, next),
+                    _ => false,
+                }
+        }
+        _ => false,
+    };
+    colors_continue && depth_continues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::Gpu;
+
+    #[test]
+    fn test_compatible_passes_are_merged() {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping pass merging test");
+            return;
+        };
+        let target = gpu.device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: 4,
+                height: 4,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = target.create_view(&Default::default());
+        let attachment = |load| {
+            Some(wgpu::RenderPassColorAttachment {
+                view: &view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })
+        };
+
+        let overlay = gpu
+            .device
+            .create_render_bundle_encoder(&wgpu::RenderBundleEncoderDescriptor {
+                label: None,
+                color_formats: &[Some(target.format())],
+                depth_stencil: None,
+                sample_count: 1,
+                multiview: None,
+            })
+            .finish(&wgpu::RenderBundleDescriptor { label: None });
+
+        let mut passes = PassQueue::new();
+        passes.push(
+            Some("clear"),
+            &[attachment(wgpu::LoadOp::Clear(wgpu::Color::RED))],
+            None,
+            [],
+        );
+        passes.push(
+            Some("overlay"),
+            &[attachment(wgpu::LoadOp::Load)],
+            None,
+            [&overlay],
+        );
+        assert_eq!(passes.len(), 2);
+        assert_eq!(gpu.submit_passes(passes), 1);
+        gpu.finish();
+        let frame = gpu.read_texture(&target).unwrap();
+        assert_eq!(frame.pixels[..4], [255, 0, 0, 255]);
+
+        // Clearing again needs a pass of its own.
+        let mut passes = PassQueue::new();
+        passes.push(
+            None,
+            &[attachment(wgpu::LoadOp::Clear(wgpu::Color::RED))],
+            None,
+            [],
+        );
+        passes.push(
+            None,
+            &[attachment(wgpu::LoadOp::Clear(wgpu::Color::BLUE))],
+            None,
+            [],
+        );
+        assert_eq!(gpu.submit_passes(passes), 2);
+        gpu.finish();
+        let frame = gpu.read_texture(&target).unwrap();
+        assert_eq!(frame.pixels[..4], [0, 0, 255, 255]);
+    }
+}
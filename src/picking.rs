@@ -0,0 +1,348 @@
+use anyhow::Result;
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+
+use crate::{
+    db::Id,
+    gpu::Gpu,
+    model::{InstanceBuffer, InstanceRaw, ModelVertex, Vertex},
+    pipeline::PipelineBuilder,
+    texture,
+    uniform::DynamicUniformBuffer,
+    ModelEntry, ModelId,
+};
+
+/// Id of a model as the picking shader writes it, 0 is the background.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct PickId {
+    id: u32,
+    _padding: [u32; 3],
+}
+
+impl PickId {
+    fn new(id: ModelId) -> Option<Self> {
+        let id = u32::try_from(id.0).ok()?.checked_add(1)?;
+        Some(Self {
+            id,
+            _padding: [0; 3],
+        })
+    }
+
+    fn model_id(id: u32) -> Option<ModelId> {
+        id.checked_sub(1).map(|id| Id(id as usize))
+    }
+}
+
+/// Pixel under `position`, in physical window pixels, of a `size` target
+/// rendered at `scale` of the window's resolution. `None` outside of it.
+pub fn framebuffer_pixel(
+    position: PhysicalPosition<f64>,
+    scale: f32,
+    size: PhysicalSize<u32>,
+) -> Option<(u32, u32)> {
+    let x = (position.x * scale as f64).floor();
+    let y = (position.y * scale as f64).floor();
+    if x < 0.0 || y < 0.0 || x >= size.width as f64 || y >= size.height as f64 {
+        return None;
+    }
+    Some((x as u32, y as u32))
+}
+
+/// Finds the model under a pixel by drawing every model's id into an
+/// `R32Uint` target and reading the pixel back. Only the picked pixel is
+/// rasterized, so picking on click costs little next to a frame.
+///
+/// The scene pass can't write the ids itself: with MSAA on its targets are
+/// multisampled, and integer formats can't be resolved.
+pub struct PickingPass {
+    pipeline: wgpu::RenderPipeline,
+    ids: DynamicUniformBuffer<PickId>,
+    id_layout: wgpu::BindGroupLayout,
+    id_bind_group: wgpu::BindGroup,
+    target: texture::Texture,
+    depth: texture::Texture,
+    /// The picked id, copied out of `target`.
+    readback: wgpu::Buffer,
+}
+
+impl PickingPass {
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R32Uint;
+    /// Models the id buffer has room for before it first grows.
+    const INITIAL_CAPACITY: usize = 64;
+
+    /// `camera_layout` is the layout of the camera bind group, bound to
+    /// group 0 when picking.
+    pub fn new(gpu: &Gpu, camera_layout: &wgpu::BindGroupLayout, width: u32, height: u32) -> Self {
+        let device = &gpu.device;
+        let id_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("PickingPass::id_layout"),
+            entries: &[DynamicUniformBuffer::<PickId>::layout_entry(
+                0,
+                wgpu::ShaderStages::FRAGMENT,
+            )],
+        });
+        let ids = DynamicUniformBuffer::new(gpu, Some("PickingPass::ids"), Self::INITIAL_CAPACITY);
+        let id_bind_group = Self::create_id_bind_group(gpu, &id_layout, &ids);
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("PickingPass::layout"),
+            bind_group_layouts: &[camera_layout, &id_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline =
+            PipelineBuilder::new(&layout, Self::FORMAT, wgpu::include_wgsl!("pick.wgsl"))
+                .depth_format(Some(texture::Texture::DEPTH_FORMAT))
+                .vertex_layouts(&[ModelVertex::desc(), InstanceRaw::desc()])
+                .build(gpu);
+
+        let (target, depth) = Self::create_targets(gpu, width, height);
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("PickingPass::readback"),
+            size: Self::FORMAT.block_copy_size(None).unwrap() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            pipeline,
+            ids,
+            id_layout,
+            id_bind_group,
+            target,
+            depth,
+            readback,
+        }
+    }
+
+    fn create_id_bind_group(
+        gpu: &Gpu,
+        layout: &wgpu::BindGroupLayout,
+        ids: &DynamicUniformBuffer<PickId>,
+    ) -> wgpu::BindGroup {
+        gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("PickingPass::id_bind_group"),
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: ids.binding(),
+            }],
+        })
+    }
+
+    fn create_targets(gpu: &Gpu, width: u32, height: u32) -> (texture::Texture, texture::Texture) {
+        let target = texture::Texture::create_render_target(
+            &gpu.device,
+            width,
+            height,
+            Self::FORMAT,
+            wgpu::TextureUsages::COPY_SRC,
+        );
+        let depth = texture::Texture::create_render_target(
+            &gpu.device,
+            width,
+            height,
+            texture::Texture::DEPTH_FORMAT,
+            wgpu::TextureUsages::empty(),
+        );
+        (target, depth)
+    }
+
+    /// Size of the targets, which has to match the scene's for the picked
+    /// pixel to show what's on screen.
+    pub fn size(&self) -> PhysicalSize<u32> {
+        PhysicalSize::new(self.target.size.width, self.target.size.height)
+    }
+
+    pub fn resize(&mut self, gpu: &Gpu, width: u32, height: u32) {
+        (self.target, self.depth) = Self::create_targets(gpu, width, height);
+    }
+
+    /// The model drawn at `pixel` of the targets, `None` for the
+    /// background. Models with ids past `u32::MAX - 1` can't be picked.
+    pub fn pick<'a>(
+        &mut self,
+        gpu: &Gpu,
+        camera_bind_group: &wgpu::BindGroup,
+        models: impl Iterator<Item = (ModelId, &'a ModelEntry)>,
+        pixel: (u32, u32),
+    ) -> Result<Option<ModelId>> {
+        let (ids, models): (Vec<_>, Vec<_>) = models
+            .filter(|(_, entry)| !entry.instances.is_empty())
+            .filter_map(|(id, entry)| Some((PickId::new(id)?, entry)))
+            .unzip();
+        if ids.len() > self.ids.capacity() {
+            self.ids = DynamicUniformBuffer::new(
+                gpu,
+                Some("PickingPass::ids"),
+                ids.len().next_power_of_two(),
+            );
+            self.id_bind_group = Self::create_id_bind_group(gpu, &self.id_layout, &self.ids);
+        }
+        self.ids.write(&gpu.queue, &ids)?;
+        let offsets = self.ids.offsets(&(0..ids.len()).collect::<Vec<_>>())?;
+
+        let mut encoder = gpu.device.create_command_encoder(&Default::default());
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("PickingPass::pick"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &self.target.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: wgpu::StoreOp::Discard,
+                    }),
+                    stencil_ops: None,
+                }),
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+            pass.set_scissor_rect(pixel.0, pixel.1, 1, 1);
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, camera_bind_group, &[]);
+            for (entry, offset) in models.iter().zip(offsets) {
+                pass.set_bind_group(1, &self.id_bind_group, &[offset]);
+                pass.set_vertex_buffer(InstanceBuffer::SLOT, entry.instances.slice());
+                for mesh in &entry.model.meshes {
+                    pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                    pass.set_index_buffer(mesh.index_slice(), mesh.index_format);
+                    pass.draw_indexed(
+                        0..mesh.num_elements,
+                        mesh.base_vertex,
+                        0..entry.instances.len(),
+                    );
+                }
+            }
+        }
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: &self.target.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: pixel.0,
+                    y: pixel.1,
+                    z: 0,
+                },
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &self.readback,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: None,
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+        );
+        gpu.queue.submit([encoder.finish()]);
+
+        let id = bytemuck::pod_read_unaligned::<u32>(&gpu.read_buffer(&self.readback)?);
+        Ok(PickId::model_id(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Mesh, Model};
+    use wgpu::util::DeviceExt;
+
+    #[test]
+    fn test_framebuffer_pixel() {
+        let size = PhysicalSize::new(50, 40);
+        assert_eq!(
+            framebuffer_pixel(PhysicalPosition::new(99.5, 10.0), 0.5, size),
+            Some((49, 5))
+        );
+        assert_eq!(
+            framebuffer_pixel(PhysicalPosition::new(100.0, 10.0), 0.5, size),
+            None
+        );
+        assert_eq!(
+            framebuffer_pixel(PhysicalPosition::new(-0.5, 10.0), 1.0, size),
+            None
+        );
+        assert_eq!(PickId::model_id(0), None);
+        assert_eq!(
+            PickId::model_id(PickId::new(Id(7)).unwrap().id),
+            Some(Id(7))
+        );
+    }
+
+    #[test]
+    fn test_pick_the_nearest_model() -> Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping picking test");
+            return Ok(());
+        };
+        let device = &gpu.device;
+        let camera_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        // Identity matrices, the positions are in clip space.
+        let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&[crate::camera::CameraUniform::new()]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &camera_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: camera_buffer.as_entire_binding(),
+            }],
+        });
+
+        let triangle = |corners: [[f32; 2]; 3], depth: f32| {
+            let vertices = corners.map(|[x, y]| ModelVertex {
+                position: [x, y, depth],
+                tex_coord: [0.0; 2],
+                normal: [0.0, 0.0, 1.0],
+                tangent: [1.0, 0.0, 0.0, 1.0],
+            });
+            let mesh = Mesh::new(device, "triangle", &vertices, &[0, 1, 2], 0);
+            ModelEntry::new(
+                &gpu,
+                Model {
+                    meshes: vec![mesh],
+                    materials: Vec::new(),
+                    skin: None,
+                },
+            )
+        };
+        // The bottom left half, and a closer corner of it drawn first.
+        let half = triangle([[-1.0, -1.0], [1.0, -1.0], [-1.0, 1.0]], 0.7);
+        let corner = triangle([[-1.0, -1.0], [0.0, -1.0], [-1.0, 0.0]], 0.3);
+        let models = [(Id(4), &corner), (Id(0), &half)];
+
+        let mut picking = PickingPass::new(&gpu, &camera_layout, 4, 4);
+        let mut pick = |pixel| picking.pick(&gpu, &camera_bind_group, models.into_iter(), pixel);
+        assert_eq!(pick((0, 3))?, Some(Id(4)));
+        assert_eq!(pick((1, 2))?, Some(Id(0)));
+        assert_eq!(pick((3, 0))?, None);
+        Ok(())
+    }
+}
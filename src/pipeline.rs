@@ -0,0 +1,1440 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+};
+
+use notify::Watcher;
+
+use crate::gpu::Gpu;
+
+/// Handle to a pipeline in a [`PipelineCache`], builders describing the same
+/// pipeline get the same id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PipelineId(u64);
+
+/// Resolves `#ifdef NAME`, `#ifndef NAME`, `#else` and `#endif` lines in
+/// WGSL `source`, keeping the lines of branches whose name is in `defines`.
+/// Dropped lines are left empty so shader errors keep their line numbers.
+pub fn preprocess(source: &str, defines: &[&str]) -> anyhow::Result<String> {
+    // Whether each open block, and everything around it, is kept.
+    let mut blocks: Vec<bool> = Vec::new();
+    let mut output = String::with_capacity(source.len());
+
+    for (number, line) in source.lines().enumerate() {
+        let active = blocks.last().copied().unwrap_or(true);
+        let mut directive = line.split_whitespace();
+        match (directive.next(), directive.next()) {
+            (Some("#ifdef"), Some(name)) => blocks.push(active && defines.contains(&name)),
+            (Some("#ifndef"), Some(name)) => blocks.push(active && !defines.contains(&name)),
+            (Some("#else"), None) => {
+                let Some(kept) = blocks.pop() else {
+                    anyhow::bail!("#else without #ifdef on line {}", number + 1);
+                };
+                let parent = blocks.last().copied().unwrap_or(true);
+                blocks.push(parent && !kept);
+            }
+            (Some("#endif"), None) => {
+                if blocks.pop().is_none() {
+                    anyhow::bail!("#endif without #ifdef on line {}", number + 1);
+                }
+            }
+            _ => {
+                if active {
+                    output.push_str(line);
+                }
+            }
+        }
+        output.push('\n');
+    }
+
+    if !blocks.is_empty() {
+        anyhow::bail!("{} #ifdef blocks are never closed", blocks.len());
+    }
+    Ok(output)
+}
+
+/// Checks `ranges` can be declared on a device with `features` and `limits`,
+/// push constants being native only.
+fn check_push_constant_ranges(
+    features: wgpu::Features,
+    limits: &wgpu::Limits,
+    ranges: &[wgpu::PushConstantRange],
+) -> anyhow::Result<()> {
+    if ranges.is_empty() {
+        return Ok(());
+    }
+    if !features.contains(wgpu::Features::PUSH_CONSTANTS) {
+        anyhow::bail!("Push constants need Features::PUSH_CONSTANTS, which the device lacks");
+    }
+    if let Some(range) = ranges
+        .iter()
+        .find(|range| range.range.end > limits.max_push_constant_size)
+    {
+        anyhow::bail!(
+            "Push constant range {:?} exceeds the device limit of {} bytes",
+            range.range,
+            limits.max_push_constant_size
+        );
+    }
+    Ok(())
+}
+
+/// Pipeline layout for [`PipelineBuilder`], failing instead of panicking
+/// when the device can't have the push constants in `push_constant_ranges`.
+/// Draws set them with `RenderPass::set_push_constants`.
+pub fn create_layout(
+    gpu: &Gpu,
+    label: Option<&str>,
+    bind_group_layouts: &[&wgpu::BindGroupLayout],
+    push_constant_ranges: &[wgpu::PushConstantRange],
+) -> anyhow::Result<wgpu::PipelineLayout> {
+    check_push_constant_ranges(
+        gpu.device.features(),
+        &gpu.device.limits(),
+        push_constant_ranges,
+    )?;
+    Ok(gpu
+        .device
+        .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label,
+            bind_group_layouts,
+            push_constant_ranges,
+        }))
+}
+
+/// Checks no shader location is declared twice, and that each buffer's
+/// attributes neither overlap nor run past its stride.
+fn check_vertex_layouts(layouts: &[wgpu::VertexBufferLayout]) -> anyhow::Result<()> {
+    let mut locations = HashMap::new();
+    for (slot, layout) in layouts.iter().enumerate() {
+        if layout.array_stride % wgpu::VERTEX_STRIDE_ALIGNMENT != 0 {
+            anyhow::bail!(
+                "Stride {} of slot {slot} isn't a multiple of {}",
+                layout.array_stride,
+                wgpu::VERTEX_STRIDE_ALIGNMENT
+            );
+        }
+
+        let mut attributes = layout.attributes.to_vec();
+        attributes.sort_by_key(|attribute| attribute.offset);
+        let mut end = 0;
+        for attribute in &attributes {
+            if let Some(other) = locations.insert(attribute.shader_location, slot) {
+                anyhow::bail!(
+                    "Location {} is declared by slot {other} and slot {slot}",
+                    attribute.shader_location
+                );
+            }
+            if attribute.offset < end {
+                anyhow::bail!(
+                    "Location {} at offset {} overlaps the previous attribute of slot {slot}",
+                    attribute.shader_location,
+                    attribute.offset
+                );
+            }
+            end = attribute.offset + attribute.format.size();
+            if layout.array_stride != 0 && end > layout.array_stride {
+                anyhow::bail!(
+                    "Location {} ends at byte {end}, past the stride {} of slot {slot}",
+                    attribute.shader_location,
+                    layout.array_stride
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Common ways of blending a color target, for [`PipelineBuilder::blend`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlendPreset {
+    /// Fragments overwrite the target, what opaque surfaces use.
+    #[default]
+    Replace,
+    /// Straight alpha, for transparent surfaces drawn back to front.
+    AlphaBlend,
+    /// For colors already multiplied by their alpha, such as UI textures.
+    PremultipliedAlpha,
+    /// Adds the fragment to the target, for glows and particles. The order
+    /// they're drawn in doesn't matter.
+    Additive,
+}
+
+impl From<BlendPreset> for Option<wgpu::BlendState> {
+    fn from(preset: BlendPreset) -> Self {
+        match preset {
+            // No blend state rather than `BlendState::REPLACE`, which wgpu
+            // rejects on formats that can't be blended such as integers.
+            BlendPreset::Replace => None,
+            BlendPreset::AlphaBlend => Some(wgpu::BlendState::ALPHA_BLENDING),
+            BlendPreset::PremultipliedAlpha => Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+            BlendPreset::Additive => {
+                let add = wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::One,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                };
+                Some(wgpu::BlendState {
+                    color: add,
+                    alpha: add,
+                })
+            }
+        }
+    }
+}
+
+/// Render pipeline with the defaults used across the renderer: `vs_main` and
+/// `fs_main` entry points, back face culling and a single color target,
+/// more for MRT with [`PipelineBuilder::color_target`].
+pub struct PipelineBuilder<'a> {
+    layout: &'a wgpu::PipelineLayout,
+    shader: wgpu::ShaderModuleDescriptor<'a>,
+    /// At the shader's `@location`s in order.
+    targets: Vec<wgpu::ColorTargetState>,
+    depth_format: Option<wgpu::TextureFormat>,
+    vertex_layouts: Vec<wgpu::VertexBufferLayout<'a>>,
+    topology: wgpu::PrimitiveTopology,
+    polygon_mode: wgpu::PolygonMode,
+    depth_write: bool,
+    depth_compare: wgpu::CompareFunction,
+    sample_count: u32,
+}
+
+impl<'a> PipelineBuilder<'a> {
+    pub fn new(
+        layout: &'a wgpu::PipelineLayout,
+        color_format: wgpu::TextureFormat,
+        shader: wgpu::ShaderModuleDescriptor<'a>,
+    ) -> Self {
+        Self {
+            layout,
+            shader,
+            targets: vec![wgpu::ColorTargetState {
+                format: color_format,
+                blend: None,
+                write_mask: wgpu::ColorWrites::ALL,
+            }],
+            depth_format: None,
+            vertex_layouts: Vec::new(),
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            depth_write: true,
+            depth_compare: wgpu::CompareFunction::LessEqual,
+            sample_count: 1,
+        }
+    }
+
+    /// A pipeline without color targets that only writes `depth_format`,
+    /// e.g. for shadow maps. The shader doesn't need an `fs_main` then.
+    pub fn depth_only(
+        layout: &'a wgpu::PipelineLayout,
+        depth_format: wgpu::TextureFormat,
+        shader: wgpu::ShaderModuleDescriptor<'a>,
+    ) -> Self {
+        Self {
+            targets: Vec::new(),
+            depth_format: Some(depth_format),
+            ..Self::new(layout, depth_format, shader)
+        }
+    }
+
+    pub fn depth_format(mut self, format: Option<wgpu::TextureFormat>) -> Self {
+        self.depth_format = format;
+        self
+    }
+
+    pub fn vertex_layouts(mut self, layouts: &[wgpu::VertexBufferLayout<'a>]) -> Self {
+        self.vertex_layouts = layouts.to_vec();
+        self
+    }
+
+    /// Layout of the per vertex buffer in slot 0, for geometry with its own
+    /// attributes such as point clouds or skinned meshes.
+    pub fn vertex_layout(self, layout: wgpu::VertexBufferLayout<'a>) -> Self {
+        self.slot_layout(0, layout)
+    }
+
+    /// Layout of the per instance buffer in slot 1.
+    pub fn instance_layout(self, layout: wgpu::VertexBufferLayout<'a>) -> Self {
+        self.slot_layout(1, layout)
+    }
+
+    fn slot_layout(mut self, slot: usize, layout: wgpu::VertexBufferLayout<'a>) -> Self {
+        if self.vertex_layouts.len() <= slot {
+            self.vertex_layouts.resize(
+                slot + 1,
+                wgpu::VertexBufferLayout {
+                    array_stride: 0,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes: &[],
+                },
+            );
+        }
+        self.vertex_layouts[slot] = layout;
+        self
+    }
+
+    pub fn topology(mut self, topology: wgpu::PrimitiveTopology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    /// `Line` and `Point` need [`wgpu::Features::POLYGON_MODE_LINE`] and
+    /// [`wgpu::Features::POLYGON_MODE_POINT`], without them the pipeline is
+    /// built filled with a warning.
+    pub fn polygon_mode(mut self, mode: wgpu::PolygonMode) -> Self {
+        self.polygon_mode = mode;
+        self
+    }
+
+    /// A [`BlendPreset`] or any `Option<wgpu::BlendState>`, by default
+    /// [`BlendPreset::Replace`]. [`BlendPreset::AlphaBlend`] for transparent
+    /// surfaces, which usually also turn [`PipelineBuilder::depth_write`]
+    /// off. Only for the color target given to [`PipelineBuilder::new`], the
+    /// others have their own.
+    pub fn blend(mut self, blend: impl Into<Option<wgpu::BlendState>>) -> Self {
+        self.targets[0].blend = blend.into();
+        self
+    }
+
+    /// Adds a color target at the next `@location`, for shaders writing
+    /// several attachments at once. `blend` applies to this target only, so
+    /// e.g. lighting can be accumulated additively next to opaque normals.
+    pub fn color_target(
+        mut self,
+        format: wgpu::TextureFormat,
+        blend: impl Into<Option<wgpu::BlendState>>,
+    ) -> Self {
+        self.targets.push(wgpu::ColorTargetState {
+            format,
+            blend: blend.into(),
+            write_mask: wgpu::ColorWrites::ALL,
+        });
+        self
+    }
+
+    /// Whether fragments write their depth, they're depth tested either way.
+    pub fn depth_write(mut self, enabled: bool) -> Self {
+        self.depth_write = enabled;
+        self
+    }
+
+    /// How fragments are depth tested, `LessEqual` by default. `Equal` with
+    /// depth writes off shades only the visible surface after a
+    /// [`PipelineBuilder::depth_only`] pre-pass wrote the depth with the
+    /// same vertex stage.
+    pub fn depth_compare(mut self, compare: wgpu::CompareFunction) -> Self {
+        self.depth_compare = compare;
+        self
+    }
+
+    /// Must match the sample count of every attachment the pipeline is used with.
+    pub fn sample_count(mut self, count: u32) -> Self {
+        self.sample_count = count;
+        self
+    }
+
+    /// Specializes the WGSL shader for `defines`, see [`preprocess`]. Each
+    /// set of defines is a separate pipeline in a [`PipelineCache`].
+    pub fn defines(mut self, defines: &[&str]) -> anyhow::Result<Self> {
+        if let wgpu::ShaderSource::Wgsl(source) = &self.shader.source {
+            self.shader.source = wgpu::ShaderSource::Wgsl(preprocess(source, defines)?.into());
+        }
+        Ok(self)
+    }
+
+    /// Hash of everything the pipeline is built from, `None` unless the
+    /// shader is WGSL source.
+    pub fn id(&self) -> Option<PipelineId> {
+        let wgpu::ShaderSource::Wgsl(source) = &self.shader.source else {
+            return None;
+        };
+
+        let mut hasher = DefaultHasher::new();
+        self.layout.global_id().hash(&mut hasher);
+        source.hash(&mut hasher);
+        self.targets.hash(&mut hasher);
+        self.depth_format.hash(&mut hasher);
+        for layout in &self.vertex_layouts {
+            layout.array_stride.hash(&mut hasher);
+            layout.step_mode.hash(&mut hasher);
+            layout.attributes.hash(&mut hasher);
+        }
+        self.topology.hash(&mut hasher);
+        self.polygon_mode.hash(&mut hasher);
+        self.depth_write.hash(&mut hasher);
+        self.depth_compare.hash(&mut hasher);
+        self.sample_count.hash(&mut hasher);
+        Some(PipelineId(hasher.finish()))
+    }
+
+    /// [`PipelineBuilder::build`], failing instead of leaving it to wgpu's
+    /// validation when the vertex layouts don't add up.
+    pub fn try_build(self, gpu: &Gpu) -> anyhow::Result<wgpu::RenderPipeline> {
+        check_vertex_layouts(&self.vertex_layouts)?;
+        Ok(self.build(gpu))
+    }
+
+    pub fn build(self, gpu: &Gpu) -> wgpu::RenderPipeline {
+        let device = &gpu.device;
+        let shader = device.create_shader_module(self.shader);
+
+        let required = match self.polygon_mode {
+            wgpu::PolygonMode::Fill => wgpu::Features::empty(),
+            wgpu::PolygonMode::Line => wgpu::Features::POLYGON_MODE_LINE,
+            wgpu::PolygonMode::Point => wgpu::Features::POLYGON_MODE_POINT,
+        };
+        let polygon_mode = if device.features().contains(required) {
+            self.polygon_mode
+        } else {
+            log::warn!(
+                "{:?} polygons need {required:?}, building a filled pipeline instead",
+                self.polygon_mode
+            );
+            wgpu::PolygonMode::Fill
+        };
+
+        let targets = self.targets.iter().cloned().map(Some).collect::<Vec<_>>();
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(&format!("{:?}", shader)),
+            layout: Some(self.layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &self.vertex_layouts,
+            },
+            fragment: (!targets.is_empty()).then(|| wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &targets,
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: self.topology,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                polygon_mode,
+                // Requires Features::DEPTH_CLIP_CONTROL
+                unclipped_depth: false,
+                // Requires Features::CONSERVATIVE_RASTERIZATION
+                conservative: false,
+            },
+            depth_stencil: self.depth_format.map(|format| wgpu::DepthStencilState {
+                format,
+                depth_write_enabled: self.depth_write,
+                depth_compare: self.depth_compare,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: self.sample_count,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            // If the pipeline will be used with a multiview render pass, this
+            // indicates how many array layers the attachments will have.
+            multiview: None,
+        })
+    }
+}
+
+/// Layout entry for a storage buffer, `read_only` has to match the access
+/// the shader declares (`var<storage, read>` or `var<storage, read_write>`).
+pub fn storage_buffer_entry(
+    binding: u32,
+    visibility: wgpu::ShaderStages,
+    read_only: bool,
+) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+/// Bind group and its layout built from the same list of resources, bound in
+/// the order they're added starting at binding 0.
+pub struct BindGroupBuilder<'a> {
+    label: Option<&'a str>,
+    visibility: wgpu::ShaderStages,
+    layout_entries: Vec<wgpu::BindGroupLayoutEntry>,
+    resources: Vec<wgpu::BindingResource<'a>>,
+}
+
+impl<'a> BindGroupBuilder<'a> {
+    /// Every binding is visible to the `visibility` stages.
+    pub fn new(label: Option<&'a str>, visibility: wgpu::ShaderStages) -> Self {
+        Self {
+            label,
+            visibility,
+            layout_entries: Vec::new(),
+            resources: Vec::new(),
+        }
+    }
+
+    pub fn storage_buffer(mut self, buffer: &'a wgpu::Buffer, read_only: bool) -> Self {
+        let binding = self.layout_entries.len() as u32;
+        self.layout_entries
+            .push(storage_buffer_entry(binding, self.visibility, read_only));
+        self.resources.push(buffer.as_entire_binding());
+        self
+    }
+
+    pub fn uniform_buffer(mut self, buffer: &'a wgpu::Buffer) -> Self {
+        self.layout_entries.push(wgpu::BindGroupLayoutEntry {
+            binding: self.layout_entries.len() as u32,
+            visibility: self.visibility,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+        self.resources.push(buffer.as_entire_binding());
+        self
+    }
+
+    pub fn layout(&self, gpu: &Gpu) -> wgpu::BindGroupLayout {
+        gpu.device
+            .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: self.label,
+                entries: &self.layout_entries,
+            })
+    }
+
+    pub fn build(self, gpu: &Gpu) -> (wgpu::BindGroupLayout, wgpu::BindGroup) {
+        let layout = self.layout(gpu);
+        let entries = self
+            .resources
+            .into_iter()
+            .enumerate()
+            .map(|(binding, resource)| wgpu::BindGroupEntry {
+                binding: binding as u32,
+                resource,
+            })
+            .collect::<Vec<_>>();
+        let bind_group = gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: self.label,
+            layout: &layout,
+            entries: &entries,
+        });
+        (layout, bind_group)
+    }
+}
+
+/// Compute pipeline with bind group `i` laid out like the `i`th layout
+/// added, usually from [`BindGroupBuilder::layout`].
+pub struct ComputePipelineBuilder<'a> {
+    shader: wgpu::ShaderModuleDescriptor<'a>,
+    entry_point: &'a str,
+    bind_group_layouts: Vec<&'a wgpu::BindGroupLayout>,
+}
+
+impl<'a> ComputePipelineBuilder<'a> {
+    pub fn new(shader: wgpu::ShaderModuleDescriptor<'a>, entry_point: &'a str) -> Self {
+        Self {
+            shader,
+            entry_point,
+            bind_group_layouts: Vec::new(),
+        }
+    }
+
+    pub fn bind_group_layout(mut self, layout: &'a wgpu::BindGroupLayout) -> Self {
+        self.bind_group_layouts.push(layout);
+        self
+    }
+
+    /// Fails without compute shader support, or when the shader doesn't
+    /// match the layouts, e.g. writing to a buffer declared read only.
+    pub fn build(self, gpu: &Gpu) -> anyhow::Result<wgpu::ComputePipeline> {
+        if !gpu.capabilities().compute_shaders {
+            anyhow::bail!("The device doesn't support compute shaders");
+        }
+        let device = &gpu.device;
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let shader = device.create_shader_module(self.shader);
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(self.entry_point),
+            bind_group_layouts: &self.bind_group_layouts,
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(self.entry_point),
+            layout: Some(&layout),
+            module: &shader,
+            entry_point: self.entry_point,
+        });
+        if let Some(err) = futures::executor::block_on(device.pop_error_scope()) {
+            anyhow::bail!("Compute pipeline {} is invalid: {err}", self.entry_point);
+        }
+        Ok(pipeline)
+    }
+}
+
+/// Render pipelines by [`PipelineId`], so identical pipelines are only
+/// compiled once.
+#[derive(Default)]
+pub struct PipelineCache {
+    pipelines: HashMap<PipelineId, Arc<wgpu::RenderPipeline>>,
+}
+
+impl PipelineCache {
+    /// Builds the pipeline unless an identical one is cached already.
+    pub fn get_or_create(
+        &mut self,
+        gpu: &Gpu,
+        builder: PipelineBuilder,
+    ) -> anyhow::Result<PipelineId> {
+        let Some(id) = builder.id() else {
+            anyhow::bail!("Only pipelines with WGSL shaders can be cached");
+        };
+        check_vertex_layouts(&builder.vertex_layouts)?;
+        self.pipelines
+            .entry(id)
+            .or_insert_with(|| Arc::new(builder.build(gpu)));
+        Ok(id)
+    }
+
+    pub fn get(&self, id: PipelineId) -> Option<Arc<wgpu::RenderPipeline>> {
+        self.pipelines.get(&id).cloned()
+    }
+
+    /// Swaps in a rebuilt `pipeline` for `id`, users fetching `id` afterwards
+    /// get the new one.
+    pub fn replace(&mut self, id: PipelineId, pipeline: wgpu::RenderPipeline) {
+        self.pipelines.insert(id, Arc::new(pipeline));
+    }
+
+    pub fn len(&self) -> usize {
+        self.pipelines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pipelines.is_empty()
+    }
+
+    /// Drops every pipeline, e.g. after shaders changed on disk. Pipelines
+    /// still in use stay alive until their last [`Arc`] is dropped.
+    pub fn clear(&mut self) {
+        self.pipelines.clear();
+    }
+}
+
+/// What a [`ShaderWatcher`] does with a change that doesn't compile.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReloadFailure {
+    /// Keeps drawing with the last pipeline that compiled.
+    #[default]
+    KeepPrevious,
+    /// Draws in magenta until the shader is fixed, so the breakage can't go
+    /// unnoticed. The vertex stage of the last source that compiled is kept
+    /// and its `fs_main` swapped for [`ERROR_FRAGMENT`].
+    ShowError,
+}
+
+/// Fragment entry point of the pipelines shown by [`ReloadFailure::ShowError`].
+pub const ERROR_FRAGMENT: &str = "
+@fragment
+fn fs_main() -> @location(0) vec4<f32> {
+    return vec4<f32>(1.0, 0.0, 1.0, 1.0);
+}
+";
+
+/// `source` drawing everything in magenta, its own `fs_main` renamed out of
+/// the way.
+fn error_source(source: &str) -> String {
+    source.replacen("fn fs_main(", "fn fs_main_replaced(", 1) + ERROR_FRAGMENT
+}
+
+/// Rebuilds a cached pipeline whenever its WGSL file changes on disk, see
+/// [`Gpu::watch_shader`]. Stops watching when dropped.
+pub struct ShaderWatcher {
+    _watcher: notify::RecommendedWatcher,
+    reloads: Arc<AtomicUsize>,
+    failures: Arc<AtomicUsize>,
+}
+
+impl ShaderWatcher {
+    pub(crate) fn new(
+        gpu: Weak<Gpu>,
+        path: &Path,
+        id: PipelineId,
+        on_failure: ReloadFailure,
+        rebuild: impl Fn(&Gpu, &str) -> wgpu::RenderPipeline + Send + 'static,
+    ) -> anyhow::Result<Self> {
+        let path = path.canonicalize()?;
+        let Some(dir) = path.parent().map(Path::to_path_buf) else {
+            anyhow::bail!("{} has no parent directory to watch", path.display());
+        };
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let failures = Arc::new(AtomicUsize::new(0));
+        let mut last_compiled = std::fs::read_to_string(&path)?;
+
+        let (watched, reload_count, failure_count) =
+            (path.clone(), reloads.clone(), failures.clone());
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let Ok(event) = event else {
+                    return;
+                };
+                let changed = matches!(
+                    event.kind,
+                    notify::EventKind::Create(_) | notify::EventKind::Modify(_)
+                ) && event.paths.contains(&watched);
+                let Some(gpu) = gpu.upgrade().filter(|_| changed) else {
+                    return;
+                };
+                match reload(&gpu, &watched, id, &rebuild) {
+                    Ok(source) => {
+                        log::info!("Reloaded {}", watched.display());
+                        last_compiled = source;
+                        reload_count.fetch_add(1, Ordering::Release);
+                    }
+                    Err(err) => {
+                        match on_failure {
+                            ReloadFailure::KeepPrevious => {
+                                log::error!("Keeping the previous pipeline: {err}")
+                            }
+                            ReloadFailure::ShowError => {
+                                log::error!("Showing the error pipeline: {err}");
+                                let source = error_source(&last_compiled);
+                                if let Err(err) = build_checked(&gpu, id, &source, &rebuild) {
+                                    log::error!("The error pipeline didn't build either: {err}");
+                                }
+                            }
+                        }
+                        failure_count.fetch_add(1, Ordering::Release);
+                    }
+                }
+            })?;
+        // Editors often replace the file instead of writing to it, which a
+        // watch on the file itself wouldn't survive.
+        watcher.watch(&dir, notify::RecursiveMode::NonRecursive)?;
+
+        Ok(Self {
+            _watcher: watcher,
+            reloads,
+            failures,
+        })
+    }
+
+    /// How many times the pipeline was rebuilt.
+    pub fn reloads(&self) -> usize {
+        self.reloads.load(Ordering::Acquire)
+    }
+
+    /// How many changes didn't compile and were skipped.
+    pub fn failures(&self) -> usize {
+        self.failures.load(Ordering::Acquire)
+    }
+}
+
+/// Rebuilds the pipeline from the source at `path`, swapping it in only when
+/// it compiled without validation errors. Returns the source.
+fn reload(
+    gpu: &Gpu,
+    path: &Path,
+    id: PipelineId,
+    rebuild: &impl Fn(&Gpu, &str) -> wgpu::RenderPipeline,
+) -> anyhow::Result<String> {
+    let source = std::fs::read_to_string(path)?;
+    build_checked(gpu, id, &source, rebuild)
+        .map_err(|err| anyhow::anyhow!("{} failed to compile: {err}", path.display()))?;
+    Ok(source)
+}
+
+/// Swaps in the pipeline `rebuild` makes from `source` unless that raised
+/// validation errors.
+fn build_checked(
+    gpu: &Gpu,
+    id: PipelineId,
+    source: &str,
+    rebuild: &impl Fn(&Gpu, &str) -> wgpu::RenderPipeline,
+) -> anyhow::Result<()> {
+    gpu.device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let pipeline = rebuild(gpu, source);
+    if let Some(err) = futures::executor::block_on(gpu.device.pop_error_scope()) {
+        anyhow::bail!("{err}");
+    }
+    gpu.replace_pipeline(id, pipeline);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_constants_require_feature() {
+        let ranges = [wgpu::PushConstantRange {
+            stages: wgpu::ShaderStages::VERTEX,
+            range: 0..64,
+        }];
+        let mut limits = wgpu::Limits {
+            max_push_constant_size: 128,
+            ..Default::default()
+        };
+
+        assert!(check_push_constant_ranges(wgpu::Features::empty(), &limits, &[]).is_ok());
+        assert!(check_push_constant_ranges(wgpu::Features::empty(), &limits, &ranges).is_err());
+        assert!(
+            check_push_constant_ranges(wgpu::Features::PUSH_CONSTANTS, &limits, &ranges).is_ok()
+        );
+
+        limits.max_push_constant_size = 32;
+        assert!(
+            check_push_constant_ranges(wgpu::Features::PUSH_CONSTANTS, &limits, &ranges).is_err()
+        );
+    }
+
+    #[test]
+    fn test_blend_presets() {
+        let blend = |preset: BlendPreset| Option::<wgpu::BlendState>::from(preset);
+
+        assert_eq!(blend(BlendPreset::default()), None);
+        assert_eq!(
+            blend(BlendPreset::AlphaBlend),
+            Some(wgpu::BlendState::ALPHA_BLENDING)
+        );
+        assert_eq!(
+            blend(BlendPreset::PremultipliedAlpha),
+            Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING)
+        );
+        let additive = blend(BlendPreset::Additive).unwrap();
+        assert_eq!(additive.color.dst_factor, wgpu::BlendFactor::One);
+        assert_eq!(additive.alpha, additive.color);
+    }
+
+    #[test]
+    fn test_check_vertex_layouts() {
+        use crate::model::{InstanceRaw, ModelVertex, Vertex};
+
+        assert!(check_vertex_layouts(&[ModelVertex::desc(), InstanceRaw::desc()]).is_ok());
+        // The same locations twice.
+        assert!(check_vertex_layouts(&[ModelVertex::desc(), ModelVertex::desc()]).is_err());
+
+        let layout = |array_stride, attributes| wgpu::VertexBufferLayout {
+            array_stride,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes,
+        };
+        let point = wgpu::vertex_attr_array![0 => Float32x3, 1 => Unorm8x4];
+        assert!(check_vertex_layouts(&[layout(16, &point)]).is_ok());
+        // The color runs past a stride only holding the position.
+        assert!(check_vertex_layouts(&[layout(12, &point)]).is_err());
+        assert!(check_vertex_layouts(&[layout(18, &point)]).is_err());
+
+        let overlapping = [
+            wgpu::VertexAttribute {
+                format: wgpu::VertexFormat::Float32x3,
+                offset: 0,
+                shader_location: 0,
+            },
+            wgpu::VertexAttribute {
+                format: wgpu::VertexFormat::Float32x2,
+                offset: 8,
+                shader_location: 1,
+            },
+        ];
+        assert!(check_vertex_layouts(&[layout(20, &overlapping)]).is_err());
+    }
+
+    #[test]
+    fn test_polygon_mode_changes_id() {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping polygon mode test");
+            return;
+        };
+        let layout = gpu
+            .device
+            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts: &[],
+                push_constant_ranges: &[],
+            });
+        let builder = |mode| {
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl("".into()),
+            };
+            PipelineBuilder::new(&layout, wgpu::TextureFormat::Rgba8UnormSrgb, shader)
+                .polygon_mode(mode)
+        };
+
+        assert_ne!(
+            builder(wgpu::PolygonMode::Fill).id(),
+            builder(wgpu::PolygonMode::Line).id()
+        );
+
+        let has_lines = gpu
+            .device
+            .features()
+            .contains(wgpu::Features::POLYGON_MODE_LINE);
+        assert_eq!(gpu.set_wireframe(true).is_ok(), has_lines);
+        assert_eq!(gpu.wireframe(), has_lines);
+        gpu.set_wireframe(false).unwrap();
+        assert!(!gpu.wireframe());
+    }
+
+    #[test]
+    fn test_blend_per_color_target() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping MRT blend test");
+            return Ok(());
+        };
+        let layout = gpu
+            .device
+            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts: &[],
+                push_constant_ranges: &[],
+            });
+        let shader = "
+            @vertex
+            fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+                return vec4<f32>(f32(index), 0.0, 0.0, 1.0);
+            }
+
+            struct Targets {
+                @location(0) lighting: vec4<f32>,
+                @location(1) normal: vec4<f32>,
+            }
+
+            @fragment
+            fn fs_main() -> Targets {
+                return Targets(vec4<f32>(0.1), vec4<f32>(0.5, 0.5, 1.0, 1.0));
+            }
+        ";
+        let additive = wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+            alpha: wgpu::BlendComponent::REPLACE,
+        };
+        let builder = |lighting_blend| {
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl(shader.into()),
+            };
+            PipelineBuilder::new(&layout, wgpu::TextureFormat::Rgba16Float, shader)
+                .blend(lighting_blend)
+                .color_target(wgpu::TextureFormat::Rgba8Unorm, None)
+        };
+
+        let mrt = builder(Some(additive));
+        assert_eq!(mrt.targets.len(), 2);
+        assert_eq!(mrt.targets[0].blend, Some(additive));
+        assert_eq!(mrt.targets[1].blend, None);
+        assert_eq!(mrt.targets[1].format, wgpu::TextureFormat::Rgba8Unorm);
+        assert_ne!(mrt.id(), builder(None).id());
+
+        gpu.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        mrt.build(&gpu);
+        if let Some(err) = futures::executor::block_on(gpu.device.pop_error_scope()) {
+            anyhow::bail!("{err}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_depth_prepass_shades_each_pixel_once() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping depth pre-pass test");
+            return Ok(());
+        };
+        let device = &gpu.device;
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[],
+            push_constant_ranges: &[],
+        });
+        // Two fullscreen triangles, the far one first, each adding a quarter
+        // to the red channel.
+        let shader = "
+            @vertex
+            fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+                let corner = vec2<f32>(f32((index % 3u) & 1u), f32((index % 3u) >> 1u));
+                let depth = select(0.8, 0.2, index >= 3u);
+                return vec4<f32>(corner * 4.0 - 1.0, depth, 1.0);
+            }
+
+            @fragment
+            fn fs_main() -> @location(0) vec4<f32> {
+                return vec4<f32>(0.25, 0.0, 0.0, 1.0);
+            }
+        ";
+        let shader = || wgpu::ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(shader.into()),
+        };
+        let color_format = wgpu::TextureFormat::Rgba8Unorm;
+        let depth_format = wgpu::TextureFormat::Depth32Float;
+        let additive = wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+            alpha: wgpu::BlendComponent::REPLACE,
+        };
+        let color = |compare| {
+            PipelineBuilder::new(&layout, color_format, shader())
+                .depth_format(Some(depth_format))
+                .depth_compare(compare)
+                .depth_write(compare != wgpu::CompareFunction::Equal)
+                .blend(additive)
+        };
+        assert_ne!(
+            color(wgpu::CompareFunction::Equal).id(),
+            color(wgpu::CompareFunction::LessEqual).id()
+        );
+        let prepass = PipelineBuilder::depth_only(&layout, depth_format, shader()).build(&gpu);
+        let shaded = color(wgpu::CompareFunction::LessEqual).build(&gpu);
+        let equal = color(wgpu::CompareFunction::Equal).build(&gpu);
+
+        let texture = |format, usage| {
+            device.create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size: wgpu::Extent3d {
+                    width: 1,
+                    height: 1,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | usage,
+                view_formats: &[],
+            })
+        };
+        let render = |with_prepass: bool| -> anyhow::Result<u8> {
+            let target = texture(color_format, wgpu::TextureUsages::COPY_SRC);
+            let view = target.create_view(&Default::default());
+            let depth = texture(depth_format, wgpu::TextureUsages::empty());
+            let depth_view = depth.create_view(&Default::default());
+            let depth_attachment = |load| wgpu::RenderPassDepthStencilAttachment {
+                view: &depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load,
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            };
+
+            let mut encoder = device.create_command_encoder(&Default::default());
+            if with_prepass {
+                let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: None,
+                    color_attachments: &[],
+                    depth_stencil_attachment: Some(depth_attachment(wgpu::LoadOp::Clear(1.0))),
+                    occlusion_query_set: None,
+                    timestamp_writes: None,
+                });
+                pass.set_pipeline(&prepass);
+                pass.draw(0..6, 0..1);
+            }
+            {
+                let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: None,
+                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                        view: &view,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                            store: wgpu::StoreOp::Store,
+                        },
+                    })],
+                    depth_stencil_attachment: Some(depth_attachment(match with_prepass {
+                        true => wgpu::LoadOp::Load,
+                        false => wgpu::LoadOp::Clear(1.0),
+                    })),
+                    occlusion_query_set: None,
+                    timestamp_writes: None,
+                });
+                pass.set_pipeline(if with_prepass { &equal } else { &shaded });
+                pass.draw(0..6, 0..1);
+            }
+            gpu.queue.submit([encoder.finish()]);
+            Ok(gpu.read_texture(&target)?.pixels[0])
+        };
+
+        // Both triangles pass the depth test in draw order, only the near
+        // one matches the pre-pass depth.
+        assert!(render(false)?.abs_diff(128) <= 1);
+        assert!(render(true)?.abs_diff(64) <= 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preprocess() -> anyhow::Result<()> {
+        let source =
+            "a\n#ifdef HAS_NORMAL_MAP\nb\n#ifndef HAS_EMISSIVE\nc\n#endif\n#else\nd\n#endif\ne";
+
+        assert_eq!(preprocess(source, &[])?, "a\n\n\n\n\n\n\nd\n\ne\n");
+        assert_eq!(
+            preprocess(source, &["HAS_NORMAL_MAP"])?,
+            "a\n\nb\n\nc\n\n\n\n\ne\n"
+        );
+        assert_eq!(
+            preprocess(source, &["HAS_NORMAL_MAP", "HAS_EMISSIVE"])?,
+            "a\n\nb\n\n\n\n\n\n\ne\n"
+        );
+        assert!(preprocess("#ifdef A\n", &[]).is_err());
+        assert!(preprocess("#endif\n", &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_shader_variants() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping shader variant test");
+            return Ok(());
+        };
+        let layout = gpu
+            .device
+            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts: &[],
+                push_constant_ranges: &[],
+            });
+        let shader = "
+            @vertex
+            fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+                return vec4<f32>(f32(index), 0.0, 0.0, 1.0);
+            }
+
+            @fragment
+            fn fs_main() -> @location(0) vec4<f32> {
+            #ifdef HAS_NORMAL_MAP
+                return vec4<f32>(0.5, 0.5, 1.0, 1.0);
+            #else
+                return vec4<f32>(1.0);
+            #endif
+            }
+        ";
+        let builder = |defines: &[&str]| {
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl(shader.into()),
+            };
+            PipelineBuilder::new(&layout, wgpu::TextureFormat::Rgba8UnormSrgb, shader)
+                .defines(defines)
+        };
+
+        let mut cache = PipelineCache::default();
+        let plain = cache.get_or_create(&gpu, builder(&[])?)?;
+        let normal_mapped = cache.get_or_create(&gpu, builder(&["HAS_NORMAL_MAP"])?)?;
+
+        assert_ne!(plain, normal_mapped);
+        assert_eq!(cache.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_identical_pipelines_are_cached() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping pipeline cache test");
+            return Ok(());
+        };
+        let layout = gpu
+            .device
+            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts: &[],
+                push_constant_ranges: &[],
+            });
+        let shader = "
+            @vertex
+            fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+                return vec4<f32>(f32(index), 0.0, 0.0, 1.0);
+            }
+
+            @fragment
+            fn fs_main() -> @location(0) vec4<f32> {
+                return vec4<f32>(1.0);
+            }
+        ";
+        let builder = |format| {
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl(shader.into()),
+            };
+            PipelineBuilder::new(&layout, format, shader)
+        };
+
+        let mut cache = PipelineCache::default();
+        let srgb = cache.get_or_create(&gpu, builder(wgpu::TextureFormat::Rgba8UnormSrgb))?;
+        let again = cache.get_or_create(&gpu, builder(wgpu::TextureFormat::Rgba8UnormSrgb))?;
+        let linear = cache.get_or_create(&gpu, builder(wgpu::TextureFormat::Rgba8Unorm))?;
+
+        assert_eq!(srgb, again);
+        assert_ne!(srgb, linear);
+        assert_eq!(cache.len(), 2);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert!(cache.get(srgb).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_shader_hot_reload() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping shader reload test");
+            return Ok(());
+        };
+        let gpu = Arc::new(gpu);
+        let dir = std::env::temp_dir().join(format!("void-shaders-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("reload.wgsl");
+        let shader = |color: &str| {
+            format!(
+                "
+                @vertex
+                fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {{
+                    return vec4<f32>(f32(index), 0.0, 0.0, 1.0);
+                }}
+
+                @fragment
+                fn fs_main() -> @location(0) vec4<f32> {{
+                    return vec4<f32>({color});
+                }}
+                "
+            )
+        };
+        std::fs::write(&path, shader("1.0"))?;
+
+        let layout = Arc::new(
+            gpu.device
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: None,
+                    bind_group_layouts: &[],
+                    push_constant_ranges: &[],
+                }),
+        );
+        fn builder<'a>(layout: &'a wgpu::PipelineLayout, source: &str) -> PipelineBuilder<'a> {
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl(source.to_string().into()),
+            };
+            PipelineBuilder::new(layout, wgpu::TextureFormat::Rgba8Unorm, shader)
+        }
+        let id = gpu.get_or_create_pipeline(builder(&layout, &shader("1.0")))?;
+        let original = gpu.pipeline(id).unwrap();
+
+        let rebuild_layout = layout.clone();
+        let watcher = gpu.watch_shader(
+            &path,
+            id,
+            ReloadFailure::KeepPrevious,
+            move |gpu, source| builder(&rebuild_layout, source).build(gpu),
+        )?;
+        let wait_for = |done: &dyn Fn() -> bool| {
+            let start = std::time::Instant::now();
+            while !done() && start.elapsed() < std::time::Duration::from_secs(5) {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            done()
+        };
+
+        std::fs::write(&path, shader("0.5"))?;
+        assert!(
+            wait_for(&|| watcher.reloads() > 0),
+            "shader was not reloaded"
+        );
+        let reloaded = gpu.pipeline(id).unwrap();
+        assert!(!Arc::ptr_eq(&original, &reloaded));
+
+        std::fs::write(&path, shader("oops"))?;
+        assert!(
+            wait_for(&|| watcher.failures() > 0),
+            "broken shader went unnoticed"
+        );
+        assert!(Arc::ptr_eq(&reloaded, &gpu.pipeline(id).unwrap()));
+
+        drop(watcher);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_shader_reload_failure_shows_error() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping shader reload error test");
+            return Ok(());
+        };
+        let gpu = Arc::new(gpu);
+        let dir = std::env::temp_dir().join(format!("void-shaders-error-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("error.wgsl");
+        // A triangle covering the whole target.
+        let shader = |color: &str| {
+            format!(
+                "
+                @vertex
+                fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {{
+                    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+                    return vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+                }}
+
+                @fragment
+                fn fs_main() -> @location(0) vec4<f32> {{
+                    return vec4<f32>({color});
+                }}
+                "
+            )
+        };
+        std::fs::write(&path, shader("0.0, 1.0, 0.0, 1.0"))?;
+
+        let layout = Arc::new(
+            gpu.device
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: None,
+                    bind_group_layouts: &[],
+                    push_constant_ranges: &[],
+                }),
+        );
+        fn builder<'a>(layout: &'a wgpu::PipelineLayout, source: &str) -> PipelineBuilder<'a> {
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl(source.to_string().into()),
+            };
+            PipelineBuilder::new(layout, wgpu::TextureFormat::Rgba8Unorm, shader)
+        }
+        let id = gpu.get_or_create_pipeline(builder(&layout, &shader("0.0, 1.0, 0.0, 1.0")))?;
+
+        let rebuild_layout = layout.clone();
+        let watcher =
+            gpu.watch_shader(&path, id, ReloadFailure::ShowError, move |gpu, source| {
+                builder(&rebuild_layout, source).build(gpu)
+            })?;
+        std::fs::write(&path, shader("oops"))?;
+        let start = std::time::Instant::now();
+        while watcher.failures() == 0 && start.elapsed() < std::time::Duration::from_secs(5) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(watcher.failures() > 0, "broken shader went unnoticed");
+
+        let target = gpu.device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: 4,
+                height: 4,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = target.create_view(&Default::default());
+        let pipeline = gpu.pipeline(id).unwrap();
+        let mut encoder = gpu.device.create_command_encoder(&Default::default());
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations::default(),
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&pipeline);
+            pass.draw(0..3, 0..1);
+        }
+        gpu.queue.submit([encoder.finish()]);
+
+        let pixels = gpu.read_texture_mip(&target, 0)?;
+        assert!(pixels.chunks(4).all(|pixel| pixel == [255, 0, 255, 255]));
+
+        drop(watcher);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_compute_kernel_end_to_end() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping compute kernel test");
+            return Ok(());
+        };
+        if !gpu.capabilities().compute_shaders {
+            eprintln!("No compute shaders, skipping compute kernel test");
+            return Ok(());
+        }
+        const SHADER: &str = "
+            @group(0) @binding(0) var<storage, read> input: array<u32>;
+            @group(0) @binding(1) var<storage, read_write> output: array<u32>;
+
+            @compute @workgroup_size(64)
+            fn double(@builtin(global_invocation_id) id: vec3<u32>) {
+                if id.x < arrayLength(&input) {
+                    output[id.x] = input[id.x] * 2u;
+                }
+            }
+        ";
+        let shader = || wgpu::ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        };
+
+        let values = (0..100u32).collect::<Vec<_>>();
+        let size = std::mem::size_of_val(values.as_slice()) as wgpu::BufferAddress;
+        let input = gpu.create_storage_buffer(size, wgpu::BufferUsages::empty());
+        let output = gpu.create_storage_buffer(size, wgpu::BufferUsages::empty());
+        gpu.write_buffer(input, &values)?;
+
+        let (input_buffer, output_buffer) =
+            (gpu.buffer(input).unwrap(), gpu.buffer(output).unwrap());
+        let (layout, bind_group) = BindGroupBuilder::new(None, wgpu::ShaderStages::COMPUTE)
+            .storage_buffer(&input_buffer, true)
+            .storage_buffer(&output_buffer, false)
+            .build(&gpu);
+        let pipeline = ComputePipelineBuilder::new(shader(), "double")
+            .bind_group_layout(&layout)
+            .build(&gpu)?;
+
+        let mut encoder = gpu.device.create_command_encoder(&Default::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&Default::default());
+            pass.set_pipeline(&pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(values.len().div_ceil(64) as u32, 1, 1);
+        }
+        gpu.queue.submit([encoder.finish()]);
+
+        let doubled = gpu.read_buffer_by_id(output)?;
+        let doubled: &[u32] = bytemuck::cast_slice(&doubled);
+        assert!(doubled.iter().zip(&values).all(|(d, v)| *d == v * 2));
+
+        // The shader writes to the output, declaring it read only can't work.
+        let read_only = BindGroupBuilder::new(None, wgpu::ShaderStages::COMPUTE)
+            .storage_buffer(&input_buffer, true)
+            .storage_buffer(&output_buffer, true)
+            .layout(&gpu);
+        assert!(ComputePipelineBuilder::new(shader(), "double")
+            .bind_group_layout(&read_only)
+            .build(&gpu)
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+struct Camera {
+    view_pos: vec4<f32>,
+    view: mat4x4<f32>,
+    view_proj: mat4x4<f32>,
+    inv_proj: mat4x4<f32>,
+    inv_view: mat4x4<f32>,
+}
+@group(0) @binding(0)
+var<uniform> camera: Camera;
+
+// Instances are laid out on a grid in the xz plane, GRID_WIDTH per row.
+const GRID_WIDTH: u32 = 4u;
+const SPACING: f32 = 2.0;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) normal: vec3<f32>,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    @builtin(instance_index) instance: u32,
+) -> VertexOutput {
+    let offset = vec3<f32>(
+        f32(instance % GRID_WIDTH) * SPACING,
+        0.0,
+        f32(instance / GRID_WIDTH) * SPACING,
+    );
+
+    var out: VertexOutput;
+    out.normal = model.normal;
+    out.clip_position = camera.view_proj * vec4<f32>(model.position + offset, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = normalize(in.normal) * 0.5 + 0.5;
+    return vec4<f32>(color, 1.0);
+}
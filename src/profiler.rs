@@ -0,0 +1,427 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    mem,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+};
+
+use crate::gpu::Gpu;
+
+/// Nothing in the readback buffer, it can take the next frame's timestamps.
+const IDLE: u8 = 0;
+/// A copy into the readback buffer was recorded, it has to be submitted
+/// before the buffer can be mapped.
+const COPIED: u8 = 1;
+const MAPPING: u8 = 2;
+const MAPPED: u8 = 3;
+
+const TIMESTAMP_SIZE: wgpu::BufferAddress = mem::size_of::<u64>() as wgpu::BufferAddress;
+/// Markers a frame can insert with [`Profiler::insert_marker`], after the
+/// two timestamps of the timed pass.
+const MAX_MARKERS: u32 = 16;
+/// Name of the timed pass in the trace.
+const PASS_NAME: &str = "Scene";
+
+/// Milliseconds between two timestamps `period` nanoseconds per tick apart,
+/// `None` when the counter went backwards.
+fn elapsed_ms(begin: u64, end: u64, period: f32) -> Option<f64> {
+    let ticks = end.checked_sub(begin)?;
+    Some(ticks as f64 * period as f64 / 1_000_000.0)
+}
+
+/// Something that happened on the GPU timeline, in nanoseconds of the
+/// GPU's clock.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TraceEvent {
+    /// A timed pass.
+    Span { name: String, begin: f64, end: f64 },
+    /// A marker inserted with [`Profiler::insert_marker`].
+    Marker { name: String, time: f64 },
+}
+
+/// Events of the last frames read back by the [`Profiler`], oldest first.
+#[derive(Debug, Default)]
+pub struct GpuTrace {
+    events: VecDeque<TraceEvent>,
+}
+
+impl GpuTrace {
+    /// Events kept before the oldest are dropped.
+    const MAX_EVENTS: usize = 4096;
+
+    fn extend(&mut self, events: impl IntoIterator<Item = TraceEvent>) {
+        self.events.extend(events);
+        let excess = self.events.len().saturating_sub(Self::MAX_EVENTS);
+        self.events.drain(..excess);
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = &TraceEvent> {
+        self.events.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    /// The events in the Chrome trace event format, for `chrome://tracing`
+    /// or Perfetto. Spans are complete events and markers instant events,
+    /// timestamped in microseconds.
+    pub fn to_chrome_json(&self) -> String {
+        let mut json = String::from("{\"traceEvents\":[");
+        for (i, event) in self.events.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = match event {
+                TraceEvent::Span { name, begin, end } => write!(
+                    json,
+                    "{{\"name\":{},\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":0,\"tid\":0}}",
+                    json_string(name),
+                    begin / 1000.0,
+                    (end - begin) / 1000.0
+                ),
+                TraceEvent::Marker { name, time } => write!(
+                    json,
+                    "{{\"name\":{},\"ph\":\"i\",\"s\":\"g\",\"ts\":{},\"pid\":0,\"tid\":0}}",
+                    json_string(name),
+                    time / 1000.0
+                ),
+            };
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+/// `value` as a quoted JSON string.
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Events of a frame from its `timestamps`: the timed pass followed by one
+/// per marker in `markers`. A pass whose counter went backwards is left out.
+fn frame_events(timestamps: &[u64], markers: &[String], period: f32) -> Vec<TraceEvent> {
+    let ns = |ticks: u64| ticks as f64 * period as f64;
+    let pass = (timestamps[1] >= timestamps[0]).then(|| TraceEvent::Span {
+        name: PASS_NAME.to_string(),
+        begin: ns(timestamps[0]),
+        end: ns(timestamps[1]),
+    });
+    let markers = markers
+        .iter()
+        .zip(&timestamps[2..])
+        .map(|(name, ticks)| TraceEvent::Marker {
+            name: name.clone(),
+            time: ns(*ticks),
+        });
+    pass.into_iter().chain(markers).collect()
+}
+
+struct Queries {
+    query_set: wgpu::QuerySet,
+    resolve_buffer: wgpu::Buffer,
+    readback_buffer: wgpu::Buffer,
+    /// Nanoseconds per timestamp tick.
+    period: f32,
+    state: Arc<AtomicU8>,
+    /// Names of the markers written this frame, at the queries after the
+    /// pass's.
+    frame_markers: Vec<String>,
+    /// Names of the markers in the readback buffer.
+    copied_markers: Vec<String>,
+}
+
+/// Times the scene's render pass with timestamp queries. Reading the result
+/// back takes a couple of frames, which never wait on the GPU for it. The
+/// pass and the markers of the frames read back are collected in a
+/// [`GpuTrace`].
+///
+/// Does nothing without [`wgpu::Features::TIMESTAMP_QUERY`].
+pub struct Profiler {
+    queries: Option<Queries>,
+    last_frame_ms: Option<f64>,
+    trace: GpuTrace,
+}
+
+impl Profiler {
+    pub fn new(gpu: &Gpu) -> Self {
+        let queries = gpu.capabilities().timestamp_queries.then(|| {
+            let device = &gpu.device;
+            let count = 2 + MAX_MARKERS;
+            let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+                label: Some("Profiler::query_set"),
+                ty: wgpu::QueryType::Timestamp,
+                count,
+            });
+            let resolve_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Profiler::resolve"),
+                size: count as wgpu::BufferAddress * TIMESTAMP_SIZE,
+                usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            });
+            let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Profiler::readback"),
+                size: count as wgpu::BufferAddress * TIMESTAMP_SIZE,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+
+            Queries {
+                query_set,
+                resolve_buffer,
+                readback_buffer,
+                period: gpu.queue.get_timestamp_period(),
+                state: Arc::new(AtomicU8::new(IDLE)),
+                frame_markers: Vec::new(),
+                copied_markers: Vec::new(),
+            }
+        });
+
+        Self {
+            queries,
+            last_frame_ms: None,
+            trace: GpuTrace::default(),
+        }
+    }
+
+    /// For the `timestamp_writes` of the pass to time.
+    pub fn timestamp_writes(&self) -> Option<wgpu::RenderPassTimestampWrites<'_>> {
+        self.queries
+            .as_ref()
+            .map(|queries| wgpu::RenderPassTimestampWrites {
+                query_set: &queries.query_set,
+                beginning_of_pass_write_index: Some(0),
+                end_of_pass_write_index: Some(1),
+            })
+    }
+
+    /// Records a timestamp named `name` on the GPU timeline at this point
+    /// of `encoder`, e.g. "Shadows done", which shows up in the
+    /// [`GpuTrace`] once the frame is read back. Markers have to come before
+    /// [`Profiler::resolve`], past [`MAX_MARKERS`] a frame they're dropped.
+    pub fn insert_marker(&mut self, encoder: &mut wgpu::CommandEncoder, name: &str) {
+        let Some(queries) = &mut self.queries else {
+            return;
+        };
+        let index = queries.frame_markers.len() as u32;
+        if index >= MAX_MARKERS {
+            return;
+        }
+        encoder.write_timestamp(&queries.query_set, 2 + index);
+        queries.frame_markers.push(name.to_string());
+    }
+
+    /// Records resolving this frame's timestamps at the end of the frame,
+    /// and picks up the timestamps of an earlier frame once they're
+    /// readable.
+    pub fn resolve(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        let Some(queries) = &mut self.queries else {
+            return;
+        };
+
+        match queries.state.load(Ordering::Acquire) {
+            MAPPED => {
+                let timestamps = {
+                    let view = queries.readback_buffer.slice(..).get_mapped_range();
+                    bytemuck::cast_slice::<_, u64>(&view).to_vec()
+                };
+                queries.readback_buffer.unmap();
+                self.last_frame_ms = elapsed_ms(timestamps[0], timestamps[1], queries.period);
+                self.trace.extend(frame_events(
+                    &timestamps,
+                    &queries.copied_markers,
+                    queries.period,
+                ));
+                queries.state.store(IDLE, Ordering::Release);
+            }
+            // The copy was submitted with the last frame.
+            COPIED => {
+                queries.state.store(MAPPING, Ordering::Release);
+                let state = queries.state.clone();
+                queries
+                    .readback_buffer
+                    .slice(..)
+                    .map_async(wgpu::MapMode::Read, move |result| {
+                        let next = if result.is_ok() { MAPPED } else { IDLE };
+                        state.store(next, Ordering::Release);
+                    });
+            }
+            _ => {}
+        }
+
+        let count = 2 + queries.frame_markers.len() as u32;
+        encoder.resolve_query_set(&queries.query_set, 0..count, &queries.resolve_buffer, 0);
+        if queries.state.load(Ordering::Acquire) == IDLE {
+            encoder.copy_buffer_to_buffer(
+                &queries.resolve_buffer,
+                0,
+                &queries.readback_buffer,
+                0,
+                count as wgpu::BufferAddress * TIMESTAMP_SIZE,
+            );
+            queries.copied_markers = mem::take(&mut queries.frame_markers);
+            queries.state.store(COPIED, Ordering::Release);
+        } else {
+            // Frames that can't be read back aren't traced.
+            queries.frame_markers.clear();
+        }
+    }
+
+    /// GPU time of the most recent timed pass read back so far, `None` until
+    /// the first one arrives or without timestamp queries.
+    pub fn last_frame_ms(&self) -> Option<f64> {
+        self.last_frame_ms
+    }
+
+    pub fn trace(&self) -> &GpuTrace {
+        &self.trace
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_elapsed_ms() {
+        assert_eq!(elapsed_ms(1_000, 3_000_000, 1.0), Some(2.999));
+        assert_eq!(elapsed_ms(0, 1_000, 1_000.0), Some(1.0));
+        assert_eq!(elapsed_ms(10, 5, 1.0), None);
+    }
+
+    #[test]
+    fn test_markers_in_chrome_trace() {
+        let markers = ["Frame start".to_string(), "Shadows \"done\"".to_string()];
+        let mut trace = GpuTrace::default();
+        trace.extend(frame_events(&[1_000, 5_000, 500, 3_000], &markers, 2.0));
+        assert_eq!(
+            trace.events().cloned().collect::<Vec<_>>(),
+            [
+                TraceEvent::Span {
+                    name: PASS_NAME.to_string(),
+                    begin: 2_000.0,
+                    end: 10_000.0
+                },
+                TraceEvent::Marker {
+                    name: markers[0].clone(),
+                    time: 1_000.0
+                },
+                TraceEvent::Marker {
+                    name: markers[1].clone(),
+                    time: 6_000.0
+                },
+            ]
+        );
+
+        let json = trace.to_chrome_json();
+        let first = json
+            .find(r#"{"name":"Frame start","ph":"i","s":"g","ts":1,"#)
+            .unwrap();
+        let second = json
+            .find(r#"{"name":"Shadows \"done\"","ph":"i","s":"g","ts":6,"#)
+            .unwrap();
+        assert!(first < second);
+        assert!(json.contains(r#""ph":"X","ts":2,"dur":8,"#));
+    }
+
+    #[test]
+    fn test_trace_keeps_the_last_events() {
+        let mut trace = GpuTrace::default();
+        let marker = |time| TraceEvent::Marker {
+            name: String::new(),
+            time,
+        };
+        trace.extend((0..GpuTrace::MAX_EVENTS + 2).map(|i| marker(i as f64)));
+        assert_eq!(trace.events().count(), GpuTrace::MAX_EVENTS);
+        assert_eq!(trace.events().next(), Some(&marker(2.0)));
+    }
+
+    #[test]
+    fn test_profiler_times_a_pass() {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping profiler test");
+            return;
+        };
+        let target = gpu.device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: 4,
+                height: 4,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+        let view = target.create_view(&Default::default());
+
+        let mut profiler = Profiler::new(&gpu);
+        assert_eq!(
+            profiler.queries.is_some(),
+            gpu.capabilities().timestamp_queries
+        );
+
+        // Copied on the first frame, mapped on the second, read on the third.
+        for _ in 0..3 {
+            let mut encoder = gpu.device.create_command_encoder(&Default::default());
+            profiler.insert_marker(&mut encoder, "Frame start");
+            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations::default(),
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: profiler.timestamp_writes(),
+            });
+            profiler.insert_marker(&mut encoder, "Pass done");
+            profiler.resolve(&mut encoder);
+            gpu.queue.submit([encoder.finish()]);
+            gpu.device.poll(wgpu::Maintain::Wait);
+        }
+
+        match profiler.last_frame_ms() {
+            Some(ms) => assert!(profiler.queries.is_some() && ms >= 0.0),
+            None => assert!(!profiler.queries.is_some()),
+        }
+
+        // Both markers of the frame read back, in the order they were
+        // inserted.
+        let markers = profiler
+            .trace()
+            .events()
+            .filter_map(|event| match event {
+                TraceEvent::Marker { name, time } => Some((name.as_str(), *time)),
+                TraceEvent::Span { .. } => None,
+            })
+            .collect::<Vec<_>>();
+        if profiler.queries.is_some() {
+            assert_eq!(markers.len(), 2);
+            assert_eq!((markers[0].0, markers[1].0), ("Frame start", "Pass done"));
+            assert!(markers[0].1 <= markers[1].1);
+        } else {
+            assert!(markers.is_empty());
+        }
+    }
+}
@@ -1,11 +1,13 @@
 use crate::{
     gpu::Gpu,
-    io::fs::{IMeshFile, MeshFile},
+    io::{
+        event::{self, ChannelReceiver, ChannelSender},
+        fs::{generate_tangents, IMeshFile, MeshFile},
+    },
     model, texture,
 };
 use image::codecs::hdr::HdrDecoder;
-use std::{io::Cursor, path::PathBuf};
-use wgpu::util::DeviceExt;
+use std::{ffi::OsStr, io::Cursor, path::PathBuf, sync::Arc};
 
 pub async fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
     let path = std::path::Path::new("./").join("models").join(file_name);
@@ -14,44 +16,92 @@ pub async fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
 }
 
 pub async fn load_model(path: PathBuf, gpu: &Gpu) -> anyhow::Result<model::Model> {
+    match path.extension().and_then(OsStr::to_str) {
+        Some("gltf" | "glb") => return model::Model::from_gltf(gpu, &path),
+        Some("obj") => return model::Model::from_obj(gpu, &path),
+        _ => {}
+    }
+
     let (device, queue) = (&gpu.device, &gpu.queue);
     let file_name = path.display().to_string();
     let mesh_file = MeshFile::new(path)?;
 
-    let vertices = mesh_file.get_vertices()?;
+    let mut vertices = mesh_file.get_vertices()?;
     let indices = mesh_file.get_indices()?;
+    generate_tangents(&mut vertices, &indices);
 
     let default_texture = texture::Texture::random_texture(device, queue)?;
 
-    let bind_group = texture::Texture::load(gpu, &default_texture);
-
-    let materials = vec![model::Material {
-        bind_group,
-        diffuse_texture: default_texture,
-        name: "Default texture".to_string(),
-    }];
-
-    let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some(&format!("{:?} Vertex Buffer", file_name)),
-        contents: bytemuck::cast_slice(&vertices),
-        usage: wgpu::BufferUsages::VERTEX,
-    });
-
-    let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some(&format!("{:?} Index Buffer", file_name)),
-        contents: bytemuck::cast_slice(&indices),
-        usage: wgpu::BufferUsages::INDEX,
-    });
-
-    let meshes = vec![model::Mesh {
-        name: file_name.to_string(),
-        vertex_buffer,
-        index_buffer,
-        material: 0,
-        num_elements: indices.len() as u32,
-    }];
-
-    Ok(model::Model { meshes, materials })
+    let materials = vec![model::Material::new(
+        gpu,
+        "Default texture",
+        default_texture,
+    )];
+
+    let meshes = vec![model::Mesh::new(device, &file_name, &vertices, &indices, 0)];
+
+    Ok(model::Model {
+        meshes,
+        materials,
+        skin: None,
+    })
+}
+
+/// A model finished by [`AssetLoader`], or why it couldn't be loaded.
+pub struct LoadedModel {
+    pub path: PathBuf,
+    pub model: anyhow::Result<model::Model>,
+}
+
+/// Loads models on the runtime's blocking threads, images being decoded on
+/// the rayon pool, and hands them back through a channel so the frame loop
+/// only ever picks up finished models.
+pub struct AssetLoader {
+    gpu: Arc<Gpu>,
+    runtime: tokio::runtime::Handle,
+    sender: ChannelSender<LoadedModel>,
+    receiver: ChannelReceiver<LoadedModel>,
+}
+
+impl AssetLoader {
+    /// A loader that holds at most `capacity` finished models. Once that
+    /// many are waiting, further finished models wait in their tasks until
+    /// the frame loop picks some up. The tasks don't hold a runtime thread
+    /// while they wait, only the model.
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn bounded(gpu: Arc<Gpu>, runtime: tokio::runtime::Handle, capacity: usize) -> Self {
+        let (sender, receiver) = event::create_bounded_channel(capacity);
+        Self {
+            gpu,
+            runtime,
+            sender,
+            receiver,
+        }
+    }
+
+    /// Starts loading `path`, see [`load_model`] for the formats.
+    pub fn load(&self, path: impl Into<PathBuf>) {
+        let path = path.into();
+        let gpu = self.gpu.clone();
+        let sender = self.sender.clone();
+        self.runtime.spawn(async move {
+            let loading = path.clone();
+            let model = tokio::task::spawn_blocking(move || {
+                futures::executor::block_on(load_model(loading, &gpu))
+            })
+            .await
+            .unwrap_or_else(|err| Err(anyhow::anyhow!("Loading panicked: {err}")));
+            // The loader was dropped, nobody is waiting for the model.
+            let _ = sender.send(LoadedModel { path, model }).await;
+        });
+    }
+
+    /// A model that finished loading, if any, without waiting.
+    pub fn try_recv(&mut self) -> Option<LoadedModel> {
+        // The loader holds a sender itself, the channel never closes.
+        self.receiver.try_recv().ok().flatten()
+    }
 }
 
 pub struct HdrLoader {
@@ -225,3 +275,120 @@ impl HdrLoader {
         Ok(dst)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A textured triangle, the glTF file referencing a separate buffer and
+    /// image.
+    fn write_scene(dir: &std::path::Path) -> anyhow::Result<PathBuf> {
+        let positions: [[f32; 3]; 3] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+        let indices: [u16; 3] = [0, 1, 2];
+        let mut buffer = bytemuck::cast_slice::<_, u8>(&positions).to_vec();
+        buffer.extend_from_slice(bytemuck::cast_slice(&indices));
+        std::fs::write(dir.join("triangle.bin"), &buffer)?;
+
+        image::RgbaImage::from_pixel(2, 2, image::Rgba([255, 0, 0, 255]))
+            .save(dir.join("red.png"))?;
+
+        let gltf = format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "buffers": [{{ "uri": "triangle.bin", "byteLength": {} }}],
+                "bufferViews": [
+                    {{ "buffer": 0, "byteOffset": 0, "byteLength": 36 }},
+                    {{ "buffer": 0, "byteOffset": 36, "byteLength": 6 }}
+                ],
+                "accessors": [
+                    {{
+                        "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                        "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0]
+                    }},
+                    {{ "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }}
+                ],
+                "images": [{{ "uri": "red.png" }}],
+                "textures": [{{ "source": 0 }}],
+                "materials": [{{
+                    "name": "red",
+                    "pbrMetallicRoughness": {{ "baseColorTexture": {{ "index": 0 }} }}
+                }}],
+                "meshes": [{{
+                    "name": "triangle",
+                    "primitives": [{{
+                        "attributes": {{ "POSITION": 0 }}, "indices": 1, "material": 0
+                    }}]
+                }}]
+            }}"#,
+            buffer.len()
+        );
+        let path = dir.join("triangle.gltf");
+        std::fs::write(&path, gltf)?;
+        Ok(path)
+    }
+
+    #[test]
+    fn test_asset_loader_delivers_models() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping asset loader test");
+            return Ok(());
+        };
+        let dir = std::env::temp_dir().join(format!("void-assets-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = write_scene(&dir)?;
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .build()?;
+        let mut loader = AssetLoader::bounded(Arc::new(gpu), runtime.handle().clone(), 2);
+        loader.load(&path);
+        loader.load(dir.join("missing.gltf"));
+
+        let mut loaded = (0..2)
+            .map(|_| runtime.block_on(loader.receiver.recv()).unwrap())
+            .collect::<Vec<_>>();
+        loaded.sort_by_key(|loaded| loaded.path != path);
+        std::fs::remove_dir_all(&dir)?;
+
+        let model = loaded[0].model.as_ref().unwrap();
+        assert_eq!(model.meshes.len(), 1);
+        assert_eq!(model.meshes[0].num_elements, 3);
+        assert_eq!(model.materials.len(), 1);
+        assert_eq!(model.materials[0].name, "red");
+        assert!(loaded[1].model.is_err());
+        assert!(loader.try_recv().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bounded_loader_delivers_past_capacity() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping bounded asset loader test");
+            return Ok(());
+        };
+        let dir = std::env::temp_dir().join(format!("void-bounded-{}", std::process::id()));
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .build()?;
+        let mut loader = AssetLoader::bounded(Arc::new(gpu), runtime.handle().clone(), 1);
+        // Missing files fail right away, all but one of them have to wait
+        // for room in the channel.
+        for i in 0..4 {
+            loader.load(dir.join(format!("missing-{i}.gltf")));
+        }
+
+        let mut paths = (0..4)
+            .map(|_| runtime.block_on(loader.receiver.recv()).unwrap())
+            .inspect(|loaded| assert!(loaded.model.is_err()))
+            .map(|loaded| loaded.path)
+            .collect::<Vec<_>>();
+        paths.sort();
+        let expected = (0..4)
+            .map(|i| dir.join(format!("missing-{i}.gltf")))
+            .collect::<Vec<_>>();
+        assert_eq!(paths, expected);
+        assert!(loader.try_recv().is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,11 @@
+// Samples `t` at an explicit mip `level` instead of the one picked from
+// screen space derivatives, which only fragment shaders have. Integer levels
+// read exactly that mip, fractional ones blend the two around it when the
+// sampler's mipmap filter is linear. Levels past the last mip read the last.
+//
+// Prepended to the shaders that use it, see `Texture::create_level_sampler`
+// for a matching sampler.
+fn sample_level(t: texture_2d<f32>, s: sampler, uv: vec2<f32>, level: f32) -> vec4<f32> {
+    return textureSampleLevel(t, s, uv, level);
+}
+
@@ -9,11 +9,31 @@ var<uniform> camera: CameraUniform;
 struct Light {
     position: vec3<f32>,
     color: vec3<f32>,
+    ambient: f32,
+    // Direction the light travels in, for directional lights.
+    direction: vec3<f32>,
+    directional: u32,
 }
 
 @group(2) @binding(0)
 var<uniform> light: Light;
 
+// See ShadowUniform.
+struct Shadow {
+    view_proj: mat4x4<f32>,
+    bias: f32,
+    normal_bias: f32,
+    texel_size: f32,
+    enabled: u32,
+}
+
+@group(2) @binding(1)
+var<uniform> shadow: Shadow;
+@group(2) @binding(2)
+var t_shadow: texture_depth_2d;
+@group(2) @binding(3)
+var s_shadow: sampler_comparison;
+
 struct InstanceInput {
     @location(5) model_matrix_0: vec4<f32>,
     @location(6) model_matrix_1: vec4<f32>,
@@ -30,19 +50,60 @@ struct VertexInput {
     @location(0) position: vec3<f32>,
     @location(1) tex_coords: vec2<f32>,
     @location(2) normal: vec3<f32>,
+    // w is the handedness of the bitangent.
+    @location(3) tangent: vec4<f32>,
+}
+
+#ifdef SKINNED
+// Skin::MAX_JOINTS matrices, see Skeleton::joint_matrices.
+struct Joints {
+    matrices: array<mat4x4<f32>, 128>,
+}
+
+@group(3) @binding(0)
+var<uniform> joints: Joints;
+
+struct JointInput {
+    @location(12) indices: vec4<u32>,
+    @location(13) weights: vec4<f32>,
+}
+
+// The joint matrices blended by the weights, vertices without weights stay
+// where they are.
+fn skin_matrix(joint: JointInput) -> mat4x4<f32> {
+    let total = dot(joint.weights, vec4<f32>(1.0));
+    if total == 0.0 {
+        return mat4x4<f32>(
+            vec4<f32>(1.0, 0.0, 0.0, 0.0),
+            vec4<f32>(0.0, 1.0, 0.0, 0.0),
+            vec4<f32>(0.0, 0.0, 1.0, 0.0),
+            vec4<f32>(0.0, 0.0, 0.0, 1.0),
+        );
+    }
+    return joints.matrices[joint.indices.x] * joint.weights.x
+        + joints.matrices[joint.indices.y] * joint.weights.y
+        + joints.matrices[joint.indices.z] * joint.weights.z
+        + joints.matrices[joint.indices.w] * joint.weights.w;
 }
+#endif
 
 struct VertexOutput {
-    @builtin(position) clip_position: vec4<f32>,
+    // The depth pre-pass runs the same vertex stage without a fragment one,
+    // the scene pass tests its depth for equality against it.
+    @builtin(position) @invariant clip_position: vec4<f32>,
     @location(0) tex_coords: vec2<f32>,
     @location(1) world_normal: vec3<f32>,
     @location(2) world_position: vec3<f32>,
+    @location(3) world_tangent: vec4<f32>,
 }
 
 @vertex
 fn vs_main(
     model: VertexInput,
-    instance: InstanceInput
+    instance: InstanceInput,
+#ifdef SKINNED
+    joint: JointInput,
+#endif
 ) -> VertexOutput {
     var out: VertexOutput;
     let model_matrix = mat4x4<f32>(
@@ -58,9 +119,24 @@ fn vs_main(
         instance.normal_matrix_2,
     );
 
+#ifdef SKINNED
+    // Joints are assumed to scale uniformly, the normals only need the
+    // rotation then.
+    let skin = skin_matrix(joint);
+    let skin_rotation = mat3x3<f32>(skin[0].xyz, skin[1].xyz, skin[2].xyz);
+    let position = (skin * vec4<f32>(model.position, 1.0)).xyz;
+    let normal = skin_rotation * model.normal;
+    let tangent = skin_rotation * model.tangent.xyz;
+#else
+    let position = model.position;
+    let normal = model.normal;
+    let tangent = model.tangent.xyz;
+#endif
+
     out.tex_coords = model.tex_coords;
-    out.world_normal = normal_matrix * model.normal;
-    var world_position: vec4<f32> = model_matrix * vec4<f32>(model.position, 1.0);
+    out.world_normal = normal_matrix * normal;
+    out.world_tangent = vec4<f32>(normal_matrix * tangent, model.tangent.w);
+    var world_position: vec4<f32> = model_matrix * vec4<f32>(position, 1.0);
     out.world_position = world_position.xyz;
     out.clip_position = camera.view_proj * world_position;
     return out;
@@ -68,34 +144,112 @@ fn vs_main(
 
 // Fragment shader
 
+// Columns are the tangent, bitangent and normal, taking tangent space
+// vectors such as normal map samples to world space. The bitangent isn't
+// stored, mirrored UVs flip it through the tangent's w.
+fn tangent_frame(normal: vec3<f32>, tangent: vec4<f32>) -> mat3x3<f32> {
+    let bitangent = cross(normal, tangent.xyz) * tangent.w;
+    return mat3x3<f32>(tangent.xyz, bitangent, normal);
+}
+
+// Fraction of the light reaching `world_position`, averaging 3x3 shadow map
+// comparisons (PCF) to soften the shadow's edges.
+fn shadow_factor(world_position: vec3<f32>, normal: vec3<f32>) -> f32 {
+    if shadow.enabled == 0u {
+        return 1.0;
+    }
+    let clip = shadow.view_proj * vec4<f32>(world_position + normal * shadow.normal_bias, 1.0);
+    let ndc = clip.xyz / clip.w;
+    // Nothing outside the shadow map casts shadows.
+    if any(abs(ndc.xy) > vec2<f32>(1.0)) || ndc.z > 1.0 {
+        return 1.0;
+    }
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + 0.5;
+    let depth = ndc.z - shadow.bias;
+    var lit = 0.0;
+    for (var x = -1; x <= 1; x++) {
+        for (var y = -1; y <= 1; y++) {
+            let offset = vec2<f32>(f32(x), f32(y)) * shadow.texel_size;
+            lit += textureSampleCompareLevel(t_shadow, s_shadow, uv + offset, depth);
+        }
+    }
+    return lit / 9.0;
+}
+
 @group(0) @binding(0)
 var t_diffuse: texture_2d<f32>;
 @group(0) @binding(1)
 var s_diffuse: sampler;
 
+struct Material {
+    base_color: vec4<f32>,
+    emissive: vec3<f32>,
+    emissive_intensity: f32,
+    // Negative picks the mip from derivatives as usual.
+    mip_level: f32,
+    metallic: f32,
+    roughness: f32,
+}
+@group(0) @binding(2)
+var<uniform> material: Material;
+
+// Flat, white and white when the material doesn't have them.
+@group(0) @binding(3)
+var t_normal: texture_2d<f32>;
+@group(0) @binding(4)
+var t_metallic_roughness: texture_2d<f32>;
+@group(0) @binding(5)
+var t_occlusion: texture_2d<f32>;
+
 fn check_coords(in: VertexOutput) -> vec4f {
-	return textureSample(t_diffuse, s_diffuse, in.tex_coords);
+	var color: vec4f;
+	if material.mip_level >= 0.0 {
+		color = sample_level(t_diffuse, s_diffuse, in.tex_coords, material.mip_level);
+	} else {
+		color = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+	}
+	return color * material.base_color;
 }
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     let object_color: vec4<f32> = check_coords(in);
-    let ambient_strength = 0.1;
-    let ambient_color = light.color * ambient_strength;
-
-    let light_dir = normalize(light.position - in.world_position);
-
-    let diffuse_strength = max(dot(in.world_normal, light_dir), 0.0);
-    let diffuse_color = light.color * diffuse_strength;
+    // Roughness in green and metalness in blue, like glTF.
+    let metallic_roughness = textureSample(t_metallic_roughness, s_diffuse, in.tex_coords);
+    let metallic = material.metallic * metallic_roughness.b;
+    let roughness = material.roughness * metallic_roughness.g;
+    let occlusion = textureSample(t_occlusion, s_diffuse, in.tex_coords).r;
+
+    let ambient_color = light.color * light.ambient * occlusion;
+
+    var light_dir = normalize(light.position - in.world_position);
+    if light.directional != 0u {
+        light_dir = -light.direction;
+    }
+    // Interpolation shortens the normals between vertices.
+    let vertex_normal = normalize(in.world_normal);
+    let tangent_normal = textureSample(t_normal, s_diffuse, in.tex_coords).xyz * 2.0 - 1.0;
+    let normal = normalize(tangent_frame(vertex_normal, in.world_tangent) * tangent_normal);
+
+    // Shadows take the direct light, the ambient light stays.
+    let lit = shadow_factor(in.world_position, vertex_normal);
+
+    // Metals don't scatter light diffusely.
+    let diffuse_strength = max(dot(normal, light_dir), 0.0) * (1.0 - metallic);
+    let diffuse_color = light.color * diffuse_strength * lit;
 
     let view_dir = normalize(camera.view_pos.xyz - in.world_position);
     let half_dir = normalize(view_dir + light_dir);
-    let reflect_dir = reflect(-light_dir, in.world_normal);
 
-    let specular_strength = pow(max(dot(in.world_normal, half_dir), 0.0), 32.0);
-    let specular_color = specular_strength * light.color;
+    // The Blinn-Phong exponent matching the roughness, 32 at 0.5.
+    let alpha = roughness * roughness;
+    let shininess = 2.0 / max(alpha * alpha, 1e-4);
+    let specular_strength = pow(max(dot(normal, half_dir), 0.0), shininess);
+    let specular_color = specular_strength * light.color * lit;
 
     let result = (ambient_color + diffuse_color + specular_color) * object_color.xyz;
 
-    return vec4<f32>(result, object_color.a);
+    let emissive = material.emissive * material.emissive_intensity;
+
+    return vec4<f32>(result + emissive, object_color.a);
 }
@@ -0,0 +1,465 @@
+use nalgebra as na;
+
+use crate::{
+    frustum::Aabb,
+    gpu::Gpu,
+    light::{LightUniform, ShadowSettings},
+    model::{InstanceBuffer, InstanceRaw, Model, ModelVertex, Vertex},
+    pipeline::PipelineBuilder,
+    skin::{JointVertex, Skin},
+    texture, ModelEntry,
+};
+
+/// The light's side of shadow mapping, bound next to the [`LightUniform`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ShadowUniform {
+    /// World space to the light's clip space, see [`light_view_proj`].
+    pub view_proj: [[f32; 4]; 4],
+    pub bias: f32,
+    pub normal_bias: f32,
+    /// Size of a shadow map texel in UV units, for the PCF taps.
+    pub texel_size: f32,
+    /// 0 when nothing casts shadows and the map isn't looked at.
+    pub enabled: u32,
+}
+
+/// Orthographic projection of a directional light travelling in `direction`,
+/// fit around the sphere holding `bounds` so that everything in them casts
+/// shadows whichever way the light points.
+pub fn light_view_proj(direction: &na::Vector3<f32>, bounds: &Aabb) -> na::Matrix4<f32> {
+    let direction = direction
+        .try_normalize(f32::EPSILON)
+        .unwrap_or(-na::Vector3::y());
+    let center = bounds.center();
+    let radius = ((bounds.max - bounds.min).norm() * 0.5).max(1e-3);
+    let eye = center - direction * radius;
+    let up = if direction.y.abs() > 0.99 {
+        na::Vector3::z()
+    } else {
+        na::Vector3::y()
+    };
+    let view = na::Matrix4::look_at_rh(&eye, &center, &up);
+    let projection =
+        crate::camera::orthographic(-radius, radius, -radius, radius, 0.0, radius * 2.0);
+    projection * view
+}
+
+/// Renders the depth of the scene as seen from a directional light into a
+/// shadow map, which the scene shader samples with PCF to darken what the
+/// light doesn't reach.
+pub struct ShadowPass {
+    pipeline: wgpu::RenderPipeline,
+    /// Takes the joint matrices of a model's [`Skin`] at group 1.
+    skinned_pipeline: wgpu::RenderPipeline,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    map: texture::Texture,
+    sampler: wgpu::Sampler,
+}
+
+impl ShadowPass {
+    pub const FORMAT: wgpu::TextureFormat = texture::Texture::DEPTH_FORMAT;
+
+    pub fn new(gpu: &Gpu, map_size: u32) -> Self {
+        let device = &gpu.device;
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("ShadowPass::uniform_buffer"),
+            size: std::mem::size_of::<ShadowUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("ShadowPass::layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("ShadowPass::bind_group"),
+            layout: &layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            }],
+        });
+
+        let skin_layout = Skin::layout(device);
+        let pipeline = |skinned: bool| {
+            let (bind_group_layouts, defines, vertex_layouts): (&[_], &[&str], &[_]) = if skinned {
+                (
+                    &[&layout, &skin_layout],
+                    &["SKINNED"],
+                    &[
+                        ModelVertex::desc(),
+                        InstanceRaw::desc(),
+                        JointVertex::desc(),
+                    ],
+                )
+            } else {
+                (&[&layout], &[], &[ModelVertex::desc(), InstanceRaw::desc()])
+            };
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("ShadowPass::pipeline_layout"),
+                bind_group_layouts,
+                push_constant_ranges: &[],
+            });
+            PipelineBuilder::depth_only(
+                &pipeline_layout,
+                Self::FORMAT,
+                wgpu::include_wgsl!("shadow.wgsl"),
+            )
+            .defines(defines)
+            .expect("shadow.wgsl's #ifdef blocks are balanced")
+            .vertex_layouts(vertex_layouts)
+            .build(gpu)
+        };
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("ShadowPass::sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            // Linear filtering blends four comparisons, smoothing the PCF.
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        Self {
+            pipeline: pipeline(false),
+            skinned_pipeline: pipeline(true),
+            uniform_buffer,
+            bind_group,
+            map: Self::create_map(gpu, map_size),
+            sampler,
+        }
+    }
+
+    fn create_map(gpu: &Gpu, size: u32) -> texture::Texture {
+        texture::Texture::create_render_target(
+            &gpu.device,
+            size,
+            size,
+            Self::FORMAT,
+            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_SRC,
+        )
+    }
+
+    pub fn map_size(&self) -> u32 {
+        self.map.size.width
+    }
+
+    pub fn map(&self) -> &texture::Texture {
+        &self.map
+    }
+
+    /// Reallocates the shadow map, the light bind group has to be created
+    /// again with the new [`ShadowPass::bind_group_entries`].
+    pub fn resize(&mut self, gpu: &Gpu, map_size: u32) {
+        if map_size != self.map_size() {
+            self.map = Self::create_map(gpu, map_size);
+        }
+    }
+
+    /// Bindings 1 to 3 of the light bind group, see
+    /// [`crate::light::bind_group_layout`].
+    pub fn bind_group_entries(&self) -> [wgpu::BindGroupEntry<'_>; 3] {
+        [
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: self.uniform_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(&self.map.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::Sampler(&self.sampler),
+            },
+        ]
+    }
+
+    /// Points the shadow map at `light`, covering `bounds`. Returns whether
+    /// the light casts shadows, only directional lights with `settings` do.
+    pub fn update(
+        &self,
+        gpu: &Gpu,
+        light: &LightUniform,
+        settings: Option<ShadowSettings>,
+        bounds: &Aabb,
+    ) -> bool {
+        let settings = settings.filter(|_| light.directional != 0);
+        let uniform = match settings {
+            Some(settings) => ShadowUniform {
+                view_proj: light_view_proj(&light.direction.into(), bounds).into(),
+                bias: settings.bias,
+                normal_bias: settings.normal_bias,
+                texel_size: 1.0 / self.map_size() as f32,
+                enabled: 1,
+            },
+            None => ShadowUniform {
+                view_proj: na::Matrix4::identity().into(),
+                bias: 0.0,
+                normal_bias: 0.0,
+                texel_size: 0.0,
+                enabled: 0,
+            },
+        };
+        gpu.write_uniform(&self.uniform_buffer, 0, &uniform);
+        settings.is_some()
+    }
+
+    /// Draws the depth of `models` with `pass`, which draws into
+    /// [`ShadowPass::map`]. Every model with instances casts shadows, whether
+    /// the camera sees it or not.
+    pub fn render<'a>(
+        &'a self,
+        pass: &mut wgpu::RenderPass<'a>,
+        models: impl Iterator<Item = &'a ModelEntry>,
+    ) {
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        for entry in models.filter(|entry| !entry.instances.is_empty()) {
+            self.draw_model(pass, &entry.model, &entry.instances);
+        }
+    }
+
+    fn draw_model<'a>(
+        &'a self,
+        pass: &mut wgpu::RenderPass<'a>,
+        model: &'a Model,
+        instances: &'a InstanceBuffer,
+    ) {
+        match &model.skin {
+            Some(skin) => {
+                pass.set_pipeline(&self.skinned_pipeline);
+                pass.set_bind_group(1, skin.bind_group(), &[]);
+            }
+            None => pass.set_pipeline(&self.pipeline),
+        }
+        pass.set_vertex_buffer(InstanceBuffer::SLOT, instances.slice());
+        for mesh in &model.meshes {
+            pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+            if let Some(joints) = &mesh.joints {
+                pass.set_vertex_buffer(JointVertex::SLOT, joints.slice(..));
+            }
+            pass.set_index_buffer(mesh.index_slice(), mesh.index_format);
+            pass.draw_indexed(0..mesh.num_elements, mesh.base_vertex, 0..instances.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{RenderGraph, TextureInit};
+    use crate::model::Mesh;
+
+    /// Per texel of the shadow map, 1 where `depth` is at most the stored
+    /// depth, looked up with the pass's comparison sampler like the scene
+    /// shader does. Copying depth textures out is a downlevel feature.
+    fn lit_texels(gpu: &Gpu, shadow: &ShadowPass, depth: f32) -> anyhow::Result<Vec<f32>> {
+        let map = shadow.map();
+        let device = &gpu.device;
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
+            ],
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&map.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&shadow.sampler),
+                },
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let shader = wgpu::ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(
+                format!(
+                    r#"
+                @group(0) @binding(0)
+                var t_depth: texture_depth_2d;
+                @group(0) @binding(1)
+                var s_depth: sampler_comparison;
+
+                @vertex
+                fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {{
+                    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+                    return vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+                }}
+
+                @fragment
+                fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) f32 {{
+                    let uv = position.xy / vec2<f32>(textureDimensions(t_depth));
+                    return textureSampleCompareLevel(t_depth, s_depth, uv, {depth:?});
+                }}
+                "#
+                )
+                .into(),
+            ),
+        };
+        let format = wgpu::TextureFormat::R32Float;
+        let pipeline = PipelineBuilder::new(&pipeline_layout, format, shader).build(gpu);
+        let target = texture::Texture::create_render_target(
+            device,
+            map.size.width,
+            map.size.height,
+            format,
+            wgpu::TextureUsages::COPY_SRC,
+        );
+
+        let mut encoder = gpu.create_cmd_encoder();
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &target.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations::default(),
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+        gpu.queue.submit([encoder.finish()]);
+        let lit = gpu.read_texture_mip(&target.texture, 0)?;
+        Ok(bytemuck::cast_slice(&lit).to_vec())
+    }
+
+    #[test]
+    fn test_light_view_proj_covers_bounds() {
+        let bounds = Aabb {
+            min: na::Point3::new(-2.0, 0.0, -1.0),
+            max: na::Point3::new(4.0, 3.0, 1.0),
+        };
+        for direction in [
+            na::Vector3::new(0.0, -1.0, 0.0),
+            na::Vector3::new(1.0, -2.0, 0.5),
+        ] {
+            let view_proj = light_view_proj(&direction, &bounds);
+            let project = |point: na::Point3<f32>| view_proj.transform_point(&point);
+            for x in [bounds.min.x, bounds.max.x] {
+                for y in [bounds.min.y, bounds.max.y] {
+                    for z in [bounds.min.z, bounds.max.z] {
+                        let ndc = project(na::Point3::new(x, y, z));
+                        assert!(ndc.x.abs() <= 1.0 && ndc.y.abs() <= 1.0, "{ndc}");
+                        assert!((0.0..=1.0).contains(&ndc.z), "{ndc}");
+                    }
+                }
+            }
+            // Closer to the light is closer to 0.
+            let center = bounds.center();
+            assert!(project(center - direction).z < project(center).z);
+        }
+    }
+
+    #[test]
+    fn test_occluder_shadows_the_floor() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping shadow map test");
+            return Ok(());
+        };
+        let quad = |half: f32, height: f32| {
+            let vertices =
+                [[-half, -half], [half, -half], [half, half], [-half, half]].map(|[x, z]| {
+                    ModelVertex {
+                        position: [x, height, z],
+                        tex_coord: [0.0; 2],
+                        normal: [0.0, 1.0, 0.0],
+                        tangent: [1.0, 0.0, 0.0, 1.0],
+                    }
+                });
+            let mesh = Mesh::new(&gpu.device, "quad", &vertices, &[0, 2, 1, 0, 3, 2], 0);
+            ModelEntry::new(
+                &gpu,
+                Model {
+                    meshes: vec![mesh],
+                    materials: Vec::new(),
+                    skin: None,
+                },
+            )
+        };
+        // A floor, and a smaller square floating over its middle.
+        let floor = quad(1.0, 0.0);
+        let occluder = quad(0.5, 1.0);
+        let bounds = floor.aabb.union(&occluder.aabb);
+
+        let shadow = ShadowPass::new(&gpu, 8);
+        let light =
+            LightUniform::from(crate::light::Light::directional([0.0, -1.0, 0.0], [1.0; 3]));
+        assert!(shadow.update(&gpu, &light, Some(ShadowSettings::default()), &bounds));
+        let mut graph = RenderGraph::new();
+        let map = graph.import(
+            "shadow map",
+            &shadow.map().view,
+            TextureInit::ClearDepth(1.0),
+        );
+        graph
+            .add_pass("shadow")
+            .depth(map)
+            .record(|encoder, targets| {
+                shadow.render(&mut targets.begin(encoder), [&floor, &occluder].into_iter());
+            })?;
+        graph.execute(&gpu)?;
+        gpu.finish();
+
+        // Halfway between the floor and the occluder, which shadows it.
+        let view_proj = light_view_proj(&na::Vector3::new(0.0, -1.0, 0.0), &bounds);
+        let halfway = view_proj.transform_point(&na::Point3::new(0.0, 0.5, 0.0)).z;
+        let lit = lit_texels(&gpu, &shadow, halfway)?;
+        let at = |x: usize, y: usize| lit[y * 8 + x];
+        assert_eq!((at(4, 4), at(1, 4)), (0.0, 1.0));
+        // Past the floor, where the map stays cleared.
+        let below = view_proj
+            .transform_point(&na::Point3::new(0.0, -0.5, 0.0))
+            .z;
+        let lit = lit_texels(&gpu, &shadow, below)?;
+        assert_eq!((lit[4 * 8 + 1], lit[4 * 8]), (0.0, 1.0));
+
+        let point = LightUniform::from(crate::light::Light::point([0.0; 3], [1.0; 3]));
+        assert!(!shadow.update(&gpu, &point, Some(ShadowSettings::default()), &bounds));
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+// Depth of the scene as seen from the light, see ShadowPass.
+
+struct Shadow {
+    view_proj: mat4x4<f32>,
+    bias: f32,
+    normal_bias: f32,
+    texel_size: f32,
+    enabled: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> shadow: Shadow;
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+};
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+}
+
+#ifdef SKINNED
+// Same as in shader.wgsl, at group 1 here.
+struct Joints {
+    matrices: array<mat4x4<f32>, 128>,
+}
+
+@group(1) @binding(0)
+var<uniform> joints: Joints;
+
+struct JointInput {
+    @location(12) indices: vec4<u32>,
+    @location(13) weights: vec4<f32>,
+}
+
+fn skin_matrix(joint: JointInput) -> mat4x4<f32> {
+    let total = dot(joint.weights, vec4<f32>(1.0));
+    if total == 0.0 {
+        return mat4x4<f32>(
+            vec4<f32>(1.0, 0.0, 0.0, 0.0),
+            vec4<f32>(0.0, 1.0, 0.0, 0.0),
+            vec4<f32>(0.0, 0.0, 1.0, 0.0),
+            vec4<f32>(0.0, 0.0, 0.0, 1.0),
+        );
+    }
+    return joints.matrices[joint.indices.x] * joint.weights.x
+        + joints.matrices[joint.indices.y] * joint.weights.y
+        + joints.matrices[joint.indices.z] * joint.weights.z
+        + joints.matrices[joint.indices.w] * joint.weights.w;
+}
+#endif
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+#ifdef SKINNED
+    joint: JointInput,
+#endif
+) -> @builtin(position) vec4<f32> {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+#ifdef SKINNED
+    let position = skin_matrix(joint) * vec4<f32>(model.position, 1.0);
+#else
+    let position = vec4<f32>(model.position, 1.0);
+#endif
+    return shadow.view_proj * model_matrix * position;
+}
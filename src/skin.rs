@@ -0,0 +1,431 @@
+use std::{mem, num::NonZeroU64};
+
+use anyhow::{bail, Result};
+use nalgebra as na;
+
+use crate::{gpu::Gpu, model::Vertex};
+
+/// Joints a vertex follows and how much, bound to vertex buffer slot
+/// [`JointVertex::SLOT`] next to the mesh's [`crate::model::ModelVertex`]es.
+/// Vertices whose weights are all 0 don't move with the skeleton.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct JointVertex {
+    /// Indices into [`Skeleton::joints`].
+    pub joints: [u32; 4],
+    pub weights: [f32; 4],
+}
+
+impl JointVertex {
+    pub const SLOT: u32 = 2;
+}
+
+impl Vertex for JointVertex {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<JointVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            // After the instance attributes at 5 to 11.
+            attributes: &[
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Uint32x4,
+                    offset: 0,
+                    shader_location: 12,
+                },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32x4,
+                    offset: mem::size_of::<[u32; 4]>() as wgpu::BufferAddress,
+                    shader_location: 13,
+                },
+            ],
+        }
+    }
+}
+
+/// Transform of a joint relative to its parent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct JointTransform {
+    pub translation: na::Vector3<f32>,
+    pub rotation: na::UnitQuaternion<f32>,
+    pub scale: na::Vector3<f32>,
+}
+
+impl Default for JointTransform {
+    fn default() -> Self {
+        Self {
+            translation: na::Vector3::zeros(),
+            rotation: na::UnitQuaternion::identity(),
+            scale: na::Vector3::repeat(1.0),
+        }
+    }
+}
+
+impl JointTransform {
+    pub fn to_matrix(self) -> na::Matrix4<f32> {
+        na::Matrix4::new_translation(&self.translation)
+            * self.rotation.to_homogeneous()
+            * na::Matrix4::new_nonuniform_scaling(&self.scale)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Joint {
+    pub name: String,
+    /// Index of the parent in [`Skeleton::joints`], `None` for roots.
+    pub parent: Option<usize>,
+    /// Transform when no animation moves the joint.
+    pub rest: JointTransform,
+}
+
+/// Joint hierarchy a skinned mesh is bound to.
+#[derive(Clone, Debug, Default)]
+pub struct Skeleton {
+    pub joints: Vec<Joint>,
+    /// Per joint, takes model space positions to the joint's space at the
+    /// time the mesh was bound.
+    pub inverse_bind_matrices: Vec<na::Matrix4<f32>>,
+}
+
+impl Skeleton {
+    pub fn rest_pose(&self) -> Vec<JointTransform> {
+        self.joints.iter().map(|joint| joint.rest).collect()
+    }
+
+    /// Per joint, the matrix moving bound vertices to where `pose` puts the
+    /// joint, in model space. `pose` has a local transform per joint.
+    pub fn joint_matrices(&self, pose: &[JointTransform]) -> Vec<na::Matrix4<f32>> {
+        let mut globals = vec![None; self.joints.len()];
+        (0..self.joints.len())
+            .map(|joint| {
+                let inverse_bind = self
+                    .inverse_bind_matrices
+                    .get(joint)
+                    .copied()
+                    .unwrap_or_else(na::Matrix4::identity);
+                self.global(joint, pose, &mut globals) * inverse_bind
+            })
+            .collect()
+    }
+
+    /// Model space transform of `joint`, parents don't have to come first.
+    fn global(
+        &self,
+        joint: usize,
+        pose: &[JointTransform],
+        globals: &mut [Option<na::Matrix4<f32>>],
+    ) -> na::Matrix4<f32> {
+        if let Some(global) = globals[joint] {
+            return global;
+        }
+        let local = pose
+            .get(joint)
+            .unwrap_or(&self.joints[joint].rest)
+            .to_matrix();
+        let global = match self.joints[joint].parent {
+            Some(parent) => self.global(parent, pose, globals) * local,
+            None => local,
+        };
+        globals[joint] = Some(global);
+        global
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Holds each keyframe until the next one.
+    Step,
+    /// Lerps translations and scales, slerps rotations.
+    Linear,
+}
+
+/// Keyframe values of a [`Channel`], one per time.
+#[derive(Clone, Debug)]
+pub enum ChannelValues {
+    Translation(Vec<na::Vector3<f32>>),
+    Rotation(Vec<na::UnitQuaternion<f32>>),
+    Scale(Vec<na::Vector3<f32>>),
+}
+
+/// Animates one property of one joint.
+#[derive(Clone, Debug)]
+pub struct Channel {
+    /// Index into [`Skeleton::joints`].
+    pub joint: usize,
+    pub interpolation: Interpolation,
+    /// Seconds since the start of the animation, sorted.
+    pub times: Vec<f32>,
+    pub values: ChannelValues,
+}
+
+impl Channel {
+    /// The keyframes around `time` and how far between them it is, holding
+    /// the first and last keyframes outside of them.
+    fn keyframes(&self, time: f32) -> Option<(usize, usize, f32)> {
+        let last = self.times.len().checked_sub(1)?;
+        if time <= self.times[0] {
+            return Some((0, 0, 0.0));
+        }
+        if time >= self.times[last] {
+            return Some((last, last, 0.0));
+        }
+        let next = self.times.partition_point(|t| *t <= time);
+        let (a, b) = (self.times[next - 1], self.times[next]);
+        let t = match self.interpolation {
+            Interpolation::Step => 0.0,
+            Interpolation::Linear => (time - a) / (b - a),
+        };
+        Some((next - 1, next, t))
+    }
+
+    fn apply(&self, time: f32, pose: &mut [JointTransform]) {
+        let (Some((a, b, t)), Some(transform)) = (self.keyframes(time), pose.get_mut(self.joint))
+        else {
+            return;
+        };
+        match &self.values {
+            ChannelValues::Translation(values) => {
+                if let (Some(a), Some(b)) = (values.get(a), values.get(b)) {
+                    transform.translation = a.lerp(b, t);
+                }
+            }
+            ChannelValues::Rotation(values) => {
+                if let (Some(a), Some(b)) = (values.get(a), values.get(b)) {
+                    // Rotations half a turn apart have no single shortest
+                    // arc, `try_slerp` fails on them and the first is kept.
+                    transform.rotation = a.try_slerp(b, t, 1e-6).unwrap_or(*a);
+                }
+            }
+            ChannelValues::Scale(values) => {
+                if let (Some(a), Some(b)) = (values.get(a), values.get(b)) {
+                    transform.scale = a.lerp(b, t);
+                }
+            }
+        }
+    }
+}
+
+/// Keyframed joint transforms, such as a glTF animation.
+#[derive(Clone, Debug, Default)]
+pub struct SkeletalAnimation {
+    pub name: String,
+    pub channels: Vec<Channel>,
+}
+
+impl SkeletalAnimation {
+    /// Length of the animation, the time of the latest keyframe.
+    pub fn duration(&self) -> f32 {
+        self.channels
+            .iter()
+            .filter_map(|channel| channel.times.last().copied())
+            .fold(0.0, f32::max)
+    }
+
+    /// Overwrites the transforms of the joints the animation moves in `pose`
+    /// with their value at `time` seconds.
+    pub fn sample(&self, time: f32, pose: &mut [JointTransform]) {
+        for channel in &self.channels {
+            channel.apply(time, pose);
+        }
+    }
+}
+
+/// The skeleton of a [`crate::model::Model`] with its animation, and the
+/// joint matrices the skinned vertex shader reads from a uniform buffer.
+pub struct Skin {
+    pub skeleton: Skeleton,
+    pub animation: Option<SkeletalAnimation>,
+    buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+impl Skin {
+    /// Joints the uniform buffer has room for, what `shader.wgsl` declares.
+    pub const MAX_JOINTS: usize = 128;
+    const BUFFER_SIZE: u64 = (Self::MAX_JOINTS * mem::size_of::<[[f32; 4]; 4]>()) as u64;
+
+    /// Layout of the joint matrices, bound to group 3 of skinned pipelines.
+    pub fn layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Skin::layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: NonZeroU64::new(Self::BUFFER_SIZE),
+                },
+                count: None,
+            }],
+        })
+    }
+
+    /// Uploads the rest pose, fails for skeletons with more than
+    /// [`Skin::MAX_JOINTS`] joints.
+    pub fn new(
+        gpu: &Gpu,
+        skeleton: Skeleton,
+        animation: Option<SkeletalAnimation>,
+    ) -> Result<Self> {
+        if skeleton.joints.len() > Self::MAX_JOINTS {
+            bail!(
+                "Skeleton has {} joints, at most {} are supported",
+                skeleton.joints.len(),
+                Self::MAX_JOINTS
+            );
+        }
+        let device = &gpu.device;
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Skin::joint_matrices"),
+            size: Self::BUFFER_SIZE,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Skin::bind_group"),
+            layout: &Self::layout(device),
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+        });
+
+        let skin = Self {
+            skeleton,
+            animation,
+            buffer,
+            bind_group,
+        };
+        skin.write(&gpu.queue, &skin.skeleton.rest_pose());
+        Ok(skin)
+    }
+
+    /// Poses the skeleton at `time` seconds into the animation, looping it,
+    /// and uploads the joint matrices. Without an animation the skeleton
+    /// stays in its rest pose.
+    pub fn animate(&self, queue: &wgpu::Queue, time: f32) {
+        let mut pose = self.skeleton.rest_pose();
+        if let Some(animation) = &self.animation {
+            let duration = animation.duration();
+            let time = if duration > 0.0 { time % duration } else { 0.0 };
+            animation.sample(time, &mut pose);
+        }
+        self.write(queue, &pose);
+    }
+
+    fn write(&self, queue: &wgpu::Queue, pose: &[JointTransform]) {
+        let matrices = self
+            .skeleton
+            .joint_matrices(pose)
+            .iter()
+            .map(|matrix| (*matrix).into())
+            .collect::<Vec<[[f32; 4]; 4]>>();
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&matrices));
+    }
+
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joint(parent: Option<usize>, translation: [f32; 3]) -> Joint {
+        Joint {
+            name: String::new(),
+            parent,
+            rest: JointTransform {
+                translation: translation.into(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_joint_matrices_follow_parents() {
+        // The child is listed first, parents don't have to come before.
+        let mut skeleton = Skeleton {
+            joints: vec![
+                joint(Some(1), [0.0, 1.0, 0.0]),
+                joint(None, [2.0, 0.0, 0.0]),
+            ],
+            inverse_bind_matrices: Vec::new(),
+        };
+        let matrices = skeleton.joint_matrices(&skeleton.rest_pose());
+        let origin = na::Point3::origin();
+        assert_eq!(
+            matrices[0].transform_point(&origin),
+            na::Point3::new(2.0, 1.0, 0.0)
+        );
+
+        // Bound in the rest pose, which then leaves vertices in place.
+        skeleton.inverse_bind_matrices = matrices
+            .iter()
+            .map(|matrix| matrix.try_inverse().unwrap())
+            .collect();
+        for matrix in skeleton.joint_matrices(&skeleton.rest_pose()) {
+            assert!((matrix - na::Matrix4::identity()).abs().max() < 1e-6);
+        }
+
+        let mut pose = skeleton.rest_pose();
+        pose[1].rotation = na::UnitQuaternion::from_axis_angle(
+            &na::Vector3::z_axis(),
+            std::f32::consts::FRAC_PI_2,
+        );
+        // The child at (2, 1), turned a quarter around the root at (2, 0).
+        let point =
+            skeleton.joint_matrices(&pose)[0].transform_point(&na::Point3::new(2.0, 1.0, 0.0));
+        assert!((point - na::Point3::new(1.0, 0.0, 0.0)).norm() < 1e-6);
+    }
+
+    #[test]
+    fn test_sample_keyframes() {
+        let quarter = na::UnitQuaternion::from_axis_angle(
+            &na::Vector3::y_axis(),
+            std::f32::consts::FRAC_PI_2,
+        );
+        let animation = SkeletalAnimation {
+            name: String::new(),
+            channels: vec![
+                Channel {
+                    joint: 0,
+                    interpolation: Interpolation::Linear,
+                    times: vec![0.0, 2.0],
+                    values: ChannelValues::Translation(vec![
+                        na::Vector3::zeros(),
+                        na::Vector3::new(4.0, 0.0, 0.0),
+                    ]),
+                },
+                Channel {
+                    joint: 0,
+                    interpolation: Interpolation::Linear,
+                    times: vec![0.0, 1.0],
+                    values: ChannelValues::Rotation(vec![na::UnitQuaternion::identity(), quarter]),
+                },
+                Channel {
+                    joint: 1,
+                    interpolation: Interpolation::Step,
+                    times: vec![0.0, 1.0],
+                    values: ChannelValues::Scale(vec![
+                        na::Vector3::repeat(1.0),
+                        na::Vector3::repeat(3.0),
+                    ]),
+                },
+            ],
+        };
+        assert_eq!(animation.duration(), 2.0);
+
+        let mut pose = vec![JointTransform::default(); 2];
+        animation.sample(0.5, &mut pose);
+        assert_eq!(pose[0].translation, na::Vector3::new(1.0, 0.0, 0.0));
+        assert!((pose[0].rotation.angle() - std::f32::consts::FRAC_PI_4).abs() < 1e-6);
+        assert_eq!(pose[1].scale, na::Vector3::repeat(1.0));
+
+        // The rotation holds its last keyframe.
+        animation.sample(1.5, &mut pose);
+        assert!(pose[0].rotation.angle_to(&quarter) < 1e-6);
+        assert_eq!(pose[1].scale, na::Vector3::repeat(3.0));
+    }
+}
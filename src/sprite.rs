@@ -0,0 +1,477 @@
+use nalgebra as na;
+use std::{mem, ops::Range};
+
+use crate::{
+    gpu::{needs_srgb_encoding, Gpu},
+    model::Vertex,
+    pipeline::{BlendPreset, PipelineBuilder},
+    texture,
+};
+
+/// A textured quad of a [`SpriteBatch`], one instance of its draw.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Sprite {
+    /// Center of the quad, in the units of the batch's [`SpriteView`].
+    pub position: [f32; 3],
+    /// Counter clockwise around the view direction, in radians.
+    pub rotation: f32,
+    pub size: [f32; 2],
+    /// Top left and bottom right corner of the sprite in its texture, in UV
+    /// coordinates.
+    pub uv_rect: [f32; 4],
+    /// Multiplies the texture's color.
+    pub color: [f32; 4],
+}
+
+impl Sprite {
+    /// A white sprite showing all of its texture.
+    pub fn new(position: na::Point3<f32>, size: na::Vector2<f32>) -> Self {
+        Self {
+            position: position.into(),
+            rotation: 0.0,
+            size: size.into(),
+            uv_rect: [0.0, 0.0, 1.0, 1.0],
+            color: [1.0; 4],
+        }
+    }
+
+    pub fn with_uv_rect(self, uv_rect: [f32; 4]) -> Self {
+        Self { uv_rect, ..self }
+    }
+
+    pub fn with_color(self, color: [f32; 4]) -> Self {
+        Self { color, ..self }
+    }
+
+    pub fn with_rotation(self, rotation: f32) -> Self {
+        Self { rotation, ..self }
+    }
+
+    /// UV rect of cell `index` of an atlas split into a `columns` by `rows`
+    /// grid, counting left to right from the top left, e.g. the frames of a
+    /// flipbook.
+    pub fn grid_cell(columns: u32, rows: u32, index: u32) -> [f32; 4] {
+        let (column, row) = (index % columns, index / columns % rows);
+        let (width, height) = (1.0 / columns as f32, 1.0 / rows as f32);
+        [
+            column as f32 * width,
+            row as f32 * height,
+            (column + 1) as f32 * width,
+            (row + 1) as f32 * height,
+        ]
+    }
+}
+
+impl Vertex for Sprite {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        const ATTRIBUTES: [wgpu::VertexAttribute; 5] = wgpu::vertex_attr_array![
+            0 => Float32x3,
+            1 => Float32,
+            2 => Float32x2,
+            3 => Float32x4,
+            4 => Float32x4,
+        ];
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<Sprite>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &ATTRIBUTES,
+        }
+    }
+}
+
+/// Where the sprites of a [`SpriteBatch`] are and which way they face.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SpriteView {
+    view_proj: [[f32; 4]; 4],
+    /// The directions of the sprites' x and y axes, w is unused.
+    right: [f32; 4],
+    up: [f32; 4],
+}
+
+impl SpriteView {
+    /// Sprites positioned in pixels from the top left of a `width` by
+    /// `height` target, for overlays.
+    pub fn screen(width: u32, height: u32) -> Self {
+        let (width, height) = (width.max(1) as f32, height.max(1) as f32);
+        #[rustfmt::skip]
+        let view_proj = na::Matrix4::new(
+            2.0 / width, 0.0, 0.0, -1.0,
+            0.0, -2.0 / height, 0.0, 1.0,
+            0.0, 0.0, 0.0, 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        );
+        Self {
+            view_proj: view_proj.into(),
+            right: [1.0, 0.0, 0.0, 0.0],
+            // Pixels count down.
+            up: [0.0, -1.0, 0.0, 0.0],
+        }
+    }
+
+    /// Sprites in world space, always facing a camera with the `view` and
+    /// `view_proj` matrices, e.g. particles and impostors.
+    pub fn billboard(view_proj: &na::Matrix4<f32>, view: &na::Matrix4<f32>) -> Self {
+        // The view's rows are the camera's axes in world space.
+        let axis = |row: usize| {
+            let axis = view.fixed_view::<1, 3>(row, 0).transpose();
+            axis.normalize().push(0.0).into()
+        };
+        Self {
+            view_proj: (*view_proj).into(),
+            right: axis(0),
+            up: axis(1),
+        }
+    }
+}
+
+/// A texture registered with [`SpriteBatch::add_texture`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SpriteTexture(usize);
+
+/// Textured quads, such as particles, impostors or 2D overlays, drawn
+/// instanced with one draw call per texture. Sprites are collected over a
+/// frame with [`SpriteBatch::push`], sorted by texture on
+/// [`SpriteBatch::upload`] and cleared after the frame. Packing them into
+/// one atlas makes a single draw.
+///
+/// Sprites are depth tested when the batch has a depth format but don't
+/// write depth, and blend in texture order rather than back to front.
+pub struct SpriteBatch {
+    pipeline: wgpu::RenderPipeline,
+    texture_layout: wgpu::BindGroupLayout,
+    textures: Vec<wgpu::BindGroup>,
+    view: SpriteView,
+    view_buffer: wgpu::Buffer,
+    view_bind_group: wgpu::BindGroup,
+    sprites: Vec<(SpriteTexture, Sprite)>,
+    buffer: wgpu::Buffer,
+    /// Instances of `buffer` drawn with each texture, by
+    /// [`SpriteBatch::upload`].
+    draws: Vec<(SpriteTexture, Range<u32>)>,
+}
+
+impl SpriteBatch {
+    /// Sprites the buffer has room for before it first grows.
+    const INITIAL_CAPACITY: usize = 256;
+
+    /// Draws sprites into `color_format` targets with `blend`, usually
+    /// [`BlendPreset::AlphaBlend`], or [`BlendPreset::Additive`] for glowing
+    /// particles. `depth_format` and `sample_count` have to match the pass
+    /// the sprites are drawn in.
+    pub fn new(
+        gpu: &Gpu,
+        color_format: wgpu::TextureFormat,
+        depth_format: Option<wgpu::TextureFormat>,
+        sample_count: u32,
+        blend: BlendPreset,
+    ) -> Self {
+        let device = &gpu.device;
+        let view = SpriteView::screen(1, 1);
+        let view_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("SpriteBatch::view"),
+            size: mem::size_of::<SpriteView>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let view_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("SpriteBatch::view_layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let view_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("SpriteBatch::view_bind_group"),
+            layout: &view_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: view_buffer.as_entire_binding(),
+            }],
+        });
+        let texture_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("SpriteBatch::texture_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("SpriteBatch::pipeline_layout"),
+            bind_group_layouts: &[&view_layout, &texture_layout],
+            push_constant_ranges: &[],
+        });
+        let defines: &[&str] = if needs_srgb_encoding(color_format) {
+            &["ENCODE_SRGB"]
+        } else {
+            &[]
+        };
+        let pipeline = PipelineBuilder::new(
+            &pipeline_layout,
+            color_format,
+            wgpu::include_wgsl!("sprite.wgsl"),
+        )
+        .defines(defines)
+        .expect("sprite.wgsl's #ifdef blocks are balanced")
+        .blend(blend)
+        .depth_format(depth_format)
+        .depth_write(false)
+        .vertex_layouts(&[Sprite::desc()])
+        .sample_count(sample_count)
+        .build(gpu);
+
+        Self {
+            pipeline,
+            texture_layout,
+            textures: Vec::new(),
+            view,
+            view_buffer,
+            view_bind_group,
+            sprites: Vec::new(),
+            buffer: Self::create_buffer(gpu, Self::INITIAL_CAPACITY),
+            draws: Vec::new(),
+        }
+    }
+
+    fn create_buffer(gpu: &Gpu, capacity: usize) -> wgpu::Buffer {
+        gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("SpriteBatch::buffer"),
+            size: (capacity * mem::size_of::<Sprite>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Makes `texture` available to sprites, usually an atlas shared by
+    /// many of them.
+    pub fn add_texture(&mut self, gpu: &Gpu, texture: &texture::Texture) -> SpriteTexture {
+        self.textures
+            .push(gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("SpriteBatch::texture"),
+                layout: &self.texture_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                    },
+                ],
+            }));
+        SpriteTexture(self.textures.len() - 1)
+    }
+
+    pub fn push(&mut self, texture: SpriteTexture, sprite: Sprite) {
+        self.sprites.push((texture, sprite));
+    }
+
+    /// Sets where the sprites are drawn from, written with the next
+    /// [`SpriteBatch::upload`].
+    pub fn set_view(&mut self, view: SpriteView) {
+        self.view = view;
+    }
+
+    /// Number of sprites collected this frame.
+    pub fn len(&self) -> usize {
+        self.sprites.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sprites.is_empty()
+    }
+
+    /// Drops the collected sprites, done by the renderer once a frame was
+    /// submitted.
+    pub fn clear(&mut self) {
+        self.sprites.clear();
+    }
+
+    /// Number of draw calls of the last [`SpriteBatch::upload`].
+    pub fn draw_count(&self) -> usize {
+        self.draws.len()
+    }
+
+    /// Sorts the collected sprites by texture and writes them and the view
+    /// to the GPU, growing the buffer when they don't fit. Has to happen
+    /// before the pass they're drawn in.
+    pub fn upload(&mut self, gpu: &Gpu) {
+        // Stable, so sprites of a texture keep the order they were pushed in.
+        self.sprites.sort_by_key(|(texture, _)| *texture);
+        self.draws.clear();
+        for (i, (texture, _)) in self.sprites.iter().enumerate() {
+            match self.draws.last_mut() {
+                Some((last, range)) if last == texture => range.end += 1,
+                _ => self.draws.push((*texture, i as u32..i as u32 + 1)),
+            }
+        }
+
+        let instances = self
+            .sprites
+            .iter()
+            .map(|(_, sprite)| *sprite)
+            .collect::<Vec<_>>();
+        let size = mem::size_of_val(instances.as_slice()) as wgpu::BufferAddress;
+        if size > self.buffer.size() {
+            self.buffer = Self::create_buffer(gpu, instances.len().next_power_of_two());
+        }
+        gpu.queue
+            .write_buffer(&self.buffer, 0, bytemuck::cast_slice(&instances));
+        gpu.queue
+            .write_buffer(&self.view_buffer, 0, bytemuck::bytes_of(&self.view));
+    }
+
+    /// Draws the sprites of the last [`SpriteBatch::upload`] in `pass`.
+    pub fn draw<'a>(&'a self, pass: &mut wgpu::RenderPass<'a>) {
+        if self.draws.is_empty() {
+            return;
+        }
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.view_bind_group, &[]);
+        pass.set_vertex_buffer(0, self.buffer.slice(..));
+        for (texture, instances) in &self.draws {
+            pass.set_bind_group(1, &self.textures[texture.0], &[]);
+            pass.draw(0..6, instances.clone());
+        }
+    }
+
+    /// Draws the sprites over `view` in their own pass, keeping what's
+    /// already there. For batches without a depth format, such as screen
+    /// space overlays.
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, view: &wgpu::TextureView) {
+        if self.draws.is_empty() {
+            return;
+        }
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("SpriteBatch::render"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        self.draw(&mut pass);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grid_cell() {
+        assert_eq!(Sprite::grid_cell(4, 2, 0), [0.0, 0.0, 0.25, 0.5]);
+        assert_eq!(Sprite::grid_cell(4, 2, 5), [0.25, 0.5, 0.5, 1.0]);
+        // Flipbooks loop.
+        assert_eq!(Sprite::grid_cell(4, 2, 8), Sprite::grid_cell(4, 2, 0));
+    }
+
+    #[test]
+    fn test_billboard_faces_the_camera() {
+        let view = na::Matrix4::look_at_rh(
+            &na::Point3::new(3.0, 0.0, 0.0),
+            &na::Point3::origin(),
+            &na::Vector3::y(),
+        );
+        let billboard = SpriteView::billboard(&na::Matrix4::identity(), &view);
+        let right = na::Vector4::from(billboard.right).xyz();
+        let up = na::Vector4::from(billboard.up).xyz();
+        // Looking down -x, the quad lies in the yz plane.
+        assert!((right - na::Vector3::new(0.0, 0.0, -1.0)).norm() < 1e-6);
+        assert!((up - na::Vector3::y()).norm() < 1e-6);
+    }
+
+    #[test]
+    fn test_sprites_draw_once_per_texture() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(8, 8)) else {
+            eprintln!("No adapter, skipping sprite batch test");
+            return Ok(());
+        };
+        let format = wgpu::TextureFormat::Rgba8Unorm;
+        let target = texture::Texture::create_render_target(
+            &gpu.device,
+            8,
+            8,
+            format,
+            wgpu::TextureUsages::COPY_SRC,
+        );
+        let mut batch = SpriteBatch::new(&gpu, format, None, 1, BlendPreset::AlphaBlend);
+        let white = texture::Texture::from_color(&gpu.device, &gpu.queue, [255; 4], "white")?;
+        let red = texture::Texture::from_color(&gpu.device, &gpu.queue, [255, 0, 0, 255], "red")?;
+        let (white, red) = (
+            batch.add_texture(&gpu, &white),
+            batch.add_texture(&gpu, &red),
+        );
+
+        // Particles alternating between the textures, squeezed into the
+        // top left quarter.
+        for i in 0..10_000 {
+            let texture = if i % 2 == 0 { white } else { red };
+            let position = na::Point3::new(2.0, 2.0, 0.0);
+            batch.push(texture, Sprite::new(position, na::Vector2::new(4.0, 4.0)));
+        }
+        // A green tinted one in the bottom right.
+        let position = na::Point3::new(6.0, 6.0, 0.0);
+        let sprite = Sprite::new(position, na::Vector2::new(4.0, 4.0))
+            .with_color([0.0, 1.0, 0.0, 1.0])
+            .with_rotation(std::f32::consts::FRAC_PI_2);
+        batch.push(white, sprite);
+        batch.set_view(SpriteView::screen(8, 8));
+        batch.upload(&gpu);
+        assert_eq!(batch.draw_count(), 2);
+
+        let mut encoder = gpu.create_cmd_encoder();
+        {
+            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &target.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations::default(),
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+        }
+        batch.render(&mut encoder, &target.view);
+        gpu.submit_cmd(encoder.finish());
+        gpu.finish();
+        let frame = gpu.read_texture(&target.texture)?;
+        let pixel = |x: usize, y: usize| &frame.pixels[(y * 8 + x) * 4..][..4];
+        // The red sprites are drawn after all the white ones.
+        assert_eq!(pixel(1, 1), [255, 0, 0, 255]);
+        assert_eq!(pixel(6, 6), [0, 255, 0, 255]);
+        assert_eq!(pixel(6, 1), [0, 0, 0, 0]);
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+// Instanced quads of the SpriteBatch, each a Sprite placed with the
+// SpriteView.
+
+struct View {
+    view_proj: mat4x4<f32>,
+    right: vec4<f32>,
+    up: vec4<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> view: View;
+@group(1) @binding(0)
+var sprite_texture: texture_2d<f32>;
+@group(1) @binding(1)
+var sprite_sampler: sampler;
+
+struct SpriteInput {
+    @location(0) position: vec3<f32>,
+    @location(1) rotation: f32,
+    @location(2) size: vec2<f32>,
+    // Top left and bottom right UVs.
+    @location(3) uv_rect: vec4<f32>,
+    @location(4) color: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+};
+
+// Two counter clockwise triangles from -0.5 to 0.5, y up.
+const CORNERS = array<vec2<f32>, 6>(
+    vec2<f32>(-0.5, -0.5),
+    vec2<f32>(0.5, -0.5),
+    vec2<f32>(0.5, 0.5),
+    vec2<f32>(-0.5, -0.5),
+    vec2<f32>(0.5, 0.5),
+    vec2<f32>(-0.5, 0.5),
+);
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32, sprite: SpriteInput) -> VertexOutput {
+    var corners = CORNERS;
+    let corner = corners[index];
+    let c = cos(sprite.rotation);
+    let s = sin(sprite.rotation);
+    let offset = mat2x2<f32>(c, s, -s, c) * (corner * sprite.size);
+    let position = sprite.position + view.right.xyz * offset.x + view.up.xyz * offset.y;
+
+    var out: VertexOutput;
+    out.clip_position = view.view_proj * vec4<f32>(position, 1.0);
+    // The top of the quad samples the top of the rect.
+    let t = vec2<f32>(corner.x + 0.5, 0.5 - corner.y);
+    out.uv = mix(sprite.uv_rect.xy, sprite.uv_rect.zw, t);
+    out.color = sprite.color;
+    return out;
+}
+
+#ifdef ENCODE_SRGB
+// The sRGB transfer function, for linear surfaces that are displayed as
+// sRGB but don't encode what's written to them.
+fn linear_to_srgb(linear: vec3<f32>) -> vec3<f32> {
+    let low = linear * 12.92;
+    let high = 1.055 * pow(linear, vec3(1.0 / 2.4)) - 0.055;
+    return select(high, low, linear <= vec3(0.0031308));
+}
+#endif
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(sprite_texture, sprite_sampler, in.uv) * in.color;
+#ifdef ENCODE_SRGB
+    return vec4(linear_to_srgb(color.rgb), color.a);
+#else
+    return color;
+#endif
+}
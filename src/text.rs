@@ -0,0 +1,585 @@
+use ab_glyph::{Font, FontArc, GlyphId, ScaleFont};
+use nalgebra as na;
+use std::{collections::HashMap, mem};
+
+use crate::{
+    gpu::{needs_srgb_encoding, Gpu},
+    model::Vertex,
+    pipeline::{BlendPreset, PipelineBuilder},
+    texture,
+};
+
+/// Hack Regular, the font the renderer draws its text with. See
+/// `res/fonts/Hack-Regular.txt` for its license.
+pub const DEFAULT_FONT: &[u8] = include_bytes!("../res/fonts/Hack-Regular.ttf");
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct TextVertex {
+    /// In pixels from the top left of the screen.
+    pub position: [f32; 2],
+    pub uv: [f32; 2],
+    pub color: [f32; 4],
+}
+
+impl Vertex for TextVertex {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<TextVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32x2,
+                    offset: 0,
+                    shader_location: 0,
+                },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32x2,
+                    offset: mem::size_of::<[f32; 2]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32x4,
+                    offset: mem::size_of::<[f32; 2]>() as wgpu::BufferAddress * 2,
+                    shader_location: 2,
+                },
+            ],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct ScreenUniform {
+    size: [f32; 2],
+    _padding: [f32; 2],
+}
+
+/// Where a rasterized glyph is in the atlas.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct AtlasGlyph {
+    /// Top left corner in the atlas, in pixels.
+    min: [u32; 2],
+    size: [u32; 2],
+    /// Of the top left corner from the pen position on the baseline.
+    offset: [f32; 2],
+}
+
+/// Glyphs rasterized on first use and packed into rows of a square
+/// coverage texture, each row as tall as its tallest glyph.
+struct GlyphAtlas {
+    size: u32,
+    /// By glyph and pixel size, `None` for glyphs without an outline such
+    /// as spaces and for those that didn't fit.
+    glyphs: HashMap<(GlyphId, u32), Option<AtlasGlyph>>,
+    cursor: [u32; 2],
+    row_height: u32,
+    /// Rasterized glyphs and their coverage, not written to the texture yet.
+    pending: Vec<(AtlasGlyph, Vec<u8>)>,
+}
+
+impl GlyphAtlas {
+    /// Empty pixels between glyphs, so filtering doesn't bleed into the
+    /// neighbours.
+    const PADDING: u32 = 1;
+
+    fn new(size: u32) -> Self {
+        Self {
+            size,
+            glyphs: HashMap::new(),
+            cursor: [0, 0],
+            row_height: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    fn glyph(&mut self, font: &FontArc, id: GlyphId, px: u32) -> Option<AtlasGlyph> {
+        if let Some(glyph) = self.glyphs.get(&(id, px)) {
+            return *glyph;
+        }
+        let glyph = font
+            .outline_glyph(id.with_scale(px as f32))
+            .and_then(|outline| {
+                let bounds = outline.px_bounds();
+                let size = [bounds.width() as u32, bounds.height() as u32];
+                let min = self.allocate(size)?;
+                let mut coverage = vec![0; (size[0] * size[1]) as usize];
+                outline.draw(|x, y, c| {
+                    coverage[(y * size[0] + x) as usize] = (c * 255.0).round() as u8;
+                });
+                let glyph = AtlasGlyph {
+                    min,
+                    size,
+                    offset: [bounds.min.x, bounds.min.y],
+                };
+                self.pending.push((glyph, coverage));
+                Some(glyph)
+            });
+        self.glyphs.insert((id, px), glyph);
+        glyph
+    }
+
+    /// Top left corner of a free `size` area, `None` once the atlas is full.
+    fn allocate(&mut self, size: [u32; 2]) -> Option<[u32; 2]> {
+        let width = size[0] + Self::PADDING;
+        let height = size[1] + Self::PADDING;
+        if self.cursor[0] + width > self.size {
+            self.cursor = [0, self.cursor[1] + self.row_height];
+            self.row_height = 0;
+        }
+        if self.cursor[0] + width > self.size || self.cursor[1] + height > self.size {
+            log::warn!(
+                "Glyph atlas is full, skipping a {}x{} glyph",
+                size[0],
+                size[1]
+            );
+            return None;
+        }
+        let min = self.cursor;
+        self.cursor[0] += width;
+        self.row_height = self.row_height.max(height);
+        Some(min)
+    }
+}
+
+/// Lays out the lines of `text` at `px` pixels, calling `glyph` with each
+/// glyph and its pen position relative to the top left of the text.
+/// Returns the size of the text.
+fn layout(
+    font: &FontArc,
+    text: &str,
+    px: f32,
+    mut glyph: impl FnMut(GlyphId, na::Point2<f32>),
+) -> na::Vector2<f32> {
+    let font = font.as_scaled(px);
+    let mut pen = na::Point2::new(0.0, font.ascent());
+    let mut width = 0.0f32;
+    let mut previous = None;
+    for c in text.chars() {
+        if c == '\n' {
+            width = width.max(pen.x);
+            pen = na::Point2::new(0.0, pen.y + font.height() + font.line_gap());
+            previous = None;
+            continue;
+        }
+        if c.is_control() {
+            continue;
+        }
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            pen.x += font.kern(previous, id);
+        }
+        glyph(id, pen);
+        pen.x += font.h_advance(id);
+        previous = Some(id);
+    }
+    na::Vector2::new(width.max(pen.x), pen.y - font.descent())
+}
+
+/// Text drawn over the finished frame, such as object labels or an FPS
+/// counter, without going through egui. Text is collected over a frame
+/// with [`TextRenderer::draw_text`] and drawn in its own pass by
+/// [`TextRenderer::render`], loading what's already in the target.
+pub struct TextRenderer {
+    font: FontArc,
+    atlas: GlyphAtlas,
+    texture: texture::Texture,
+    pipeline: wgpu::RenderPipeline,
+    bind_group: wgpu::BindGroup,
+    screen_buffer: wgpu::Buffer,
+    vertices: Vec<TextVertex>,
+    buffer: wgpu::Buffer,
+    /// Vertices uploaded to `buffer` by [`TextRenderer::upload`].
+    uploaded: u32,
+}
+
+impl TextRenderer {
+    /// Width and height of the glyph atlas.
+    const ATLAS_SIZE: u32 = 1024;
+    /// Vertices the buffer has room for before it first grows.
+    const INITIAL_CAPACITY: usize = 6 * 256;
+
+    /// `font` is the data of a TrueType or OpenType font, `color_format`
+    /// the format of the targets the text is rendered to, usually the
+    /// surface format.
+    pub fn new(gpu: &Gpu, font: &[u8], color_format: wgpu::TextureFormat) -> anyhow::Result<Self> {
+        let font = FontArc::try_from_vec(font.to_vec())?;
+        let device = &gpu.device;
+
+        let texture = texture::Texture::create_2d_texture(
+            gpu,
+            Self::ATLAS_SIZE,
+            Self::ATLAS_SIZE,
+            wgpu::TextureFormat::R8Unorm,
+            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            wgpu::FilterMode::Nearest,
+            Some("TextRenderer::atlas"),
+        );
+        let screen_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("TextRenderer::screen"),
+            size: mem::size_of::<ScreenUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("TextRenderer::layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("TextRenderer::bind_group"),
+            layout: &layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: screen_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("TextRenderer::pipeline_layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let defines: &[&str] = if needs_srgb_encoding(color_format) {
+            &["ENCODE_SRGB"]
+        } else {
+            &[]
+        };
+        let pipeline = PipelineBuilder::new(
+            &pipeline_layout,
+            color_format,
+            wgpu::include_wgsl!("text.wgsl"),
+        )
+        .defines(defines)
+        .expect("text.wgsl's #ifdef blocks are balanced")
+        .blend(BlendPreset::AlphaBlend)
+        .vertex_layouts(&[TextVertex::desc()])
+        .build(gpu);
+
+        Ok(Self {
+            font,
+            atlas: GlyphAtlas::new(Self::ATLAS_SIZE),
+            texture,
+            pipeline,
+            bind_group,
+            screen_buffer,
+            vertices: Vec::new(),
+            buffer: Self::create_buffer(gpu, Self::INITIAL_CAPACITY),
+            uploaded: 0,
+        })
+    }
+
+    fn create_buffer(gpu: &Gpu, capacity: usize) -> wgpu::Buffer {
+        gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("TextRenderer::buffer"),
+            size: (capacity * mem::size_of::<TextVertex>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Draws `text` with its top left corner at `position`, in pixels from
+    /// the top left of the surface, `size` pixels high. Lines are split at
+    /// `\n`. Glyphs are snapped to whole pixels to stay sharp.
+    pub fn draw_text(&mut self, position: na::Point2<f32>, text: &str, size: f32, color: [f32; 4]) {
+        let px = size.round().max(1.0) as u32;
+        let atlas_size = self.atlas.size as f32;
+        let Self {
+            font,
+            atlas,
+            vertices,
+            ..
+        } = self;
+        layout(font, text, px as f32, |id, pen| {
+            let Some(glyph) = atlas.glyph(font, id, px) else {
+                return;
+            };
+            let min = na::Point2::new(
+                (position.x + pen.x).round() + glyph.offset[0],
+                (position.y + pen.y).round() + glyph.offset[1],
+            );
+            let max = min + na::Vector2::new(glyph.size[0] as f32, glyph.size[1] as f32);
+            let uv_min = [
+                glyph.min[0] as f32 / atlas_size,
+                glyph.min[1] as f32 / atlas_size,
+            ];
+            let uv_max = [
+                (glyph.min[0] + glyph.size[0]) as f32 / atlas_size,
+                (glyph.min[1] + glyph.size[1]) as f32 / atlas_size,
+            ];
+            let vertex = |x: f32, y: f32, u: f32, v: f32| TextVertex {
+                position: [x, y],
+                uv: [u, v],
+                color,
+            };
+            let top_left = vertex(min.x, min.y, uv_min[0], uv_min[1]);
+            let bottom_left = vertex(min.x, max.y, uv_min[0], uv_max[1]);
+            let bottom_right = vertex(max.x, max.y, uv_max[0], uv_max[1]);
+            let top_right = vertex(max.x, min.y, uv_max[0], uv_min[1]);
+            // Counter clockwise once y points up in clip space.
+            vertices.extend([
+                top_left,
+                bottom_left,
+                bottom_right,
+                top_left,
+                bottom_right,
+                top_right,
+            ]);
+        });
+    }
+
+    /// Width and height `text` takes up when drawn `size` pixels high, e.g.
+    /// to right align it.
+    pub fn measure(&self, text: &str, size: f32) -> na::Vector2<f32> {
+        layout(&self.font, text, size.round().max(1.0), |_, _| {})
+    }
+
+    /// Pixel position of the world space `point` on a `width` by `height`
+    /// surface, for labels anchored to objects. `None` when it's behind
+    /// the camera.
+    pub fn screen_position(
+        view_proj: &na::Matrix4<f32>,
+        point: &na::Point3<f32>,
+        width: u32,
+        height: u32,
+    ) -> Option<na::Point2<f32>> {
+        let clip = view_proj * point.to_homogeneous();
+        if clip.w <= 0.0 {
+            return None;
+        }
+        Some(na::Point2::new(
+            (clip.x / clip.w + 1.0) * 0.5 * width as f32,
+            (1.0 - clip.y / clip.w) * 0.5 * height as f32,
+        ))
+    }
+
+    /// Number of glyph vertices collected this frame.
+    pub fn len(&self) -> usize {
+        self.vertices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vertices.is_empty()
+    }
+
+    /// Drops the collected text, done by the renderer once a frame was
+    /// submitted.
+    pub fn clear(&mut self) {
+        self.vertices.clear();
+    }
+
+    /// Writes newly rasterized glyphs to the atlas and the collected text
+    /// to the vertex buffer, growing it when it doesn't fit. Positions are
+    /// relative to the surface size at the time of the upload.
+    pub fn upload(&mut self, gpu: &Gpu) {
+        for (glyph, coverage) in self.atlas.pending.drain(..) {
+            gpu.queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &self.texture.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: glyph.min[0],
+                        y: glyph.min[1],
+                        z: 0,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                &coverage,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(glyph.size[0]),
+                    rows_per_image: Some(glyph.size[1]),
+                },
+                wgpu::Extent3d {
+                    width: glyph.size[0],
+                    height: glyph.size[1],
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        let (width, height) = gpu.get_config_read(|config| (config.width, config.height));
+        let screen = ScreenUniform {
+            size: [width as f32, height as f32],
+            _padding: [0.0; 2],
+        };
+        gpu.queue
+            .write_buffer(&self.screen_buffer, 0, bytemuck::bytes_of(&screen));
+
+        let size = (self.vertices.len() * mem::size_of::<TextVertex>()) as wgpu::BufferAddress;
+        if size > self.buffer.size() {
+            self.buffer = Self::create_buffer(gpu, self.vertices.len().next_power_of_two());
+        }
+        gpu.queue
+            .write_buffer(&self.buffer, 0, bytemuck::cast_slice(&self.vertices));
+        self.uploaded = self.vertices.len() as u32;
+    }
+
+    /// Draws the text of the last [`TextRenderer::upload`] over `view`,
+    /// keeping what's already there.
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, view: &wgpu::TextureView) {
+        if self.uploaded == 0 {
+            return;
+        }
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("TextRenderer::render"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        pass.set_vertex_buffer(0, self.buffer.slice(..));
+        pass.draw(0..self.uploaded, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let font = FontArc::try_from_slice(DEFAULT_FONT).unwrap();
+        let one = layout(&font, "a", 16.0, |_, _| {});
+        let two = layout(&font, "ab", 16.0, |_, _| {});
+        let lines = layout(&font, "ab\na", 16.0, |_, _| {});
+        assert!(two.x > one.x);
+        assert_eq!(lines.x, two.x);
+        assert!(lines.y > 1.5 * two.y);
+
+        let mut pens = Vec::new();
+        layout(&font, "a\nb", 16.0, |_, pen| pens.push(pen));
+        assert_eq!(pens[0].x, pens[1].x);
+        assert!(pens[1].y > pens[0].y);
+
+        let mut atlas = GlyphAtlas::new(64);
+        let a = atlas.glyph(&font, font.glyph_id('a'), 16);
+        assert!(a.is_some());
+        assert_eq!(atlas.glyph(&font, font.glyph_id('a'), 16), a);
+        assert_eq!(atlas.glyph(&font, font.glyph_id(' '), 16), None);
+        assert_eq!(atlas.pending.len(), 1);
+        // Rows of glyphs until nothing fits.
+        let alphabet = ('A'..='Z').chain('a'..='z');
+        let packed = alphabet
+            .filter_map(|c| atlas.glyph(&font, font.glyph_id(c), 16))
+            .collect::<Vec<_>>();
+        assert!(packed.len() < 52);
+        assert!(packed
+            .iter()
+            .all(|glyph| glyph.min[0] + glyph.size[0] <= 64 && glyph.min[1] + glyph.size[1] <= 64));
+    }
+
+    #[test]
+    fn test_screen_position() {
+        let identity = na::Matrix4::identity();
+        assert_eq!(
+            TextRenderer::screen_position(&identity, &na::Point3::new(0.0, 0.0, 0.5), 100, 50),
+            Some(na::Point2::new(50.0, 25.0))
+        );
+        assert_eq!(
+            TextRenderer::screen_position(&identity, &na::Point3::new(-1.0, 1.0, 0.5), 100, 50),
+            Some(na::Point2::new(0.0, 0.0))
+        );
+        let behind = na::Matrix4::new_perspective(1.0, 1.0, 0.1, 100.0);
+        assert_eq!(
+            TextRenderer::screen_position(&behind, &na::Point3::new(0.0, 0.0, 1.0), 100, 50),
+            None
+        );
+    }
+
+    #[test]
+    fn test_text_is_drawn_over_the_target() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(16, 16)) else {
+            eprintln!("No adapter, skipping text test");
+            return Ok(());
+        };
+        let format = wgpu::TextureFormat::Rgba8Unorm;
+        let target = texture::Texture::create_render_target(
+            &gpu.device,
+            16,
+            16,
+            format,
+            wgpu::TextureUsages::COPY_SRC,
+        );
+        let mut text = TextRenderer::new(&gpu, DEFAULT_FONT, format)?;
+        text.draw_text(na::Point2::new(0.0, 0.0), "#", 16.0, [1.0; 4]);
+        assert_eq!(text.len(), 6);
+        text.upload(&gpu);
+
+        let mut encoder = gpu.device.create_command_encoder(&Default::default());
+        // The scene, which the text goes over.
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &target.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLUE),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        text.render(&mut encoder, &target.view);
+        gpu.queue.submit([encoder.finish()]);
+        text.clear();
+        assert!(text.is_empty());
+
+        let frame = gpu.read_texture(&target.texture)?;
+        let pixels = frame.pixels.chunks(4).collect::<Vec<_>>();
+        assert!(pixels.iter().any(|pixel| pixel[0] > 200));
+        assert!(pixels.contains(&[0, 0, 255, 255].as_slice()));
+        assert!(pixels
+            .iter()
+            .all(|pixel| pixel[2] == 255 && pixel[0] == pixel[1]));
+        Ok(())
+    }
+}
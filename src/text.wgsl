@@ -0,0 +1,55 @@
+// Glyph quads of the TextRenderer, positioned in pixels from the top left
+// of the screen.
+
+struct Screen {
+    size: vec2<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> screen: Screen;
+@group(0) @binding(1)
+var atlas: texture_2d<f32>;
+@group(0) @binding(2)
+var atlas_sampler: sampler;
+
+struct VertexInput {
+    @location(0) position: vec2<f32>,
+    @location(1) uv: vec2<f32>,
+    @location(2) color: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+};
+
+@vertex
+fn vs_main(in: VertexInput) -> VertexOutput {
+    let ndc = in.position / screen.size * 2.0 - 1.0;
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(ndc.x, -ndc.y, 0.0, 1.0);
+    out.uv = in.uv;
+    out.color = in.color;
+    return out;
+}
+
+#ifdef ENCODE_SRGB
+// The sRGB transfer function, for linear surfaces that are displayed as
+// sRGB but don't encode what's written to them.
+fn linear_to_srgb(linear: vec3<f32>) -> vec3<f32> {
+    let low = linear * 12.92;
+    let high = 1.055 * pow(linear, vec3(1.0 / 2.4)) - 0.055;
+    return select(high, low, linear <= vec3(0.0031308));
+}
+#endif
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let coverage = textureSample(atlas, atlas_sampler, in.uv).r;
+#ifdef ENCODE_SRGB
+    return vec4(linear_to_srgb(in.color.rgb), in.color.a * coverage);
+#else
+    return vec4(in.color.rgb, in.color.a * coverage);
+#endif
+}
@@ -1,6 +1,8 @@
 use std::ops::RangeInclusive;
 
+use crate::bcn;
 use crate::gpu::Gpu;
+use crate::ktx2::Ktx2;
 use anyhow::*;
 use image::{DynamicImage, GenericImageView};
 use image::{ImageBuffer, Rgba};
@@ -9,6 +11,125 @@ use rand::Rng;
 const WHITE: [u8; 4] = [255, 255, 255, 255];
 const BRIGHT_RANGE: RangeInclusive<u8> = 124..=255;
 
+/// Errors worth telling apart from a broken file, returned inside the
+/// [`anyhow::Error`] so callers can downcast to them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TextureError {
+    /// The data is in a format the device can't sample, either unknown to
+    /// the loader or missing the feature it needs.
+    UnsupportedFormat(String),
+}
+
+impl std::fmt::Display for TextureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedFormat(reason) => write!(f, "Unsupported texture format: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for TextureError {}
+
+/// How a [`Texture`] is sampled, see [`Texture::with_sampler`]. The default
+/// is the sampler textures are created with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SamplerDesc {
+    pub mag_filter: wgpu::FilterMode,
+    pub min_filter: wgpu::FilterMode,
+    pub mipmap_filter: wgpu::FilterMode,
+    pub address_mode_u: wgpu::AddressMode,
+    pub address_mode_v: wgpu::AddressMode,
+    pub address_mode_w: wgpu::AddressMode,
+    /// 1 turns anisotropic filtering off, anything above needs all filters
+    /// to be linear.
+    pub anisotropy_clamp: u16,
+}
+
+impl Default for SamplerDesc {
+    fn default() -> Self {
+        Self {
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            anisotropy_clamp: 1,
+        }
+    }
+}
+
+impl SamplerDesc {
+    /// Linear filtering repeating in every direction, for tiling textures.
+    pub fn repeat() -> Self {
+        Self {
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            address_mode_w: wgpu::AddressMode::Repeat,
+            ..Self::default()
+        }
+    }
+
+    /// `self` filtering anisotropically up to `clamp` samples, e.g. for
+    /// terrain seen at grazing angles. Switches every filter to linear.
+    pub fn anisotropic(self, clamp: u16) -> Self {
+        Self {
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            anisotropy_clamp: clamp,
+            ..self
+        }
+    }
+
+    /// Checks what wgpu would otherwise panic on.
+    pub fn validate(&self) -> Result<()> {
+        if self.anisotropy_clamp == 0 {
+            bail!("Anisotropy clamp has to be at least 1");
+        }
+        let linear = [self.mag_filter, self.min_filter, self.mipmap_filter]
+            .iter()
+            .all(|filter| *filter == wgpu::FilterMode::Linear);
+        if self.anisotropy_clamp > 1 && !linear {
+            bail!(
+                "Anisotropic filtering needs linear filters, got {:?}/{:?}/{:?} (mag/min/mipmap)",
+                self.mag_filter,
+                self.min_filter,
+                self.mipmap_filter
+            );
+        }
+        Ok(())
+    }
+
+    pub fn create(&self, device: &wgpu::Device, label: Option<&str>) -> Result<wgpu::Sampler> {
+        self.validate()?;
+        Ok(device.create_sampler(&wgpu::SamplerDescriptor {
+            label,
+            address_mode_u: self.address_mode_u,
+            address_mode_v: self.address_mode_v,
+            address_mode_w: self.address_mode_w,
+            mag_filter: self.mag_filter,
+            min_filter: self.min_filter,
+            mipmap_filter: self.mipmap_filter,
+            anisotropy_clamp: self.anisotropy_clamp,
+            ..Default::default()
+        }))
+    }
+}
+
+/// What a depth buffer is used for besides depth testing, see
+/// [`Texture::choose_depth_format`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DepthRequirements {
+    /// Stencil testing, which needs a stencil aspect.
+    pub stencil: bool,
+    /// Copying the depth out, e.g. to read it back on the CPU. Not every
+    /// depth format allows it.
+    pub readback: bool,
+}
+
 pub struct Texture {
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
@@ -17,6 +138,8 @@ pub struct Texture {
 }
 
 impl Texture {
+    /// Format of the renderer's depth buffer, what
+    /// [`Texture::choose_depth_format`] picks for readback without stencil.
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
     pub const BIND_GROUP_LAYOUT_DESCRIPTOR: wgpu::BindGroupLayoutDescriptor<'static> =
         wgpu::BindGroupLayoutDescriptor {
@@ -40,11 +163,49 @@ impl Texture {
                     ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                // The normal, metallic-roughness and occlusion maps, sampled
+                // with the diffuse texture's sampler.
+                Self::material_map_entry(3),
+                Self::material_map_entry(4),
+                Self::material_map_entry(5),
             ],
         };
-    pub fn load(gpu: &Gpu, texture: &Texture) -> wgpu::BindGroup {
+
+    const fn material_map_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        }
+    }
+
+    /// Binds the diffuse `texture` and the material's normal,
+    /// metallic-roughness and occlusion `maps` together with the material
+    /// parameters in `material_buffer`, see [`crate::model::MaterialUniform`].
+    pub fn load(
+        gpu: &Gpu,
+        texture: &Texture,
+        maps: [&Texture; 3],
+        material_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
         let device = &gpu.device;
         let layout = Self::get_bind_group_layout(gpu);
+        let [normal, metallic_roughness, occlusion] = maps;
         device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
             layout: &layout,
@@ -57,6 +218,22 @@ impl Texture {
                     binding: 1,
                     resource: wgpu::BindingResource::Sampler(&texture.sampler),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: material_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&normal.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&metallic_roughness.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&occlusion.view),
+                },
             ],
         })
     }
@@ -99,6 +276,99 @@ impl Texture {
         }
     }
 
+    /// Single sampled 2D render target, which [`Texture::resize`] can
+    /// reallocate when the window changes size. Zero sizes are clamped to 1.
+    pub fn create_render_target(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        format: wgpu::TextureFormat,
+        usage: wgpu::TextureUsages,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width: width.max(1),
+            height: height.max(1),
+            depth_or_array_layers: 1,
+        };
+        Self::create_texture(
+            device,
+            Some("render_target"),
+            size,
+            format,
+            usage | wgpu::TextureUsages::RENDER_ATTACHMENT,
+            wgpu::TextureDimension::D2,
+            wgpu::FilterMode::Linear,
+        )
+    }
+
+    /// Reallocates the texture and its view at `width` x `height`, keeping
+    /// the format, usage, sample count and sampler. Zero sizes, e.g. of a
+    /// minimized window, are clamped to 1. The contents are lost and bind
+    /// groups using the old view have to be recreated.
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        let size = wgpu::Extent3d {
+            width: width.max(1),
+            height: height.max(1),
+            depth_or_array_layers: self.size.depth_or_array_layers,
+        };
+        if size == self.size {
+            return;
+        }
+        self.texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size,
+            mip_level_count: self
+                .texture
+                .mip_level_count()
+                .min(Self::mip_level_count(size.width, size.height)),
+            sample_count: self.texture.sample_count(),
+            dimension: self.texture.dimension(),
+            format: self.texture.format(),
+            usage: self.texture.usage(),
+            view_formats: &[],
+        });
+        self.view = self
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        self.size = size;
+    }
+
+    /// Multisampled render target, only usable as a render attachment that
+    /// gets resolved into a single sampled texture.
+    pub fn create_multisampled_texture(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+        label: &str,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size,
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+
+        Self {
+            texture,
+            view,
+            sampler,
+            size,
+        }
+    }
+
     pub fn create_2d_texture(
         gpu: &Gpu,
         height: u32,
@@ -185,15 +455,257 @@ impl Texture {
         bytes: &[u8],
         label: &str,
     ) -> Result<Self> {
+        if Ktx2::is_ktx2(bytes) {
+            return Self::from_ktx2(device, queue, bytes);
+        }
         let img = image::load_from_memory(bytes)?;
         Self::from_image(device, queue, &img, Some(label))
     }
 
+    /// [`Texture::from_bytes`] sampled as `sampler` describes.
+    pub fn from_bytes_with_sampler(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        bytes: &[u8],
+        label: &str,
+        sampler: &SamplerDesc,
+    ) -> Result<Self> {
+        Self::from_bytes(device, queue, bytes, label)?.with_sampler(device, sampler)
+    }
+
+    /// Replaces the sampler, failing if `sampler` is invalid.
+    pub fn with_sampler(self, device: &wgpu::Device, sampler: &SamplerDesc) -> Result<Self> {
+        Ok(Self {
+            sampler: sampler.create(device, None)?,
+            ..self
+        })
+    }
+
+    /// Like [`Texture::from_bytes`] but with a full mip chain generated on
+    /// the GPU, sampled with trilinear filtering.
+    pub fn from_bytes_with_mips(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        bytes: &[u8],
+        label: &str,
+    ) -> Result<Self> {
+        let img = image::load_from_memory(bytes)?;
+        Self::from_image_with_mips(device, queue, &img, Some(label))
+    }
+
+    /// Decodes `bytes` and compresses them on the CPU into the block
+    /// compressed `format` before uploading.
+    pub fn from_bytes_compressed(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        bytes: &[u8],
+        format: wgpu::TextureFormat,
+        label: &str,
+    ) -> Result<Self> {
+        if !device
+            .features()
+            .contains(wgpu::Features::TEXTURE_COMPRESSION_BC)
+        {
+            bail!("Device does not support BC texture compression");
+        }
+        let Some(block_size) = bcn::block_size(format) else {
+            bail!("Unsupported compressed format {format:?}");
+        };
+
+        let img = image::load_from_memory(bytes)?;
+        let rgba = img.to_rgba8();
+        let dimensions = img.dimensions();
+        let data = bcn::compress(&rgba, dimensions.0, dimensions.1, format)?;
+
+        let size = wgpu::Extent3d {
+            width: dimensions.0,
+            height: dimensions.1,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            &data,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                // Rows are rows of 4x4 blocks for compressed formats.
+                bytes_per_row: Some(dimensions.0 / 4 * block_size),
+                rows_per_image: Some(dimensions.1 / 4),
+            },
+            size,
+        );
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Ok(Self {
+            texture,
+            view,
+            sampler,
+            size,
+        })
+    }
+
+    /// Uploads the BC1, BC3 or BC7 mip levels stored in a KTX2 container
+    /// as they are, without decoding them.
+    ///
+    /// Fails with [`TextureError::UnsupportedFormat`] for other formats or
+    /// when the device lacks [`wgpu::Features::TEXTURE_COMPRESSION_BC`].
+    pub fn from_ktx2(device: &wgpu::Device, queue: &wgpu::Queue, bytes: &[u8]) -> Result<Self> {
+        let ktx2 = Ktx2::parse(bytes)?;
+        let Some(format) = ktx2.format() else {
+            return Err(TextureError::UnsupportedFormat(format!(
+                "VkFormat {} is not BC1, BC3 or BC7",
+                ktx2.vk_format
+            ))
+            .into());
+        };
+        let required = format.required_features();
+        if !device.features().contains(required) {
+            return Err(TextureError::UnsupportedFormat(format!(
+                "{format:?} needs {required:?}, which the device doesn't have"
+            ))
+            .into());
+        }
+
+        let size = wgpu::Extent3d {
+            width: ktx2.width,
+            height: ktx2.height,
+            depth_or_array_layers: 1,
+        };
+        let mip_level_count = ktx2.levels.len() as u32;
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("KTX2 texture"),
+            size,
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+
+        let (block_width, block_height) = format.block_dimensions();
+        let block_size = format.block_copy_size(None).unwrap();
+        for (mip, data) in ktx2.levels.iter().enumerate() {
+            let mip = mip as u32;
+            // Mips smaller than a block still take up a whole one.
+            let mip_size = size
+                .mip_level_size(mip, wgpu::TextureDimension::D2)
+                .physical_size(format);
+            let blocks_wide = mip_size.width / block_width;
+            let blocks_high = mip_size.height / block_height;
+            if data.len() != (blocks_wide * blocks_high * block_size) as usize {
+                bail!(
+                    "KTX2 mip level {mip} has {} bytes, expected {}",
+                    data.len(),
+                    blocks_wide * blocks_high * block_size
+                );
+            }
+
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level: mip,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(blocks_wide * block_size),
+                    rows_per_image: Some(blocks_high),
+                },
+                mip_size,
+            );
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Ok(Self {
+            texture,
+            view,
+            sampler,
+            size,
+        })
+    }
+
     pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
         label: Option<&str>,
+    ) -> Result<Self> {
+        Self::from_image_as(
+            device,
+            queue,
+            img,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            label,
+        )
+    }
+
+    /// Like [`Texture::from_image`] but without the sRGB decoding, for data
+    /// such as normal or metallic-roughness maps.
+    pub fn from_image_linear(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &image::DynamicImage,
+        label: Option<&str>,
+    ) -> Result<Self> {
+        Self::from_image_as(device, queue, img, wgpu::TextureFormat::Rgba8Unorm, label)
+    }
+
+    /// A 1x1 linear texture of `color`, e.g. the fallback for a material map
+    /// a model doesn't have.
+    pub fn from_color(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color: [u8; 4],
+        label: &str,
+    ) -> Result<Self> {
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(1, 1, Rgba(color)));
+        Self::from_image_linear(device, queue, &img, Some(label))
+    }
+
+    fn from_image_as(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &image::DynamicImage,
+        format: wgpu::TextureFormat,
+        label: Option<&str>,
     ) -> Result<Self> {
         let rgba = img.to_rgba8();
         let dimensions = img.dimensions();
@@ -209,7 +721,7 @@ impl Texture {
             mip_level_count: 1,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            format,
             usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
             view_formats: &[],
         });
@@ -249,9 +761,293 @@ impl Texture {
         })
     }
 
+    pub fn from_image_with_mips(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &image::DynamicImage,
+        label: Option<&str>,
+    ) -> Result<Self> {
+        let rgba = img.to_rgba8();
+        let dimensions = img.dimensions();
+
+        let size = wgpu::Extent3d {
+            width: dimensions.0,
+            height: dimensions.1,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label,
+            size,
+            mip_level_count: Self::mip_level_count(dimensions.0, dimensions.1),
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            // The mips are rendered from the level above them.
+            usage: wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_DST
+                | wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            &rgba,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * dimensions.0),
+                rows_per_image: Some(dimensions.1),
+            },
+            size,
+        );
+
+        Self::generate_mips(device, queue, &texture)?;
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Ok(Self {
+            texture,
+            view,
+            sampler,
+            size,
+        })
+    }
+
+    /// Sampler for `sample_level` in `sample_level.wgsl`. The nearest mipmap
+    /// filter keeps pinned levels from blending with the next mip.
+    pub fn create_level_sampler(device: &wgpu::Device) -> wgpu::Sampler {
+        device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Texture::level_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        })
+    }
+
+    /// `floor(log2(max(width, height))) + 1`, the length of the mip chain
+    /// down to 1x1. Odd sizes round down at every level.
+    pub fn mip_level_count(width: u32, height: u32) -> u32 {
+        u32::BITS - width.max(height).max(1).leading_zeros()
+    }
+
+    /// Bytes `texture` takes in GPU memory, see [`Texture::memory_size_of`].
+    pub fn memory_size(texture: &wgpu::Texture) -> u64 {
+        Self::memory_size_of(
+            texture.format(),
+            texture.size(),
+            texture.dimension(),
+            texture.mip_level_count(),
+            texture.sample_count(),
+        )
+    }
+
+    /// Bytes a texture takes in GPU memory, summed over its mip levels, array
+    /// layers and samples. Compressed formats count whole blocks, so a 1x1
+    /// BC1 mip takes 8 bytes. Drivers may pad on top of this.
+    pub fn memory_size_of(
+        format: wgpu::TextureFormat,
+        size: wgpu::Extent3d,
+        dimension: wgpu::TextureDimension,
+        mip_level_count: u32,
+        sample_count: u32,
+    ) -> u64 {
+        let (block_width, block_height) = format.block_dimensions();
+        let block_size = Self::block_memory_size(format);
+        (0..mip_level_count)
+            .map(|level| {
+                let size = size.mip_level_size(level, dimension);
+                let blocks = u64::from(size.width.div_ceil(block_width))
+                    * u64::from(size.height.div_ceil(block_height))
+                    * u64::from(size.depth_or_array_layers);
+                blocks * block_size
+            })
+            .sum::<u64>()
+            * u64::from(sample_count)
+    }
+
+    fn block_memory_size(format: wgpu::TextureFormat) -> u64 {
+        if let Some(size) = format.block_copy_size(None) {
+            return size.into();
+        }
+        // Combined depth-stencil formats only have sizes per aspect, and
+        // 24 bit depth none at all since it can't be copied.
+        let depth = match format.block_copy_size(Some(wgpu::TextureAspect::DepthOnly)) {
+            Some(size) => size,
+            None if format.has_depth_aspect() => 4,
+            None => 0,
+        };
+        let stencil = format
+            .block_copy_size(Some(wgpu::TextureAspect::StencilOnly))
+            .unwrap_or(0);
+        (depth + stencil).into()
+    }
+
+    /// Fills every mip level after the first by downsampling the level
+    /// above it. `texture` needs `TEXTURE_BINDING` and `RENDER_ATTACHMENT`
+    /// usage and a filterable, renderable format.
+    pub fn generate_mips(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+    ) -> Result<()> {
+        let format = texture.format();
+        let features = format.guaranteed_format_features(device.features());
+        if !features
+            .allowed_usages
+            .contains(wgpu::TextureUsages::RENDER_ATTACHMENT)
+            || !features
+                .flags
+                .contains(wgpu::TextureFormatFeatureFlags::FILTERABLE)
+        {
+            bail!("Can't generate mips for {format:?}, it must be filterable and renderable");
+        }
+        let usage = wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT;
+        if !texture.usage().contains(usage) {
+            bail!("Generating mips requires {usage:?} usage");
+        }
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("blit.wgsl"));
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Texture::generate_mips"),
+            layout: None,
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(format.into())],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+        let layout = pipeline.get_bind_group_layout(0);
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Texture::generate_mips"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let views = (0..texture.mip_level_count())
+            .map(|mip| {
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    base_mip_level: mip,
+                    mip_level_count: Some(1),
+                    ..Default::default()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut encoder = device.create_command_encoder(&Default::default());
+        for target in 1..views.len() {
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&views[target - 1]),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&sampler),
+                    },
+                ],
+            });
+
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Texture::generate_mips"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &views[target],
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+        queue.submit([encoder.finish()]);
+
+        Ok(())
+    }
+
+    /// The smallest depth format meeting `requirements` on a device with
+    /// `features`: `Depth24Plus` for plain depth testing, `Depth24PlusStencil8`
+    /// with stencil and `Depth32Float` for readback, as the depth of the
+    /// 24 bit formats can't be copied. Stencil and readback together need
+    /// [`wgpu::Features::DEPTH32FLOAT_STENCIL8`].
+    pub fn choose_depth_format(
+        requirements: DepthRequirements,
+        features: wgpu::Features,
+    ) -> Result<wgpu::TextureFormat> {
+        use wgpu::TextureFormat::*;
+        let format = match (requirements.stencil, requirements.readback) {
+            (false, false) => Depth24Plus,
+            (true, false) => Depth24PlusStencil8,
+            (false, true) => Depth32Float,
+            (true, true) => Depth32FloatStencil8,
+        };
+        let missing = format.required_features() - features;
+        if !missing.is_empty() {
+            bail!("{requirements:?} needs {format:?}, which needs the missing {missing:?}");
+        }
+        Ok(format)
+    }
+
     pub fn create_depth_texture(
         device: &wgpu::Device,
         config: &wgpu::SurfaceConfiguration,
+        sample_count: u32,
+        label: &str,
+    ) -> Self {
+        Self::create_depth_texture_with_format(
+            device,
+            config,
+            sample_count,
+            Self::DEPTH_FORMAT,
+            wgpu::TextureUsages::empty(),
+            label,
+        )
+    }
+
+    /// Depth texture in `format` with `usage` on top of render attachment
+    /// and texture binding, see [`Texture::choose_depth_format`].
+    pub fn create_depth_texture_with_format(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        sample_count: u32,
+        format: wgpu::TextureFormat,
+        usage: wgpu::TextureUsages,
         label: &str,
     ) -> Self {
         let size = wgpu::Extent3d {
@@ -264,10 +1060,11 @@ impl Texture {
             label: Some(label),
             size,
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
-            format: Self::DEPTH_FORMAT,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT // 3.
+            format,
+            usage: usage
+                | wgpu::TextureUsages::RENDER_ATTACHMENT // 3.
                 | wgpu::TextureUsages::TEXTURE_BINDING,
             view_formats: &[],
         };
@@ -283,6 +1080,10 @@ impl Texture {
             min_filter: wgpu::FilterMode::Linear,
             mipmap_filter: wgpu::FilterMode::Nearest,
             compare: Some(wgpu::CompareFunction::LessEqual), // 5.
+            // There's no LOD bias to soften comparisons with: wgpu samplers
+            // don't have one (WebGPU has no mipLodBias), comparison sampling
+            // in WGSL only reads level 0 and this texture has a single mip.
+            // Softer edges have to come from filtering taps in the shader.
             lod_min_clamp: 0.0,
             lod_max_clamp: 100.0,
             ..Default::default()
@@ -297,6 +1098,52 @@ impl Texture {
     }
 }
 
+/// Depth buffer sized to the surface, recreated whenever the surface is.
+pub struct DepthTexture {
+    texture: Texture,
+}
+
+impl DepthTexture {
+    /// A depth buffer in the format [`Texture::choose_depth_format`] picks
+    /// for `requirements` on `device`, copyable when readback is required.
+    /// Pipelines drawing into it have to use [`DepthTexture::format`].
+    pub fn with_requirements(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        sample_count: u32,
+        requirements: DepthRequirements,
+        label: &str,
+    ) -> Result<Self> {
+        let format = Texture::choose_depth_format(requirements, device.features())?;
+        let usage = if requirements.readback {
+            wgpu::TextureUsages::COPY_SRC
+        } else {
+            wgpu::TextureUsages::empty()
+        };
+        let texture = Texture::create_depth_texture_with_format(
+            device,
+            config,
+            sample_count,
+            format,
+            usage,
+            label,
+        );
+        Ok(Self { texture })
+    }
+
+    pub fn format(&self) -> wgpu::TextureFormat {
+        self.texture.texture.format()
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) {
+        self.texture.resize(device, config.width, config.height);
+    }
+
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.texture.view
+    }
+}
+
 pub struct CubeTexture {
     texture: wgpu::Texture,
     sampler: wgpu::Sampler,
@@ -367,3 +1214,275 @@ impl CubeTexture {
         &self.sampler
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mip_level_count() {
+        assert_eq!(Texture::mip_level_count(1, 1), 1);
+        assert_eq!(Texture::mip_level_count(256, 256), 9);
+        assert_eq!(Texture::mip_level_count(256, 16), 9);
+        // Non power of two sizes round down: 300 -> 150 -> ... -> 2 -> 1.
+        assert_eq!(Texture::mip_level_count(300, 200), 9);
+    }
+
+    #[test]
+    fn test_sampler_desc_validation() {
+        assert!(SamplerDesc::default().validate().is_ok());
+        assert!(SamplerDesc::repeat().validate().is_ok());
+        assert!(SamplerDesc::repeat().anisotropic(16).validate().is_ok());
+
+        let nearest_anisotropic = SamplerDesc {
+            mag_filter: wgpu::FilterMode::Nearest,
+            ..SamplerDesc::repeat().anisotropic(16)
+        };
+        assert!(nearest_anisotropic.validate().is_err());
+        let zero = SamplerDesc {
+            anisotropy_clamp: 0,
+            ..SamplerDesc::default()
+        };
+        assert!(zero.validate().is_err());
+
+        let Some(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)).ok() else {
+            eprintln!("No adapter, skipping sampler test");
+            return;
+        };
+        let texture = Texture::from_color(&gpu.device, &gpu.queue, [255; 4], "white").unwrap();
+        assert!(texture
+            .with_sampler(&gpu.device, &nearest_anisotropic)
+            .is_err());
+    }
+
+    #[test]
+    fn test_memory_size() {
+        use wgpu::{TextureDimension::*, TextureFormat::*};
+        let size = |width, height, depth_or_array_layers| wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers,
+        };
+
+        // 4x4 blocks of 8 bytes, down to the 4x4, 2x2 and 1x1 mips each
+        // taking a whole block.
+        let blocks: u64 = [64, 32, 16, 8, 4, 2, 1, 1, 1].iter().map(|n| n * n).sum();
+        assert_eq!(
+            Texture::memory_size_of(
+                Bc1RgbaUnorm,
+                size(256, 256, 1),
+                D2,
+                Texture::mip_level_count(256, 256),
+                1
+            ),
+            blocks * 8
+        );
+        // Array layers don't shrink with the mips, 3D depth does.
+        assert_eq!(
+            Texture::memory_size_of(Rgba8Unorm, size(4, 4, 6), D2, 2, 1),
+            (16 + 4) * 6 * 4
+        );
+        assert_eq!(
+            Texture::memory_size_of(Rgba8Unorm, size(4, 4, 4), D3, 2, 1),
+            (64 + 8) * 4
+        );
+        assert_eq!(
+            Texture::memory_size_of(Depth24PlusStencil8, size(2, 2, 1), D2, 1, 4),
+            4 * 5 * 4
+        );
+    }
+
+    #[test]
+    fn test_choose_depth_format() {
+        use wgpu::{Features, TextureFormat::*};
+        let requirements = |stencil, readback| DepthRequirements { stencil, readback };
+        let choose = |stencil, readback, features| {
+            Texture::choose_depth_format(requirements(stencil, readback), features).ok()
+        };
+        assert_eq!(choose(false, false, Features::empty()), Some(Depth24Plus));
+        assert_eq!(
+            choose(true, false, Features::empty()),
+            Some(Depth24PlusStencil8)
+        );
+        assert_eq!(choose(false, true, Features::empty()), Some(Depth32Float));
+
+        // The depth of Depth24PlusStencil8 can't be copied, so stencil and
+        // readback need the 32 bit format and its feature.
+        assert_eq!(choose(true, true, Features::empty()), None);
+        let format = choose(true, true, Features::DEPTH32FLOAT_STENCIL8).unwrap();
+        assert!(format.has_stencil_aspect());
+        assert_eq!(
+            format.aspect_specific_format(wgpu::TextureAspect::DepthOnly),
+            Some(Depth32Float)
+        );
+
+        let Some(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)).ok() else {
+            eprintln!("No adapter, skipping depth format test");
+            return;
+        };
+        let requirements = requirements(true, gpu.supports(Features::DEPTH32FLOAT_STENCIL8));
+        let depth = DepthTexture::with_requirements(
+            &gpu.device,
+            &gpu.get_config(),
+            1,
+            requirements,
+            "depth",
+        )
+        .unwrap();
+        assert!(depth.format().has_stencil_aspect());
+        assert_eq!(
+            depth
+                .texture
+                .texture
+                .usage()
+                .contains(wgpu::TextureUsages::COPY_SRC),
+            requirements.readback
+        );
+    }
+
+    #[test]
+    fn test_render_target_resize() {
+        let Some(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)).ok() else {
+            eprintln!("No adapter, skipping render target resize test");
+            return;
+        };
+        let format = wgpu::TextureFormat::Rgba16Float;
+        let mut target = Texture::create_render_target(
+            &gpu.device,
+            0,
+            0,
+            format,
+            wgpu::TextureUsages::TEXTURE_BINDING,
+        );
+        assert_eq!((target.size.width, target.size.height), (1, 1));
+
+        let sampler = target.sampler.global_id();
+        target.resize(&gpu.device, 8, 6);
+        assert_eq!((target.size.width, target.size.height), (8, 6));
+        assert_eq!((target.texture.width(), target.texture.height()), (8, 6));
+        assert_eq!(target.texture.format(), format);
+        assert_eq!(
+            target.texture.usage(),
+            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT
+        );
+        assert_eq!(target.sampler.global_id(), sampler);
+
+        // A minimized window.
+        target.resize(&gpu.device, 0, 0);
+        assert_eq!((target.texture.width(), target.texture.height()), (1, 1));
+    }
+
+    #[test]
+    fn test_sample_level_reads_pinned_mip() -> anyhow::Result<()> {
+        let Some(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)).ok() else {
+            eprintln!("No adapter, skipping sample level test");
+            return Ok(());
+        };
+        let device = &gpu.device;
+        // Every mip of the 4x4 texture is a solid color of its own.
+        let colors = [[255u8, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]];
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: 4,
+                height: 4,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 3,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        for (level, color) in colors.iter().enumerate() {
+            let size = 4 >> level;
+            gpu.queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &texture,
+                    mip_level: level as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                &color.repeat(size * size),
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * size as u32),
+                    rows_per_image: None,
+                },
+                wgpu::Extent3d {
+                    width: size as u32,
+                    height: size as u32,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        let source = concat!(
+            include_str!("sample_level.wgsl"),
+            "
+            @group(0) @binding(0) var t: texture_2d<f32>;
+            @group(0) @binding(1) var s: sampler;
+            @group(0) @binding(2) var<storage, read_write> colors: array<vec4<f32>, 3>;
+
+            @compute @workgroup_size(3)
+            fn main(@builtin(local_invocation_index) level: u32) {
+                colors[level] = sample_level(t, s, vec2<f32>(0.5), f32(level));
+            }
+            "
+        );
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: None,
+            module: &shader,
+            entry_point: "main",
+        });
+        let output = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: 3 * 16,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let view = texture.create_view(&Default::default());
+        let sampler = Texture::create_level_sampler(device);
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: output.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut encoder = device.create_command_encoder(&Default::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&Default::default());
+            pass.set_pipeline(&pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(1, 1, 1);
+        }
+        gpu.queue.submit([encoder.finish()]);
+
+        let bytes = gpu.read_buffer(&output)?;
+        let sampled: &[[f32; 4]] = bytemuck::cast_slice(&bytes);
+        for (sampled, color) in sampled.iter().zip(colors) {
+            let expected = color.map(|channel| channel as f32 / 255.0);
+            assert_eq!(*sampled, expected);
+        }
+        Ok(())
+    }
+}
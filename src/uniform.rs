@@ -0,0 +1,266 @@
+use std::{marker::PhantomData, mem, num::NonZeroU64, time::Duration};
+
+use anyhow::*;
+use wgpu::DynamicOffset;
+
+use crate::gpu::Gpu;
+
+/// Size of `size` rounded up to `alignment`, a power of two.
+fn aligned_stride(size: usize, alignment: u32) -> u64 {
+    (size as u64).next_multiple_of(alignment as u64)
+}
+
+/// Fails unless every offset is a multiple of `alignment`, which wgpu only
+/// reports once the pass is validated.
+pub fn check_dynamic_offsets(offsets: &[DynamicOffset], alignment: u32) -> Result<()> {
+    if let Some(offset) = offsets.iter().find(|offset| *offset % alignment != 0) {
+        bail!("Dynamic offset {offset} is not a multiple of {alignment}");
+    }
+    Ok(())
+}
+
+/// One uniform buffer holding a `T` per draw, each bound by passing
+/// [`DynamicUniformBuffer::offset`] to `set_bind_group` instead of creating a
+/// bind group per object.
+pub struct DynamicUniformBuffer<T> {
+    buffer: wgpu::Buffer,
+    stride: u64,
+    alignment: u32,
+    capacity: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: bytemuck::Pod> DynamicUniformBuffer<T> {
+    pub fn new(gpu: &Gpu, label: Option<&str>, capacity: usize) -> Self {
+        let alignment = gpu.device.limits().min_uniform_buffer_offset_alignment;
+        let stride = aligned_stride(mem::size_of::<T>(), alignment);
+        let buffer = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label,
+            size: stride * capacity.max(1) as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            buffer,
+            stride,
+            alignment,
+            capacity,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Layout entry for the buffer, a single `T` seen by the shader.
+    pub fn layout_entry(
+        binding: u32,
+        visibility: wgpu::ShaderStages,
+    ) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: true,
+                min_binding_size: NonZeroU64::new(mem::size_of::<T>() as u64),
+            },
+            count: None,
+        }
+    }
+
+    /// The first `T`, the dynamic offset picks which one a draw sees.
+    pub fn binding(&self) -> wgpu::BindingResource<'_> {
+        wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+            buffer: &self.buffer,
+            offset: 0,
+            size: NonZeroU64::new(mem::size_of::<T>() as u64),
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Dynamic offset of the `index`th value.
+    pub fn offset(&self, index: usize) -> DynamicOffset {
+        (self.stride * index as u64) as DynamicOffset
+    }
+
+    /// Dynamic offsets for `indices`, checked against the device alignment.
+    pub fn offsets(&self, indices: &[usize]) -> Result<Vec<DynamicOffset>> {
+        if let Some(index) = indices.iter().find(|index| **index >= self.capacity) {
+            bail!(
+                "Index {index} is past the {} values in the buffer",
+                self.capacity
+            );
+        }
+        let offsets = indices
+            .iter()
+            .map(|index| self.offset(*index))
+            .collect::<Vec<_>>();
+        check_dynamic_offsets(&offsets, self.alignment)?;
+        Ok(offsets)
+    }
+
+    /// Uploads `values`, each at its own aligned offset.
+    pub fn write(&self, queue: &wgpu::Queue, values: &[T]) -> Result<()> {
+        if values.len() > self.capacity {
+            bail!(
+                "{} values don't fit the {} the buffer was created for",
+                values.len(),
+                self.capacity
+            );
+        }
+        queue.write_buffer(&self.buffer, 0, &pack(values, self.stride));
+        Ok(())
+    }
+}
+
+fn pack<T: bytemuck::Pod>(values: &[T], stride: u64) -> Vec<u8> {
+    let mut bytes = vec![0; stride as usize * values.len()];
+    for (chunk, value) in bytes.chunks_mut(stride as usize).zip(values) {
+        chunk[..mem::size_of::<T>()].copy_from_slice(bytemuck::bytes_of(value));
+    }
+    bytes
+}
+
+/// Values that change every frame, for shaders varying effects such as
+/// dithering or noise over time. Bound at [`FrameUniform::BINDING`] of the
+/// camera bind group, so every pass using the camera can read it:
+///
+/// ```wgsl
+/// struct Frame { index: u32, time: f32, delta_time: f32, seed: u32 }
+/// @group(N) @binding(1) var<uniform> frame: Frame;
+/// ```
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct FrameUniform {
+    /// Frames advanced so far, wrapping around.
+    pub index: u32,
+    /// Seconds since the first frame.
+    pub time: f32,
+    /// Seconds since the previous frame.
+    pub delta_time: f32,
+    /// Random bits, different each frame but the same for every invocation.
+    pub seed: u32,
+}
+
+impl FrameUniform {
+    /// Binding in the camera bind group, the camera itself is at 0.
+    pub const BINDING: u32 = 1;
+
+    /// The values of the frame `delta` after this one.
+    pub fn next(&self, delta: Duration) -> Self {
+        let index = self.index.wrapping_add(1);
+        Self {
+            index,
+            time: self.time + delta.as_secs_f32(),
+            delta_time: delta.as_secs_f32(),
+            seed: pcg_hash(index),
+        }
+    }
+
+    pub fn layout_entry(visibility: wgpu::ShaderStages) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding: Self::BINDING,
+            visibility,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: NonZeroU64::new(mem::size_of::<Self>() as u64),
+            },
+            count: None,
+        }
+    }
+}
+
+/// The PCG hash, which spreads consecutive integers over all 32 bits.
+fn pcg_hash(value: u32) -> u32 {
+    let state = value.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+/// A [`FrameUniform`] and the buffer it's uploaded to.
+pub struct FrameUniformBuffer {
+    uniform: FrameUniform,
+    buffer: wgpu::Buffer,
+}
+
+impl FrameUniformBuffer {
+    pub fn new(gpu: &Gpu) -> Self {
+        let uniform = FrameUniform::default();
+        let buffer = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("FrameUniformBuffer::buffer"),
+            size: mem::size_of::<FrameUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        // Buffers start out zeroed, which is the default uniform.
+        Self { uniform, buffer }
+    }
+
+    /// Moves on to the frame `delta` after the current one and stages the
+    /// upload, see [`Gpu::write_uniform`].
+    pub fn advance(&mut self, gpu: &Gpu, delta: Duration) {
+        self.uniform = self.uniform.next(delta);
+        gpu.write_uniform(&self.buffer, 0, &self.uniform);
+    }
+
+    pub fn uniform(&self) -> &FrameUniform {
+        &self.uniform
+    }
+
+    pub fn binding(&self) -> wgpu::BindingResource<'_> {
+        self.buffer.as_entire_binding()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_values_are_packed_at_aligned_offsets() {
+        let stride = aligned_stride(mem::size_of::<[f32; 4]>(), 256);
+        assert_eq!(stride, 256);
+
+        let bytes = pack(&[[1.0f32; 4], [2.0; 4]], stride);
+        assert_eq!(bytes.len(), 512);
+        let second: &[f32] = bytemuck::cast_slice(&bytes[256..272]);
+        assert_eq!(second, [2.0; 4]);
+
+        assert!(check_dynamic_offsets(&[0, 256, 512], 256).is_ok());
+        assert!(check_dynamic_offsets(&[0, 16], 256).is_err());
+    }
+
+    #[test]
+    fn test_frame_uniform_advances() -> Result<()> {
+        let Some(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)).ok() else {
+            eprintln!("No adapter, skipping frame uniform test");
+            return Ok(());
+        };
+        let mut frame = FrameUniformBuffer::new(&gpu);
+        let read = |frame: &FrameUniformBuffer| -> Result<FrameUniform> {
+            Ok(*bytemuck::from_bytes(&gpu.read_buffer(&frame.buffer)?))
+        };
+        assert_eq!(read(&frame)?, FrameUniform::default());
+
+        frame.advance(&gpu, Duration::from_millis(250));
+        gpu.finish();
+        let first = read(&frame)?;
+        assert_eq!(first, *frame.uniform());
+        assert_eq!((first.index, first.time, first.delta_time), (1, 0.25, 0.25));
+
+        frame.advance(&gpu, Duration::from_millis(500));
+        gpu.finish();
+        let second = read(&frame)?;
+        assert_eq!(
+            (second.index, second.time, second.delta_time),
+            (2, 0.75, 0.5)
+        );
+        assert_ne!(first.seed, second.seed);
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use crate::gpu::Gpu;
+
+enum Destination {
+    Buffer {
+        buffer: Arc<wgpu::Buffer>,
+        offset: wgpu::BufferAddress,
+    },
+    Texture {
+        texture: Arc<wgpu::Texture>,
+        mip_level: u32,
+        origin: wgpu::Origin3d,
+        layout: wgpu::ImageDataLayout,
+        size: wgpu::Extent3d,
+    },
+}
+
+struct Upload {
+    destination: Destination,
+    data: Vec<u8>,
+}
+
+/// Queues buffer and texture uploads and writes at most `budget` bytes of
+/// them each frame, so loading many assets at once doesn't stall a frame.
+///
+/// Uploads aren't split, one larger than the budget takes a frame on its own.
+pub struct UploadScheduler {
+    queue: VecDeque<Upload>,
+    budget: u64,
+}
+
+impl UploadScheduler {
+    /// Bytes per frame when no budget is given.
+    pub const DEFAULT_BUDGET: u64 = 4 * 1024 * 1024;
+
+    pub fn new(budget: u64) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            budget,
+        }
+    }
+
+    pub fn budget(&self) -> u64 {
+        self.budget
+    }
+
+    pub fn set_budget(&mut self, budget: u64) {
+        self.budget = budget;
+    }
+
+    /// Queues writing `data` into `buffer` at `offset`.
+    pub fn write_buffer(
+        &mut self,
+        buffer: Arc<wgpu::Buffer>,
+        offset: wgpu::BufferAddress,
+        data: Vec<u8>,
+    ) {
+        self.queue.push_back(Upload {
+            destination: Destination::Buffer { buffer, offset },
+            data,
+        });
+    }
+
+    /// Queues writing `data` laid out as `layout` into the `size` region of
+    /// `texture`'s `mip_level` at `origin`.
+    pub fn write_texture(
+        &mut self,
+        texture: Arc<wgpu::Texture>,
+        mip_level: u32,
+        origin: wgpu::Origin3d,
+        layout: wgpu::ImageDataLayout,
+        size: wgpu::Extent3d,
+        data: Vec<u8>,
+    ) {
+        self.queue.push_back(Upload {
+            destination: Destination::Texture {
+                texture,
+                mip_level,
+                origin,
+                layout,
+                size,
+            },
+            data,
+        });
+    }
+
+    /// Bytes still waiting for a flush.
+    pub fn pending_bytes(&self) -> u64 {
+        self.queue
+            .iter()
+            .map(|upload| upload.data.len() as u64)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Writes queued uploads in order until the next one would go over the
+    /// budget, returning how many bytes were written. Called once per frame.
+    pub fn flush(&mut self, gpu: &Gpu) -> u64 {
+        let mut written = 0;
+        while let Some(upload) = self.queue.front() {
+            let size = upload.data.len() as u64;
+            if written > 0 && written + size > self.budget {
+                break;
+            }
+            let upload = self.queue.pop_front().unwrap();
+            match upload.destination {
+                Destination::Buffer { buffer, offset } => {
+                    gpu.queue.write_buffer(&buffer, offset, &upload.data);
+                }
+                Destination::Texture {
+                    texture,
+                    mip_level,
+                    origin,
+                    layout,
+                    size,
+                } => gpu.queue.write_texture(
+                    wgpu::ImageCopyTexture {
+                        texture: &texture,
+                        mip_level,
+                        origin,
+                        aspect: wgpu::TextureAspect::All,
+                    },
+                    &upload.data,
+                    layout,
+                    size,
+                ),
+            }
+            written += size;
+        }
+        written
+    }
+}
+
+impl Default for UploadScheduler {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_BUDGET)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    #[test]
+    fn test_uploads_are_spread_over_frames() {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping upload scheduler test");
+            return;
+        };
+        let buffer = Arc::new(gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: 10 * MB,
+            usage: wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }));
+
+        let mut uploads = UploadScheduler::new(2 * MB);
+        for i in 0..10 {
+            uploads.write_buffer(buffer.clone(), i * MB, vec![i as u8; MB as usize]);
+        }
+        assert_eq!(uploads.pending_bytes(), 10 * MB);
+
+        let mut frames = 0;
+        while !uploads.is_empty() {
+            assert!(uploads.flush(&gpu) <= 2 * MB);
+            gpu.queue.submit([]);
+            frames += 1;
+        }
+        assert_eq!(frames, 5);
+    }
+
+    #[test]
+    fn test_upload_over_budget_gets_its_own_frame() {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping upload scheduler test");
+            return;
+        };
+        let buffer = Arc::new(gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: 4096,
+            usage: wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }));
+
+        let mut uploads = UploadScheduler::new(1024);
+        uploads.write_buffer(buffer.clone(), 0, vec![0; 2048]);
+        uploads.write_buffer(buffer, 2048, vec![0; 512]);
+        assert_eq!(uploads.flush(&gpu), 2048);
+        assert_eq!(uploads.flush(&gpu), 512);
+        assert_eq!(uploads.flush(&gpu), 0);
+    }
+}
@@ -0,0 +1,238 @@
+/// Part of a render target the scene is drawn to, in pixels from the top
+/// left, and the depth range it maps to. Two viewports side by side and a
+/// camera each make a split screen.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Viewport {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub min_depth: f32,
+    pub max_depth: f32,
+}
+
+impl Viewport {
+    pub fn new(x: f32, y: f32, width: f32, height: f32, min_depth: f32, max_depth: f32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+            min_depth,
+            max_depth,
+        }
+    }
+
+    /// `self` with its area multiplied by `scale`, for targets rendered at
+    /// a fraction of the surface's resolution.
+    pub fn scaled(&self, scale: f32) -> Self {
+        Self {
+            x: self.x * scale,
+            y: self.y * scale,
+            width: self.width * scale,
+            height: self.height * scale,
+            ..*self
+        }
+    }
+
+    /// The part of `self` inside a `width` by `height` target with depths
+    /// clamped to `0..=1`, which wgpu fails validation on otherwise. `None`
+    /// when nothing of it is inside.
+    pub fn clamped(&self, width: u32, height: u32) -> Option<Self> {
+        let x = self.x.clamp(0.0, width as f32);
+        let y = self.y.clamp(0.0, height as f32);
+        let right = (self.x + self.width).clamp(x, width as f32);
+        let bottom = (self.y + self.height).clamp(y, height as f32);
+        if right <= x || bottom <= y {
+            return None;
+        }
+        let min_depth = self.min_depth.clamp(0.0, 1.0);
+        Some(Self {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+            min_depth,
+            max_depth: self.max_depth.clamp(min_depth, 1.0),
+        })
+    }
+
+    pub fn apply(&self, render_pass: &mut wgpu::RenderPass) {
+        render_pass.set_viewport(
+            self.x,
+            self.y,
+            self.width,
+            self.height,
+            self.min_depth,
+            self.max_depth,
+        );
+    }
+}
+
+/// Part of a render target fragments are kept in, in pixels from the top
+/// left, e.g. to clip drawing to a UI region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScissorRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ScissorRect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// `self` with its area multiplied by `scale`, rounded outwards.
+    pub fn scaled(&self, scale: f32) -> Self {
+        let x = (self.x as f32 * scale).floor() as u32;
+        let y = (self.y as f32 * scale).floor() as u32;
+        let right = (self.x.saturating_add(self.width) as f32 * scale).ceil() as u32;
+        let bottom = (self.y.saturating_add(self.height) as f32 * scale).ceil() as u32;
+        Self::new(x, y, right - x, bottom - y)
+    }
+
+    /// The part of `self` inside a `width` by `height` target, as wgpu fails
+    /// validation on rects reaching outside it. Empty when nothing is left,
+    /// which keeps every fragment out.
+    pub fn clamped(&self, width: u32, height: u32) -> Self {
+        let x = self.x.min(width);
+        let y = self.y.min(height);
+        let right = self.x.saturating_add(self.width).min(width);
+        let bottom = self.y.saturating_add(self.height).min(height);
+        Self::new(x, y, right - x, bottom - y)
+    }
+
+    pub fn apply(&self, render_pass: &mut wgpu::RenderPass) {
+        render_pass.set_scissor_rect(self.x, self.y, self.width, self.height);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gpu::Gpu, texture};
+
+    #[test]
+    fn test_clamped_to_the_target() {
+        let viewport = Viewport::new(-10.0, 50.0, 100.0, 100.0, -1.0, 2.0);
+        assert_eq!(
+            viewport.clamped(64, 64),
+            Some(Viewport::new(0.0, 50.0, 64.0, 14.0, 0.0, 1.0))
+        );
+        assert_eq!(viewport.scaled(0.5).x, -5.0);
+        assert_eq!(
+            Viewport::new(70.0, 0.0, 10.0, 10.0, 0.0, 1.0).clamped(64, 64),
+            None
+        );
+
+        let scissor = ScissorRect::new(32, 60, 100, 100);
+        assert_eq!(scissor.clamped(64, 64), ScissorRect::new(32, 60, 32, 4));
+        assert_eq!(
+            ScissorRect::new(70, 0, 10, 10).clamped(64, 64),
+            ScissorRect::new(64, 0, 0, 10)
+        );
+        assert_eq!(
+            ScissorRect::new(1, 1, 1, 1).scaled(0.5),
+            ScissorRect::new(0, 0, 1, 1)
+        );
+    }
+
+    #[test]
+    fn test_draws_stay_inside_the_rects() -> anyhow::Result<()> {
+        let Ok(gpu) = futures::executor::block_on(Gpu::new_headless(4, 4)) else {
+            eprintln!("No adapter, skipping viewport test");
+            return Ok(());
+        };
+        let device = &gpu.device;
+        let format = wgpu::TextureFormat::Rgba8Unorm;
+        let target = texture::Texture::create_render_target(
+            device,
+            4,
+            4,
+            format,
+            wgpu::TextureUsages::COPY_SRC,
+        );
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(
+                "
+                @vertex
+                fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+                    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+                    return vec4<f32>(uv * 4.0 - 1.0, 0.0, 1.0);
+                }
+
+                @fragment
+                fn fs_main() -> @location(0) vec4<f32> {
+                    return vec4<f32>(1.0, 0.0, 0.0, 1.0);
+                }
+                "
+                .into(),
+            ),
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: None,
+            layout: None,
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(format.into())],
+            }),
+            primitive: Default::default(),
+            depth_stencil: None,
+            multisample: Default::default(),
+            multiview: None,
+        });
+
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let mut encoder = device.create_command_encoder(&Default::default());
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &target.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        // The right half, the scissor cuts it down to the bottom right
+        // quarter. Both reach past the target unclamped.
+        Viewport::new(2.0, 0.0, 10.0, 4.0, 0.0, 1.0)
+            .clamped(4, 4)
+            .unwrap()
+            .apply(&mut pass);
+        ScissorRect::new(0, 2, 10, 10)
+            .clamped(4, 4)
+            .apply(&mut pass);
+        pass.set_pipeline(&pipeline);
+        pass.draw(0..3, 0..1);
+        drop(pass);
+        gpu.queue.submit([encoder.finish()]);
+        assert!(futures::executor::block_on(device.pop_error_scope()).is_none());
+
+        let frame = gpu.read_texture(&target.texture)?;
+        for (i, pixel) in frame.pixels.chunks(4).enumerate() {
+            let (x, y) = (i % 4, i / 4);
+            let red = if x >= 2 && y >= 2 { 255 } else { 0 };
+            assert_eq!(pixel, [red, 0, 0, 255], "pixel ({x}, {y})");
+        }
+        Ok(())
+    }
+}